@@ -1,7 +1,36 @@
 use anyhow::{anyhow, Result};
+use chrono::NaiveDate;
 use regex::Regex;
 use url::Url;
 
+/// The paper was withdrawn: arXiv serves a withdrawal notice instead of its source.
+#[derive(Debug)]
+pub struct Withdrawn {
+    pub paper_id: String,
+}
+
+impl std::fmt::Display for Withdrawn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} has been withdrawn from arXiv", self.paper_id)
+    }
+}
+
+impl std::error::Error for Withdrawn {}
+
+/// Most words a withdrawal notice has; longer texts mentioning a withdrawal are
+/// real papers.
+const MAX_NOTICE_WORDS: usize = 300;
+
+/// Whether `text` (a TeX source or an arXiv page) is a withdrawal notice, e.g.
+/// "This paper has been withdrawn by the author due to an error in Lemma 2."
+pub fn is_withdrawal_notice(text: &str, is_html: bool) -> bool {
+    let re = Regex::new(
+        r"(?i)\b(?:paper|article|submission|manuscript|preprint)\s+(?:has\s+been|was|is)\s+withdrawn\b",
+    )
+    .unwrap();
+    re.is_match(text) && (is_html || text.split_whitespace().count() <= MAX_NOTICE_WORDS)
+}
+
 #[derive(Debug, Clone)]
 pub struct ArxivUrl {
     pub paper_id: String,
@@ -9,11 +38,20 @@ pub struct ArxivUrl {
 }
 
 impl ArxivUrl {
+    /// Accepts an arXiv URL, an arXiv DOI (`10.48550/arXiv.2401.08027`, optionally
+    /// prefixed with `https://doi.org/`), or a bare paper ID.
     pub fn parse(url: &str) -> Result<Self> {
-        let url = Url::parse(url)?;
+        let input = url.trim();
+
+        let paper_id = match extract_doi_id(input).or_else(|| extract_bare_id(input)) {
+            Some(paper_id) => paper_id,
+            None => {
+                let url = Url::parse(input)?;
 
-        // Extract paper ID from URL
-        let paper_id = extract_paper_id(url.as_str())?;
+                // Extract paper ID from URL
+                extract_paper_id(url.as_str())?
+            }
+        };
 
         Ok(ArxivUrl {
             paper_id: paper_id.clone(),
@@ -24,6 +62,51 @@ impl ArxivUrl {
     pub fn paper_id(&self) -> &str {
         &self.paper_id
     }
+
+    /// The paper's HTML rendering, `html/<id>v<version>` next to `src/<id>`; without
+    /// a version arXiv serves the latest.
+    pub fn html_url(&self, version: Option<u32>) -> String {
+        let base = self
+            .src_url
+            .rsplit_once("/src/")
+            .map_or("https://arxiv.org", |(base, _)| base);
+        match version.filter(|_| version_of(&self.paper_id).is_none()) {
+            Some(version) => format!("{base}/html/{}v{version}", self.paper_id),
+            None => format!("{base}/html/{}", self.paper_id),
+        }
+    }
+}
+
+/// New-style (`2401.08027v2`) or old-style (`hep-th/9901001`, `math.GT/0309136`) ID.
+const PAPER_ID_PATTERN: &str =
+    r"[0-9]{4}\.[0-9]{4,5}(?:v[0-9]+)?|[a-zA-Z-]+(?:\.[A-Za-z]{2})?/[0-9]{7}(?:v[0-9]+)?";
+
+fn extract_doi_id(input: &str) -> Option<String> {
+    let re = Regex::new(&format!(
+        r"(?i)^(?:https?://(?:dx\.)?doi\.org/|doi:)?10\.48550/arxiv\.({PAPER_ID_PATTERN})$"
+    ))
+    .unwrap();
+    re.captures(input).map(|caps| caps[1].to_string())
+}
+
+fn extract_bare_id(input: &str) -> Option<String> {
+    let re = Regex::new(&format!(r"^(?:arXiv:)?({PAPER_ID_PATTERN})$")).unwrap();
+    re.captures(input).map(|caps| caps[1].to_string())
+}
+
+/// Drop a trailing version suffix, e.g. `2401.08027v2` -> `2401.08027`.
+pub fn strip_version(paper_id: &str) -> &str {
+    match paper_id.rsplit_once('v') {
+        Some((base, version))
+            if !base.is_empty()
+                && !version.is_empty()
+                && version.bytes().all(|b| b.is_ascii_digit())
+                && base.ends_with(|c: char| c.is_ascii_digit()) =>
+        {
+            base
+        }
+        _ => paper_id,
+    }
 }
 
 fn extract_paper_id(url: &str) -> Result<String> {
@@ -45,3 +128,252 @@ fn extract_paper_id(url: &str) -> Result<String> {
 
     Err(anyhow!("Invalid arXiv URL format: {}", url))
 }
+
+/// A single entry from an arXiv API (Atom) search feed.
+#[derive(Debug, Clone)]
+pub struct ArxivEntry {
+    pub paper_id: String,
+    pub title: String,
+    /// The abstract as listed on arXiv
+    pub summary: String,
+    pub published: NaiveDate,
+}
+
+impl ArxivEntry {
+    pub fn abs_url(&self) -> String {
+        format!("https://arxiv.org/abs/{}", self.paper_id)
+    }
+}
+
+/// Query the arXiv API and return the matching entries, newest submissions first.
+pub async fn search(query: &str, max_results: usize) -> Result<Vec<ArxivEntry>> {
+    query_api(&[
+        ("search_query", query),
+        ("start", "0"),
+        ("max_results", &max_results.to_string()),
+        ("sortBy", "submittedDate"),
+        ("sortOrder", "descending"),
+    ])
+    .await
+}
+
+/// The version number in an ID such as `2401.08027v2`, if it has one.
+pub fn version_of(paper_id: &str) -> Option<u32> {
+    let base = strip_version(paper_id);
+    paper_id[base.len()..].strip_prefix('v')?.parse().ok()
+}
+
+/// The year a paper was submitted, from the YYMM its ID starts with
+/// (`2401.08027`, `hep-th/9901001`).
+pub fn submission_year(paper_id: &str) -> Option<i32> {
+    // Old-style IDs may have had their slash replaced when used as a directory name
+    let number = paper_id.rsplit(['/', '_']).next()?;
+    let yy: i32 = number.get(..2)?.parse().ok()?;
+    // Old-style IDs start in 1991
+    Some(if yy >= 91 { 1900 + yy } else { 2000 + yy })
+}
+
+/// The newest version of a paper listed by the arXiv API.
+pub async fn latest_version(paper_id: &str) -> Result<Option<u32>> {
+    let entry = fetch_entry(strip_version(paper_id)).await?;
+    Ok(entry.and_then(|e| version_of(&e.paper_id)))
+}
+
+/// Look up a single paper's metadata, or `None` if arXiv doesn't know the ID.
+pub async fn fetch_entry(paper_id: &str) -> Result<Option<ArxivEntry>> {
+    let entries = query_api(&[("id_list", paper_id)]).await?;
+    Ok(entries.into_iter().next())
+}
+
+async fn query_api(params: &[(&str, &str)]) -> Result<Vec<ArxivEntry>> {
+    let url = Url::parse_with_params("http://export.arxiv.org/api/query", params)?;
+
+    let response = reqwest::get(url).await?;
+    if !response.status().is_success() {
+        return Err(anyhow!(
+            "arXiv API request failed: HTTP {}",
+            response.status()
+        ));
+    }
+
+    parse_feed(&response.text().await?)
+}
+
+/// Parse the entries out of an arXiv Atom feed.
+pub fn parse_feed(xml: &str) -> Result<Vec<ArxivEntry>> {
+    let entry_re = Regex::new(r"(?s)<entry>(.*?)</entry>")?;
+    let id_re = Regex::new(r"<id>([^<]*)</id>")?;
+    let title_re = Regex::new(r"(?s)<title>(.*?)</title>")?;
+    let summary_re = Regex::new(r"(?s)<summary>(.*?)</summary>")?;
+    let published_re = Regex::new(r"<published>(\d{4}-\d{2}-\d{2})")?;
+
+    let mut entries = Vec::new();
+    for caps in entry_re.captures_iter(xml) {
+        let body = &caps[1];
+
+        let Some(id) = id_re.captures(body) else {
+            continue;
+        };
+        let paper_id = extract_paper_id(id[1].trim())?;
+
+        let collapse = |re: &Regex| {
+            re.captures(body)
+                .map(|c| c[1].split_whitespace().collect::<Vec<_>>().join(" "))
+                .unwrap_or_default()
+        };
+        let title = collapse(&title_re);
+        let summary = collapse(&summary_re);
+
+        let published = published_re
+            .captures(body)
+            .ok_or_else(|| anyhow!("Feed entry {} has no submission date", paper_id))?;
+        let published = NaiveDate::parse_from_str(&published[1], "%Y-%m-%d")?;
+
+        entries.push(ArxivEntry {
+            paper_id,
+            title,
+            summary,
+            published,
+        });
+    }
+
+    Ok(entries)
+}
+
+/// Keep entries submitted within `[since, until]` (both inclusive).
+/// Returns the surviving entries and how many were dropped.
+pub fn filter_by_date(
+    entries: Vec<ArxivEntry>,
+    since: Option<NaiveDate>,
+    until: Option<NaiveDate>,
+) -> (Vec<ArxivEntry>, usize) {
+    let total = entries.len();
+    let kept: Vec<ArxivEntry> = entries
+        .into_iter()
+        .filter(|e| since.is_none_or(|s| e.published >= s))
+        .filter(|e| until.is_none_or(|u| e.published <= u))
+        .collect();
+    let dropped = total - kept.len();
+
+    (kept, dropped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_FEED: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom">
+  <entry>
+    <id>http://arxiv.org/abs/2401.08027v2</id>
+    <published>2024-01-15T18:59:59Z</published>
+    <title>A Recent
+      Paper</title>
+    <summary>  We study recent
+  things.
+    </summary>
+  </entry>
+  <entry>
+    <id>http://arxiv.org/abs/2312.00001v1</id>
+    <published>2023-12-01T10:00:00Z</published>
+    <title>An Older Paper</title>
+  </entry>
+  <entry>
+    <id>http://arxiv.org/abs/2402.00002v1</id>
+    <published>2024-02-20T09:30:00Z</published>
+    <title>A Newer Paper</title>
+  </entry>
+</feed>"#;
+
+    #[test]
+    fn test_parse_doi_with_prefix() {
+        let url = ArxivUrl::parse("https://doi.org/10.48550/arXiv.2401.08027").unwrap();
+        assert_eq!(url.paper_id(), "2401.08027");
+        assert_eq!(url.src_url, "https://arxiv.org/src/2401.08027");
+    }
+
+    #[test]
+    fn test_parse_bare_doi() {
+        let url = ArxivUrl::parse("10.48550/arXiv.2401.08027v2").unwrap();
+        assert_eq!(url.paper_id(), "2401.08027v2");
+
+        let url = ArxivUrl::parse("10.48550/ARXIV.hep-th/9901001").unwrap();
+        assert_eq!(url.paper_id(), "hep-th/9901001");
+    }
+
+    #[test]
+    fn test_parse_url_and_bare_id() {
+        let url = ArxivUrl::parse("https://arxiv.org/abs/2401.08027").unwrap();
+        assert_eq!(url.paper_id(), "2401.08027");
+
+        let url = ArxivUrl::parse("2401.08027").unwrap();
+        assert_eq!(url.paper_id(), "2401.08027");
+
+        assert!(ArxivUrl::parse("10.1000/not-arxiv").is_err());
+    }
+
+    #[test]
+    fn test_withdrawal_notice() {
+        assert!(is_withdrawal_notice(
+            "This paper has been withdrawn by the author due to a crucial error.",
+            false
+        ));
+        assert!(is_withdrawal_notice("The submission was WITHDRAWN.", false));
+        let long = format!("This paper is withdrawn. {}", "word ".repeat(400));
+        assert!(!is_withdrawal_notice(&long, false));
+        assert!(is_withdrawal_notice(&long, true));
+        assert!(!is_withdrawal_notice(
+            "Withdrawn requests are retried.",
+            false
+        ));
+    }
+
+    #[test]
+    fn test_strip_version() {
+        assert_eq!(strip_version("2401.08027v2"), "2401.08027");
+        assert_eq!(strip_version("2401.08027"), "2401.08027");
+        assert_eq!(strip_version("solv-int/9901001v1"), "solv-int/9901001");
+        assert_eq!(strip_version("solv-int/9901001"), "solv-int/9901001");
+
+        assert_eq!(version_of("2401.08027v12"), Some(12));
+        assert_eq!(version_of("solv-int/9901001v1"), Some(1));
+        assert_eq!(version_of("2401.08027"), None);
+
+        assert_eq!(submission_year("2401.08027v2"), Some(2024));
+        assert_eq!(submission_year("hep-th/9901001"), Some(1999));
+        assert_eq!(submission_year("hep-th_0101001"), Some(2001));
+        assert_eq!(submission_year("notes"), None);
+    }
+
+    #[test]
+    fn test_parse_feed() {
+        let entries = parse_feed(SAMPLE_FEED).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].paper_id, "2401.08027v2");
+        assert_eq!(entries[0].title, "A Recent Paper");
+        assert_eq!(entries[0].summary, "We study recent things.");
+        assert_eq!(entries[1].summary, "");
+        assert_eq!(
+            entries[0].published,
+            NaiveDate::from_ymd_opt(2024, 1, 15).unwrap()
+        );
+        assert_eq!(entries[0].abs_url(), "https://arxiv.org/abs/2401.08027v2");
+    }
+
+    #[test]
+    fn test_filter_by_date() {
+        let entries = parse_feed(SAMPLE_FEED).unwrap();
+        let since = NaiveDate::from_ymd_opt(2024, 1, 1);
+        let until = NaiveDate::from_ymd_opt(2024, 1, 31);
+
+        let (kept, dropped) = filter_by_date(entries.clone(), since, until);
+        assert_eq!(dropped, 2);
+        assert_eq!(kept.len(), 1);
+        assert_eq!(kept[0].paper_id, "2401.08027v2");
+
+        let (kept, dropped) = filter_by_date(entries, since, None);
+        assert_eq!(dropped, 1);
+        let ids: Vec<&str> = kept.iter().map(|e| e.paper_id.as_str()).collect();
+        assert_eq!(ids, ["2401.08027v2", "2402.00002v1"]);
+    }
+}
@@ -0,0 +1,421 @@
+use regex::Regex;
+use scraper::{ElementRef, Html, Node, Selector};
+
+use crate::processor::{AbstractSource, Equation, Figure, ProcessedContent, Section};
+
+/// Parse arXiv's HTML rendering of a paper (`arxiv.org/html/<id>`, produced by
+/// LaTeXML) into the same content the LaTeX source gives. Math is kept as the TeX
+/// in its `alttext`, so the prompt looks as it would from the source. Returns
+/// `None` for a page that isn't a LaTeXML rendering, e.g. arXiv's "No HTML for
+/// this paper" notice.
+///
+/// The page's images aren't downloaded: figures keep their captions and the `src`
+/// of their images, but `image_files` is empty, so nothing is attached for
+/// `--vision` or copied into bundles.
+pub fn parse(paper_id: &str, html: &str) -> Option<ProcessedContent> {
+    let page = Html::parse_document(html);
+    let document = page.select(&selector(".ltx_document")).next()?;
+
+    let title = document
+        .select(&selector("h1.ltx_title_document"))
+        .next()
+        .or_else(|| page.select(&selector("title")).next())
+        .map(|title| single_line(&render(title, Skip::Numbers)))
+        .unwrap_or_else(|| "Untitled".to_string());
+
+    // A name is followed by the affiliation, on its own line
+    let authors = document
+        .select(&selector(".ltx_personname"))
+        .filter_map(|name| {
+            render(name, Skip::Numbers)
+                .lines()
+                .next()
+                .map(str::to_string)
+        })
+        .map(|name| single_line(&name))
+        .filter(|name| !name.is_empty())
+        .collect();
+
+    let abstract_text = document
+        .select(&selector("div.ltx_abstract"))
+        .next()
+        .map(|div| text(&render(div, Skip::Headings)))
+        .unwrap_or_default();
+
+    let figures = extract_figures(document);
+    let figure_references = figures.iter().flat_map(|f| f.graphics.clone()).collect();
+
+    let mut sections = Vec::new();
+    collect_sections(document, &mut false, &mut sections);
+
+    let display_math = Regex::new(r"(?s)\\\[(.*?)\\\]").unwrap();
+    let equations = sections
+        .iter()
+        .flat_map(|section| {
+            display_math
+                .captures_iter(&section.content)
+                .map(|caps| Equation {
+                    content: caps[1].trim().to_string(),
+                    display: true,
+                    label: None,
+                    referenced: false,
+                    section: Some(section.title.clone()),
+                })
+        })
+        .collect();
+
+    Some(ProcessedContent {
+        paper_id: paper_id.to_string(),
+        title,
+        authors,
+        abstract_source: if abstract_text.is_empty() {
+            AbstractSource::Missing
+        } else {
+            AbstractSource::Html
+        },
+        abstract_text,
+        alternate_abstracts: Vec::new(),
+        full_text: text(&render(document, Skip::Body)),
+        sections,
+        keywords: Vec::new(),
+        code_links: Vec::new(),
+        contributions: Vec::new(),
+        figure_references,
+        figures,
+        equations,
+        statements: Vec::new(),
+        algorithms: Vec::new(),
+        arxiv_version: crate::arxiv::version_of(paper_id),
+        references: Vec::new(),
+        image_files: Vec::new(),
+    })
+}
+
+fn selector(css: &str) -> Selector {
+    Selector::parse(css).unwrap()
+}
+
+fn has_class(element: ElementRef, class: &str) -> bool {
+    element.value().classes().any(|c| c == class)
+}
+
+/// What `render` leaves out besides scripts and styles.
+#[derive(Clone, Copy, PartialEq)]
+enum Skip {
+    /// Section and figure numbers ("1 ", "Figure 1: "), which are in their own span
+    Numbers,
+    /// Headings, such as the abstract's "Abstract"
+    Headings,
+    /// Figures, which go in with their captions, not as stray text in the middle of
+    /// a section, and the references, which aren't sent at all
+    Body,
+    /// `Body`, and subsections and the heading, which `collect_sections` reads
+    /// separately
+    SectionBody,
+}
+
+fn is_section(element: ElementRef) -> bool {
+    element.value().name() == "section"
+        && ["ltx_section", "ltx_subsection", "ltx_appendix"]
+            .iter()
+            .any(|class| has_class(element, class))
+}
+
+fn is_heading(element: ElementRef) -> bool {
+    matches!(
+        element.value().name(),
+        "h1" | "h2" | "h3" | "h4" | "h5" | "h6"
+    )
+}
+
+/// Whether `render` leaves `element` out.
+fn skipped(element: ElementRef, skip: Skip) -> bool {
+    let body = matches!(skip, Skip::Body | Skip::SectionBody)
+        && ((element.value().name() == "figure" && has_class(element, "ltx_figure"))
+            || has_class(element, "ltx_bibliography"));
+    matches!(element.value().name(), "script" | "style")
+        || body
+        || (skip == Skip::Numbers && has_class(element, "ltx_tag"))
+        || (skip == Skip::Headings && is_heading(element))
+        || (skip == Skip::SectionBody && (is_section(element) || is_heading(element)))
+}
+
+/// The text of `element`'s children, with math as its TeX (`$...$` inline and
+/// `\[...\]` for display math) and a line break around each block-level element.
+fn render(element: ElementRef, skip: Skip) -> String {
+    let mut out = String::new();
+    render_into(element, skip, &mut out);
+    out
+}
+
+fn render_into(element: ElementRef, skip: Skip, out: &mut String) {
+    for child in element.children() {
+        match child.value() {
+            Node::Text(text) => out.push_str(text),
+            Node::Element(_) => {
+                let child = ElementRef::wrap(child).unwrap();
+                if skipped(child, skip) {
+                    continue;
+                }
+                let name = child.value().name();
+                if name == "math" {
+                    let Some(tex) = child.value().attr("alttext") else {
+                        continue;
+                    };
+                    if child.value().attr("display") == Some("block") {
+                        out.push_str(&format!(" \\[{tex}\\] "));
+                    } else {
+                        out.push_str(&format!("${tex}$"));
+                    }
+                    continue;
+                }
+                let block = is_heading(child)
+                    || matches!(
+                        name,
+                        "br" | "p" | "div" | "li" | "tr" | "table" | "ul" | "ol" | "section"
+                    );
+                if block {
+                    out.push('\n');
+                }
+                render_into(child, skip, out);
+                if block {
+                    out.push('\n');
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Sections and subsections under `element`, in document order, each with its
+/// own text and not its subsections'. Everything from the first appendix on is
+/// marked as appendix.
+fn collect_sections(element: ElementRef, appendix: &mut bool, sections: &mut Vec<Section>) {
+    for child in element.children().filter_map(ElementRef::wrap) {
+        if skipped(child, Skip::Body) {
+            continue;
+        }
+        if !is_section(child) {
+            collect_sections(child, appendix, sections);
+            continue;
+        }
+        *appendix |= has_class(child, "ltx_appendix");
+        let title = child
+            .children()
+            .filter_map(ElementRef::wrap)
+            .find(|heading| is_heading(*heading))
+            .map(|heading| single_line(&render(heading, Skip::Numbers)))
+            .unwrap_or_default();
+        sections.push(Section {
+            title,
+            content: text(&render(child, Skip::SectionBody)),
+            level: if has_class(child, "ltx_subsection") {
+                2
+            } else {
+                1
+            },
+            appendix: *appendix,
+        });
+        collect_sections(child, appendix, sections);
+    }
+}
+
+/// `<figure class="ltx_figure">` elements with their caption, image `src`s and id.
+/// Panels are figures inside a figure and belong to it. Tables are
+/// `<figure class="ltx_table">` and not included.
+fn extract_figures(document: ElementRef) -> Vec<Figure> {
+    let is_figure = |element: ElementRef| {
+        element.value().name() == "figure" && has_class(element, "ltx_figure")
+    };
+    document
+        .select(&selector("figure.ltx_figure"))
+        .filter(|figure| {
+            !figure
+                .ancestors()
+                .filter_map(ElementRef::wrap)
+                .any(is_figure)
+        })
+        .map(|figure| Figure {
+            label: figure.value().attr("id").map(str::to_string),
+            // The last caption is the figure's own; earlier ones belong to panels
+            caption: figure
+                .select(&selector("figcaption"))
+                .last()
+                .map(|caption| single_line(&render(caption, Skip::Numbers)))
+                .unwrap_or_default(),
+            graphics: figure
+                .select(&selector("img"))
+                .filter_map(|image| image.value().attr("src"))
+                .map(str::to_string)
+                .collect(),
+            tikz: false,
+        })
+        .collect()
+}
+
+/// Runs of spaces collapsed and blank lines dropped.
+fn text(rendered: &str) -> String {
+    rendered
+        .lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Like `text`, on one line.
+fn single_line(rendered: &str) -> String {
+    text(rendered).replace('\n', " ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_PAGE: &str = r##"<!DOCTYPE html>
+<html lang="en">
+<head><title>[2401.08027] Fast Attention</title><script>var x = "<h2 class='ltx_title_section'>";</script></head>
+<body>
+<nav class="ltx_page_navbar"><a href="#S1">1 Introduction</a></nav>
+<div class="ltx_page_main"><div class="ltx_page_content">
+<article class="ltx_document ltx_authors_1line">
+<h1 class="ltx_title ltx_title_document">Fast Attention
+for <em class="ltx_emph">Long</em> Sequences</h1>
+<div class="ltx_authors">
+<span class="ltx_creator ltx_role_author"><span class="ltx_personname">Ada Lovelace<br class="ltx_break"/>Analytical Engines Ltd.</span></span>
+<span class="ltx_creator ltx_role_author"><span class="ltx_personname">Alan Turing</span></span>
+</div>
+<div class="ltx_abstract">
+<h6 class="ltx_title ltx_title_abstract">Abstract</h6>
+<p class="ltx_p">We make attention cost <math id="m1" class="ltx_Math" alttext="O(n\log n)" display="inline"><semantics><mi>O</mi></semantics></math> &amp; keep accuracy.</p>
+</div>
+<section id="S1" class="ltx_section">
+<h2 class="ltx_title ltx_title_section"><span class="ltx_tag ltx_tag_section">1 </span>Introduction</h2>
+<div id="S1.p1" class="ltx_para"><p class="ltx_p">Attention is quadratic&nbsp;<cite class="ltx_cite">[<a href="#bib.bib1">1</a>]</cite>.</p></div>
+<figure id="S1.F1" class="ltx_figure"><img src="x1.png" id="S1.F1.g1" class="ltx_graphics" alt=""/>
+<figcaption class="ltx_caption"><span class="ltx_tag ltx_tag_figure">Figure 1: </span>Cost against length.</figcaption>
+</figure>
+<section id="S1.SS1" class="ltx_subsection">
+<h3 class="ltx_title ltx_title_subsection"><span class="ltx_tag ltx_tag_subsection">1.1 </span>Setting</h3>
+<table id="S1.E1" class="ltx_equation ltx_eqn_table"><tr><td><math id="S1.E1.m1" class="ltx_Math" alttext="a_{i}&lt;b" display="block"><mi>a</mi></math></td></tr></table>
+</section>
+</section>
+<section id="bib" class="ltx_bibliography"><h2 class="ltx_title ltx_title_bibliography">References</h2>
+<ul class="ltx_biblist"><li id="bib.bib1" class="ltx_bibitem">Vaswani et al. Attention is all you need.</li></ul>
+</section>
+<section id="A1" class="ltx_appendix">
+<h2 class="ltx_title ltx_title_appendix"><span class="ltx_tag ltx_tag_appendix">Appendix A </span>Proofs</h2>
+<p class="ltx_p">By induction.</p>
+</section>
+<section id="A2" class="ltx_appendix">
+<h2 class="ltx_title ltx_title_appendix"><span class="ltx_tag ltx_tag_appendix">Appendix B </span>More Runs</h2>
+<p class="ltx_p">Seeds agree.</p>
+</section>
+</article>
+</div></div>
+<footer class="ltx_page_footer">Generated by LaTeXML</footer>
+</body>
+</html>"##;
+
+    #[test]
+    fn test_parse_saved_rendering() {
+        let content = parse("2401.08027v2", SAMPLE_PAGE).unwrap();
+
+        assert_eq!(content.title, "Fast Attention for Long Sequences");
+        assert_eq!(content.authors, ["Ada Lovelace", "Alan Turing"]);
+        assert_eq!(
+            content.abstract_text,
+            "We make attention cost $O(n\\log n)$ & keep accuracy."
+        );
+        assert_eq!(content.abstract_source, AbstractSource::Html);
+        assert_eq!(content.arxiv_version, Some(2));
+
+        let sections: Vec<_> = content
+            .sections
+            .iter()
+            .map(|s| (s.title.as_str(), s.level, s.appendix, s.content.as_str()))
+            .collect();
+        assert_eq!(
+            sections,
+            [
+                ("Introduction", 1, false, "Attention is quadratic [1]."),
+                ("Setting", 2, false, "\\[a_{i}<b\\]"),
+                // After the references, as LaTeXML keeps source order
+                ("Proofs", 1, true, "By induction."),
+                ("More Runs", 1, true, "Seeds agree."),
+            ]
+        );
+
+        assert_eq!(
+            content.figures,
+            [Figure {
+                label: Some("S1.F1".to_string()),
+                caption: "Cost against length.".to_string(),
+                graphics: vec!["x1.png".to_string()],
+                tikz: false,
+            }]
+        );
+        assert_eq!(content.figure_references, ["x1.png"]);
+        assert_eq!(content.equations.len(), 1);
+        assert_eq!(content.equations[0].content, "a_{i}<b");
+        assert_eq!(content.equations[0].section.as_deref(), Some("Setting"));
+        assert!(!content.full_text.contains("Vaswani"));
+        assert!(!content.full_text.contains("LaTeXML"));
+    }
+
+    #[test]
+    fn test_attribute_values_containing_angle_brackets() {
+        let page = r#"<html><body><article class="ltx_document">
+<section class="ltx_section" id="S1" data-note="a > b">
+<h2 class="ltx_title ltx_title_section" title="1 > 0"><span class="ltx_tag ltx_tag_section">1 </span>Bounds</h2>
+<div class="ltx_para" data-cond="x>y"><p class="ltx_p">Since <math alttext="x>y" display="inline"><mi>x</mi></math> holds, we stop.</p></div>
+</section>
+</article></body></html>"#;
+
+        let content = parse("2401.08027", page).unwrap();
+
+        assert_eq!(content.sections.len(), 1);
+        assert_eq!(content.sections[0].title, "Bounds");
+        assert_eq!(content.sections[0].content, "Since $x>y$ holds, we stop.");
+    }
+
+    #[test]
+    fn test_nested_elements_of_the_same_class() {
+        let page = r#"<html><body><article class="ltx_document">
+<section class="ltx_section" id="S1">
+<h2 class="ltx_title ltx_title_section"><span class="ltx_tag ltx_tag_section"><span class="ltx_tag">1</span> </span>Results</h2>
+<div class="ltx_para"><div class="ltx_para"><p class="ltx_p">Inner.</p></div><p class="ltx_p">Outer.</p></div>
+<figure class="ltx_figure" id="S1.F1">
+<figure class="ltx_figure" id="S1.F1.sf1"><img src="x1.png"/><figcaption class="ltx_caption">(a) Left</figcaption></figure>
+<figure class="ltx_figure" id="S1.F1.sf2"><img src="x2.png"/><figcaption class="ltx_caption">(b) Right</figcaption></figure>
+<figcaption class="ltx_caption"><span class="ltx_tag ltx_tag_figure">Figure 1: </span>Both runs.</figcaption>
+</figure>
+<p class="ltx_p">After the figure.</p>
+</section>
+</article></body></html>"#;
+
+        let content = parse("2401.08027", page).unwrap();
+
+        assert_eq!(content.sections[0].title, "Results");
+        assert_eq!(
+            content.sections[0].content,
+            "Inner.\nOuter.\nAfter the figure."
+        );
+        assert_eq!(
+            content.figures,
+            [Figure {
+                label: Some("S1.F1".to_string()),
+                caption: "Both runs.".to_string(),
+                graphics: vec!["x1.png".to_string(), "x2.png".to_string()],
+                tikz: false,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_page_without_rendering_is_rejected() {
+        let page = "<html><body><h1>No HTML for '2401.08027'</h1></body></html>";
+
+        assert!(parse("2401.08027", page).is_none());
+    }
+}
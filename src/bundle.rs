@@ -0,0 +1,151 @@
+use anyhow::{Context, Result};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use crate::validate;
+
+/// Directory next to a note that `--overleaf-bundle` writes to
+pub const BUNDLE_DIR: &str = "bundle";
+/// Where the bundle keeps its figures, relative to the note
+const BUNDLE_FIGURES_DIR: &str = "figures";
+
+/// Copy a LaTeX note into `<note dir>/bundle/` together with every figure it
+/// includes, rewriting `\includegraphics` paths to `figures/<name>` so the folder
+/// compiles on its own (e.g. uploaded to Overleaf as a zip). Figures that can't be
+/// found are left as they are, with a warning. Returns the bundled note's path.
+pub fn write_bundle(note_path: &Path) -> Result<PathBuf> {
+    let note_dir = note_path.parent().unwrap_or(Path::new(""));
+    let bundle_dir = note_dir.join(BUNDLE_DIR);
+    let figures_dir = bundle_dir.join(BUNDLE_FIGURES_DIR);
+    std::fs::create_dir_all(&figures_dir)
+        .with_context(|| format!("Failed to create {}", figures_dir.display()))?;
+
+    let note = std::fs::read_to_string(note_path)
+        .with_context(|| format!("Failed to read {}", note_path.display()))?;
+    let mut content = String::with_capacity(note.len());
+    let mut copied_to = 0;
+    let mut used_names = HashSet::new();
+    // Bundled path of each figure copied so far, so references spelled differently
+    // (`figs/arch`, `figs/arch.pdf`) share one copy
+    let mut bundled: HashMap<PathBuf, String> = HashMap::new();
+    for (argument, reference) in validate::graphics_spans(&note) {
+        let Some(figure) = validate::resolve_figure(note_dir, &reference) else {
+            eprintln!("Warning: figure {reference} not found; left out of the bundle");
+            continue;
+        };
+        let new_reference = match bundled.get(&figure) {
+            Some(new_reference) => new_reference.clone(),
+            None => {
+                let name = unique_name(&figure, &mut used_names);
+                std::fs::copy(&figure, figures_dir.join(&name))
+                    .with_context(|| format!("Failed to copy {}", figure.display()))?;
+                let new_reference = format!("{BUNDLE_FIGURES_DIR}/{name}");
+                bundled.insert(figure, new_reference.clone());
+                new_reference
+            }
+        };
+        content.push_str(&note[copied_to..argument.start]);
+        content.push_str(&new_reference);
+        copied_to = argument.end;
+    }
+    content.push_str(&note[copied_to..]);
+
+    let bundled_note = bundle_dir.join(note_path.file_name().context("note has no file name")?);
+    std::fs::write(&bundled_note, content)
+        .with_context(|| format!("Failed to write {}", bundled_note.display()))?;
+    Ok(bundled_note)
+}
+
+/// The figure's file name, with `-2`, `-3`, ... added to the stem when several
+/// figures share a name.
+fn unique_name(figure: &Path, used: &mut HashSet<String>) -> String {
+    let file_name = figure
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "figure".to_string());
+    let stem = figure
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let extension = figure
+        .extension()
+        .map(|e| format!(".{}", e.to_string_lossy()))
+        .unwrap_or_default();
+
+    let mut name = file_name;
+    let mut n = 1;
+    while !used.insert(name.clone()) {
+        n += 1;
+        name = format!("{stem}-{n}{extension}");
+    }
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bundle_holds_the_note_and_its_figures() {
+        let dir = tempfile::tempdir().unwrap();
+        let note_dir = dir.path().join("tex/2401.08027");
+        let figures = dir.path().join("output/2401.08027/extracted");
+        std::fs::create_dir_all(&note_dir).unwrap();
+        std::fs::create_dir_all(figures.join("figs")).unwrap();
+        std::fs::create_dir_all(figures.join("appendix")).unwrap();
+        std::fs::write(figures.join("figs/arch.pdf"), "arch").unwrap();
+        std::fs::write(figures.join("figs/plot.png"), "plot").unwrap();
+        std::fs::write(figures.join("appendix/plot.png"), "appendix plot").unwrap();
+        let note = note_dir.join("2401.08027.tex");
+        std::fs::write(
+            &note,
+            "\\section{Method}\n\
+             \\includegraphics[width=0.5\\linewidth]{../../output/2401.08027/extracted/figs/arch}\n\
+             \\includegraphics{../../output/2401.08027/extracted/figs/plot.png}\n\
+             \\includegraphics{../../output/2401.08027/extracted/appendix/plot.png}\n\
+             \\includegraphics{../../output/2401.08027/extracted/figs/arch.pdf}\n\
+             \\includegraphics{missing.png}\n\
+             Built from \\texttt{../../output/2401.08027/extracted/figs/plot.png}.\n",
+        )
+        .unwrap();
+
+        let bundled = write_bundle(&note).unwrap();
+
+        assert_eq!(bundled, note_dir.join("bundle/2401.08027.tex"));
+        let content = std::fs::read_to_string(&bundled).unwrap();
+        let references: Vec<String> = validate::graphics_spans(&content)
+            .into_iter()
+            .map(|(_, reference)| reference)
+            .collect();
+        assert_eq!(
+            references,
+            [
+                "figures/arch.pdf",
+                "figures/plot.png",
+                "figures/plot-2.png",
+                "figures/arch.pdf",
+                "missing.png"
+            ]
+        );
+        // Only `\\includegraphics` arguments are rewritten, and each file is copied once
+        assert!(content.contains("\\texttt{../../output/2401.08027/extracted/figs/plot.png}"));
+        let mut copied: Vec<String> = std::fs::read_dir(note_dir.join("bundle/figures"))
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        copied.sort();
+        assert_eq!(copied, ["arch.pdf", "plot-2.png", "plot.png"]);
+        let bundle = note_dir.join("bundle");
+        assert_eq!(
+            std::fs::read_to_string(bundle.join("figures/plot-2.png")).unwrap(),
+            "appendix plot"
+        );
+        for reference in ["figures/arch.pdf", "figures/plot.png"] {
+            assert!(validate::resolve_figure(&bundle, reference).is_some());
+        }
+        // The original note is untouched
+        assert!(std::fs::read_to_string(&note)
+            .unwrap()
+            .contains("../../output/"));
+    }
+}
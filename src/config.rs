@@ -0,0 +1,780 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::events::EventLog;
+use crate::figures::ImageLimits;
+use crate::limits::StageLimits;
+use crate::llm_client::{self, LLMConfig};
+use crate::note_generator::{NoteTemplate, OutputFormat};
+
+/// How `--print-config` prints the effective configuration.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ConfigFormat {
+    #[default]
+    Toml,
+    Json,
+}
+
+/// What to do when a paper already has a note.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OverwritePolicy {
+    /// Leave the existing note alone and skip the paper
+    #[default]
+    Skip,
+    /// Regenerate the note in place
+    Overwrite,
+    /// Keep existing notes and write `<name>.v2.tex`, `<name>.v3.tex`, ...
+    Version,
+}
+
+/// What happens to a downloaded archive once its paper has been processed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum CleanupMode {
+    /// Delete it
+    #[default]
+    Delete,
+    /// Move it aside to `output/cache/` instead of deleting it
+    Cache,
+    /// Leave it in the paper's working directory
+    Keep,
+}
+
+/// Directory under the working directory that `--cleanup-mode cache` moves archives to.
+pub const ARCHIVE_CACHE_DIR: &str = "cache";
+
+/// What the prompt is built from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum SummarySource {
+    /// Title, abstract, sections, figures and equations
+    #[default]
+    Full,
+    /// Title and abstract only, unless the paper has no abstract
+    Abstract,
+    /// The abstract when it is substantial, else the full content
+    Auto,
+}
+
+/// Which sections of the paper the prompt is built from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Focus {
+    /// Every section
+    #[default]
+    All,
+    /// The abstract and the conclusion/discussion sections only
+    AbstractConclusion,
+    /// Method sections, with equations, algorithms and figures
+    Method,
+}
+
+/// What arXiv papers are parsed from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum PaperSource {
+    /// The LaTeX source archive
+    #[default]
+    Latex,
+    /// arXiv's HTML rendering, falling back to the source when there is none
+    Html,
+}
+
+/// What to do with a paper over `--max-sections` or `--max-words`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OnLarge {
+    /// Report the paper and generate no note
+    #[default]
+    Skip,
+    /// Send only the start of each section
+    Summarize,
+    /// Generate the note from the full text anyway
+    Proceed,
+}
+
+/// Config file looked up in the current directory.
+pub const CONFIG_FILE: &str = "paper_scan.toml";
+
+const DEFAULT_BASE_URL: &str = "https://generativelanguage.googleapis.com/v1beta/openai";
+const DEFAULT_MODEL: &str = "gemini-1.5-flash";
+const DEFAULT_TEMPERATURE: f32 = 0.7;
+const DEFAULT_NOTES_DIR: &str = "tex";
+const DEFAULT_CONCURRENCY: usize = 4;
+/// arXiv asks for gentle crawling, so downloads default to fewer slots than LLM calls
+const DEFAULT_DOWNLOAD_CONCURRENCY: usize = 2;
+pub const DEFAULT_MAX_FIGURES: usize = 8;
+/// Total size of the encoded figures in one vision request; providers cap request
+/// bodies at around 20 MB
+pub const DEFAULT_MAX_FIGURE_BYTES: usize = 15 * 1024 * 1024;
+pub const DEFAULT_MAX_EQUATIONS: usize = 20;
+pub const DEFAULT_MIN_SECTIONS: usize = 1;
+/// Extracted abstracts shorter than this are replaced by the arXiv API's abstract
+pub const DEFAULT_MIN_ABSTRACT_CHARS: usize = 100;
+pub const DEFAULT_FILENAME_PATTERN: &str = "{id}";
+/// Sections left out of the prompt by default: they cost tokens without improving the note
+pub const DEFAULT_EXCLUDE_SECTIONS: [&str; 7] = [
+    "References",
+    "Bibliography",
+    "Acknowledgments",
+    "Acknowledgements",
+    "Appendix",
+    "Supplementary",
+    "Checklist",
+];
+/// Sections whose equations rank higher when trimming to `--max-equations`
+pub const DEFAULT_EQUATION_SECTIONS: [&str; 6] = [
+    "Abstract",
+    "Method",
+    "Approach",
+    "Model",
+    "Algorithm",
+    "Proposed",
+];
+/// Argument-less layout commands removed from the text sent to the model
+pub const DEFAULT_STRIP_COMMANDS: [&str; 26] = [
+    "twocolumn",
+    "onecolumn",
+    "maketitle",
+    "newpage",
+    "clearpage",
+    "cleardoublepage",
+    "pagebreak",
+    "noindent",
+    "indent",
+    "centering",
+    "raggedright",
+    "raggedleft",
+    "hline",
+    "toprule",
+    "midrule",
+    "bottomrule",
+    "smallskip",
+    "medskip",
+    "bigskip",
+    "vfill",
+    "hfill",
+    "normalsize",
+    "footnotesize",
+    "scriptsize",
+    "tiny",
+    "tableofcontents",
+];
+pub const DEFAULT_MIN_ARCHIVE_SIZE: u64 = 512;
+pub const DEFAULT_MAX_ARCHIVE_SIZE: u64 = 200 * 1024 * 1024;
+
+/// One layer of optional settings.
+///
+/// The same shape is filled from command-line flags, the environment and the
+/// config file, then merged with precedence CLI > env > file > default.
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub base_url: Option<String>,
+    pub api_key: Option<String>,
+    /// File holding the API key (e.g. a Docker secret); wins over an `api_key` set
+    /// in the same layer
+    pub api_key_file: Option<PathBuf>,
+    pub model: Option<String>,
+    #[serde(serialize_with = "serialize_f32")]
+    pub temperature: Option<f32>,
+    pub max_tokens: Option<u32>,
+    #[serde(serialize_with = "serialize_f32")]
+    pub top_p: Option<f32>,
+    #[serde(serialize_with = "serialize_f32")]
+    pub frequency_penalty: Option<f32>,
+    #[serde(serialize_with = "serialize_f32")]
+    pub presence_penalty: Option<f32>,
+    pub template: Option<PathBuf>,
+    pub output_dir: Option<PathBuf>,
+    pub concurrency: Option<usize>,
+    pub download_concurrency: Option<usize>,
+    pub llm_concurrency: Option<usize>,
+    /// Estimated prompt tokens sent to the LLM per rolling minute
+    pub tpm_limit: Option<usize>,
+    pub lang: Option<String>,
+    pub prompt_file: Option<PathBuf>,
+    pub on_complete: Option<String>,
+    pub log_llm: Option<PathBuf>,
+    /// Extra main-file names to look for when no file has `\documentclass`
+    pub main_hints: Option<Vec<String>>,
+}
+
+impl Settings {
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        Self::from_toml(&content)
+            .with_context(|| format!("Failed to parse config file {}", path.display()))
+    }
+
+    pub fn from_toml(content: &str) -> Result<Self> {
+        Ok(toml::from_str(content)?)
+    }
+
+    pub fn from_env() -> Self {
+        Self::from_lookup(|key| env::var(key).ok())
+    }
+
+    /// Build settings from an environment-like lookup, ignoring unparsable values.
+    pub fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Self {
+        Self {
+            base_url: lookup("BASE_URL"),
+            api_key: lookup("API_KEY"),
+            api_key_file: lookup("API_KEY_FILE").map(PathBuf::from),
+            model: lookup("MODEL"),
+            temperature: lookup("TEMPERATURE").and_then(|s| s.parse().ok()),
+            max_tokens: lookup("MAX_TOKENS").and_then(|s| s.parse().ok()),
+            top_p: lookup("TOP_P").and_then(|s| s.parse().ok()),
+            frequency_penalty: lookup("FREQUENCY_PENALTY").and_then(|s| s.parse().ok()),
+            presence_penalty: lookup("PRESENCE_PENALTY").and_then(|s| s.parse().ok()),
+            template: lookup("TEMPLATE").map(PathBuf::from),
+            output_dir: lookup("OUTPUT_DIR").map(PathBuf::from),
+            concurrency: lookup("CONCURRENCY").and_then(|s| s.parse().ok()),
+            download_concurrency: lookup("DOWNLOAD_CONCURRENCY").and_then(|s| s.parse().ok()),
+            llm_concurrency: lookup("LLM_CONCURRENCY").and_then(|s| s.parse().ok()),
+            tpm_limit: lookup("TPM_LIMIT").and_then(|s| s.parse().ok()),
+            lang: lookup("OUTPUT_LANG"),
+            prompt_file: lookup("PROMPT_FILE").map(PathBuf::from),
+            on_complete: lookup("ON_COMPLETE"),
+            log_llm: lookup("LLM_LOG").map(PathBuf::from),
+            main_hints: lookup("MAIN_HINTS").map(|s| {
+                s.split(',')
+                    .map(|hint| hint.trim().to_string())
+                    .filter(|hint| !hint.is_empty())
+                    .collect()
+            }),
+        }
+    }
+
+    /// Merge CLI flags with the environment and the first config file found.
+    pub fn resolve(cli: Settings) -> Result<Settings> {
+        let config_path = config_file_candidates(
+            env::var_os("XDG_CONFIG_HOME").map(PathBuf::from),
+            env::var_os("HOME").map(PathBuf::from),
+        )
+        .into_iter()
+        .find(|path| path.is_file());
+
+        let file = match config_path {
+            Some(path) => Settings::from_file(&path)?,
+            None => Settings::default(),
+        };
+
+        Ok(cli.or(Settings::from_env()).or(file))
+    }
+
+    /// These settings with the defaults `RunOptions::from_settings` would apply
+    /// filled in and the API key redacted, for `--print-config`. Settings without
+    /// a default stay unset.
+    pub fn effective(self) -> Settings {
+        Settings {
+            base_url: Some(self.base_url.map_or_else(
+                || DEFAULT_BASE_URL.to_string(),
+                |url| llm_client::normalize_base_url(&url),
+            )),
+            api_key: self.api_key.map(|_| llm_client::REDACTED.to_string()),
+            model: self.model.or_else(|| Some(DEFAULT_MODEL.to_string())),
+            temperature: self.temperature.or(Some(DEFAULT_TEMPERATURE)),
+            output_dir: self
+                .output_dir
+                .or_else(|| Some(PathBuf::from(DEFAULT_NOTES_DIR))),
+            download_concurrency: self
+                .download_concurrency
+                .or(self.concurrency)
+                .or(Some(DEFAULT_DOWNLOAD_CONCURRENCY)),
+            llm_concurrency: self
+                .llm_concurrency
+                .or(self.concurrency)
+                .or(Some(DEFAULT_CONCURRENCY)),
+            main_hints: self.main_hints.or_else(|| Some(Vec::new())),
+            ..self
+        }
+    }
+
+    pub fn render(&self, format: ConfigFormat) -> Result<String> {
+        Ok(match format {
+            ConfigFormat::Toml => toml::to_string(self)?,
+            ConfigFormat::Json => serde_json::to_string_pretty(self)?,
+        })
+    }
+
+    /// Fill every unset field from `lower`, keeping values already set in `self`.
+    /// `api_key` and `api_key_file` count as one setting, so a key given in `self`
+    /// isn't overridden by a key file from `lower`.
+    pub fn or(self, lower: Settings) -> Settings {
+        let (api_key, api_key_file) = if self.api_key.is_some() || self.api_key_file.is_some() {
+            (self.api_key, self.api_key_file)
+        } else {
+            (lower.api_key, lower.api_key_file)
+        };
+        Settings {
+            base_url: self.base_url.or(lower.base_url),
+            api_key,
+            api_key_file,
+            model: self.model.or(lower.model),
+            temperature: self.temperature.or(lower.temperature),
+            max_tokens: self.max_tokens.or(lower.max_tokens),
+            top_p: self.top_p.or(lower.top_p),
+            frequency_penalty: self.frequency_penalty.or(lower.frequency_penalty),
+            presence_penalty: self.presence_penalty.or(lower.presence_penalty),
+            template: self.template.or(lower.template),
+            output_dir: self.output_dir.or(lower.output_dir),
+            concurrency: self.concurrency.or(lower.concurrency),
+            download_concurrency: self.download_concurrency.or(lower.download_concurrency),
+            llm_concurrency: self.llm_concurrency.or(lower.llm_concurrency),
+            tpm_limit: self.tpm_limit.or(lower.tpm_limit),
+            lang: self.lang.or(lower.lang),
+            prompt_file: self.prompt_file.or(lower.prompt_file),
+            on_complete: self.on_complete.or(lower.on_complete),
+            log_llm: self.log_llm.or(lower.log_llm),
+            main_hints: self.main_hints.or(lower.main_hints),
+        }
+    }
+}
+
+/// Fully resolved options for a run, built once in `main` and passed down the pipeline.
+#[derive(Debug, Clone)]
+pub struct RunOptions {
+    pub llm: LLMConfig,
+    pub template: Option<NoteTemplate>,
+    /// Where archives are downloaded and extracted
+    pub work_dir: PathBuf,
+    /// Where generated notes are written
+    pub notes_dir: PathBuf,
+    /// How many downloads and LLM requests a batch runs at once
+    pub limits: StageLimits,
+    /// Language the note should be written in, if not the prompt's default
+    pub lang: Option<String>,
+    /// System prompt file; defaults to the built-in prompt for `format`
+    pub prompt_file: Option<PathBuf>,
+    pub format: OutputFormat,
+    /// Shell command run after each note is saved
+    pub on_complete: Option<String>,
+    /// What to do with downloaded archives after processing
+    pub cleanup: CleanupMode,
+    /// Send figures to the model as images rather than listing their paths
+    pub vision: bool,
+    /// Maximum number of figures attached to a vision request
+    pub max_figures: usize,
+    /// How large attached figures may be
+    pub image_limits: ImageLimits,
+    /// Total size of the `data:` URLs attached to one vision request
+    pub max_figure_bytes: usize,
+    /// Ask the model for JSON and render it through a fixed note structure
+    pub structured: bool,
+    /// Most equations listed in the prompt (0 disables them)
+    pub max_equations: usize,
+    /// Equations in sections whose titles start with one of these (ignoring case)
+    /// rank higher when trimming to `max_equations`
+    pub equation_sections: Vec<String>,
+    /// Keep equations inline in their sections rather than in a separate list
+    pub combine_equations: bool,
+    /// Generate notes even from near-empty extractions
+    pub allow_poor_extraction: bool,
+    /// Without an abstract, extractions with fewer sections than this are skipped
+    pub min_sections: usize,
+    /// Abstracts extracted from the source shorter than this many characters are
+    /// taken for a fragment, and the arXiv API's abstract is used instead
+    pub min_abstract_chars: usize,
+    /// Papers with more sections or words than this count as large
+    pub max_sections: Option<usize>,
+    pub max_words: Option<usize>,
+    pub on_large: OnLarge,
+    pub summary_source: SummarySource,
+    /// Sections the prompt is limited to
+    pub focus: Focus,
+    /// Whether arXiv papers are parsed from their source or their HTML rendering
+    pub source: PaperSource,
+    /// Combined file every generated note is also appended to
+    pub append_to: Option<PathBuf>,
+    /// Also write each LaTeX note with copies of its figures to `<note dir>/bundle/`
+    pub overleaf_bundle: bool,
+    /// Second model every note is also generated with, for comparison
+    pub compare_model: Option<String>,
+    /// Downloaded archives smaller than this many bytes are treated as error pages
+    pub min_archive_size: u64,
+    /// Downloaded archives larger than this many bytes are rejected
+    pub max_archive_size: u64,
+    /// File the URLs that failed in a batch are written to
+    pub failed_urls: Option<PathBuf>,
+    /// Name of the note file (without extension), e.g. `{date}-{title_slug}`
+    pub filename_pattern: String,
+    /// What to do when a paper already has a note
+    pub overwrite_policy: OverwritePolicy,
+    /// Main-file names tried before the built-in ones
+    pub main_hints: Vec<String>,
+    /// Scan arXiv's `anc/` ancillary directory along with the source
+    pub include_ancillary: bool,
+    /// Look up the latest arXiv version for IDs given without one
+    pub resolve_version: bool,
+    /// Regenerate notes made from an older arXiv version
+    pub update: bool,
+    /// Sections whose titles start with one of these (ignoring case) are left out
+    /// of the prompt, along with their subsections
+    pub exclude_sections: Vec<String>,
+    /// Argument-less commands (without the backslash) removed when cleaning the text
+    pub strip_commands: Vec<String>,
+    /// File the exact prompt is written to before each LLM call
+    pub dump_prompt: Option<PathBuf>,
+    /// Run-specific instructions appended to the system prompt
+    pub instructions: Vec<String>,
+    /// Run-specific instructions placed before the system prompt
+    pub prepend_instructions: Vec<String>,
+    /// User tags recorded in each generated note's metadata
+    pub tags: Vec<String>,
+    /// Versions `--resolve-version` has looked up, by paper ID, so `--limit` and
+    /// processing ask arXiv only once per paper
+    pub resolved_versions: Arc<Mutex<HashMap<String, Option<u32>>>>,
+    /// Receives `--json-logs` events
+    pub events: EventLog,
+}
+
+impl RunOptions {
+    /// Where `--cleanup-mode cache` keeps processed archives.
+    pub fn archive_cache_dir(&self) -> PathBuf {
+        self.work_dir.join(ARCHIVE_CACHE_DIR)
+    }
+
+    pub fn from_settings(settings: Settings) -> Result<Self> {
+        let template = settings
+            .template
+            .as_deref()
+            .map(NoteTemplate::load)
+            .transpose()?;
+        let api_key = match &settings.api_key_file {
+            Some(path) => Some(read_api_key_file(path)?),
+            None => settings.api_key,
+        };
+
+        Ok(Self {
+            llm: LLMConfig {
+                base_url: settings.base_url.map_or_else(
+                    || DEFAULT_BASE_URL.to_string(),
+                    |url| llm_client::normalize_base_url(&url),
+                ),
+                api_key,
+                model: settings.model.unwrap_or_else(|| DEFAULT_MODEL.to_string()),
+                temperature: settings.temperature.unwrap_or(DEFAULT_TEMPERATURE),
+                max_tokens: settings.max_tokens,
+                top_p: settings.top_p,
+                frequency_penalty: settings.frequency_penalty,
+                presence_penalty: settings.presence_penalty,
+                seed: None,
+                json_mode: false,
+                log_path: settings.log_llm,
+            },
+            template,
+            work_dir: PathBuf::from("output"),
+            notes_dir: settings
+                .output_dir
+                .unwrap_or_else(|| PathBuf::from(DEFAULT_NOTES_DIR)),
+            // `concurrency` sets both stages unless a stage has its own value
+            limits: StageLimits::new(
+                settings
+                    .download_concurrency
+                    .or(settings.concurrency)
+                    .unwrap_or(DEFAULT_DOWNLOAD_CONCURRENCY),
+                settings
+                    .llm_concurrency
+                    .or(settings.concurrency)
+                    .unwrap_or(DEFAULT_CONCURRENCY),
+            )
+            .with_tpm_limit(settings.tpm_limit),
+            lang: settings.lang,
+            prompt_file: settings.prompt_file,
+            format: OutputFormat::default(),
+            on_complete: settings.on_complete,
+            cleanup: CleanupMode::Delete,
+            vision: false,
+            max_figures: DEFAULT_MAX_FIGURES,
+            image_limits: ImageLimits::default(),
+            max_figure_bytes: DEFAULT_MAX_FIGURE_BYTES,
+            structured: false,
+            max_equations: DEFAULT_MAX_EQUATIONS,
+            equation_sections: DEFAULT_EQUATION_SECTIONS.map(String::from).to_vec(),
+            combine_equations: false,
+            allow_poor_extraction: false,
+            min_sections: DEFAULT_MIN_SECTIONS,
+            min_abstract_chars: DEFAULT_MIN_ABSTRACT_CHARS,
+            max_sections: None,
+            max_words: None,
+            on_large: OnLarge::default(),
+            summary_source: SummarySource::default(),
+            focus: Focus::default(),
+            source: PaperSource::default(),
+            append_to: None,
+            overleaf_bundle: false,
+            compare_model: None,
+            min_archive_size: DEFAULT_MIN_ARCHIVE_SIZE,
+            max_archive_size: DEFAULT_MAX_ARCHIVE_SIZE,
+            failed_urls: None,
+            filename_pattern: DEFAULT_FILENAME_PATTERN.to_string(),
+            overwrite_policy: OverwritePolicy::default(),
+            main_hints: settings.main_hints.unwrap_or_default(),
+            include_ancillary: false,
+            resolve_version: false,
+            update: false,
+            exclude_sections: DEFAULT_EXCLUDE_SECTIONS.map(String::from).to_vec(),
+            strip_commands: DEFAULT_STRIP_COMMANDS.map(String::from).to_vec(),
+            dump_prompt: None,
+            instructions: Vec::new(),
+            prepend_instructions: Vec::new(),
+            tags: Vec::new(),
+            resolved_versions: Arc::default(),
+            events: EventLog::default(),
+        })
+    }
+}
+
+/// Write an `f32` as the shortest decimal that reads back as it (`0.7`, not
+/// `0.699999988079071`).
+fn serialize_f32<S: serde::Serializer>(
+    value: &Option<f32>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(value) => serializer.serialize_some(
+            &value
+                .to_string()
+                .parse::<f64>()
+                .unwrap_or(f64::from(*value)),
+        ),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn read_api_key_file(path: &Path) -> Result<String> {
+    let key = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read API key file {}", path.display()))?;
+    let key = key.trim();
+    if key.is_empty() {
+        anyhow::bail!("API key file {} is empty", path.display());
+    }
+    Ok(key.to_string())
+}
+
+/// Config file locations in lookup order: `./paper_scan.toml`, then
+/// `$XDG_CONFIG_HOME/paper_scan/config.toml` (defaulting to `~/.config`).
+fn config_file_candidates(xdg_config_home: Option<PathBuf>, home: Option<PathBuf>) -> Vec<PathBuf> {
+    let mut candidates = vec![PathBuf::from(CONFIG_FILE)];
+
+    let config_home = xdg_config_home
+        .filter(|p| p.is_absolute())
+        .or_else(|| home.map(|h| h.join(".config")));
+    if let Some(dir) = config_home {
+        candidates.push(dir.join("paper_scan").join("config.toml"));
+    }
+
+    candidates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_config_file_overridden_by_env() {
+        let file = Settings::from_toml(
+            r#"
+            model = "file-model"
+            temperature = 0.2
+            max_tokens = 4096
+            "#,
+        )
+        .unwrap();
+        assert_eq!(file.model.as_deref(), Some("file-model"));
+
+        let env: HashMap<&str, &str> = [("MODEL", "env-model")].into();
+        let env = Settings::from_lookup(|key| env.get(key).map(|v| v.to_string()));
+
+        let options = RunOptions::from_settings(Settings::default().or(env).or(file)).unwrap();
+        assert_eq!(options.llm.model, "env-model");
+        assert_eq!(options.llm.temperature, 0.2);
+        assert_eq!(options.llm.max_tokens, Some(4096));
+        assert_eq!(options.llm.base_url, DEFAULT_BASE_URL);
+    }
+
+    #[test]
+    fn test_printed_config_shows_env_over_file() {
+        let file = Settings::from_toml(
+            r#"
+            model = "file-model"
+            api_key = "sk-file-secret"
+            max_tokens = 4096
+            "#,
+        )
+        .unwrap();
+        let env: HashMap<&str, &str> =
+            [("MODEL", "env-model"), ("API_KEY", "sk-env-secret")].into();
+        let env = Settings::from_lookup(|key| env.get(key).map(|v| v.to_string()));
+
+        let effective = Settings::default().or(env).or(file).effective();
+
+        let toml = effective.render(ConfigFormat::Toml).unwrap();
+        assert!(toml.contains(r#"model = "env-model""#), "{toml}");
+        assert!(toml.contains("max_tokens = 4096"), "{toml}");
+        assert!(toml.contains(r#"api_key = "[REDACTED]""#), "{toml}");
+        assert!(toml.contains("llm_concurrency = 4"), "{toml}");
+        assert!(toml.contains("temperature = 0.7\n"), "{toml}");
+        assert!(!toml.contains("secret"), "{toml}");
+        // The printed TOML is itself a valid config file
+        assert_eq!(
+            Settings::from_toml(&toml).unwrap().model.as_deref(),
+            Some("env-model")
+        );
+
+        let json = effective.render(ConfigFormat::Json).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(json["model"], "env-model");
+        assert_eq!(json["api_key"], "[REDACTED]");
+        assert!(json["template"].is_null());
+    }
+
+    #[test]
+    fn test_sample_config_file() {
+        let file = Settings::from_toml(
+            r#"
+            model = "gpt-4o-mini"
+            base_url = "https://api.openai.com/v1"
+            temperature = 0.3
+            max_tokens = 8192
+            output_dir = "notes"
+            concurrency = 2
+            lang = "English"
+            prompt_file = "my_prompt.txt"
+            "#,
+        )
+        .unwrap();
+
+        let env: HashMap<&str, &str> = [("CONCURRENCY", "8")].into();
+        let env = Settings::from_lookup(|key| env.get(key).map(|v| v.to_string()));
+
+        let options = RunOptions::from_settings(env.or(file)).unwrap();
+        assert_eq!(options.llm.model, "gpt-4o-mini");
+        assert_eq!(options.llm.base_url, "https://api.openai.com/v1");
+        assert_eq!(options.llm.temperature, 0.3);
+        assert_eq!(options.llm.max_tokens, Some(8192));
+        assert_eq!(options.notes_dir, PathBuf::from("notes"));
+        assert_eq!(options.limits.download_slots, 8);
+        assert_eq!(options.limits.llm_slots, 8);
+        assert_eq!(options.lang.as_deref(), Some("English"));
+        assert_eq!(options.prompt_file, Some(PathBuf::from("my_prompt.txt")));
+    }
+
+    #[test]
+    fn test_main_hints_from_file_and_env() {
+        let file = Settings::from_toml(r#"main_hints = ["thesis.tex"]"#).unwrap();
+        assert_eq!(
+            RunOptions::from_settings(file.clone()).unwrap().main_hints,
+            ["thesis.tex"]
+        );
+
+        let env: HashMap<&str, &str> = [("MAIN_HINTS", "body.tex, chapter1 ,")].into();
+        let env = Settings::from_lookup(|key| env.get(key).map(|v| v.to_string()));
+        let options = RunOptions::from_settings(env.or(file)).unwrap();
+        assert_eq!(options.main_hints, ["body.tex", "chapter1"]);
+    }
+
+    #[test]
+    fn test_stage_concurrency_overrides_shared_value() {
+        let file = Settings::from_toml("concurrency = 6\nllm_concurrency = 10").unwrap();
+        let options = RunOptions::from_settings(file).unwrap();
+        assert_eq!(options.limits.download_slots, 6);
+        assert_eq!(options.limits.llm_slots, 10);
+
+        let options = RunOptions::from_settings(Settings::default()).unwrap();
+        assert_eq!(options.limits.download_slots, DEFAULT_DOWNLOAD_CONCURRENCY);
+        assert_eq!(options.limits.llm_slots, DEFAULT_CONCURRENCY);
+    }
+
+    #[test]
+    fn test_api_key_from_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let key_file = dir.path().join("api_key");
+        std::fs::write(&key_file, "sk-from-file\n").unwrap();
+
+        let env: HashMap<&str, String> = [
+            ("API_KEY", "sk-from-env".to_string()),
+            ("API_KEY_FILE", key_file.to_string_lossy().to_string()),
+        ]
+        .into();
+        let env = Settings::from_lookup(|key| env.get(key).cloned());
+
+        let options = RunOptions::from_settings(env).unwrap();
+        assert_eq!(options.llm.api_key.as_deref(), Some("sk-from-file"));
+
+        let missing = Settings {
+            api_key_file: Some(dir.path().join("nope")),
+            ..Default::default()
+        };
+        let err = RunOptions::from_settings(missing).unwrap_err();
+        assert!(
+            err.to_string().contains("Failed to read API key file"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_api_key_from_env_beats_key_file_from_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let key_file = dir.path().join("api_key");
+        std::fs::write(&key_file, "sk-from-file\n").unwrap();
+        let file = Settings {
+            api_key_file: Some(key_file.clone()),
+            ..Default::default()
+        };
+
+        let env: HashMap<&str, &str> = [("API_KEY", "sk-from-env")].into();
+        let env = Settings::from_lookup(|key| env.get(key).map(|v| v.to_string()));
+        let options = RunOptions::from_settings(env.or(file)).unwrap();
+        assert_eq!(options.llm.api_key.as_deref(), Some("sk-from-env"));
+
+        // A key file in a higher layer still beats a key in a lower one
+        let file = Settings {
+            api_key: Some("sk-from-config".to_string()),
+            ..Default::default()
+        };
+        let env = Settings {
+            api_key_file: Some(key_file),
+            ..Default::default()
+        };
+        let options = RunOptions::from_settings(env.or(file)).unwrap();
+        assert_eq!(options.llm.api_key.as_deref(), Some("sk-from-file"));
+    }
+
+    #[test]
+    fn test_config_file_candidates() {
+        let candidates = config_file_candidates(Some("/xdg".into()), Some("/home/u".into()));
+        assert_eq!(
+            candidates,
+            [
+                PathBuf::from(CONFIG_FILE),
+                PathBuf::from("/xdg/paper_scan/config.toml")
+            ]
+        );
+
+        let candidates = config_file_candidates(None, Some("/home/u".into()));
+        assert_eq!(
+            candidates[1],
+            PathBuf::from("/home/u/.config/paper_scan/config.toml")
+        );
+    }
+
+    #[test]
+    fn test_cli_overrides_env() {
+        let cli = Settings {
+            model: Some("cli-model".to_string()),
+            ..Default::default()
+        };
+        let env = Settings::from_lookup(|key| (key == "MODEL").then(|| "env-model".to_string()));
+
+        let options = RunOptions::from_settings(cli.or(env)).unwrap();
+        assert_eq!(options.llm.model, "cli-model");
+    }
+
+    #[test]
+    fn test_unknown_config_key_is_rejected() {
+        assert!(Settings::from_toml("modle = \"typo\"").is_err());
+    }
+}
@@ -0,0 +1,206 @@
+use anyhow::Result;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::config::RunOptions;
+use crate::note_generator;
+
+/// Outcome of one environment check.
+#[derive(Debug)]
+pub struct CheckResult {
+    pub name: String,
+    pub passed: bool,
+    pub detail: String,
+    /// How to fix a failed check
+    pub hint: Option<String>,
+}
+
+impl CheckResult {
+    fn pass(name: &str, detail: impl Into<String>) -> Self {
+        Self {
+            name: name.to_string(),
+            passed: true,
+            detail: detail.into(),
+            hint: None,
+        }
+    }
+
+    fn fail(name: &str, detail: impl Into<String>, hint: impl Into<String>) -> Self {
+        Self {
+            name: name.to_string(),
+            passed: false,
+            detail: detail.into(),
+            hint: Some(hint.into()),
+        }
+    }
+}
+
+/// Run every check, print the results, and fail if any check failed.
+pub async fn run(options: &RunOptions) -> Result<()> {
+    let api_key = options.llm.api_key.as_deref();
+    let checks = vec![
+        check_api_key(api_key),
+        check_endpoint(&options.llm.base_url, api_key).await,
+        check_prompt_file(&note_generator::prompt_file(options)),
+        check_writable("working directory", &options.work_dir),
+        check_writable("notes directory", &options.notes_dir),
+    ];
+
+    for check in &checks {
+        let status = if check.passed { "PASS" } else { "FAIL" };
+        println!("[{status}] {}: {}", check.name, check.detail);
+        if let Some(hint) = &check.hint {
+            println!("       hint: {hint}");
+        }
+    }
+
+    let failed = checks.iter().filter(|c| !c.passed).count();
+    if failed > 0 {
+        anyhow::bail!("{failed} check(s) failed");
+    }
+    println!("All checks passed");
+    Ok(())
+}
+
+pub fn check_api_key(api_key: Option<&str>) -> CheckResult {
+    const NAME: &str = "API key";
+    match api_key {
+        Some(key) if !key.trim().is_empty() => CheckResult::pass(NAME, "set"),
+        _ => CheckResult::fail(
+            NAME,
+            "API_KEY is not set",
+            "add API_KEY=... to .env or export it in your shell",
+        ),
+    }
+}
+
+/// List the provider's models to confirm `BASE_URL` is reachable and accepts the key.
+pub async fn check_endpoint(base_url: &str, api_key: Option<&str>) -> CheckResult {
+    const NAME: &str = "API endpoint";
+    let url = format!("{base_url}/models");
+    let client = match reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+    {
+        Ok(client) => client,
+        Err(e) => return CheckResult::fail(NAME, e.to_string(), "check your TLS setup"),
+    };
+
+    let mut request = client.get(&url);
+    if let Some(key) = api_key {
+        request = request.bearer_auth(key);
+    }
+
+    match request.send().await {
+        Ok(response) if response.status().is_success() => {
+            CheckResult::pass(NAME, format!("{url} responded"))
+        }
+        Ok(response) if matches!(response.status().as_u16(), 401 | 403) => CheckResult::fail(
+            NAME,
+            format!("{url} rejected the API key (HTTP {})", response.status()),
+            "check that API_KEY is valid for BASE_URL",
+        ),
+        // Some providers don't implement /models; any other answer still proves reachability
+        Ok(response) => CheckResult::pass(
+            NAME,
+            format!(
+                "{base_url} is reachable (HTTP {} from /models)",
+                response.status()
+            ),
+        ),
+        Err(e) => CheckResult::fail(
+            NAME,
+            format!("could not reach {url}: {e}"),
+            "check BASE_URL (it should end in the API version, e.g. .../v1) and your network",
+        ),
+    }
+}
+
+pub fn check_prompt_file(path: &Path) -> CheckResult {
+    const NAME: &str = "prompt file";
+    match std::fs::read_to_string(path) {
+        Ok(content) if !content.trim().is_empty() => {
+            CheckResult::pass(NAME, format!("{} is readable", path.display()))
+        }
+        Ok(_) => CheckResult::fail(
+            NAME,
+            format!("{} is empty", path.display()),
+            "restore the prompt file from the repository or point --prompt-file at another one",
+        ),
+        Err(e) => CheckResult::fail(
+            NAME,
+            format!("cannot read {}: {e}", path.display()),
+            "run from the repository root or pass --prompt-file",
+        ),
+    }
+}
+
+/// Confirm a file can be created in `dir`, creating the directory if needed.
+pub fn check_writable(name: &str, dir: &Path) -> CheckResult {
+    let result = std::fs::create_dir_all(dir).and_then(|_| tempfile::tempfile_in(dir));
+    match result {
+        Ok(_) => CheckResult::pass(name, format!("{} is writable", dir.display())),
+        Err(e) => CheckResult::fail(
+            name,
+            format!("cannot write to {}: {e}", dir.display()),
+            "fix the directory permissions or choose another one with --output-dir",
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_api_key_check() {
+        assert!(check_api_key(Some("sk-test")).passed);
+        assert!(!check_api_key(Some("  ")).passed);
+        let missing = check_api_key(None);
+        assert!(!missing.passed);
+        assert!(missing.hint.unwrap().contains(".env"));
+    }
+
+    #[tokio::test]
+    async fn test_endpoint_check() {
+        let mut server = mockito::Server::new_async().await;
+        let ok = server
+            .mock("GET", "/v1/models")
+            .match_header("authorization", "Bearer good")
+            .with_body(r#"{"data":[]}"#)
+            .create_async()
+            .await;
+        let rejected = server
+            .mock("GET", "/v1/models")
+            .match_header("authorization", "Bearer bad")
+            .with_status(401)
+            .create_async()
+            .await;
+        let base_url = format!("{}/v1", server.url());
+
+        assert!(check_endpoint(&base_url, Some("good")).await.passed);
+        let result = check_endpoint(&base_url, Some("bad")).await;
+        assert!(!result.passed);
+        assert!(result.detail.contains("rejected"), "{}", result.detail);
+        ok.assert_async().await;
+        rejected.assert_async().await;
+
+        // Nothing listens on port 9 (discard)
+        assert!(!check_endpoint("http://127.0.0.1:9/v1", None).await.passed);
+    }
+
+    #[test]
+    fn test_prompt_and_directory_checks() {
+        let dir = tempfile::tempdir().unwrap();
+        let prompt = dir.path().join("prompts.txt");
+
+        assert!(!check_prompt_file(&prompt).passed);
+        std::fs::write(&prompt, "summarize").unwrap();
+        assert!(check_prompt_file(&prompt).passed);
+
+        let notes = dir.path().join("tex");
+        assert!(check_writable("notes directory", &notes).passed);
+        assert!(notes.is_dir());
+        assert!(!check_writable("notes directory", &prompt.join("sub")).passed);
+    }
+}
@@ -1,9 +1,11 @@
-use anyhow::Result;
-use std::fs::File;
-use std::io::Write;
+use anyhow::{Context, Result};
+use futures::StreamExt;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
 
 use crate::arxiv::ArxivUrl;
+use crate::config::RunOptions;
 
 #[derive(Debug)]
 #[allow(dead_code)]
@@ -25,16 +27,28 @@ impl PaperData {
 
 pub struct PaperDownloader {
     client: reqwest::Client,
+    work_dir: PathBuf,
+    /// Smaller archives are rejected as likely error pages
+    min_size: u64,
+    /// Larger archives are rejected before being written to disk
+    max_size: u64,
 }
 
 impl PaperDownloader {
-    pub fn new() -> Self {
+    pub fn new(options: &RunOptions) -> Self {
+        // No overall timeout: a large archive may take minutes to stream, so only a
+        // stalled connection is given up on
         let client = reqwest::Client::builder()
-            .timeout(std::time::Duration::from_secs(30))
+            .connect_timeout(CONNECT_TIMEOUT)
             .build()
             .unwrap_or_else(|_| reqwest::Client::new());
 
-        Self { client }
+        Self {
+            client,
+            work_dir: options.work_dir.clone(),
+            min_size: options.min_archive_size,
+            max_size: options.max_archive_size,
+        }
     }
 
     pub async fn download(&self, arxiv_url: &ArxivUrl) -> Result<PaperData> {
@@ -43,14 +57,14 @@ impl PaperDownloader {
 
         // Create output directory for this paper
         let paper_id = arxiv_url.paper_id().to_string();
-        let output_dir = Path::new("output").join(&paper_id);
+        let output_dir = self.work_dir.join(&paper_id);
         std::fs::create_dir_all(&output_dir)?;
 
         // Create archive file path in output directory
         let archive_path = output_dir.join(format!("{paper_id}.tar.gz"));
 
         // Download the file
-        let response = self.client.get(download_url.as_str()).send().await?;
+        let response = self.get(download_url).await?;
 
         if !response.status().is_success() {
             return Err(anyhow::anyhow!(
@@ -59,36 +73,307 @@ impl PaperDownloader {
             ));
         }
 
-        // Save the downloaded content to file
-        let bytes = response.bytes().await?;
-        let mut file = File::create(&archive_path)?;
-        file.write_all(&bytes)?;
+        let is_html_type = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.trim_start().to_ascii_lowercase().starts_with("text/html"));
+        if is_html_type {
+            let page = response.text().await.unwrap_or_default();
+            if crate::arxiv::is_withdrawal_notice(&page, true) {
+                return Err(crate::arxiv::Withdrawn { paper_id }.into());
+            }
+            return Err(html_page_error(&paper_id));
+        }
 
-        println!(
-            "Downloaded {} bytes to {}",
-            bytes.len(),
-            archive_path.display()
-        );
+        // Refuse oversized archives up front when the server announces the size
+        if let Some(len) = response.content_length() {
+            self.check_size(&paper_id, len)?;
+        }
+
+        // Stream into a partial file and rename it once complete, so the archive
+        // path never holds a truncated archive
+        let partial_path = output_dir.join(format!("{paper_id}.tar.gz.part"));
+        let written = match self.write_body(&paper_id, response, &partial_path).await {
+            Ok(written) => written,
+            Err(e) => {
+                let _ = tokio::fs::remove_file(&partial_path).await;
+                return Err(e);
+            }
+        };
+        tokio::fs::rename(&partial_path, &archive_path).await?;
+
+        println!("Downloaded {written} bytes to {}", archive_path.display());
 
         Ok(PaperData::new(paper_id, archive_path, output_dir))
     }
-}
 
-impl Default for PaperDownloader {
-    fn default() -> Self {
-        Self::new()
+    /// The page at `url`, or `None` when arXiv has no HTML rendering of the paper.
+    pub async fn download_html(&self, paper_id: &str, url: &str) -> Result<Option<String>> {
+        println!("Downloading from: {url}");
+        let response = self.get(url).await?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        if !response.status().is_success() {
+            anyhow::bail!("Failed to download HTML: HTTP {}", response.status());
+        }
+
+        let page = tokio::time::timeout(READ_TIMEOUT, response.text())
+            .await
+            .with_context(|| format!("Download of {url} stalled"))??;
+        if crate::arxiv::is_withdrawal_notice(&page, true) {
+            return Err(crate::arxiv::Withdrawn {
+                paper_id: paper_id.to_string(),
+            }
+            .into());
+        }
+        Ok(Some(page))
+    }
+
+    /// Send a GET request, giving up if the response doesn't start within
+    /// `READ_TIMEOUT`.
+    async fn get(&self, url: &str) -> Result<reqwest::Response> {
+        tokio::time::timeout(READ_TIMEOUT, self.client.get(url).send())
+            .await
+            .with_context(|| format!("No response from {url} within {READ_TIMEOUT:?}"))?
+            .map_err(Into::into)
+    }
+
+    /// Write the response body to `path` chunk by chunk, checking the size limits and
+    /// for an HTML error page as it arrives. Returns the number of bytes written.
+    async fn write_body(
+        &self,
+        paper_id: &str,
+        response: reqwest::Response,
+        path: &Path,
+    ) -> Result<u64> {
+        let expected = response.content_length();
+        let mut stream = response.bytes_stream();
+        let mut file = tokio::fs::File::create(path).await?;
+        let mut head = Vec::with_capacity(SNIFF_LEN);
+        let mut written = 0u64;
+
+        loop {
+            let next = tokio::time::timeout(READ_TIMEOUT, stream.next())
+                .await
+                .with_context(|| {
+                    format!("Download of {paper_id} stalled: no data for {READ_TIMEOUT:?}")
+                })?;
+            let Some(chunk) = next else {
+                break;
+            };
+            let chunk = chunk.with_context(|| format!("Download of {paper_id} was interrupted"))?;
+            if head.len() < SNIFF_LEN {
+                let take = chunk.len().min(SNIFF_LEN - head.len());
+                head.extend_from_slice(&chunk[..take]);
+                if looks_like_html(&head) {
+                    return Err(html_page_error(paper_id));
+                }
+            }
+            written += chunk.len() as u64;
+            if written > self.max_size {
+                self.check_size(paper_id, written)?;
+            }
+            file.write_all(&chunk).await?;
+        }
+        file.flush().await?;
+
+        if let Some(expected) = expected {
+            if written < expected {
+                anyhow::bail!(
+                    "Download of {paper_id} was truncated: got {written} of {expected} bytes"
+                );
+            }
+        }
+        self.check_size(paper_id, written)?;
+        Ok(written)
+    }
+
+    fn check_size(&self, paper_id: &str, len: u64) -> Result<()> {
+        if len < self.min_size {
+            anyhow::bail!(
+                "Downloaded source for {paper_id} is only {len} bytes (minimum {}, see --min-size); \
+                 arXiv probably returned an error page instead of an archive",
+                self.min_size
+            );
+        }
+        if len > self.max_size {
+            anyhow::bail!(
+                "Source archive for {paper_id} is {len} bytes, over the {}-byte limit (--max-size)",
+                self.max_size
+            );
+        }
+        Ok(())
     }
 }
 
+/// How long to wait for a connection to arXiv
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long to wait for a response to start, or for the next chunk of its body
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How much of the body is kept to recognise an HTML page; enough to get past a
+/// BOM and leading whitespace.
+const SNIFF_LEN: usize = 64;
+
+/// Whether a body starts like an HTML document rather than an archive.
+fn looks_like_html(bytes: &[u8]) -> bool {
+    let start = bytes.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(bytes);
+    let start = &start[start.iter().take_while(|b| b.is_ascii_whitespace()).count()..];
+    let head = start[..start.len().min(9)].to_ascii_lowercase();
+    head.starts_with(b"<!doctype") || head.starts_with(b"<html")
+}
+
+/// File name of a paper's archive in the cache directory; old-style IDs lose their `/`.
+pub fn cached_archive_name(paper_id: &str) -> String {
+    format!("{}.tar.gz", paper_id.replace('/', "_"))
+}
+
+fn html_page_error(paper_id: &str) -> anyhow::Error {
+    anyhow::anyhow!(
+        "arXiv returned an HTML page, not an archive, for {paper_id} \
+         (paper may be withdrawn or source unavailable)"
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::arxiv::ArxivUrl;
 
+    #[tokio::test]
+    async fn test_tiny_download_is_rejected() {
+        let mut server = mockito::Server::new_async().await;
+        let src = server
+            .mock("GET", "/src/2401.08027")
+            .with_body("Service unavailable")
+            .create_async()
+            .await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut options = RunOptions::from_settings(Default::default()).unwrap();
+        options.work_dir = dir.path().to_path_buf();
+        let arxiv_url = ArxivUrl {
+            paper_id: "2401.08027".to_string(),
+            src_url: format!("{}/src/2401.08027", server.url()),
+        };
+
+        let err = PaperDownloader::new(&options)
+            .download(&arxiv_url)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("only 19 bytes"), "{err}");
+        src.assert_async().await;
+        assert!(!dir.path().join("2401.08027/2401.08027.tar.gz").exists());
+
+        options.max_archive_size = 1024;
+        let err = PaperDownloader::new(&options)
+            .check_size("2401.08027", 1025)
+            .unwrap_err();
+        assert!(err.to_string().contains("--max-size"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn test_html_page_is_rejected() {
+        let mut server = mockito::Server::new_async().await;
+        let page = format!(
+            "\n<!DOCTYPE html>\n<html><body>{}</body></html>",
+            "Source not available. ".repeat(100)
+        );
+        let typed = server
+            .mock("GET", "/src/typed")
+            .with_header("content-type", "text/html; charset=utf-8")
+            .with_body(&page)
+            .create_async()
+            .await;
+        let untyped = server
+            .mock("GET", "/src/untyped")
+            .with_header("content-type", "application/x-eprint-tar")
+            .with_body(&page)
+            .create_async()
+            .await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut options = RunOptions::from_settings(Default::default()).unwrap();
+        options.work_dir = dir.path().to_path_buf();
+        let downloader = PaperDownloader::new(&options);
+
+        for path in ["typed", "untyped"] {
+            let arxiv_url = ArxivUrl {
+                paper_id: "2401.08027".to_string(),
+                src_url: format!("{}/src/{path}", server.url()),
+            };
+            let err = downloader.download(&arxiv_url).await.unwrap_err();
+            assert!(
+                err.to_string()
+                    .contains("arXiv returned an HTML page, not an archive"),
+                "{err}"
+            );
+        }
+        typed.assert_async().await;
+        untyped.assert_async().await;
+        assert!(!looks_like_html(b"\x1f\x8b\x08\x00"));
+    }
+
+    #[tokio::test]
+    async fn test_streamed_download_is_written_whole() {
+        let chunks: Vec<Vec<u8>> = (0..50u8).map(|i| vec![i; 4096]).collect();
+        let expected = chunks.concat();
+
+        let mut server = mockito::Server::new_async().await;
+        let src = server
+            .mock("GET", "/src/2401.08027")
+            .with_chunked_body(move |w| {
+                for chunk in &chunks {
+                    w.write_all(chunk)?;
+                }
+                Ok(())
+            })
+            .create_async()
+            .await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut options = RunOptions::from_settings(Default::default()).unwrap();
+        options.work_dir = dir.path().to_path_buf();
+        let arxiv_url = ArxivUrl {
+            paper_id: "2401.08027".to_string(),
+            src_url: format!("{}/src/2401.08027", server.url()),
+        };
+
+        let paper_data = PaperDownloader::new(&options)
+            .download(&arxiv_url)
+            .await
+            .unwrap();
+        src.assert_async().await;
+
+        assert_eq!(std::fs::read(&paper_data.archive_path).unwrap(), expected);
+        assert!(!dir
+            .path()
+            .join("2401.08027/2401.08027.tar.gz.part")
+            .exists());
+
+        // Over the limit mid-stream: nothing is left behind
+        std::fs::remove_file(&paper_data.archive_path).unwrap();
+        options.max_archive_size = 10_000;
+        let err = PaperDownloader::new(&options)
+            .download(&arxiv_url)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("--max-size"), "{err}");
+        assert!(!dir
+            .path()
+            .join("2401.08027/2401.08027.tar.gz.part")
+            .exists());
+        assert!(!paper_data.archive_path.exists());
+    }
+
     #[tokio::test]
     async fn test_download_structure() {
         let arxiv_url = ArxivUrl::parse("https://arxiv.org/abs/2401.08027").unwrap();
-        let downloader = PaperDownloader::new();
+        let options = RunOptions::from_settings(Default::default()).unwrap();
+        let downloader = PaperDownloader::new(&options);
 
         // Verify the URL structure
         assert_eq!(arxiv_url.src_url, "https://arxiv.org/src/2401.08027.tar.gz");
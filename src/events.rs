@@ -0,0 +1,143 @@
+use serde::Serialize;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+/// A significant step in processing a paper, emitted as one NDJSON line with
+/// `--json-logs`.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    DownloadStart {
+        paper_id: &'a str,
+        url: &'a str,
+    },
+    DownloadDone {
+        paper_id: &'a str,
+        bytes: u64,
+        secs: f64,
+    },
+    ExtractDone {
+        paper_id: &'a str,
+        tex_files: usize,
+        image_files: usize,
+        skipped_entries: usize,
+        secs: f64,
+    },
+    LlmDone {
+        paper_id: &'a str,
+        model: &'a str,
+        prompt_tokens: Option<u32>,
+        completion_tokens: Option<u32>,
+        total_tokens: Option<u32>,
+        secs: f64,
+    },
+    PaperDone {
+        paper_id: &'a str,
+        /// The paper already had a note and was left alone
+        skipped: bool,
+    },
+    PaperFailed {
+        paper_id: &'a str,
+        error: String,
+    },
+}
+
+/// Where events go: nowhere by default, stderr with `--json-logs`. Clones share the
+/// sink, so lines from concurrent batch tasks never interleave.
+#[derive(Clone, Default)]
+pub struct EventLog {
+    sink: Option<Arc<Mutex<dyn Write + Send>>>,
+}
+
+impl std::fmt::Debug for EventLog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EventLog")
+            .field("enabled", &self.sink.is_some())
+            .finish()
+    }
+}
+
+impl EventLog {
+    pub fn stderr() -> Self {
+        Self::to_writer(std::io::stderr())
+    }
+
+    pub fn to_writer(writer: impl Write + Send + 'static) -> Self {
+        Self {
+            sink: Some(Arc::new(Mutex::new(writer))),
+        }
+    }
+
+    pub fn emit(&self, event: Event) {
+        let Some(sink) = &self.sink else {
+            return;
+        };
+        let mut line = serde_json::to_value(&event).expect("events serialize");
+        line["timestamp"] = chrono::Utc::now().to_rfc3339().into();
+
+        let mut sink = sink.lock().unwrap_or_else(|e| e.into_inner());
+        // Losing a log line must not fail the paper
+        let _ = writeln!(sink, "{line}");
+    }
+}
+
+/// A writer tests can hand to `EventLog::to_writer` and read back.
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct Captured(pub Arc<Mutex<Vec<u8>>>);
+
+#[cfg(test)]
+impl Captured {
+    /// Every event written so far, parsed.
+    pub fn events(&self) -> Vec<serde_json::Value> {
+        let bytes = self.0.lock().unwrap();
+        String::from_utf8_lossy(&bytes)
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+impl Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_events_are_ndjson_lines() {
+        let captured = Captured::default();
+        let log = EventLog::to_writer(captured.clone());
+
+        log.emit(Event::DownloadDone {
+            paper_id: "2401.08027",
+            bytes: 1234,
+            secs: 0.5,
+        });
+        log.emit(Event::PaperFailed {
+            paper_id: "2401.08027",
+            error: "boom".to_string(),
+        });
+        EventLog::default().emit(Event::PaperDone {
+            paper_id: "x",
+            skipped: false,
+        });
+
+        let events = captured.events();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["event"], "download_done");
+        assert_eq!(events[0]["bytes"], 1234);
+        assert!(events[0]["timestamp"].is_string());
+        assert_eq!(events[1]["event"], "paper_failed");
+        assert_eq!(events[1]["error"], "boom");
+    }
+}
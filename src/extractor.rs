@@ -1,85 +1,224 @@
-use anyhow::Result;
-use flate2::read::GzDecoder;
+use anyhow::{Context, Result};
+use flate2::read::MultiGzDecoder;
+use regex::Regex;
 use std::fs::File;
-use std::path::{Path, PathBuf};
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
 use tar::Archive;
 use walkdir::WalkDir;
 use zip::ZipArchive;
 
+use crate::arxiv::strip_version;
 use crate::downloader::PaperData;
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 #[allow(dead_code)]
 pub struct ExtractedContent {
     pub tex_files: Vec<PathBuf>,
     pub bib_files: Vec<PathBuf>,
     pub image_files: Vec<PathBuf>,
     pub main_tex_file: Option<PathBuf>,
+    /// Compiled paper PDF shipped with the source, kept out of `image_files`
+    pub paper_pdf: Option<PathBuf>,
     pub extracted_dir: PathBuf,
+    /// Archive entries that could not be extracted and were left out
+    pub skipped_entries: usize,
 }
 
-pub struct ArchiveExtractor;
+/// File names (without `.tex`) tried, after the paper ID, when no file has
+/// `\documentclass` or includes the others. A hint also matches conference-style
+/// names that extend it, e.g. `neurips` matches `neurips_2024.tex`.
+const DEFAULT_MAIN_HINTS: [&str; 20] = [
+    "main",
+    "paper",
+    "article",
+    "ms",
+    "manuscript",
+    "root",
+    "template",
+    "arxiv",
+    "draft",
+    "sample",
+    "neurips",
+    "icml",
+    "iclr",
+    "acl",
+    "emnlp",
+    "naacl",
+    "cvpr",
+    "iccv",
+    "eccv",
+    "aaai",
+];
+
+/// Top-level directory arXiv reserves for ancillary files (data, code, supplements)
+const ANCILLARY_DIR: &str = "anc";
+
+pub struct ArchiveExtractor {
+    /// User-supplied main file names, tried before the defaults
+    main_hints: Vec<String>,
+    /// Scan `anc/` too instead of leaving it out
+    include_ancillary: bool,
+}
 
 impl ArchiveExtractor {
     pub fn new() -> Self {
-        Self
+        Self::with_main_hints(Vec::new())
+    }
+
+    pub fn with_main_hints(main_hints: Vec<String>) -> Self {
+        Self {
+            main_hints,
+            include_ancillary: false,
+        }
+    }
+
+    pub fn include_ancillary(mut self, include: bool) -> Self {
+        self.include_ancillary = include;
+        self
     }
 
     pub fn extract(&self, paper_data: PaperData) -> Result<ExtractedContent> {
+        let paper_id = paper_data.paper_id;
         let archive_path = paper_data.archive_path;
         let extract_dir = paper_data.output_dir.join("extracted");
         std::fs::create_dir_all(&extract_dir)?;
 
         // Determine archive type and extract accordingly
-        if archive_path.extension().and_then(|s| s.to_str()) == Some("gz") {
-            self.extract_tar_gz(&archive_path, &extract_dir)?;
+        let skipped_entries = if archive_path.is_dir() {
+            copy_source_dir(&archive_path, &extract_dir)?;
+            0
+        } else if archive_path.extension().and_then(|s| s.to_str()) == Some("gz") {
+            self.extract_gzip(&archive_path, &extract_dir, &paper_id)?
         } else if archive_path.extension().and_then(|s| s.to_str()) == Some("zip") {
-            self.extract_zip(&archive_path, &extract_dir)?;
+            self.extract_zip(&archive_path, &extract_dir)?
         } else {
             return Err(anyhow::anyhow!("Unsupported archive format"));
+        };
+        if skipped_entries > 0 {
+            eprintln!(
+                "Warning: skipped {skipped_entries} archive entr{} of {paper_id} that could not be extracted",
+                if skipped_entries == 1 { "y" } else { "ies" }
+            );
         }
 
         // Scan extracted directory for files
-        self.scan_extracted_files(&extract_dir)
+        let mut content = self.scan_extracted_files(&extract_dir, &paper_id)?;
+        content.skipped_entries = skipped_entries;
+        Ok(content)
     }
 
-    fn extract_tar_gz(&self, archive_path: &Path, extract_dir: &Path) -> Result<()> {
-        let file = File::open(archive_path)?;
-        let decoder = GzDecoder::new(file);
-        let mut archive = Archive::new(decoder);
+    /// arXiv serves a gzip that holds either a tarball or, for single-file
+    /// submissions, the bare `.tex`. Fresh downloads and cached archives both come
+    /// through here, so they are always unpacked the same way. Some are several gzip
+    /// members back to back, which are read as one stream. Returns how many tar
+    /// entries were skipped.
+    fn extract_gzip(
+        &self,
+        archive_path: &Path,
+        extract_dir: &Path,
+        paper_id: &str,
+    ) -> Result<usize> {
+        // Read the first tar header instead of decompressing the whole archive. A
+        // single read may stop short at the end of a gzip member, so read up to a
+        // full block.
+        let mut decoder = MultiGzDecoder::new(File::open(archive_path)?);
+        let mut head = Vec::with_capacity(TAR_BLOCK);
+        (&mut decoder)
+            .take(TAR_BLOCK as u64)
+            .read_to_end(&mut head)
+            .with_context(|| format!("Failed to decompress {}", archive_path.display()))?;
+        let tar = is_tar(&head);
+        let mut decompressed = head.as_slice().chain(decoder);
 
-        archive.unpack(extract_dir)?;
+        if !tar {
+            let tex_path = extract_dir.join(format!("{}.tex", paper_id.replace('/', "_")));
+            io::copy(&mut decompressed, &mut File::create(&tex_path)?)
+                .with_context(|| format!("Failed to decompress {}", archive_path.display()))?;
+            println!("Extracted single TeX file to {}", tex_path.display());
+            return Ok(0);
+        }
 
+        // Unpack entry by entry so that one bad entry doesn't lose the rest
+        let mut archive = Archive::new(decompressed);
+        let (mut extracted, mut skipped) = (0, 0);
+        let mut links = Vec::new();
+        for entry in archive.entries()? {
+            let mut entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    // The entries after a corrupt header can't be located
+                    eprintln!(
+                        "Warning: corrupt tar entry in {}: {e}",
+                        archive_path.display()
+                    );
+                    skipped += 1;
+                    break;
+                }
+            };
+            let name = entry
+                .path()
+                .map(|p| p.display().to_string())
+                .unwrap_or_default();
+            // Links are replaced by copies of their targets once every file is out
+            let entry_type = entry.header().entry_type();
+            if entry_type.is_symlink() || entry_type.is_hard_link() {
+                match entry.link_name() {
+                    Ok(Some(target)) => links.push(TarLink {
+                        path: PathBuf::from(&name),
+                        target: target.into_owned(),
+                        hard: entry_type.is_hard_link(),
+                    }),
+                    _ => {
+                        eprintln!("Warning: skipping tar link {name} without a target");
+                        skipped += 1;
+                    }
+                }
+                continue;
+            }
+            match entry.unpack_in(extract_dir) {
+                Ok(true) => extracted += 1,
+                // Paths escaping the extraction directory are refused
+                Ok(false) => {
+                    eprintln!("Warning: skipping tar entry outside the archive root: {name}");
+                    skipped += 1;
+                }
+                Err(e) => {
+                    eprintln!("Warning: skipping tar entry {name}: {e}");
+                    skipped += 1;
+                }
+            }
+        }
+        let (copied, unresolved) = copy_link_targets(&links, extract_dir);
+        extracted += copied;
+        skipped += unresolved;
+        check_extracted(archive_path, extracted, skipped)?;
         println!("Extracted tar.gz archive to {}", extract_dir.display());
-        Ok(())
+        Ok(skipped)
     }
 
-    fn extract_zip(&self, archive_path: &Path, extract_dir: &Path) -> Result<()> {
+    /// Extract a zip archive, skipping entries that fail; returns how many were skipped.
+    fn extract_zip(&self, archive_path: &Path, extract_dir: &Path) -> Result<usize> {
         let file = File::open(archive_path)?;
         let mut archive = ZipArchive::new(file)?;
 
+        let (mut extracted, mut skipped) = (0, 0);
         for i in 0..archive.len() {
-            let mut file = archive.by_index(i)?;
-            let outpath = extract_dir.join(file.name());
-
-            if file.name().ends_with('/') {
-                std::fs::create_dir_all(&outpath)?;
-            } else {
-                if let Some(p) = outpath.parent() {
-                    if !p.exists() {
-                        std::fs::create_dir_all(p)?;
-                    }
+            match extract_zip_entry(&mut archive, i, extract_dir) {
+                Ok(()) => extracted += 1,
+                Err(e) => {
+                    eprintln!("Warning: skipping zip entry {i}: {e:#}");
+                    skipped += 1;
                 }
-                let mut outfile = File::create(&outpath)?;
-                std::io::copy(&mut file, &mut outfile)?;
             }
         }
 
+        check_extracted(archive_path, extracted, skipped)?;
         println!("Extracted zip archive to {}", extract_dir.display());
-        Ok(())
+        Ok(skipped)
     }
 
-    fn scan_extracted_files(&self, extract_dir: &Path) -> Result<ExtractedContent> {
+    fn scan_extracted_files(&self, extract_dir: &Path, paper_id: &str) -> Result<ExtractedContent> {
         let mut tex_files = Vec::new();
         let mut bib_files = Vec::new();
         let mut image_files = Vec::new();
@@ -101,11 +240,20 @@ impl ArchiveExtractor {
             }
         }
 
-        for entry in WalkDir::new(extract_dir) {
+        let ancillary_dir = extract_dir.join(ANCILLARY_DIR);
+        let walker = WalkDir::new(extract_dir)
+            .into_iter()
+            .filter_entry(|entry| self.include_ancillary || entry.path() != ancillary_dir);
+        for entry in walker {
             let entry = entry?;
             let path = entry.path();
 
-            if path.is_file() {
+            // PNG conversions of EPS/PDF figures from an earlier run
+            let is_conversion = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| n.ends_with(".converted.png"));
+            if path.is_file() && !is_conversion {
                 match path.extension().and_then(|s| s.to_str()) {
                     Some("tex") => {
                         println!("Found TeX file: {}", path.display());
@@ -126,56 +274,210 @@ impl ArchiveExtractor {
                 }
             }
         }
+        // The walk's order depends on the file system, and the figure list in the
+        // prompt shouldn't
+        tex_files.sort();
+        bib_files.sort();
+        image_files.sort();
 
         // Find main TeX file (usually the one with \documentclass)
-        let main_tex_file = self.find_main_tex_file(&tex_files)?;
+        let main_tex_file = self.find_main_tex_file(&tex_files, paper_id)?;
+
+        // The compiled paper is not a figure, so don't list it as one
+        let paper_pdf = self.find_paper_pdf(&image_files, extract_dir, paper_id);
+        if let Some(pdf) = &paper_pdf {
+            println!("Found paper PDF: {}", pdf.display());
+            image_files.retain(|p| p != pdf);
+        }
 
         Ok(ExtractedContent {
             tex_files,
             bib_files,
             image_files,
             main_tex_file,
+            paper_pdf,
             extracted_dir: extract_dir.to_path_buf(),
+            skipped_entries: 0,
         })
     }
 
-    fn find_main_tex_file(&self, tex_files: &[PathBuf]) -> Result<Option<PathBuf>> {
+    /// Pick out the compiled paper among the PDFs at the archive root: one named after
+    /// the paper ID or a common main-file name, otherwise the largest multi-page PDF.
+    fn find_paper_pdf(
+        &self,
+        image_files: &[PathBuf],
+        extract_dir: &Path,
+        paper_id: &str,
+    ) -> Option<PathBuf> {
+        let root_pdfs: Vec<&PathBuf> = image_files
+            .iter()
+            .filter(|p| p.parent() == Some(extract_dir))
+            .filter(|p| {
+                p.extension()
+                    .is_some_and(|ext| ext.eq_ignore_ascii_case("pdf"))
+            })
+            .collect();
+
+        let base_id = strip_version(paper_id);
+        let main_names = [paper_id, base_id, "main", "paper", "ms"];
+        if let Some(pdf) = root_pdfs.iter().find(|p| {
+            p.file_stem()
+                .and_then(|s| s.to_str())
+                .is_some_and(|stem| main_names.iter().any(|n| stem.eq_ignore_ascii_case(n)))
+        }) {
+            return Some(pdf.to_path_buf());
+        }
+
+        // Figures are almost always single-page; the paper itself is not
+        root_pdfs
+            .into_iter()
+            .filter(|p| count_pdf_pages(p) > 1)
+            .max_by_key(|p| std::fs::metadata(p).map(|m| m.len()).unwrap_or(0))
+            .cloned()
+    }
+
+    fn find_main_tex_file(&self, tex_files: &[PathBuf], paper_id: &str) -> Result<Option<PathBuf>> {
         if tex_files.is_empty() {
             return Ok(None);
         }
 
-        // Look for documentclass in each file
-        for tex_file in tex_files {
-            if let Ok(content) = std::fs::read_to_string(tex_file) {
-                if content.contains(r"\documentclass") || content.contains(r"\documentstyle") {
-                    return Ok(Some(tex_file.clone()));
-                }
-            }
+        // Look for documentclass in each file. With the `subfiles` package every
+        // chapter has its own \documentclass, so prefer the one no other file includes.
+        let input_re = Regex::new(r"\\(?:input|include|subfile)\s*\{([^}]+)\}").unwrap();
+        let contents: Vec<(&PathBuf, String)> = tex_files
+            .iter()
+            .filter_map(|tex_file| Some((tex_file, std::fs::read_to_string(tex_file).ok()?)))
+            .collect();
+        let included: Vec<PathBuf> = contents
+            .iter()
+            .flat_map(|(_, content)| input_re.captures_iter(content))
+            .map(|caps| {
+                let name = caps[1].trim().trim_start_matches("./");
+                PathBuf::from(name.trim_end_matches(".tex"))
+            })
+            .collect();
+        let is_included = |tex_file: &Path| {
+            let stem = tex_file.with_extension("");
+            included.iter().any(|name| stem.ends_with(name))
+        };
+        let with_class: Vec<&PathBuf> = contents
+            .iter()
+            .filter(|(_, content)| {
+                content.contains(r"\documentclass") || content.contains(r"\documentstyle")
+            })
+            .map(|(tex_file, _)| *tex_file)
+            .collect();
+        if let Some(root) = with_class
+            .iter()
+            .find(|tex_file| !is_included(tex_file))
+            .or(with_class.first())
+        {
+            return Ok(Some((*root).clone()));
+        }
+
+        // The file that pulls in the most other files is usually the main document,
+        // e.g. when \documentclass lives in a custom .cls or behind a macro.
+        // Iterate in reverse so that ties go to the first file.
+        let orchestrator = contents
+            .iter()
+            .rev()
+            .filter_map(|(tex_file, content)| {
+                let inputs = input_re.find_iter(content).count();
+                (inputs > 0).then_some((tex_file, inputs))
+            })
+            .max_by_key(|(_, inputs)| *inputs);
+        if let Some((tex_file, _)) = orchestrator {
+            return Ok(Some((*tex_file).clone()));
         }
 
         // Look for files with common main file names
-        let main_names = [
-            "main.tex",
-            "paper.tex",
-            "article.tex",
-            "ms.tex",
-            "template.tex",
-        ];
-        for tex_file in tex_files {
-            if let Some(file_name) = tex_file.file_name().and_then(|n| n.to_str()) {
-                if main_names
-                    .iter()
-                    .any(|name| file_name.eq_ignore_ascii_case(name))
-                {
-                    return Ok(Some(tex_file.clone()));
-                }
-            }
+        if let Some(tex_file) = self.find_by_name_hint(tex_files, paper_id) {
+            return Ok(Some(tex_file.clone()));
         }
 
         // If no documentclass found, return None to indicate no main file
         // The processor will handle reading all files
         Ok(None)
     }
+
+    /// The first file named after a hint: user hints, then the paper ID, then the
+    /// defaults. Exact names win over conference-style extensions of a hint.
+    fn find_by_name_hint<'a>(
+        &self,
+        tex_files: &'a [PathBuf],
+        paper_id: &str,
+    ) -> Option<&'a PathBuf> {
+        let paper_id = paper_id.replace('/', "_");
+        let hints: Vec<String> = self
+            .main_hints
+            .iter()
+            .map(|hint| hint.trim().trim_end_matches(".tex").to_lowercase())
+            .chain([paper_id.clone(), strip_version(&paper_id).to_string()])
+            .chain(DEFAULT_MAIN_HINTS.iter().map(|hint| hint.to_string()))
+            .filter(|hint| !hint.is_empty())
+            .collect();
+        let stems: Vec<(String, &PathBuf)> = tex_files
+            .iter()
+            .filter_map(|path| {
+                let stem = path.file_stem()?.to_str()?.to_lowercase();
+                Some((stem, path))
+            })
+            .collect();
+
+        let exact = hints
+            .iter()
+            .find_map(|hint| stems.iter().find(|(stem, _)| stem == hint));
+        let extended = || {
+            hints.iter().find_map(|hint| {
+                stems.iter().find(|(stem, _)| {
+                    stem.strip_prefix(hint.as_str()).is_some_and(|rest| {
+                        rest.starts_with(|c: char| c == '_' || c == '-' || c.is_ascii_digit())
+                    })
+                })
+            })
+        };
+        exact.or_else(extended).map(|(_, path)| *path)
+    }
+}
+
+/// Size of a tar header, which holds the `ustar` magic
+const TAR_BLOCK: usize = 512;
+
+/// Whether decompressed bytes start with a tar header: one with the POSIX `ustar`
+/// magic at offset 257, or an old-style header without it whose checksum adds up.
+fn is_tar(bytes: &[u8]) -> bool {
+    let Some(header) = bytes.get(..TAR_BLOCK) else {
+        return false;
+    };
+    if header[257..262] == *b"ustar" {
+        return true;
+    }
+    // The checksum is the sum of the header bytes, counting its own field as spaces
+    let recorded = std::str::from_utf8(&header[148..156])
+        .ok()
+        .map(|field| field.trim_matches(|c: char| c == '\0' || c == ' '))
+        .and_then(|field| u32::from_str_radix(field, 8).ok());
+    let sum: u32 = header
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| {
+            if (148..156).contains(&i) {
+                u32::from(b' ')
+            } else {
+                u32::from(b)
+            }
+        })
+        .sum();
+    recorded == Some(sum)
+}
+
+/// Rough page count from the `/Type /Page` objects in a PDF (0 if unreadable).
+fn count_pdf_pages(path: &Path) -> usize {
+    let Ok(bytes) = std::fs::read(path) else {
+        return 0;
+    };
+    let re = regex::bytes::Regex::new(r"/Type\s*/Page\b").unwrap();
+    re.find_iter(&bytes).count()
 }
 
 impl Default for ArchiveExtractor {
@@ -183,3 +485,593 @@ impl Default for ArchiveExtractor {
         Self::new()
     }
 }
+
+fn extract_zip_entry(
+    archive: &mut ZipArchive<File>,
+    index: usize,
+    extract_dir: &Path,
+) -> Result<()> {
+    let mut file = archive.by_index(index)?;
+    // `enclosed_name` rejects absolute paths and `..` components
+    let relative = file
+        .enclosed_name()
+        .with_context(|| format!("{} points outside the archive root", file.name()))?
+        .to_path_buf();
+    let outpath = extract_dir.join(&relative);
+
+    if file.is_dir() {
+        std::fs::create_dir_all(&outpath)?;
+    } else {
+        if let Some(p) = outpath.parent() {
+            std::fs::create_dir_all(p)?;
+        }
+        let mut outfile = File::create(&outpath)
+            .with_context(|| format!("Failed to create {}", relative.display()))?;
+        std::io::copy(&mut file, &mut outfile)?;
+    }
+    Ok(())
+}
+
+/// Copy a local source directory into the work directory, so converted figures
+/// and other artifacts never land in the user's tree.
+fn copy_source_dir(source_dir: &Path, extract_dir: &Path) -> Result<()> {
+    for entry in WalkDir::new(source_dir).min_depth(1) {
+        let entry = entry?;
+        let relative = entry.path().strip_prefix(source_dir)?;
+        let target = extract_dir.join(relative);
+        if entry.file_type().is_dir() {
+            std::fs::create_dir_all(&target)?;
+        } else if entry.file_type().is_file() {
+            std::fs::copy(entry.path(), &target)
+                .with_context(|| format!("Failed to copy {}", entry.path().display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// A symlink or hard link entry of a tarball.
+struct TarLink {
+    path: PathBuf,
+    target: PathBuf,
+    /// Hard link targets are relative to the archive root, symlinks to the link
+    hard: bool,
+}
+
+/// Materialize tar links as copies of their targets, so figures shared between
+/// directories are found without following links on disk. Links pointing outside
+/// the extraction root, or at nothing, are skipped with a warning. Returns how many
+/// links were copied and how many skipped.
+fn copy_link_targets(links: &[TarLink], extract_dir: &Path) -> (usize, usize) {
+    let resolve = |link: &TarLink| {
+        let path = within(extract_dir, &link.path)?;
+        let base = if link.hard {
+            PathBuf::new()
+        } else {
+            link.path.parent().unwrap_or(Path::new("")).to_path_buf()
+        };
+        let target = within(extract_dir, &base.join(&link.target))?;
+        Some((path, target))
+    };
+
+    // Links to links resolve once their own target has been copied
+    let mut pending: Vec<&TarLink> = links.iter().collect();
+    let mut copied = 0;
+    loop {
+        let before = pending.len();
+        pending.retain(|link| {
+            let Some((path, target)) = resolve(link) else {
+                return true;
+            };
+            // A link to one of its own ancestors would copy forever
+            if !target.exists() || path.starts_with(&target) {
+                return true;
+            }
+            if let Some(parent) = path.parent() {
+                let _ = std::fs::create_dir_all(parent);
+            }
+            let result = if target.is_dir() {
+                copy_source_dir(&target, &path)
+            } else {
+                std::fs::copy(&target, &path)
+                    .map(|_| ())
+                    .map_err(anyhow::Error::from)
+            };
+            match result {
+                Ok(()) => copied += 1,
+                Err(e) => eprintln!(
+                    "Warning: failed to copy tar link {}: {e:#}",
+                    link.path.display()
+                ),
+            }
+            false
+        });
+        if pending.is_empty() || pending.len() == before {
+            break;
+        }
+    }
+
+    for link in &pending {
+        eprintln!(
+            "Warning: skipping tar link {} -> {}: target is missing, outside the archive root or contains the link",
+            link.path.display(),
+            link.target.display()
+        );
+    }
+    (copied, pending.len())
+}
+
+/// `root` joined with `relative`, or `None` if `relative` is absolute or climbs
+/// out of `root` with `..`. Resolved lexically, without touching the filesystem.
+fn within(root: &Path, relative: &Path) -> Option<PathBuf> {
+    let mut parts: Vec<&std::ffi::OsStr> = Vec::new();
+    for component in relative.components() {
+        match component {
+            Component::Normal(part) => parts.push(part),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                parts.pop()?;
+            }
+            Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    if parts.is_empty() {
+        return None;
+    }
+    Some(
+        parts
+            .iter()
+            .fold(root.to_path_buf(), |path, part| path.join(part)),
+    )
+}
+
+/// A partly broken archive is still worth processing; one that yielded nothing isn't.
+fn check_extracted(archive_path: &Path, extracted: usize, skipped: usize) -> Result<()> {
+    if extracted == 0 && skipped > 0 {
+        anyhow::bail!(
+            "No files could be extracted from {} ({skipped} entries failed)",
+            archive_path.display()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::tar_gz;
+    use std::fs;
+
+    #[test]
+    fn test_bad_entries_are_skipped() {
+        // `blocker/inner.tex` can't be written because `blocker` is a file
+        let files = [
+            (
+                "main.tex",
+                "\\documentclass{article}\n\\begin{document}\\input{intro}\\end{document}",
+            ),
+            ("blocker", "not a directory"),
+            ("blocker/inner.tex", "lost"),
+            ("intro.tex", "\\section{Introduction}"),
+        ];
+        let dir = tempfile::tempdir().unwrap();
+
+        let tar_path = dir.path().join("tar/2401.08027.tar.gz");
+        fs::create_dir_all(tar_path.parent().unwrap()).unwrap();
+        fs::write(&tar_path, tar_gz(&files)).unwrap();
+
+        let zip_path = dir.path().join("zip/2401.08027.zip");
+        fs::create_dir_all(zip_path.parent().unwrap()).unwrap();
+        let mut writer = zip::ZipWriter::new(File::create(&zip_path).unwrap());
+        for (name, content) in files {
+            writer.start_file(name, Default::default()).unwrap();
+            std::io::Write::write_all(&mut writer, content.as_bytes()).unwrap();
+        }
+        writer.finish().unwrap();
+
+        for archive_path in [tar_path, zip_path] {
+            let output_dir = archive_path.parent().unwrap().to_path_buf();
+            let paper_data = PaperData::new("2401.08027".to_string(), archive_path, output_dir);
+
+            let extracted = ArchiveExtractor::new().extract(paper_data).unwrap();
+
+            assert_eq!(extracted.skipped_entries, 1);
+            let mut names: Vec<_> = extracted
+                .tex_files
+                .iter()
+                .map(|p| p.file_name().unwrap().to_str().unwrap().to_string())
+                .collect();
+            names.sort();
+            assert_eq!(names, ["intro.tex", "main.tex"]);
+            assert!(extracted.main_tex_file.unwrap().ends_with("main.tex"));
+        }
+    }
+
+    #[test]
+    fn test_truncated_gzip_keeps_leading_entries() {
+        // Barely compressible, so cutting the archive in half lands inside it
+        let mut state = 1u32;
+        let noise: String = (0..20_000)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                char::from(b'a' + (state >> 16) as u8 % 26)
+            })
+            .collect();
+        let gz = tar_gz(&[
+            ("main.tex", "\\documentclass{article}"),
+            ("data.tex", &noise),
+        ]);
+        let dir = tempfile::tempdir().unwrap();
+        let archive_path = dir.path().join("2401.08027.tar.gz");
+        fs::write(&archive_path, &gz[..gz.len() / 2]).unwrap();
+
+        let paper_data = PaperData::new(
+            "2401.08027".to_string(),
+            archive_path,
+            dir.path().to_path_buf(),
+        );
+        let extracted = ArchiveExtractor::new().extract(paper_data).unwrap();
+
+        assert!(extracted.skipped_entries > 0);
+        assert!(extracted.main_tex_file.unwrap().ends_with("main.tex"));
+    }
+
+    #[test]
+    fn test_tarball_is_recognized_across_short_gzip_members() {
+        let content = "\\documentclass{article}";
+        // Old-style headers have no `ustar` magic
+        for mut header in [tar::Header::new_ustar(), tar::Header::new_old()] {
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_path("main.tex").unwrap();
+            header.set_cksum();
+            let mut builder = tar::Builder::new(Vec::new());
+            builder.append(&header, content.as_bytes()).unwrap();
+            let tar = builder.into_inner().unwrap();
+
+            // The first member ends before the offset of the magic
+            let mut gz = Vec::new();
+            for part in [&tar[..100], &tar[100..]] {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                std::io::Write::write_all(&mut encoder, part).unwrap();
+                gz.extend(encoder.finish().unwrap());
+            }
+            let dir = tempfile::tempdir().unwrap();
+            let archive_path = dir.path().join("2401.08027.tar.gz");
+            fs::write(&archive_path, gz).unwrap();
+
+            let paper_data = PaperData::new(
+                "2401.08027".to_string(),
+                archive_path,
+                dir.path().to_path_buf(),
+            );
+            let extracted = ArchiveExtractor::new().extract(paper_data).unwrap();
+
+            assert!(extracted.main_tex_file.unwrap().ends_with("main.tex"));
+            assert_eq!(
+                fs::read_to_string(dir.path().join("extracted/main.tex")).unwrap(),
+                content
+            );
+        }
+    }
+
+    #[test]
+    fn test_tar_links_are_copied_within_the_root() {
+        let mut builder = tar::Builder::new(Vec::new());
+        let file = |builder: &mut tar::Builder<Vec<u8>>, name: &str, content: &str| {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, name, content.as_bytes())
+                .unwrap();
+        };
+        let link = |builder: &mut tar::Builder<Vec<u8>>, kind, name: &str, target: &str| {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(kind);
+            header.set_size(0);
+            header.set_mode(0o777);
+            builder.append_link(&mut header, name, target).unwrap();
+        };
+        use tar::EntryType::{Link, Symlink};
+        // Links before their targets, and a link to a link
+        link(
+            &mut builder,
+            Symlink,
+            "paper/figs/fig.pdf",
+            "../../shared/fig.pdf",
+        );
+        link(&mut builder, Symlink, "paper/figs/copy.pdf", "fig.pdf");
+        link(&mut builder, Link, "paper/hard.tex", "paper/main.tex");
+        link(
+            &mut builder,
+            Symlink,
+            "paper/escape.pdf",
+            "../../../outside.pdf",
+        );
+        link(&mut builder, Symlink, "paper/absolute.pdf", "/etc/passwd");
+        link(&mut builder, Symlink, "paper/self", "..");
+        file(&mut builder, "shared/fig.pdf", "%PDF-1.5 figure");
+        file(&mut builder, "paper/main.tex", "\\documentclass{article}");
+        let tar = builder.into_inner().unwrap();
+
+        // Split across two gzip members, as some archives are
+        let dir = tempfile::tempdir().unwrap();
+        let archive_path = dir.path().join("2401.08027.tar.gz");
+        let mut gz = Vec::new();
+        for half in [&tar[..700], &tar[700..]] {
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            std::io::Write::write_all(&mut encoder, half).unwrap();
+            gz.extend(encoder.finish().unwrap());
+        }
+        fs::write(&archive_path, gz).unwrap();
+        fs::write(dir.path().join("outside.pdf"), "secret").unwrap();
+
+        let paper_data = PaperData::new(
+            "2401.08027".to_string(),
+            archive_path,
+            dir.path().join("out"),
+        );
+        let extracted = ArchiveExtractor::new().extract(paper_data).unwrap();
+
+        let root = dir.path().join("out/extracted");
+        for copy in ["paper/figs/fig.pdf", "paper/figs/copy.pdf"] {
+            let path = root.join(copy);
+            assert!(!path.is_symlink(), "{copy}");
+            assert_eq!(fs::read_to_string(path).unwrap(), "%PDF-1.5 figure");
+        }
+        assert_eq!(
+            fs::read_to_string(root.join("paper/hard.tex")).unwrap(),
+            "\\documentclass{article}"
+        );
+        for refused in ["paper/escape.pdf", "paper/absolute.pdf", "paper/self"] {
+            assert!(!root.join(refused).exists(), "{refused}");
+        }
+        assert_eq!(extracted.skipped_entries, 3);
+        assert_eq!(extracted.tex_files.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_cached_single_file_gzip_matches_fresh_download() {
+        use crate::arxiv::ArxivUrl;
+        use crate::config::RunOptions;
+        use crate::downloader::PaperDownloader;
+        use flate2::write::GzEncoder;
+        use std::io::Write;
+
+        let tex = "\\documentclass{article}\n\\title{Single File}\n\\begin{document}\nBody.\n\\end{document}\n";
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(tex.as_bytes()).unwrap();
+        let gzip = encoder.finish().unwrap();
+
+        let mut server = mockito::Server::new_async().await;
+        let src = server
+            .mock("GET", "/src/2401.08027")
+            .with_body(gzip)
+            .expect(1)
+            .create_async()
+            .await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut options = RunOptions::from_settings(Default::default()).unwrap();
+        options.work_dir = dir.path().to_path_buf();
+        options.min_archive_size = 1;
+        let arxiv_url = ArxivUrl {
+            paper_id: "2401.08027".to_string(),
+            src_url: format!("{}/src/2401.08027", server.url()),
+        };
+        let downloader = PaperDownloader::new(&options);
+        let extractor = ArchiveExtractor::new();
+
+        let downloaded = downloader.download(&arxiv_url).await.unwrap();
+        // A kept copy of the same archive, read again later (as `local` does)
+        let kept_dir = dir.path().join("kept");
+        fs::create_dir_all(&kept_dir).unwrap();
+        let kept_archive = kept_dir.join("2401.08027.tar.gz");
+        fs::copy(&downloaded.archive_path, &kept_archive).unwrap();
+        let fresh = extractor.extract(downloaded).unwrap();
+        let cached = extractor
+            .extract(PaperData::new(
+                "2401.08027".to_string(),
+                kept_archive,
+                dir.path().join("2401.08027"),
+            ))
+            .unwrap();
+        src.assert_async().await;
+
+        assert_eq!(cached, fresh);
+        let main_tex = fresh.main_tex_file.unwrap();
+        assert!(main_tex.ends_with("extracted/2401.08027.tex"));
+        assert_eq!(fs::read_to_string(main_tex).unwrap(), tex);
+    }
+
+    #[test]
+    fn test_main_file_without_documentclass_is_the_includer() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("sections")).unwrap();
+        fs::write(root.join("sections/intro.tex"), "\\section{Introduction}").unwrap();
+        fs::write(
+            root.join("sections/method.tex"),
+            "\\section{Method} see \\input{x}",
+        )
+        .unwrap();
+        fs::write(
+            root.join("neurips.tex"),
+            "\\mydocclass\n\\input{sections/intro}\n\\include{sections/method}\n\\input{sections/results}",
+        )
+        .unwrap();
+
+        let extracted = ArchiveExtractor::new()
+            .scan_extracted_files(root, "2401.08027")
+            .unwrap();
+
+        assert_eq!(extracted.main_tex_file, Some(root.join("neurips.tex")));
+    }
+
+    #[test]
+    fn test_subfiles_root_is_the_main_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("chapters")).unwrap();
+        let chapter = |title: &str| {
+            format!("\\documentclass[../thesis.tex]{{subfiles}}\n\\begin{{document}}\n\\section{{{title}}}\n\\end{{document}}")
+        };
+        fs::write(root.join("chapters/intro.tex"), chapter("Introduction")).unwrap();
+        fs::write(root.join("chapters/method.tex"), chapter("Method")).unwrap();
+        fs::write(
+            root.join("thesis.tex"),
+            "\\documentclass{book}\n\\usepackage{subfiles}\n\\begin{document}\n\
+             \\subfile{chapters/intro}\n\\subfile{./chapters/method.tex}\n\\end{document}",
+        )
+        .unwrap();
+
+        // Chapters listed first, as a walk of the archive may return them
+        let tex_files =
+            ["chapters/intro.tex", "chapters/method.tex", "thesis.tex"].map(|f| root.join(f));
+        let main = ArchiveExtractor::new()
+            .find_main_tex_file(&tex_files, "2401.08027")
+            .unwrap();
+
+        assert_eq!(main, Some(root.join("thesis.tex")));
+    }
+
+    #[test]
+    fn test_main_file_found_by_name_hint() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::write(root.join("macros.tex"), "\\newcommand{\\R}{\\mathbb{R}}").unwrap();
+        fs::write(
+            root.join("manuscript.tex"),
+            "\\mydocclass\n\\section{Intro}",
+        )
+        .unwrap();
+
+        let extracted = ArchiveExtractor::new()
+            .scan_extracted_files(root, "2401.08027")
+            .unwrap();
+        assert_eq!(extracted.main_tex_file, Some(root.join("manuscript.tex")));
+
+        // Conference-template names and user hints
+        fs::remove_file(root.join("manuscript.tex")).unwrap();
+        fs::write(root.join("neurips_2024.tex"), "\\section{Intro}").unwrap();
+        fs::write(root.join("thesis.tex"), "\\section{Intro}").unwrap();
+        let extracted = ArchiveExtractor::new()
+            .scan_extracted_files(root, "2401.08027")
+            .unwrap();
+        assert_eq!(extracted.main_tex_file, Some(root.join("neurips_2024.tex")));
+        let extracted = ArchiveExtractor::with_main_hints(vec!["thesis.tex".to_string()])
+            .scan_extracted_files(root, "2401.08027")
+            .unwrap();
+        assert_eq!(extracted.main_tex_file, Some(root.join("thesis.tex")));
+    }
+
+    #[test]
+    fn test_ancillary_directory_is_skipped_by_default() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("anc/code")).unwrap();
+        fs::write(root.join("main.tex"), "\\documentclass{article}").unwrap();
+        fs::write(root.join("fig.png"), "").unwrap();
+        fs::write(root.join("anc/code/notes.tex"), "\\documentclass{article}").unwrap();
+        fs::write(root.join("anc/plot.png"), "").unwrap();
+
+        let extracted = ArchiveExtractor::new()
+            .scan_extracted_files(root, "2401.08027")
+            .unwrap();
+        assert_eq!(extracted.tex_files, vec![root.join("main.tex")]);
+        assert_eq!(extracted.image_files, vec![root.join("fig.png")]);
+        assert_eq!(extracted.main_tex_file, Some(root.join("main.tex")));
+
+        let extracted = ArchiveExtractor::new()
+            .include_ancillary(true)
+            .scan_extracted_files(root, "2401.08027")
+            .unwrap();
+        assert_eq!(extracted.tex_files.len(), 2);
+        assert_eq!(extracted.image_files.len(), 2);
+    }
+
+    #[test]
+    fn test_scanned_files_are_sorted() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("figures/b")).unwrap();
+        fs::create_dir_all(root.join("sections")).unwrap();
+        // Created out of order, so the directory listing isn't sorted by chance
+        for name in [
+            "sections/results.tex",
+            "sections/intro.tex",
+            "main.tex",
+            "refs2.bib",
+            "refs.bib",
+        ] {
+            fs::write(root.join(name), "\\documentclass{article}").unwrap();
+        }
+        for name in [
+            "figures/z.png",
+            "figures/b/c.pdf",
+            "figures/a.jpg",
+            "arch.eps",
+        ] {
+            fs::write(root.join(name), "").unwrap();
+        }
+
+        let extracted = ArchiveExtractor::new()
+            .scan_extracted_files(root, "2401.08027")
+            .unwrap();
+
+        let paths = |names: &[&str]| names.iter().map(|n| root.join(n)).collect::<Vec<_>>();
+        assert_eq!(
+            extracted.tex_files,
+            paths(&["main.tex", "sections/intro.tex", "sections/results.tex"])
+        );
+        assert_eq!(extracted.bib_files, paths(&["refs.bib", "refs2.bib"]));
+        assert_eq!(
+            extracted.image_files,
+            paths(&[
+                "arch.eps",
+                "figures/a.jpg",
+                "figures/b/c.pdf",
+                "figures/z.png"
+            ])
+        );
+    }
+
+    #[test]
+    fn test_paper_pdf_is_not_an_image() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("figures")).unwrap();
+        fs::write(root.join("main.tex"), "\\documentclass{article}").unwrap();
+        fs::write(root.join("main.pdf"), "%PDF-1.5 /Type /Page /Type /Page").unwrap();
+        fs::write(root.join("figures/fig1.pdf"), "%PDF-1.5 /Type /Page").unwrap();
+
+        let extracted = ArchiveExtractor::new()
+            .scan_extracted_files(root, "2401.08027")
+            .unwrap();
+
+        assert_eq!(extracted.paper_pdf, Some(root.join("main.pdf")));
+        assert_eq!(extracted.image_files, vec![root.join("figures/fig1.pdf")]);
+    }
+
+    #[test]
+    fn test_multi_page_root_pdf_is_paper() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::write(
+            root.join("submission.pdf"),
+            "/Type /Pages /Type /Page /Type /Page",
+        )
+        .unwrap();
+        fs::write(root.join("plot.pdf"), "/Type /Pages /Type /Page").unwrap();
+
+        let extracted = ArchiveExtractor::new()
+            .scan_extracted_files(root, "2401.08027")
+            .unwrap();
+
+        assert_eq!(extracted.paper_pdf, Some(root.join("submission.pdf")));
+        assert_eq!(extracted.image_files, vec![root.join("plot.pdf")]);
+    }
+}
@@ -0,0 +1,473 @@
+use anyhow::{Context, Result};
+use base64::Engine;
+use futures::stream::{self, StreamExt};
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageOutputFormat};
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// How many figures are read and encoded at once; bounds memory for papers with
+/// many large figures.
+const MAX_PARALLEL_ENCODES: usize = 4;
+
+/// How large figures are shrunk before being attached to a vision request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageLimits {
+    /// Longest side, in pixels; larger figures are downscaled to fit
+    pub max_dim: u32,
+    /// JPEG quality (1-100) used when re-encoding a downscaled figure
+    pub quality: u8,
+}
+
+impl Default for ImageLimits {
+    fn default() -> Self {
+        Self {
+            max_dim: 1024,
+            quality: 85,
+        }
+    }
+}
+
+/// A figure ready to be attached to a vision request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncodedFigure {
+    /// Path of the figure as referenced in the prompt
+    pub path: String,
+    /// `data:` URL holding the base64-encoded image
+    pub data_url: String,
+}
+
+/// MIME type for image formats vision models accept, or `None` (e.g. PDF, EPS).
+fn mime_type(path: &Path) -> Option<&'static str> {
+    let ext = path.extension()?.to_str()?.to_lowercase();
+    match ext.as_str() {
+        "png" => Some("image/png"),
+        "jpg" | "jpeg" => Some("image/jpeg"),
+        "gif" => Some("image/gif"),
+        _ => None,
+    }
+}
+
+/// External program used to turn EPS/PDF figures into PNG.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rasterizer {
+    Ghostscript(PathBuf),
+    ImageMagick(PathBuf),
+}
+
+impl Rasterizer {
+    /// Ghostscript if installed, otherwise ImageMagick.
+    pub fn detect() -> Option<Self> {
+        find_in_path("gs")
+            .map(Rasterizer::Ghostscript)
+            .or_else(|| find_in_path("magick").map(Rasterizer::ImageMagick))
+            .or_else(|| find_in_path("convert").map(Rasterizer::ImageMagick))
+    }
+
+    /// Command rendering the first page of `input` to `output` at 150 dpi.
+    fn command(&self, input: &Path, output: &Path) -> Command {
+        match self {
+            Rasterizer::Ghostscript(program) => {
+                let mut command = Command::new(program);
+                command
+                    .args(["-q", "-dSAFER", "-dBATCH", "-dNOPAUSE", "-dEPSCrop"])
+                    .args(["-sDEVICE=png16m", "-r150", "-dFirstPage=1", "-dLastPage=1"])
+                    .arg(format!("-sOutputFile={}", output.display()))
+                    .arg(input);
+                command
+            }
+            Rasterizer::ImageMagick(program) => {
+                let mut command = Command::new(program);
+                command
+                    .args(["-density", "150"])
+                    .arg(format!("{}[0]", input.display()))
+                    .arg(output);
+                command
+            }
+        }
+    }
+}
+
+pub fn find_in_path(program: &str) -> Option<PathBuf> {
+    std::env::split_paths(&std::env::var_os("PATH")?)
+        .map(|dir| dir.join(program))
+        .find(|path| path.is_file())
+}
+
+/// Whether a figure is a vector format that must be rasterized before a vision
+/// model or a browser can show it.
+pub fn needs_rasterizing(path: &str) -> bool {
+    Path::new(path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ["eps", "ps", "pdf"].contains(&ext.to_lowercase().as_str()))
+}
+
+/// Convert an EPS/PDF figure to `<name>.converted.png` next to it, reusing an earlier
+/// conversion that is newer than the figure.
+pub fn rasterize(path: &str, rasterizer: &Rasterizer) -> Result<String> {
+    let input = Path::new(path);
+    let output = input.with_extension("converted.png");
+    let modified = |p: &Path| std::fs::metadata(p).and_then(|m| m.modified()).ok();
+    if let (Some(converted), Some(original)) = (modified(&output), modified(input)) {
+        if converted >= original {
+            return Ok(output.to_string_lossy().to_string());
+        }
+    }
+
+    let status = rasterizer
+        .command(input, &output)
+        .output()
+        .with_context(|| format!("Failed to run {rasterizer:?}"))?;
+    if !status.status.success() || !output.is_file() {
+        anyhow::bail!(
+            "Could not convert {path} to PNG: {}",
+            String::from_utf8_lossy(&status.stderr).trim()
+        );
+    }
+    Ok(output.to_string_lossy().to_string())
+}
+
+/// Replace EPS/PDF figures with PNG conversions, keeping the order. Without a
+/// rasterizer, or when a conversion fails, the original path is kept with a warning.
+pub async fn rasterize_figures(
+    image_files: &[String],
+    rasterizer: Option<&Rasterizer>,
+) -> Vec<String> {
+    let vector_count = image_files.iter().filter(|p| needs_rasterizing(p)).count();
+    let Some(rasterizer) = rasterizer.filter(|_| vector_count > 0) else {
+        if vector_count > 0 {
+            eprintln!(
+                "Warning: {vector_count} EPS/PDF figure(s) can't be shown; install Ghostscript or ImageMagick to convert them"
+            );
+        }
+        return image_files.to_vec();
+    };
+
+    let results: Vec<_> = stream::iter(image_files.iter().cloned())
+        .map(|path| {
+            let rasterizer = rasterizer.clone();
+            tokio::task::spawn_blocking(move || {
+                if !needs_rasterizing(&path) {
+                    return path;
+                }
+                rasterize(&path, &rasterizer).unwrap_or_else(|e| {
+                    eprintln!("Warning: {e:#}");
+                    path
+                })
+            })
+        })
+        .buffered(MAX_PARALLEL_ENCODES)
+        .collect()
+        .await;
+
+    results
+        .into_iter()
+        .zip(image_files)
+        .map(|(result, original)| result.unwrap_or_else(|_| original.clone()))
+        .collect()
+}
+
+/// Choose up to `max` attachable figures: those referenced by `\includegraphics`
+/// first, in order of first reference, then the rest in file order. Returns the
+/// selection and how many attachable figures were left out.
+pub fn select_figures(
+    image_files: &[String],
+    figure_references: &[String],
+    max: usize,
+) -> (Vec<String>, usize) {
+    let mut candidates: Vec<&String> = image_files
+        .iter()
+        .filter(|p| mime_type(Path::new(p)).is_some())
+        .collect();
+    let total = candidates.len();
+
+    let mut selected = Vec::new();
+    for reference in figure_references {
+        if let Some(i) = candidates
+            .iter()
+            .position(|p| matches_reference(p, reference))
+        {
+            selected.push(candidates.remove(i).clone());
+        }
+    }
+    selected.extend(candidates.into_iter().cloned());
+    selected.truncate(max);
+
+    let skipped = total - selected.len();
+    (selected, skipped)
+}
+
+/// The file among `image_files` that an `\includegraphics{reference}` points at.
+pub fn resolve_reference<'a>(image_files: &'a [String], reference: &str) -> Option<&'a String> {
+    image_files.iter().find(|p| matches_reference(p, reference))
+}
+
+/// Whether `path` is the file an `\includegraphics{reference}` points at; the
+/// reference may omit the extension.
+fn matches_reference(path: &str, reference: &str) -> bool {
+    let reference = reference.trim().trim_start_matches("./");
+    if reference.is_empty() {
+        return false;
+    }
+    let path = Path::new(path);
+    let stem_path = path.with_extension("");
+    // `arch.converted.png` stands for the `arch.eps` the paper references
+    let original_stem = if stem_path.extension().is_some_and(|ext| ext == "converted") {
+        stem_path.with_extension("")
+    } else {
+        stem_path.clone()
+    };
+    [path, stem_path.as_path(), original_stem.as_path()]
+        .iter()
+        .any(|p| p.ends_with(reference))
+}
+
+/// Read and encode one figure, downscaling it if it exceeds `limits`; unsupported
+/// formats (PDF, EPS) yield `Ok(None)`.
+pub fn encode_figure(path: &str, limits: ImageLimits) -> Result<Option<EncodedFigure>> {
+    let Some(mime) = mime_type(Path::new(path)) else {
+        return Ok(None);
+    };
+    let (width, height) =
+        image::image_dimensions(path).with_context(|| format!("Failed to read figure {path}"))?;
+
+    let (mime, bytes) = if width.max(height) > limits.max_dim {
+        downscale(path, limits).with_context(|| format!("Failed to downscale figure {path}"))?
+    } else {
+        let bytes = std::fs::read(path).with_context(|| format!("Failed to read figure {path}"))?;
+        (mime, bytes)
+    };
+    let data = base64::engine::general_purpose::STANDARD.encode(bytes);
+
+    Ok(Some(EncodedFigure {
+        path: path.to_string(),
+        data_url: format!("data:{mime};base64,{data}"),
+    }))
+}
+
+/// Shrink a figure to fit within `limits.max_dim`, keeping its aspect ratio. Figures
+/// with transparency stay PNG; everything else becomes JPEG at `limits.quality`.
+fn downscale(path: &str, limits: ImageLimits) -> Result<(&'static str, Vec<u8>)> {
+    let img = image::open(path)?.resize(limits.max_dim, limits.max_dim, FilterType::Triangle);
+
+    let mut bytes = Cursor::new(Vec::new());
+    let mime = if img.color().has_alpha() {
+        img.write_to(&mut bytes, ImageOutputFormat::Png)?;
+        "image/png"
+    } else {
+        DynamicImage::ImageRgb8(img.to_rgb8())
+            .write_to(&mut bytes, ImageOutputFormat::Jpeg(limits.quality))?;
+        "image/jpeg"
+    };
+
+    Ok((mime, bytes.into_inner()))
+}
+
+/// Keep figures, in order, until their encoded size would exceed `max_bytes`.
+/// Returns the kept figures and how many were dropped.
+pub fn apply_byte_budget(
+    mut figures: Vec<EncodedFigure>,
+    max_bytes: usize,
+) -> (Vec<EncodedFigure>, usize) {
+    let mut used = 0;
+    let fits = figures
+        .iter()
+        .take_while(|figure| {
+            used += figure.data_url.len();
+            used <= max_bytes
+        })
+        .count();
+    let dropped = figures.len() - fits;
+    figures.truncate(fits);
+    (figures, dropped)
+}
+
+/// Encode figures on the blocking thread pool, at most `MAX_PARALLEL_ENCODES` at a
+/// time, keeping the input order. Unreadable figures are skipped with a warning.
+pub async fn encode_figures(paths: &[String], limits: ImageLimits) -> Vec<EncodedFigure> {
+    let results: Vec<_> = stream::iter(paths.iter().cloned())
+        .map(|path| tokio::task::spawn_blocking(move || encode_figure(&path, limits)))
+        .buffered(MAX_PARALLEL_ENCODES)
+        .collect()
+        .await;
+
+    results
+        .into_iter()
+        .filter_map(|result| match result {
+            Ok(Ok(figure)) => figure,
+            Ok(Err(e)) => {
+                eprintln!("Warning: skipping figure: {e:#}");
+                None
+            }
+            Err(e) => {
+                eprintln!("Warning: figure encoding task failed: {e}");
+                None
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::engine::general_purpose::STANDARD;
+
+    #[tokio::test]
+    async fn test_parallel_encoding_matches_serial_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut paths = Vec::new();
+        for i in 0..20 {
+            let ext = match i % 3 {
+                0 => "png",
+                1 => "jpg",
+                _ => "eps",
+            };
+            let path = dir.path().join(format!("fig{i}.{ext}"));
+            if ext == "eps" {
+                std::fs::write(&path, "%!PS-Adobe-3.0 EPSF-3.0").unwrap();
+            } else {
+                // Vary the size so tasks finish out of order
+                let side = (20 - i as u32) * 40;
+                image::RgbImage::from_pixel(side, side, image::Rgb([i as u8, 0, 0]))
+                    .save(&path)
+                    .unwrap();
+            }
+            paths.push(path.to_string_lossy().to_string());
+        }
+
+        let limits = ImageLimits::default();
+        let serial: Vec<EncodedFigure> = paths
+            .iter()
+            .filter_map(|p| encode_figure(p, limits).unwrap())
+            .collect();
+        let parallel = encode_figures(&paths, limits).await;
+
+        assert_eq!(parallel, serial);
+        // EPS figures are skipped
+        assert_eq!(parallel.len(), 14);
+        assert!(parallel[1].data_url.starts_with("data:image/jpeg;base64,"));
+    }
+
+    #[test]
+    fn test_byte_budget_cuts_off_figures() {
+        let figures: Vec<EncodedFigure> = [400, 300, 200, 50]
+            .iter()
+            .enumerate()
+            .map(|(i, len)| EncodedFigure {
+                path: format!("fig{i}.png"),
+                data_url: "A".repeat(*len),
+            })
+            .collect();
+
+        let (kept, dropped) = apply_byte_budget(figures.clone(), 800);
+        let paths: Vec<&str> = kept.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(paths, ["fig0.png", "fig1.png"]);
+        // The small last figure would fit, but attaching stops at the first overrun
+        assert_eq!(dropped, 2);
+
+        assert_eq!(apply_byte_budget(figures.clone(), 950).1, 0);
+        assert_eq!(apply_byte_budget(figures, 100), (vec![], 4));
+    }
+
+    #[test]
+    fn test_select_figures_respects_limit() {
+        let image_files: Vec<String> = (1..=12)
+            .map(|i| format!("output/x/extracted/figs/fig{i}.png"))
+            .chain(["output/x/extracted/arch.eps".to_string()])
+            .collect();
+        let references = ["figs/fig9".to_string(), "figs/fig3.png".to_string()];
+
+        let (selected, skipped) = select_figures(&image_files, &references, 4);
+
+        assert_eq!(
+            selected,
+            [
+                "output/x/extracted/figs/fig9.png",
+                "output/x/extracted/figs/fig3.png",
+                "output/x/extracted/figs/fig1.png",
+                "output/x/extracted/figs/fig2.png",
+            ]
+        );
+        // The EPS figure can't be attached, so it isn't counted as skipped
+        assert_eq!(skipped, 8);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_eps_figure_is_rasterized_once() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        // Stands in for ImageMagick: logs each call and writes a PNG to the last argument
+        let stub = dir.path().join("magick");
+        let calls = dir.path().join("calls.log");
+        let png = dir.path().join("source.png");
+        image::RgbImage::from_pixel(4, 4, image::Rgb([0, 0, 0]))
+            .save(&png)
+            .unwrap();
+        std::fs::write(
+            &stub,
+            format!(
+                "#!/bin/sh\necho \"$@\" >> {}\nfor arg; do out=$arg; done\ncp {} \"$out\"\n",
+                calls.display(),
+                png.display()
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&stub, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let eps = dir.path().join("arch.eps");
+        std::fs::write(&eps, "%!PS-Adobe-3.0 EPSF-3.0").unwrap();
+        let image_files = vec![
+            eps.to_string_lossy().to_string(),
+            png.to_string_lossy().to_string(),
+        ];
+        let rasterizer = Rasterizer::ImageMagick(stub);
+
+        let converted = rasterize_figures(&image_files, Some(&rasterizer)).await;
+        assert_eq!(
+            converted,
+            [
+                dir.path()
+                    .join("arch.converted.png")
+                    .to_string_lossy()
+                    .to_string(),
+                image_files[1].clone(),
+            ]
+        );
+        assert!(mime_type(Path::new(&converted[0])).is_some());
+
+        // The second run reuses the cached PNG
+        rasterize_figures(&image_files, Some(&rasterizer)).await;
+        assert_eq!(std::fs::read_to_string(&calls).unwrap().lines().count(), 1);
+
+        // Without a converter the figure is left as it is
+        assert_eq!(rasterize_figures(&image_files, None).await, image_files);
+    }
+
+    #[test]
+    fn test_large_figure_is_downscaled() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("plot.png");
+        image::RgbImage::from_pixel(3000, 1500, image::Rgb([10, 20, 30]))
+            .save(&path)
+            .unwrap();
+
+        let limits = ImageLimits {
+            max_dim: 1024,
+            quality: 80,
+        };
+        let figure = encode_figure(&path.to_string_lossy(), limits)
+            .unwrap()
+            .unwrap();
+
+        let data = figure
+            .data_url
+            .strip_prefix("data:image/jpeg;base64,")
+            .expect("opaque figures are re-encoded as JPEG");
+        let decoded = image::load_from_memory(&STANDARD.decode(data).unwrap()).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (1024, 512));
+    }
+}
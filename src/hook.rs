@@ -0,0 +1,78 @@
+use anyhow::{Context, Result};
+use std::path::Path;
+use std::process::ExitStatus;
+use tokio::process::Command;
+
+/// Run the user's `--on-complete` command after a note is saved.
+/// Failures are reported as warnings and never fail the paper.
+pub async fn run_on_complete(command: &str, paper_id: &str, title: &str, note_path: &Path) {
+    match run_hook(command, paper_id, title, note_path).await {
+        Ok(status) if status.success() => {}
+        Ok(status) => eprintln!("Warning: on-complete hook for {paper_id} exited with {status}"),
+        Err(e) => eprintln!("Warning: failed to run on-complete hook for {paper_id}: {e}"),
+    }
+}
+
+async fn run_hook(
+    command: &str,
+    paper_id: &str,
+    title: &str,
+    note_path: &Path,
+) -> Result<ExitStatus> {
+    let mut child = shell_command(command);
+    child
+        .env("PAPER_ID", paper_id)
+        .env("PAPER_TITLE", title)
+        .env("NOTE_PATH", note_path);
+
+    child
+        .status()
+        .await
+        .with_context(|| format!("could not spawn `{command}`"))
+}
+
+#[cfg(windows)]
+fn shell_command(command: &str) -> Command {
+    let mut cmd = Command::new("cmd");
+    cmd.arg("/C").arg(command);
+    cmd
+}
+
+#[cfg(not(windows))]
+fn shell_command(command: &str) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(command);
+    cmd
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_hook_receives_paper_env() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("hook.txt");
+
+        #[cfg(windows)]
+        let command = format!("echo %PAPER_ID%>\"{}\"", out.display());
+        #[cfg(not(windows))]
+        let command = format!("echo \"$PAPER_ID\" > '{}'", out.display());
+
+        let status = run_hook(&command, "2401.08027", "A Title", Path::new("tex/x.tex"))
+            .await
+            .unwrap();
+
+        assert!(status.success());
+        let written = std::fs::read_to_string(&out).unwrap();
+        assert_eq!(written.trim(), "2401.08027");
+    }
+
+    #[tokio::test]
+    async fn test_failing_hook_reports_status() {
+        let status = run_hook("exit 3", "id", "t", Path::new("n.tex"))
+            .await
+            .unwrap();
+        assert!(!status.success());
+    }
+}
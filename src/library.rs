@@ -0,0 +1,517 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Datelike, Utc};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::arxiv::{strip_version, submission_year, version_of};
+
+/// Note directories that belong to versions of the same paper.
+#[derive(Debug, PartialEq, Eq)]
+pub struct DuplicateGroup {
+    /// arXiv ID without its version suffix
+    pub base_id: String,
+    /// Note directory of the newest version
+    pub keep: PathBuf,
+    /// Note directories of the older versions
+    pub older: Vec<PathBuf>,
+}
+
+/// How recent a note directory is: its arXiv version, then when it was generated.
+type Recency = (u32, Option<DateTime<Utc>>);
+
+/// Group the per-paper note directories in `notes_dir` by base arXiv ID and return
+/// the groups holding more than one version. A directory without a version in its
+/// name ranks by the `arxiv_version` its sidecars record, and below every explicit
+/// version when they record none; equal versions rank by generation time.
+pub fn find_duplicates(notes_dir: &Path) -> Result<Vec<DuplicateGroup>> {
+    let entries = std::fs::read_dir(notes_dir)
+        .with_context(|| format!("Failed to read notes directory {}", notes_dir.display()))?;
+
+    let mut groups: BTreeMap<String, Vec<(Recency, PathBuf)>> = BTreeMap::new();
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+            continue;
+        };
+        if !path.is_dir() {
+            continue;
+        }
+        let base_id = strip_version(name);
+        let (recorded, generated_at) = recorded_generation(&path);
+        let version = version_of(name).or(recorded).unwrap_or(0);
+        groups
+            .entry(base_id.to_string())
+            .or_default()
+            .push(((version, generated_at), path));
+    }
+
+    Ok(groups
+        .into_iter()
+        .filter(|(_, versions)| versions.len() > 1)
+        .map(|(base_id, mut versions)| {
+            versions.sort();
+            let (_, keep) = versions.pop().expect("group has entries");
+            DuplicateGroup {
+                base_id,
+                keep,
+                older: versions.into_iter().map(|(_, path)| path).collect(),
+            }
+        })
+        .collect())
+}
+
+/// The newest arXiv version and generation time the note sidecars in `dir` record.
+fn recorded_generation(dir: &Path) -> (Option<u32>, Option<DateTime<Utc>>) {
+    let Ok(files) = std::fs::read_dir(dir) else {
+        return (None, None);
+    };
+    let sidecars: Vec<SidecarMetadata> = files
+        .flatten()
+        .map(|e| e.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .filter_map(|path| {
+            let sidecar: Sidecar =
+                serde_json::from_str(&std::fs::read_to_string(path).ok()?).ok()?;
+            Some(sidecar.metadata)
+        })
+        .filter(|metadata| metadata.compare_of.is_none())
+        .collect();
+    let version = sidecars.iter().filter_map(|m| m.arxiv_version).max();
+    let generated_at = sidecars
+        .iter()
+        .filter_map(|m| DateTime::parse_from_rfc3339(m.generated_at.as_deref()?).ok())
+        .map(|date| date.with_timezone(&Utc))
+        .max();
+    (version, generated_at)
+}
+
+/// Report duplicate notes and, unless `dry_run`, delete all but the newest version.
+pub fn dedupe(notes_dir: &Path, dry_run: bool) -> Result<()> {
+    let groups = find_duplicates(notes_dir)?;
+    if groups.is_empty() {
+        println!("No duplicate notes in {}", notes_dir.display());
+        return Ok(());
+    }
+
+    for group in &groups {
+        println!("{}: keeping {}", group.base_id, group.keep.display());
+        for older in &group.older {
+            if dry_run {
+                println!("  would remove {}", older.display());
+            } else {
+                std::fs::remove_dir_all(older)
+                    .with_context(|| format!("Failed to remove {}", older.display()))?;
+                println!("  removed {}", older.display());
+            }
+        }
+    }
+
+    let older: usize = groups.iter().map(|g| g.older.len()).sum();
+    if dry_run {
+        println!("{older} older note(s) found; rerun with --remove to delete them");
+    } else {
+        println!("Removed {older} older note(s)");
+    }
+    Ok(())
+}
+
+/// How `list` orders the notes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum SortKey {
+    /// Generation date, most recent first
+    #[default]
+    Date,
+    /// Title, A to Z
+    Title,
+    /// arXiv ID, ascending
+    Id,
+}
+
+/// One note in the library, as described by its metadata sidecar.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NoteEntry {
+    pub paper_id: String,
+    pub title: String,
+    pub authors: Vec<String>,
+    pub keywords: Vec<String>,
+    /// `--tag` values the note was generated with
+    pub tags: Vec<String>,
+    /// When the note was generated; the sidecar's mtime for notes that don't say
+    pub date: DateTime<Utc>,
+    pub model: String,
+    /// Token usage the provider reported, if any
+    pub prompt_tokens: Option<u32>,
+    pub completion_tokens: Option<u32>,
+    pub total_tokens: Option<u32>,
+    /// Words of section text in the paper, for notes that record it
+    pub source_words: Option<usize>,
+    /// The metadata sidecar
+    pub path: PathBuf,
+}
+
+/// The parts of a sidecar `list` and `stats` need; older sidecars may lack some of
+/// them.
+#[derive(Debug, serde::Deserialize)]
+struct Sidecar {
+    paper_id: String,
+    #[serde(default)]
+    title: String,
+    #[serde(default)]
+    authors: Vec<String>,
+    #[serde(default)]
+    keywords: Vec<String>,
+    #[serde(default)]
+    metadata: SidecarMetadata,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default)]
+struct SidecarMetadata {
+    generated_at: Option<String>,
+    model_used: String,
+    arxiv_version: Option<u32>,
+    prompt_tokens: Option<u32>,
+    completion_tokens: Option<u32>,
+    total_tokens: Option<u32>,
+    source_words: Option<usize>,
+    tags: Vec<String>,
+    compare_of: Option<String>,
+}
+
+/// Every note with a readable metadata sidecar in the per-paper directories of
+/// `notes_dir`, in no particular order. `--compare` notes are left out and only
+/// the newest note in a directory counts (`--overwrite-policy version` keeps the
+/// older ones), so each paper is listed once.
+pub fn list_notes(notes_dir: &Path) -> Result<Vec<NoteEntry>> {
+    let entries = std::fs::read_dir(notes_dir)
+        .with_context(|| format!("Failed to read notes directory {}", notes_dir.display()))?;
+
+    let mut notes = Vec::new();
+    for dir in entries.flatten().map(|e| e.path()).filter(|p| p.is_dir()) {
+        let Ok(files) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+        let newest = files
+            .flatten()
+            .map(|e| e.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+            .filter_map(|path| read_sidecar(&path))
+            .max_by_key(|note| note.date);
+        notes.extend(newest);
+    }
+    Ok(notes)
+}
+
+fn read_sidecar(path: &Path) -> Option<NoteEntry> {
+    let sidecar: Sidecar = serde_json::from_str(&std::fs::read_to_string(path).ok()?).ok()?;
+    if sidecar.metadata.compare_of.is_some() {
+        return None;
+    }
+    let generated_at = sidecar
+        .metadata
+        .generated_at
+        .and_then(|date| DateTime::parse_from_rfc3339(&date).ok())
+        .map(|date| date.with_timezone(&Utc));
+    let date = match generated_at {
+        Some(date) => date,
+        None => std::fs::metadata(path).ok()?.modified().ok()?.into(),
+    };
+    Some(NoteEntry {
+        paper_id: sidecar.paper_id,
+        title: sidecar.title,
+        authors: sidecar.authors,
+        keywords: sidecar.keywords,
+        tags: sidecar.metadata.tags,
+        date,
+        model: sidecar.metadata.model_used,
+        prompt_tokens: sidecar.metadata.prompt_tokens,
+        completion_tokens: sidecar.metadata.completion_tokens,
+        total_tokens: sidecar.metadata.total_tokens,
+        source_words: sidecar.metadata.source_words,
+        path: path.to_path_buf(),
+    })
+}
+
+/// Which notes `list` prints; unset criteria match everything.
+#[derive(Debug, Clone, Default)]
+pub struct NoteFilter {
+    /// Substring of the title, an author, a keyword or a tag, ignoring case
+    pub query: Option<String>,
+    /// Substring of an author's name, ignoring case
+    pub author: Option<String>,
+    /// Submission year, taken from the arXiv ID (or the note's date for other IDs)
+    pub year: Option<i32>,
+}
+
+impl NoteFilter {
+    pub fn matches(&self, note: &NoteEntry) -> bool {
+        let contains = |text: &str, needle: &str| text.to_lowercase().contains(needle);
+
+        if let Some(query) = &self.query {
+            let query = query.to_lowercase();
+            let found = contains(&note.title, &query)
+                || note.authors.iter().any(|a| contains(a, &query))
+                || note.keywords.iter().any(|k| contains(k, &query))
+                || note.tags.iter().any(|t| contains(t, &query));
+            if !found {
+                return false;
+            }
+        }
+        if let Some(author) = &self.author {
+            let author = author.to_lowercase();
+            if !note.authors.iter().any(|a| contains(a, &author)) {
+                return false;
+            }
+        }
+        if let Some(year) = self.year {
+            let note_year = submission_year(&note.paper_id).unwrap_or(note.date.year());
+            if note_year != year {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Order notes by `key`; `reverse` flips the key's natural order.
+pub fn sort_notes(notes: &mut [NoteEntry], key: SortKey, reverse: bool) {
+    match key {
+        SortKey::Date => notes.sort_by_key(|n| std::cmp::Reverse(n.date)),
+        SortKey::Title => notes.sort_by_cached_key(|n| n.title.to_lowercase()),
+        SortKey::Id => notes.sort_by(|a, b| a.paper_id.cmp(&b.paper_id)),
+    }
+    if reverse {
+        notes.reverse();
+    }
+}
+
+/// Print the matching notes, one per line or as a JSON array.
+pub fn list(
+    notes_dir: &Path,
+    filter: &NoteFilter,
+    key: SortKey,
+    reverse: bool,
+    json: bool,
+) -> Result<()> {
+    let mut notes = list_notes(notes_dir)?;
+    notes.retain(|note| filter.matches(note));
+    sort_notes(&mut notes, key, reverse);
+
+    if json {
+        let notes: Vec<_> = notes
+            .iter()
+            .map(|note| {
+                serde_json::json!({
+                    "paper_id": note.paper_id,
+                    "title": note.title,
+                    "authors": note.authors,
+                    "keywords": note.keywords,
+                    "tags": note.tags,
+                    "date": note.date.to_rfc3339(),
+                    "path": note.path,
+                })
+            })
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&notes)?);
+        return Ok(());
+    }
+
+    for note in &notes {
+        println!(
+            "{}  {:<16}  {}",
+            note.date.format("%Y-%m-%d"),
+            note.paper_id,
+            note.title
+        );
+    }
+    println!("{} note(s) in {}", notes.len(), notes_dir.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(paper_id: &str, title: &str, authors: &[&str], keywords: &[&str]) -> NoteEntry {
+        NoteEntry {
+            paper_id: paper_id.to_string(),
+            title: title.to_string(),
+            authors: authors.iter().map(|a| a.to_string()).collect(),
+            keywords: keywords.iter().map(|k| k.to_string()).collect(),
+            tags: vec![],
+            date: DateTime::parse_from_rfc3339("2025-01-01T00:00:00Z")
+                .unwrap()
+                .with_timezone(&Utc),
+            model: String::new(),
+            prompt_tokens: None,
+            completion_tokens: None,
+            total_tokens: None,
+            source_words: None,
+            path: PathBuf::new(),
+        }
+    }
+
+    #[test]
+    fn test_list_filter() {
+        let notes = [
+            entry(
+                "1706.03762",
+                "Attention Is All You Need",
+                &["Ashish Vaswani"],
+                &[],
+            ),
+            entry(
+                "2401.08027",
+                "Sparse Models",
+                &["Jane Doe"],
+                &["attention", "MoE"],
+            ),
+            entry("2312.00001", "Diffusion", &["John Smith"], &[]),
+        ];
+        let ids = |filter: NoteFilter| -> Vec<&str> {
+            notes
+                .iter()
+                .filter(|n| filter.matches(n))
+                .map(|n| n.paper_id.as_str())
+                .collect()
+        };
+
+        let query = |q: &str| NoteFilter {
+            query: Some(q.to_string()),
+            ..Default::default()
+        };
+        assert_eq!(ids(query("ATTENTION")), ["1706.03762", "2401.08027"]);
+        assert_eq!(ids(query("smith")), ["2312.00001"]);
+        assert!(ids(query("transformer")).is_empty());
+        assert_eq!(
+            ids(NoteFilter {
+                author: Some("doe".to_string()),
+                ..Default::default()
+            }),
+            ["2401.08027"]
+        );
+        assert_eq!(
+            ids(NoteFilter {
+                query: Some("attention".to_string()),
+                year: Some(2017),
+                ..Default::default()
+            }),
+            ["1706.03762"]
+        );
+        assert_eq!(ids(NoteFilter::default()).len(), 3);
+    }
+
+    #[test]
+    fn test_list_sort_orders() {
+        let dir = tempfile::tempdir().unwrap();
+        let notes = [
+            ("2401.00002", "beta", Some("2024-03-01T00:00:00Z")),
+            ("2312.00003", "Gamma", Some("2024-05-01T00:00:00Z")),
+            // Written now, so its mtime makes it the most recent
+            ("2402.00001", "Alpha", None),
+        ];
+        for (id, title, generated_at) in notes {
+            std::fs::create_dir_all(dir.path().join(id)).unwrap();
+            let sidecar = serde_json::json!({
+                "paper_id": id,
+                "title": title,
+                "metadata": {"generated_at": generated_at},
+            });
+            std::fs::write(
+                dir.path().join(id).join(format!("{id}.json")),
+                sidecar.to_string(),
+            )
+            .unwrap();
+        }
+        std::fs::write(dir.path().join("2402.00001/notes.json"), "not json").unwrap();
+        // A regenerated note written next to the first by `--overwrite-policy version`
+        let newer = serde_json::json!({
+            "paper_id": "2401.00002",
+            "title": "beta v2",
+            "metadata": {"generated_at": "2024-04-01T00:00:00Z"},
+        });
+        std::fs::write(
+            dir.path().join("2401.00002/2401.00002.v2.json"),
+            newer.to_string(),
+        )
+        .unwrap();
+
+        let mut entries = list_notes(dir.path()).unwrap();
+        let ids = |entries: &[NoteEntry]| -> Vec<String> {
+            entries.iter().map(|n| n.paper_id.clone()).collect()
+        };
+        assert_eq!(entries.len(), 3);
+        assert!(entries.iter().any(|n| n.title == "beta v2"));
+
+        sort_notes(&mut entries, SortKey::Date, false);
+        assert_eq!(ids(&entries), ["2402.00001", "2312.00003", "2401.00002"]);
+        sort_notes(&mut entries, SortKey::Date, true);
+        assert_eq!(ids(&entries), ["2401.00002", "2312.00003", "2402.00001"]);
+        sort_notes(&mut entries, SortKey::Title, false);
+        assert_eq!(ids(&entries), ["2402.00001", "2401.00002", "2312.00003"]);
+        sort_notes(&mut entries, SortKey::Id, false);
+        assert_eq!(ids(&entries), ["2312.00003", "2401.00002", "2402.00001"]);
+        sort_notes(&mut entries, SortKey::Id, true);
+        assert_eq!(ids(&entries), ["2402.00001", "2401.00002", "2312.00003"]);
+    }
+
+    #[test]
+    fn test_versions_of_a_paper_are_grouped() {
+        let dir = tempfile::tempdir().unwrap();
+        for id in ["2401.08027", "2401.08027v2", "2401.00001v1", "2312.11111"] {
+            std::fs::create_dir_all(dir.path().join(id)).unwrap();
+        }
+        std::fs::write(dir.path().join("index.html"), "").unwrap();
+
+        let groups = find_duplicates(dir.path()).unwrap();
+
+        assert_eq!(
+            groups,
+            [DuplicateGroup {
+                base_id: "2401.08027".to_string(),
+                keep: dir.path().join("2401.08027v2"),
+                older: vec![dir.path().join("2401.08027")],
+            }]
+        );
+
+        dedupe(dir.path(), true).unwrap();
+        assert!(dir.path().join("2401.08027").exists());
+        dedupe(dir.path(), false).unwrap();
+        assert!(!dir.path().join("2401.08027").exists());
+        assert!(dir.path().join("2401.08027v2").exists());
+        assert!(find_duplicates(dir.path()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_unversioned_directory_ranks_by_its_sidecar() {
+        let dir = tempfile::tempdir().unwrap();
+        for (name, metadata) in [
+            ("2401.08027", serde_json::json!({"arxiv_version": 3})),
+            ("2401.08027v2", serde_json::json!({})),
+            (
+                "2401.00001",
+                serde_json::json!({"arxiv_version": 1, "generated_at": "2025-02-01T00:00:00Z"}),
+            ),
+            (
+                "2401.00001v1",
+                serde_json::json!({"generated_at": "2024-02-01T00:00:00Z"}),
+            ),
+        ] {
+            std::fs::create_dir_all(dir.path().join(name)).unwrap();
+            let sidecar = serde_json::json!({"paper_id": name, "metadata": metadata});
+            std::fs::write(
+                dir.path().join(name).join(format!("{name}.json")),
+                sidecar.to_string(),
+            )
+            .unwrap();
+        }
+
+        let groups = find_duplicates(dir.path()).unwrap();
+
+        let kept: Vec<&Path> = groups.iter().map(|g| g.keep.as_path()).collect();
+        assert_eq!(
+            kept,
+            [dir.path().join("2401.00001"), dir.path().join("2401.08027")]
+        );
+    }
+}
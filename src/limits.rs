@@ -0,0 +1,382 @@
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{OwnedMutexGuard, Semaphore, SemaphorePermit};
+use tokio::time::Instant;
+
+/// Default share of the download delay added or removed at random
+pub const DEFAULT_DOWNLOAD_JITTER: f64 = 0.25;
+
+/// Minimum gap between the starts of two downloads. Each gap is stretched or
+/// shrunk at random by up to `jitter` of itself, so permits released together
+/// don't send their requests to arXiv in lockstep.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Pacing {
+    pub delay: Duration,
+    /// Fraction of `delay`, between 0 and 1
+    pub jitter: f64,
+}
+
+impl Pacing {
+    /// The gap before the next download: `delay` scaled by a random factor in
+    /// `1 ± jitter`.
+    pub fn next_delay(&self) -> Duration {
+        self.delay_for(random_unit())
+    }
+
+    /// `delay` scaled by `1 + jitter * unit`, for `unit` in [-1, 1].
+    fn delay_for(&self, unit: f64) -> Duration {
+        self.delay
+            .mul_f64(1.0 + self.jitter * unit.clamp(-1.0, 1.0))
+    }
+}
+
+/// A uniformly distributed number in [-1, 1]. Every `RandomState` is seeded
+/// afresh, which is all the randomness spreading requests out needs.
+fn random_unit() -> f64 {
+    let bits = RandomState::new().build_hasher().finish();
+    (bits >> 11) as f64 / (1u64 << 52) as f64 - 1.0
+}
+
+/// The window a tokens-per-minute limit is measured over
+const TPM_WINDOW: Duration = Duration::from_secs(60);
+
+/// Estimated prompt tokens sent in the last minute, for `TPM_LIMIT`.
+#[derive(Debug)]
+struct TokenBudget {
+    limit: usize,
+    /// When each request in the window started, and its estimated tokens
+    sent: VecDeque<(Instant, usize)>,
+}
+
+impl TokenBudget {
+    /// Wait until `tokens` more fit in the rolling window, then record them. A
+    /// request larger than the whole limit goes out once the window is empty.
+    async fn reserve(&mut self, tokens: usize) {
+        loop {
+            let now = Instant::now();
+            while self
+                .sent
+                .front()
+                .is_some_and(|(at, _)| now.duration_since(*at) >= TPM_WINDOW)
+            {
+                self.sent.pop_front();
+            }
+            let used: usize = self.sent.iter().map(|(_, tokens)| tokens).sum();
+            let Some((oldest, _)) = self.sent.front() else {
+                break;
+            };
+            if used + tokens <= self.limit {
+                break;
+            }
+            tokio::time::sleep_until(*oldest + TPM_WINDOW).await;
+        }
+        self.sent.push_back((Instant::now(), tokens));
+    }
+}
+
+/// Retries left for the whole run, so a provider outage can't multiply every
+/// paper's retries into a flood of paid requests. Unlimited unless
+/// `--max-total-retries` is given.
+#[derive(Debug, Clone, Default)]
+struct RetryBudget {
+    remaining: Option<Arc<AtomicUsize>>,
+    /// The budget ran out and was reported
+    exhausted: Arc<AtomicBool>,
+}
+
+impl RetryBudget {
+    /// Use up one retry, or, once none are left, report it (the first time) and
+    /// refuse.
+    fn take(&self) -> bool {
+        let Some(remaining) = &self.remaining else {
+            return true;
+        };
+        if remaining
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            .is_ok()
+        {
+            return true;
+        }
+        if !self.exhausted.swap(true, Ordering::SeqCst) {
+            eprintln!(
+                "Warning: the --max-total-retries budget is used up; papers that need a retry now fail straight away"
+            );
+        }
+        false
+    }
+}
+
+/// The lock of each paper some task holds or waits for.
+type PaperLocks = Arc<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>>;
+
+/// A paper held by one task. Dropping it releases the paper, and forgets its lock
+/// when no other task is waiting, so a long `watch` session doesn't keep one per
+/// paper it has seen.
+pub struct PaperGuard {
+    guard: Option<OwnedMutexGuard<()>>,
+    paper_id: String,
+    papers: PaperLocks,
+}
+
+impl Drop for PaperGuard {
+    fn drop(&mut self) {
+        // Waiters clone the lock under the map's lock, so none can appear between
+        // the release and the check
+        let mut papers = self.papers.lock().unwrap_or_else(|e| e.into_inner());
+        drop(self.guard.take());
+        if papers
+            .get(&self.paper_id)
+            .is_some_and(|lock| Arc::strong_count(lock) == 1)
+        {
+            papers.remove(&self.paper_id);
+        }
+    }
+}
+
+/// Independent caps on concurrent downloads and LLM requests, shared by every task
+/// in a batch. arXiv and the LLM provider tolerate very different amounts of
+/// parallelism, so each stage waits only on its own semaphore.
+#[derive(Debug, Clone)]
+pub struct StageLimits {
+    pub download_slots: usize,
+    pub llm_slots: usize,
+    download: Arc<Semaphore>,
+    llm: Arc<Semaphore>,
+    /// One lock per paper ID, so duplicate URLs in a batch never share a working
+    /// directory at the same time
+    papers: PaperLocks,
+    pacing: Pacing,
+    /// When the next download may start
+    next_download: Arc<tokio::sync::Mutex<Instant>>,
+    /// Tokens-per-minute cap on LLM requests, if any
+    tokens: Option<Arc<tokio::sync::Mutex<TokenBudget>>>,
+    retries: RetryBudget,
+}
+
+impl StageLimits {
+    pub fn new(download_slots: usize, llm_slots: usize) -> Self {
+        let download_slots = download_slots.max(1);
+        let llm_slots = llm_slots.max(1);
+        Self {
+            download_slots,
+            llm_slots,
+            download: Arc::new(Semaphore::new(download_slots)),
+            llm: Arc::new(Semaphore::new(llm_slots)),
+            papers: Arc::default(),
+            pacing: Pacing::default(),
+            next_download: Arc::new(tokio::sync::Mutex::new(Instant::now())),
+            tokens: None,
+            retries: RetryBudget::default(),
+        }
+    }
+
+    /// Allow at most `max` retries across every paper, or any number with `None`.
+    pub fn with_retry_budget(mut self, max: Option<usize>) -> Self {
+        self.retries = RetryBudget {
+            remaining: max.map(|max| Arc::new(AtomicUsize::new(max))),
+            exhausted: Arc::default(),
+        };
+        self
+    }
+
+    /// Whether a failed step may be tried again; each `true` uses up one retry of
+    /// the run's budget.
+    pub fn retry(&self) -> bool {
+        self.retries.take()
+    }
+
+    /// The retry budget has run out.
+    pub fn retries_exhausted(&self) -> bool {
+        self.retries.exhausted.load(Ordering::SeqCst)
+    }
+
+    /// Keep the estimated prompt tokens sent per rolling minute under `limit`.
+    pub fn with_tpm_limit(mut self, limit: Option<usize>) -> Self {
+        self.tokens = limit.map(|limit| {
+            Arc::new(tokio::sync::Mutex::new(TokenBudget {
+                limit,
+                sent: VecDeque::new(),
+            }))
+        });
+        self
+    }
+
+    /// Requests counted against the TPM budget in the last minute.
+    #[cfg(test)]
+    pub async fn tpm_requests(&self) -> usize {
+        match &self.tokens {
+            Some(tokens) => tokens.lock().await.sent.len(),
+            None => 0,
+        }
+    }
+
+    pub fn with_download_pacing(mut self, pacing: Pacing) -> Self {
+        self.pacing = pacing;
+        self
+    }
+
+    /// Wait until no other task is working on `paper_id`; the paper is released
+    /// when the guard is dropped.
+    pub async fn paper(&self, paper_id: &str) -> PaperGuard {
+        let lock = self
+            .papers
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(paper_id.to_string())
+            .or_default()
+            .clone();
+        PaperGuard {
+            guard: Some(lock.lock_owned().await),
+            paper_id: paper_id.to_string(),
+            papers: self.papers.clone(),
+        }
+    }
+
+    /// Wait for a download slot, then for the pacing gap since the previous
+    /// download; the slot is released when the permit is dropped.
+    pub async fn download(&self) -> SemaphorePermit<'_> {
+        let permit = self.download.acquire().await.expect("semaphore closed");
+        if !self.pacing.delay.is_zero() {
+            let mut next = self.next_download.lock().await;
+            tokio::time::sleep_until(*next).await;
+            *next = Instant::now() + self.pacing.next_delay();
+        }
+        permit
+    }
+
+    /// Wait for an LLM slot for one note's requests; the slot is released when the
+    /// permit is dropped.
+    pub async fn llm(&self) -> SemaphorePermit<'_> {
+        self.llm.acquire().await.expect("semaphore closed")
+    }
+
+    /// With a TPM limit, wait until a request with a prompt of `prompt_tokens` fits
+    /// in the last minute's budget and count it there. Called before every request,
+    /// so retries and continuations are paid for too.
+    pub async fn reserve_tokens(&self, prompt_tokens: usize) {
+        if let Some(tokens) = &self.tokens {
+            tokens.lock().await.reserve(prompt_tokens).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    /// Run 12 tasks through one stage and return the most that ever ran at once.
+    async fn peak_concurrency(limits: &StageLimits, llm_stage: bool) -> usize {
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+
+        let tasks: Vec<_> = (0..12)
+            .map(|_| {
+                let limits = limits.clone();
+                let running = running.clone();
+                let peak = peak.clone();
+                tokio::spawn(async move {
+                    let _permit = if llm_stage {
+                        limits.llm().await
+                    } else {
+                        limits.download().await
+                    };
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    peak.fetch_max(now, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    running.fetch_sub(1, Ordering::SeqCst);
+                })
+            })
+            .collect();
+        futures::future::join_all(tasks).await;
+
+        peak.load(Ordering::SeqCst)
+    }
+
+    #[test]
+    fn test_download_delay_is_jittered_within_bounds() {
+        let pacing = Pacing {
+            delay: Duration::from_secs(2),
+            jitter: 0.25,
+        };
+        assert_eq!(pacing.delay_for(-1.0), Duration::from_millis(1500));
+        assert_eq!(pacing.delay_for(1.0), Duration::from_millis(2500));
+
+        let delays: Vec<Duration> = (0..50).map(|_| pacing.next_delay()).collect();
+        assert!(delays
+            .iter()
+            .all(|d| (Duration::from_millis(1500)..=Duration::from_millis(2500)).contains(d)));
+        assert!(delays.iter().any(|d| *d != delays[0]), "{delays:?}");
+
+        let steady = Pacing {
+            jitter: 0.0,
+            ..pacing
+        };
+        assert_eq!(steady.next_delay(), Duration::from_secs(2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_llm_calls_wait_for_the_token_budget() {
+        let limits = StageLimits::new(1, 4).with_tpm_limit(Some(1000));
+        let start = Instant::now();
+        let elapsed = || start.elapsed().as_secs();
+
+        // 600 + 300 fit in the first minute; the next 600 has to wait for the
+        // first request to leave the window
+        limits.reserve_tokens(600).await;
+        tokio::time::advance(Duration::from_secs(10)).await;
+        limits.reserve_tokens(300).await;
+        assert_eq!(elapsed(), 10);
+        limits.reserve_tokens(600).await;
+        assert_eq!(elapsed(), 60);
+        // Now 300 + 600 are in the window: 100 more fits immediately
+        limits.reserve_tokens(100).await;
+        assert_eq!(elapsed(), 60);
+        // A prompt over the whole limit waits until the window is empty
+        limits.reserve_tokens(5000).await;
+        assert_eq!(elapsed(), 120);
+
+        let unlimited = StageLimits::new(1, 4);
+        let start = Instant::now();
+        for _ in 0..5 {
+            unlimited.reserve_tokens(1_000_000).await;
+        }
+        assert_eq!(start.elapsed(), Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn test_paper_locks_are_forgotten_once_released() {
+        let limits = StageLimits::new(1, 1);
+        let tracked = || limits.papers.lock().unwrap().len();
+
+        let first = limits.paper("2401.08027").await;
+        let waiter = {
+            let limits = limits.clone();
+            tokio::spawn(async move {
+                let _second = limits.paper("2401.08027").await;
+            })
+        };
+        tokio::task::yield_now().await;
+        drop(first);
+        // The waiting task still needs the lock
+        assert_eq!(tracked(), 1);
+        waiter.await.unwrap();
+        assert_eq!(tracked(), 0);
+
+        drop(limits.paper("2401.00001").await);
+        assert_eq!(tracked(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_each_stage_is_capped_separately() {
+        let limits = StageLimits::new(2, 5);
+
+        assert_eq!(peak_concurrency(&limits, false).await, 2);
+        assert_eq!(peak_concurrency(&limits, true).await, 5);
+    }
+}
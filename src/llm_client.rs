@@ -1,6 +1,22 @@
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
-use std::env;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::figures::EncodedFigure;
+use crate::limits::StageLimits;
+use crate::util;
+
+pub const REDACTED: &str = "[REDACTED]";
+
+/// Serializes appends to the LLM log across concurrent batch tasks.
+static LOG_LOCK: Mutex<()> = Mutex::new(());
+
+/// Follow-up message asking the model to pick up a response cut off by `max_tokens`.
+const CONTINUE_INSTRUCTION: &str = "Your previous response was cut off. Continue exactly where it \
+stopped, without repeating anything or adding commentary.";
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct OpenAIRequest {
@@ -8,151 +24,758 @@ pub struct OpenAIRequest {
     pub messages: Vec<Message>,
     pub temperature: f32,
     pub max_tokens: Option<u32>,
+    /// The sampling knobs below are omitted when unset, since not every provider
+    /// accepts them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<ResponseFormat>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResponseFormat {
+    #[serde(rename = "type")]
+    pub kind: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Message {
     pub role: String,
-    pub content: String,
+    pub content: MessageContent,
+}
+
+/// Plain text, or a list of text and image parts for vision models.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum MessageContent {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+impl From<&str> for MessageContent {
+    fn from(text: &str) -> Self {
+        MessageContent::Text(text.to_string())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentPart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImageUrl {
+    pub url: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct OpenAIResponse {
     pub choices: Vec<Choice>,
+    /// `None` when the provider doesn't report usage, which is not the same as zero
+    #[serde(default, deserialize_with = "complete_usage")]
     pub usage: Option<Usage>,
 }
 
+/// Usage only when all three counts are there; some OpenAI-compatible providers
+/// send `null` or a partial object, which shouldn't fail the whole response.
+fn complete_usage<'de, D>(deserializer: D) -> std::result::Result<Option<Usage>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let value = Option::<serde_json::Value>::deserialize(deserializer)?;
+    Ok(value.and_then(|value| serde_json::from_value(value).ok()))
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Choice {
-    pub message: Message,
+    pub message: ResponseMessage,
     pub finish_reason: Option<String>,
 }
 
+/// Like `Message`, but providers may send `null` content (e.g. when a safety filter trips).
 #[derive(Debug, Serialize, Deserialize)]
+pub struct ResponseMessage {
+    #[serde(default)]
+    pub role: String,
+    pub content: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Usage {
     pub prompt_tokens: u32,
     pub completion_tokens: u32,
     pub total_tokens: u32,
 }
 
+/// Summing `Option<Usage>` gives `None` as soon as one response lacks usage, so a
+/// partial count is never passed off as the total.
+impl std::iter::Sum for Usage {
+    fn sum<I: Iterator<Item = Usage>>(iter: I) -> Self {
+        iter.fold(Usage::default(), |total, usage| Usage {
+            prompt_tokens: total.prompt_tokens + usage.prompt_tokens,
+            completion_tokens: total.completion_tokens + usage.completion_tokens,
+            total_tokens: total.total_tokens + usage.total_tokens,
+        })
+    }
+}
+
+/// The generated text, why the model stopped and the tokens it cost.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Completion {
+    /// As the model sent it, untrimmed, so a continuation can be appended without
+    /// losing the whitespace at the cut
+    pub content: String,
+    pub finish_reason: Option<String>,
+    /// As reported by the provider; not every one does
+    pub usage: Option<Usage>,
+}
+
+impl Completion {
+    /// Whether the response was cut off by the token limit.
+    pub fn truncated(&self) -> bool {
+        self.finish_reason.as_deref() == Some("length")
+    }
+}
+
 pub struct LLMClient {
     config: LLMConfig,
+    api_key: String,
     client: reqwest::Client,
+    /// Whose `--tpm-limit` budget every request is counted against
+    limits: Option<StageLimits>,
 }
 
-#[derive(Debug)]
+#[derive(Clone)]
 pub struct LLMConfig {
-    base_url: String,
-    api_key: String,
-    model: String,
-    temperature: f32,
-    max_tokens: Option<u32>,
+    pub base_url: String,
+    pub api_key: Option<String>,
+    pub model: String,
+    pub temperature: f32,
+    pub max_tokens: Option<u32>,
+    pub top_p: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+    pub presence_penalty: Option<f32>,
+    /// Sampling seed for reproducible generations, if the provider supports it
+    pub seed: Option<u64>,
+    /// Ask for a JSON object response (`response_format: json_object`)
+    pub json_mode: bool,
+    /// JSONL file each request/response pair is appended to
+    pub log_path: Option<PathBuf>,
 }
 
-impl LLMConfig {
-    /// 从环境变量加载配置，一次性完成
-    pub fn load() -> Result<Self> {
-        dotenv::dotenv().ok();
-
-        let base_url = env::var("BASE_URL").unwrap_or_else(|_| {
-            "https://generativelanguage.googleapis.com/v1beta/openai".to_string()
-        });
-
-        let api_key = env::var("API_KEY").context("必须在 .env 文件或环境中设置 API_KEY")?;
-
-        let model = env::var("MODEL").unwrap_or_else(|_| "gemini-1.5-flash".to_string());
-
-        let temperature = env::var("TEMPERATURE")
-            .ok()
-            .and_then(|s| s.parse::<f32>().ok())
-            .unwrap_or(0.7);
-
-        let max_tokens = env::var("MAX_TOKENS")
-            .ok()
-            .and_then(|s| s.parse::<u32>().ok());
-
-        Ok(Self {
-            base_url,
-            api_key,
-            model,
-            temperature,
-            max_tokens,
-        })
+impl std::fmt::Debug for LLMConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LLMConfig")
+            .field("base_url", &self.base_url)
+            .field("api_key", &self.api_key.as_ref().map(|_| REDACTED))
+            .field("model", &self.model)
+            .field("temperature", &self.temperature)
+            .field("max_tokens", &self.max_tokens)
+            .field("top_p", &self.top_p)
+            .field("frequency_penalty", &self.frequency_penalty)
+            .field("presence_penalty", &self.presence_penalty)
+            .field("seed", &self.seed)
+            .field("json_mode", &self.json_mode)
+            .field("log_path", &self.log_path)
+            .finish()
     }
 }
 
+/// One line of the `--log-llm` JSONL file.
+#[derive(Debug, Serialize)]
+struct LogEntry<'a> {
+    timestamp: String,
+    paper_id: &'a str,
+    url: &'a str,
+    request: &'a OpenAIRequest,
+    status: u16,
+    response: serde_json::Value,
+}
+
 impl LLMClient {
-    pub fn new() -> Result<Self> {
-        let config = LLMConfig::load()?;
+    pub fn new(config: LLMConfig) -> Result<Self> {
+        let api_key = config
+            .api_key
+            .clone()
+            .context("必须在 .env 文件或环境中设置 API_KEY")?;
+
         Ok(Self {
             config,
+            api_key,
             client: reqwest::Client::new(),
+            limits: None,
         })
     }
 
-    pub async fn generate_note(&self, prompt: &str, paper_content: &str) -> Result<String> {
-        let request_body = OpenAIRequest {
+    /// Count every request against the TPM budget of `limits`.
+    pub fn with_limits(mut self, limits: StageLimits) -> Self {
+        self.limits = Some(limits);
+        self
+    }
+
+    pub fn config(&self) -> &LLMConfig {
+        &self.config
+    }
+
+    pub async fn generate_note(
+        &self,
+        paper_id: &str,
+        prompt: &str,
+        paper_content: &str,
+    ) -> Result<Completion> {
+        self.complete(paper_id, self.build_request(prompt, paper_content.into()))
+            .await
+    }
+
+    /// Ask the model to continue `partial`, a response to `paper_content` that was
+    /// cut off by the token limit. Figures are not attached again.
+    pub async fn continue_note(
+        &self,
+        paper_id: &str,
+        prompt: &str,
+        paper_content: &str,
+        partial: &str,
+    ) -> Result<Completion> {
+        let mut request_body = self.build_request(prompt, paper_content.into());
+        request_body.messages.extend([
+            Message {
+                role: "assistant".to_string(),
+                content: partial.into(),
+            },
+            Message {
+                role: "user".to_string(),
+                content: CONTINUE_INSTRUCTION.into(),
+            },
+        ]);
+        self.complete(paper_id, request_body).await
+    }
+
+    async fn complete(&self, paper_id: &str, request_body: OpenAIRequest) -> Result<Completion> {
+        if let Some(limits) = &self.limits {
+            limits.reserve_tokens(request_tokens(&request_body)).await;
+        }
+
+        let url = format!("{}/chat/completions", self.config.base_url);
+        let response = self
+            .client
+            .post(&url)
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .header("Content-Type", "application/json")
+            .json(&request_body)
+            .send()
+            .await?;
+
+        let status = response.status();
+        let response_text = response.text().await?;
+
+        if let Some(log_path) = &self.config.log_path {
+            let entry = LogEntry {
+                timestamp: chrono::Utc::now().to_rfc3339(),
+                paper_id,
+                url: &url,
+                request: &request_body,
+                status: status.as_u16(),
+                response: serde_json::from_str(&response_text)
+                    .unwrap_or(serde_json::Value::String(response_text.clone())),
+            };
+            if let Err(e) = append_log(log_path, &entry, &self.api_key) {
+                eprintln!(
+                    "Warning: failed to write LLM log {}: {}",
+                    log_path.display(),
+                    e
+                );
+            }
+        }
+
+        if !status.is_success() {
+            return Err(anyhow::anyhow!("API request failed: {}", response_text));
+        }
+
+        let response_data: OpenAIResponse = serde_json::from_str(&response_text)?;
+
+        extract_content(&response_data)
+    }
+
+    fn build_request(&self, prompt: &str, user_content: MessageContent) -> OpenAIRequest {
+        OpenAIRequest {
             model: self.config.model.clone(),
             messages: vec![
                 Message {
                     role: "system".to_string(),
-                    content: prompt.to_string(),
+                    content: prompt.into(),
                 },
                 Message {
                     role: "user".to_string(),
-                    content: paper_content.to_string(),
+                    content: user_content,
                 },
             ],
             temperature: self.config.temperature,
             max_tokens: self.config.max_tokens,
-        };
+            top_p: self.config.top_p,
+            frequency_penalty: self.config.frequency_penalty,
+            presence_penalty: self.config.presence_penalty,
+            seed: self.config.seed,
+            response_format: self.config.json_mode.then(|| ResponseFormat {
+                kind: "json_object".to_string(),
+            }),
+        }
+    }
 
-        println!("{:#?}", self.config);
+    /// Send the paper text followed by the figures themselves, each introduced by its
+    /// path so the model can reference it in the note.
+    pub async fn generate_note_with_figures(
+        &self,
+        paper_id: &str,
+        prompt: &str,
+        paper_content: &str,
+        figures: &[EncodedFigure],
+    ) -> Result<Completion> {
+        let request_body = self.build_request(prompt, figure_parts(paper_content, figures));
+        self.complete(paper_id, request_body).await
+    }
+}
 
-        let response = self
-            .client
-            .post(format!("{}/chat/completions", self.config.base_url))
-            .header("Authorization", format!("Bearer {}", self.config.api_key))
-            .header("Content-Type", "application/json")
-            .json(&request_body)
-            .send()
-            .await?;
+/// API path prefixes of well-known OpenAI-compatible providers, for filling in a
+/// `BASE_URL` given as just the host.
+const KNOWN_API_PATHS: [(&str, &str); 7] = [
+    ("api.openai.com", "/v1"),
+    ("api.deepseek.com", "/v1"),
+    ("api.moonshot.cn", "/v1"),
+    ("api.mistral.ai", "/v1"),
+    ("api.together.xyz", "/v1"),
+    ("openrouter.ai", "/api/v1"),
+    ("api.groq.com", "/openai/v1"),
+];
+
+/// Tidy a user-supplied `BASE_URL` so that `{base_url}/chat/completions` is the
+/// right endpoint: trailing slashes and a pasted `/chat/completions` are dropped,
+/// and a missing API path is filled in for known providers (with a warning).
+pub fn normalize_base_url(base_url: &str) -> String {
+    let mut normalized = base_url.trim().trim_end_matches('/').to_string();
+    if let Some(stripped) = normalized.strip_suffix("/chat/completions") {
+        eprintln!("Warning: BASE_URL should not include /chat/completions; using {stripped}");
+        normalized = stripped.trim_end_matches('/').to_string();
+    }
 
-        if !response.status().is_success() {
-            let error_text = response.text().await?;
-            return Err(anyhow::anyhow!("API request failed: {}", error_text));
+    let Ok(url) = url::Url::parse(&normalized) else {
+        return normalized;
+    };
+    if url.path() != "/" && !url.path().is_empty() {
+        return normalized;
+    }
+    let host = url.host_str().unwrap_or_default();
+    match KNOWN_API_PATHS.iter().find(|(known, _)| *known == host) {
+        Some((_, path)) => {
+            eprintln!("Warning: BASE_URL {normalized} has no API path; using {normalized}{path}");
+            normalized.push_str(path);
         }
+        None => eprintln!(
+            "Warning: BASE_URL {normalized} has no API path; most providers expect one such as /v1"
+        ),
+    }
+    normalized
+}
 
-        let response_data: OpenAIResponse = response.json().await?;
+/// Body of an OpenAI-compatible `GET /models` response.
+#[derive(Debug, Deserialize)]
+struct ModelList {
+    data: Vec<ModelEntry>,
+}
 
-        if let Some(choice) = response_data.choices.first() {
-            Ok(choice.message.content.trim().to_string())
-        } else {
-            Err(anyhow::anyhow!("No response from API"))
-        }
+#[derive(Debug, Deserialize)]
+struct ModelEntry {
+    id: String,
+}
+
+/// IDs of the models the provider at `base_url` offers, sorted.
+pub async fn list_models(base_url: &str, api_key: Option<&str>) -> Result<Vec<String>> {
+    let url = format!("{base_url}/models");
+    let mut request = reqwest::Client::new().get(&url);
+    if let Some(key) = api_key {
+        request = request.bearer_auth(key);
     }
 
-    pub async fn generate_note_with_images(
-        &self,
-        prompt: &str,
-        paper_content: &str,
-        image_references: &[String],
-    ) -> Result<String> {
-        let mut full_content = paper_content.to_string();
-
-        if !image_references.is_empty() {
-            full_content.push_str("\n\n图像文件列表:\n");
-            for (i, img) in image_references.iter().enumerate() {
-                full_content.push_str(&format!("- 图像 {}: {}\n", i + 1, img));
-            }
+    let response = request
+        .send()
+        .await
+        .with_context(|| format!("could not reach {url}"))?;
+    let status = response.status();
+    if matches!(status.as_u16(), 404 | 405 | 501) {
+        anyhow::bail!(
+            "{base_url} does not expose /models (HTTP {status}); check the provider's documentation for model names"
+        );
+    }
+    if !status.is_success() {
+        anyhow::bail!("{url} returned HTTP {status}: {}", response.text().await?);
+    }
+
+    let list: ModelList = response
+        .json()
+        .await
+        .with_context(|| format!("{url} did not return an OpenAI-style model list"))?;
+    let mut ids: Vec<String> = list.data.into_iter().map(|m| m.id).collect();
+    ids.sort();
+    Ok(ids)
+}
+
+/// The paper text followed by the list of figure paths, as sent when figures aren't
+/// attached as images.
+pub fn with_image_references(paper_content: &str, image_references: &[String]) -> String {
+    let mut full_content = paper_content.to_string();
+
+    if !image_references.is_empty() {
+        full_content.push_str("\n\n图像文件列表:\n");
+        for (i, img) in image_references.iter().enumerate() {
+            full_content.push_str(&format!("- 图像 {}: {}\n", i + 1, img));
         }
+    }
+
+    full_content
+}
 
-        self.generate_note(prompt, &full_content).await
+fn figure_parts(paper_content: &str, figures: &[EncodedFigure]) -> MessageContent {
+    let mut parts = vec![ContentPart::Text {
+        text: paper_content.to_string(),
+    }];
+    for (i, figure) in figures.iter().enumerate() {
+        parts.push(ContentPart::Text {
+            text: format!("图像 {}: {}", i + 1, figure.path),
+        });
+        parts.push(ContentPart::ImageUrl {
+            image_url: ImageUrl {
+                url: figure.data_url.clone(),
+            },
+        });
     }
+    MessageContent::Parts(parts)
+}
+
+/// Estimated prompt tokens of a request's text; attached images are not counted.
+fn request_tokens(request: &OpenAIRequest) -> usize {
+    request
+        .messages
+        .iter()
+        .map(|message| match &message.content {
+            MessageContent::Text(text) => util::estimate_tokens(text),
+            MessageContent::Parts(parts) => parts
+                .iter()
+                .map(|part| match part {
+                    ContentPart::Text { text } => util::estimate_tokens(text),
+                    ContentPart::ImageUrl { .. } => 0,
+                })
+                .sum(),
+        })
+        .sum()
+}
+
+/// Append an entry to the JSONL log, scrubbing the API key from anything echoed back.
+fn append_log(path: &Path, entry: &LogEntry, api_key: &str) -> Result<()> {
+    let mut line = serde_json::to_string(entry)?;
+    if !api_key.is_empty() {
+        line = line.replace(api_key, REDACTED);
+    }
+    line.push('\n');
+
+    let _guard = LOG_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(line.as_bytes())?;
+    Ok(())
+}
+
+/// Pull the generated text out of a response, rejecting choices with no usable content.
+fn extract_content(response: &OpenAIResponse) -> Result<Completion> {
+    let choice = response
+        .choices
+        .first()
+        .ok_or_else(|| anyhow::anyhow!("No response from API"))?;
+
+    let content = choice.message.content.as_deref().unwrap_or("");
+    if content.trim().is_empty() {
+        return Err(anyhow::anyhow!(
+            "API returned an empty response (finish_reason: {}); the request may have been \
+             blocked by a content filter or the model may be overloaded",
+            choice.finish_reason.as_deref().unwrap_or("unknown")
+        ));
+    }
+
+    Ok(Completion {
+        content: content.to_string(),
+        finish_reason: choice.finish_reason.clone(),
+        usage: response.usage,
+    })
 }
 
-impl Default for LLMClient {
-    fn default() -> Self {
-        Self::new().expect("Failed to create LLM client")
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_empty_content_is_rejected() {
+        let body = r#"{"choices":[{"message":{"role":"assistant","content":"  \n "},"finish_reason":"content_filter"}]}"#;
+        let response: OpenAIResponse = serde_json::from_str(body).unwrap();
+
+        let err = extract_content(&response).unwrap_err().to_string();
+        assert!(err.contains("empty response"), "{err}");
+        assert!(err.contains("content_filter"), "{err}");
+    }
+
+    #[test]
+    fn test_null_content_is_rejected() {
+        let body = r#"{"choices":[{"message":{"role":"assistant","content":null},"finish_reason":"stop"}]}"#;
+        let response: OpenAIResponse = serde_json::from_str(body).unwrap();
+
+        let err = extract_content(&response).unwrap_err().to_string();
+        assert!(err.contains("empty response"), "{err}");
+    }
+
+    #[test]
+    fn test_missing_choices_is_distinct() {
+        let response: OpenAIResponse = serde_json::from_str(r#"{"choices":[]}"#).unwrap();
+
+        let err = extract_content(&response).unwrap_err().to_string();
+        assert_eq!(err, "No response from API");
+    }
+
+    #[test]
+    fn test_log_entry_redacts_api_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let log_path = dir.path().join("llm.jsonl");
+        let api_key = "sk-test-secret";
+
+        let request = OpenAIRequest {
+            model: "m".to_string(),
+            messages: vec![Message {
+                role: "user".to_string(),
+                content: "summarize this paper".into(),
+            }],
+            temperature: 0.7,
+            max_tokens: None,
+            top_p: None,
+            frequency_penalty: None,
+            presence_penalty: None,
+            seed: None,
+            response_format: None,
+        };
+        let entry = LogEntry {
+            timestamp: "2024-01-01T00:00:00Z".to_string(),
+            paper_id: "2401.08027",
+            url: "https://api.example.com/v1/chat/completions",
+            request: &request,
+            status: 401,
+            response: serde_json::json!({"error": format!("invalid key {api_key}")}),
+        };
+
+        append_log(&log_path, &entry, api_key).unwrap();
+        append_log(&log_path, &entry, api_key).unwrap();
+
+        let log = std::fs::read_to_string(&log_path).unwrap();
+        assert_eq!(log.lines().count(), 2);
+        assert!(log.contains("summarize this paper"));
+        assert!(log.contains("2401.08027"));
+        assert!(!log.contains(api_key));
+
+        let config = LLMConfig {
+            base_url: String::new(),
+            api_key: Some(api_key.to_string()),
+            model: String::new(),
+            temperature: 0.0,
+            max_tokens: None,
+            top_p: None,
+            frequency_penalty: None,
+            presence_penalty: None,
+            seed: None,
+            json_mode: false,
+            log_path: None,
+        };
+        assert!(!format!("{config:?}").contains(api_key));
+    }
+
+    #[test]
+    fn test_base_url_normalization() {
+        assert_eq!(
+            normalize_base_url("https://api.openai.com/v1/"),
+            "https://api.openai.com/v1"
+        );
+        assert_eq!(
+            normalize_base_url(" https://api.deepseek.com/v1/chat/completions "),
+            "https://api.deepseek.com/v1"
+        );
+        assert_eq!(
+            normalize_base_url("https://api.openai.com"),
+            "https://api.openai.com/v1"
+        );
+        assert_eq!(
+            normalize_base_url("https://openrouter.ai/"),
+            "https://openrouter.ai/api/v1"
+        );
+        assert_eq!(
+            normalize_base_url("http://localhost:8000//"),
+            "http://localhost:8000"
+        );
+        assert_eq!(
+            normalize_base_url("https://generativelanguage.googleapis.com/v1beta/openai"),
+            "https://generativelanguage.googleapis.com/v1beta/openai"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_list_models() {
+        let mut server = mockito::Server::new_async().await;
+        let models = server
+            .mock("GET", "/v1/models")
+            .match_header("authorization", "Bearer sk-test")
+            .with_body(r#"{"object":"list","data":[{"id":"gpt-4o","object":"model"},{"id":"deepseek-chat","object":"model"}]}"#)
+            .create_async()
+            .await;
+        let base_url = format!("{}/v1", server.url());
+
+        let ids = list_models(&base_url, Some("sk-test")).await.unwrap();
+        assert_eq!(ids, ["deepseek-chat", "gpt-4o"]);
+        models.assert_async().await;
+
+        server
+            .mock("GET", "/v2/models")
+            .with_status(404)
+            .create_async()
+            .await;
+        let err = list_models(&format!("{}/v2", server.url()), None)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("does not expose /models"), "{err}");
+    }
+
+    #[test]
+    fn test_content_is_not_trimmed() {
+        let body = r#"{"choices":[{"message":{"role":"assistant","content":"\n\\section{A} "}}]}"#;
+        let response: OpenAIResponse = serde_json::from_str(body).unwrap();
+
+        assert_eq!(
+            extract_content(&response).unwrap().content,
+            "\n\\section{A} "
+        );
+    }
+
+    #[test]
+    fn test_missing_usage_is_unavailable() {
+        let message = r#""choices":[{"message":{"role":"assistant","content":"\\section{A}"}}]"#;
+        for (body, expected) in [
+            (format!("{{{message}}}"), None),
+            (format!(r#"{{{message},"usage":null}}"#), None),
+            (
+                format!(r#"{{{message},"usage":{{"prompt_tokens":12}}}}"#),
+                None,
+            ),
+            (
+                format!(
+                    r#"{{{message},"usage":{{"prompt_tokens":0,"completion_tokens":0,"total_tokens":0}}}}"#
+                ),
+                Some(Usage::default()),
+            ),
+        ] {
+            let response: OpenAIResponse = serde_json::from_str(&body).unwrap();
+            assert_eq!(
+                extract_content(&response).unwrap().usage,
+                expected,
+                "{body}"
+            );
+        }
+        // One response without usage makes the total unavailable too
+        let total: Option<Usage> = [Some(Usage::default()), None].into_iter().sum();
+        assert_eq!(total, None);
+    }
+
+    fn client(seed: Option<u64>, json_mode: bool) -> LLMClient {
+        LLMClient::new(LLMConfig {
+            base_url: "https://api.example.com/v1".to_string(),
+            api_key: Some("sk-test".to_string()),
+            model: "m".to_string(),
+            temperature: 0.0,
+            max_tokens: None,
+            top_p: None,
+            frequency_penalty: None,
+            presence_penalty: None,
+            seed,
+            json_mode,
+            log_path: None,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_seed_only_sent_when_set() {
+        let body =
+            serde_json::to_value(client(Some(42), false).build_request("prompt", "paper".into()))
+                .unwrap();
+        assert_eq!(body["seed"], 42);
+
+        let body =
+            serde_json::to_value(client(None, false).build_request("prompt", "paper".into()))
+                .unwrap();
+        assert!(body.get("seed").is_none(), "{body}");
+        assert!(body.get("response_format").is_none(), "{body}");
+    }
+
+    #[test]
+    fn test_sampling_options_only_sent_when_set() {
+        use crate::config::{RunOptions, Settings};
+
+        let env = Settings::from_lookup(|key| (key == "TOP_P").then(|| "0.9".to_string()));
+        let options = RunOptions::from_settings(Settings {
+            api_key: Some("sk-test".to_string()),
+            ..env
+        })
+        .unwrap();
+        let client = LLMClient::new(options.llm).unwrap();
+
+        let body = serde_json::to_value(client.build_request("prompt", "paper".into())).unwrap();
+        assert_eq!(body["top_p"].as_f64(), Some(0.9f32 as f64));
+        assert!(body.get("frequency_penalty").is_none(), "{body}");
+        assert!(body.get("presence_penalty").is_none(), "{body}");
+    }
+
+    #[test]
+    fn test_json_mode_sets_response_format() {
+        let body = serde_json::to_value(client(None, true).build_request("prompt", "paper".into()))
+            .unwrap();
+        assert_eq!(
+            body["response_format"],
+            serde_json::json!({"type": "json_object"})
+        );
+    }
+
+    #[test]
+    fn test_figure_parts_serialize_in_order() {
+        let figures = [
+            EncodedFigure {
+                path: "output/x/fig1.png".to_string(),
+                data_url: "data:image/png;base64,AAAA".to_string(),
+            },
+            EncodedFigure {
+                path: "output/x/fig2.jpg".to_string(),
+                data_url: "data:image/jpeg;base64,BBBB".to_string(),
+            },
+        ];
+
+        let json = serde_json::to_value(figure_parts("paper", &figures)).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!([
+                {"type": "text", "text": "paper"},
+                {"type": "text", "text": "图像 1: output/x/fig1.png"},
+                {"type": "image_url", "image_url": {"url": "data:image/png;base64,AAAA"}},
+                {"type": "text", "text": "图像 2: output/x/fig2.jpg"},
+                {"type": "image_url", "image_url": {"url": "data:image/jpeg;base64,BBBB"}},
+            ])
+        );
+        let text = serde_json::to_value(MessageContent::from("hi")).unwrap();
+        assert_eq!(text, serde_json::json!("hi"));
     }
 }
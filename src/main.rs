@@ -1,26 +1,427 @@
-use anyhow::Result;
-use clap::{Parser, Subcommand};
-use futures::future::join_all;
-use std::path::Path;
+use anyhow::{Context, Result};
+use chrono::NaiveDate;
+use clap::{Args, Parser, Subcommand};
+use std::path::{Path, PathBuf};
 
 mod arxiv;
+mod arxiv_html;
+mod bundle;
+mod config;
+mod doctor;
 mod downloader;
+mod events;
 mod extractor;
+mod figures;
+mod hook;
+mod library;
+mod limits;
 mod llm_client;
 mod note_generator;
 mod processor;
+mod stats;
+mod structured;
+#[cfg(test)]
+mod test_support;
+mod timing;
+mod util;
+mod validate;
+mod watch;
 
 use arxiv::ArxivUrl;
+use config::{CleanupMode, OnLarge, OverwritePolicy, RunOptions, Settings};
 use downloader::PaperDownloader;
-use note_generator::NoteGenerator;
-use processor::PaperProcessor;
+use events::{Event, EventLog};
+use figures::ImageLimits;
+use note_generator::{append_note, GeneratedNote, NoteGenerator, OutputFormat, PromptEstimate};
+use processor::{PaperProcessor, ProcessedContent};
+use std::time::Instant;
+use timing::StageTimings;
 
 #[derive(Parser)]
 #[command(name = "paper_scan")]
 #[command(about = "arXiv paper automation note generator", long_about = None)]
+#[command(arg_required_else_help = true)]
 struct Cli {
     #[command(subcommand)]
-    command: Commands,
+    command: Option<Commands>,
+
+    /// Print the effective configuration (flags > env > config file > defaults,
+    /// API key redacted) and exit
+    #[arg(long, value_enum, num_args = 0..=1, default_missing_value = "toml")]
+    print_config: Option<config::ConfigFormat>,
+
+    #[command(flatten)]
+    config: ConfigArgs,
+
+    #[command(flatten)]
+    run: RunArgs,
+}
+
+/// Per-run flags that only come from the command line.
+#[derive(Args)]
+struct RunArgs {
+    /// Format of the generated note
+    #[arg(long, global = true, value_enum, default_value_t)]
+    format: OutputFormat,
+    /// What to do with the downloaded source archive after processing: delete it,
+    /// move it aside to output/cache/, or keep it in place
+    #[arg(long, global = true, value_enum, default_value_t)]
+    cleanup_mode: CleanupMode,
+    /// Keep the downloaded source archive (same as --cleanup-mode keep)
+    #[arg(long, global = true)]
+    no_cleanup: bool,
+    /// Attach the paper's figures as images (requires a vision-capable model)
+    #[arg(long, global = true)]
+    vision: bool,
+    /// Maximum number of figures attached to a vision request
+    #[arg(long, global = true, default_value_t = config::DEFAULT_MAX_FIGURES)]
+    max_figures: usize,
+    /// Downscale figures whose longest side exceeds this many pixels before attaching them
+    #[arg(long, global = true, default_value_t = ImageLimits::default().max_dim)]
+    image_max_dim: u32,
+    /// JPEG quality (1-100) for downscaled figures
+    #[arg(long, global = true, default_value_t = ImageLimits::default().quality,
+          value_parser = clap::value_parser!(u8).range(1..=100))]
+    image_quality: u8,
+    /// Total size in bytes of the encoded figures attached to one vision request;
+    /// figures past the budget are dropped
+    #[arg(long, global = true, default_value_t = config::DEFAULT_MAX_FIGURE_BYTES)]
+    max_input_figures_bytes: usize,
+    /// Sampling seed for reproducible generations (if the provider supports it)
+    #[arg(long, global = true)]
+    seed: Option<u64>,
+    /// Request the note as JSON (summary, contributions, method, results) and render
+    /// it into a fixed structure
+    #[arg(long, global = true)]
+    structured: bool,
+    /// Maximum number of extracted equations listed in the prompt; referenced,
+    /// labeled and display equations, and those in --equation-sections, are kept
+    /// over the rest
+    #[arg(long, global = true, default_value_t = config::DEFAULT_MAX_EQUATIONS)]
+    max_equations: usize,
+    /// Comma-separated section-title prefixes (case-insensitive) whose equations rank
+    /// higher under --max-equations
+    #[arg(long, global = true, value_delimiter = ',',
+          default_values_t = config::DEFAULT_EQUATION_SECTIONS.map(String::from))]
+    equation_sections: Vec<String>,
+    /// Don't list extracted equations in the prompt at all
+    #[arg(long, global = true)]
+    no_equations: bool,
+    /// Keep display-math environments in the section text, next to the prose that
+    /// explains them, instead of listing equations separately
+    #[arg(long, global = true)]
+    combine_equations: bool,
+    /// Generate a note even when extraction found no abstract and fewer than
+    /// --min-sections sections
+    #[arg(long, global = true)]
+    allow_poor_extraction: bool,
+    /// Without an abstract, papers with fewer sections than this are skipped
+    #[arg(long, global = true, default_value_t = config::DEFAULT_MIN_SECTIONS)]
+    min_sections: usize,
+    /// Extracted abstracts shorter than this many characters are replaced by the
+    /// arXiv API's abstract, when it has one
+    #[arg(long, global = true, default_value_t = config::DEFAULT_MIN_ABSTRACT_CHARS)]
+    min_abstract_chars: usize,
+    /// Papers with more sections than this are handled by --on-large
+    #[arg(long, global = true)]
+    max_sections: Option<usize>,
+    /// Papers with more words of section text than this are handled by --on-large
+    #[arg(long, global = true)]
+    max_words: Option<usize>,
+    /// What to do with papers over --max-sections or --max-words: skip them, send
+    /// only the start of each section, or generate from the full text anyway
+    #[arg(long, global = true, value_enum, default_value_t)]
+    on_large: OnLarge,
+    /// Build the prompt from the full paper, from the title and abstract only, or
+    /// from the abstract when it is substantial (auto)
+    #[arg(long, global = true, value_enum, default_value_t)]
+    summary_source: config::SummarySource,
+    /// Build the prompt from every section, from the abstract and the
+    /// conclusion/discussion only, or from the method sections
+    #[arg(long, global = true, value_enum, default_value_t)]
+    focus: config::Focus,
+    /// Parse arXiv papers from their LaTeX source or from their HTML rendering
+    /// (arxiv.org/html), falling back to the source for papers without one
+    #[arg(long, global = true, value_enum, default_value_t)]
+    source: config::PaperSource,
+    /// Also append every generated note to this combined file
+    #[arg(long, global = true)]
+    append_to: Option<PathBuf>,
+    /// Also write each LaTeX note, with copies of the figures it includes, to a
+    /// self-contained tex/<id>/bundle/ folder ready to upload to Overleaf
+    #[arg(long, global = true)]
+    overleaf_bundle: bool,
+    /// Also generate each note with this model, at the same time and from the same
+    /// prompt, and write it next to the first as <id>.<model>.tex; the token use and
+    /// cost of both are reported
+    #[arg(long, global = true, value_name = "MODEL")]
+    compare: Option<String>,
+    /// Seconds to wait between the starts of two downloads, to go easy on arXiv
+    #[arg(long, global = true, default_value = "0", value_parser = parse_download_delay)]
+    download_delay: std::time::Duration,
+    /// Randomly lengthen or shorten each --download-delay by up to this fraction,
+    /// so concurrent downloads don't fire together
+    #[arg(long, global = true, default_value_t = limits::DEFAULT_DOWNLOAD_JITTER,
+          value_parser = parse_download_jitter)]
+    download_jitter: f64,
+    /// Most retries (e.g. of invalid --structured responses) across the whole run;
+    /// once they are used up, papers that need one fail straight away
+    #[arg(long, global = true)]
+    max_total_retries: Option<usize>,
+    /// Reject downloaded sources smaller than this many bytes (likely an error page)
+    #[arg(long, global = true, default_value_t = config::DEFAULT_MIN_ARCHIVE_SIZE)]
+    min_size: u64,
+    /// Reject downloaded sources larger than this many bytes
+    #[arg(long, global = true, default_value_t = config::DEFAULT_MAX_ARCHIVE_SIZE)]
+    max_size: u64,
+    /// After a batch, write the URLs that failed to this file (one per line), ready
+    /// to be retried with `batch <file>`
+    #[arg(long, global = true)]
+    failed_urls: Option<PathBuf>,
+    /// Note file name without extension; placeholders: {id}, {title_slug}, {date},
+    /// {first_author} (e.g. "{date}-{first_author}-{title_slug}")
+    #[arg(long, global = true, default_value = config::DEFAULT_FILENAME_PATTERN,
+          value_parser = parse_filename_pattern)]
+    filename_pattern: String,
+    /// What to do when a paper already has a note: skip it, regenerate it in place,
+    /// or keep it and write a numbered version (<name>.v2.tex, ...)
+    #[arg(long, global = true, value_enum, default_value_t)]
+    overwrite_policy: OverwritePolicy,
+    /// Write the system prompt and user message to this file before each LLM call;
+    /// "{id}" in the path is replaced by the paper ID
+    #[arg(long, global = true)]
+    dump_prompt: Option<PathBuf>,
+    /// For IDs without a version, look up the latest version on the arXiv API and
+    /// record it in the note's metadata
+    #[arg(long, global = true)]
+    resolve_version: bool,
+    /// Regenerate existing notes when arXiv has a newer version than the one they
+    /// were generated from (implies --resolve-version)
+    #[arg(long, global = true)]
+    update: bool,
+    /// Comma-separated section titles left out of the prompt, matched case-insensitively
+    /// against the start of each title; subsections go too. Pass "" to keep everything
+    #[arg(long, global = true, value_delimiter = ',',
+          default_values_t = config::DEFAULT_EXCLUDE_SECTIONS.map(String::from))]
+    exclude_sections: Vec<String>,
+    /// Comma-separated argument-less commands (without the backslash) removed from
+    /// the text, e.g. "maketitle,noindent". Pass "" to keep them all
+    #[arg(long, global = true, value_delimiter = ',',
+          default_values_t = config::DEFAULT_STRIP_COMMANDS.map(String::from))]
+    strip_commands: Vec<String>,
+    /// Also scan the anc/ directory, where arXiv keeps ancillary data and code, for
+    /// TeX files and images
+    #[arg(long, global = true)]
+    include_ancillary: bool,
+    /// Also write one JSON object per pipeline event (download_start, download_done,
+    /// extract_done, llm_done, paper_done, paper_failed) to stderr
+    #[arg(long, global = true)]
+    json_logs: bool,
+    /// Extra instruction appended to the system prompt for this run (repeatable),
+    /// e.g. --instruction "Focus on the method"
+    #[arg(long = "instruction", global = true)]
+    instructions: Vec<String>,
+    /// Extra instruction placed before the system prompt for this run (repeatable)
+    #[arg(long = "prepend-instruction", global = true)]
+    prepend_instructions: Vec<String>,
+    /// Tag recorded in the metadata of each generated note (repeatable), e.g.
+    /// --tag thesis; `list --filter` and `stats` pick tags up
+    #[arg(long = "tag", global = true)]
+    tags: Vec<String>,
+}
+
+fn parse_filename_pattern(pattern: &str) -> Result<String, String> {
+    note_generator::validate_filename_pattern(pattern).map_err(|e| e.to_string())?;
+    Ok(pattern.to_string())
+}
+
+fn parse_download_delay(secs: &str) -> Result<std::time::Duration, String> {
+    let secs: f64 = secs.parse().map_err(|e| format!("{e}"))?;
+    std::time::Duration::try_from_secs_f64(secs)
+        .map_err(|_| "must be a non-negative number of seconds".to_string())
+}
+
+fn parse_download_jitter(fraction: &str) -> Result<f64, String> {
+    let fraction: f64 = fraction.parse().map_err(|e| format!("{e}"))?;
+    if !(0.0..=1.0).contains(&fraction) {
+        return Err("must be a fraction between 0 and 1".to_string());
+    }
+    Ok(fraction)
+}
+
+impl RunArgs {
+    fn apply(self, options: &mut RunOptions) {
+        options.format = self.format;
+        options.cleanup = if self.no_cleanup {
+            CleanupMode::Keep
+        } else {
+            self.cleanup_mode
+        };
+        options.vision = self.vision;
+        options.max_figures = self.max_figures;
+        options.max_figure_bytes = self.max_input_figures_bytes;
+        options.llm.seed = self.seed;
+        options.structured = self.structured;
+        options.allow_poor_extraction = self.allow_poor_extraction;
+        options.min_sections = self.min_sections;
+        options.min_abstract_chars = self.min_abstract_chars;
+        options.max_sections = self.max_sections;
+        options.max_words = self.max_words;
+        options.on_large = self.on_large;
+        options.summary_source = self.summary_source;
+        options.focus = self.focus;
+        options.source = self.source;
+        options.append_to = self.append_to;
+        options.overleaf_bundle = self.overleaf_bundle;
+        options.compare_model = self.compare;
+        options.limits = options
+            .limits
+            .clone()
+            .with_download_pacing(limits::Pacing {
+                delay: self.download_delay,
+                jitter: self.download_jitter,
+            })
+            .with_retry_budget(self.max_total_retries);
+        options.min_archive_size = self.min_size;
+        options.max_archive_size = self.max_size;
+        options.failed_urls = self.failed_urls;
+        options.filename_pattern = self.filename_pattern;
+        options.overwrite_policy = self.overwrite_policy;
+        options.dump_prompt = self.dump_prompt;
+        options.resolve_version = self.resolve_version || self.update;
+        options.update = self.update;
+        options.include_ancillary = self.include_ancillary;
+        if self.json_logs {
+            options.events = EventLog::stderr();
+        }
+        options.instructions = self.instructions;
+        options.prepend_instructions = self.prepend_instructions;
+        options.tags = self.tags;
+        options.exclude_sections = self
+            .exclude_sections
+            .into_iter()
+            .map(|title| title.trim().to_string())
+            .filter(|title| !title.is_empty())
+            .collect();
+        options.equation_sections = self
+            .equation_sections
+            .into_iter()
+            .map(|title| title.trim().to_string())
+            .filter(|title| !title.is_empty())
+            .collect();
+        options.strip_commands = self
+            .strip_commands
+            .into_iter()
+            .map(|name| name.trim().trim_start_matches('\\').to_string())
+            .filter(|name| !name.is_empty())
+            .collect();
+        options.combine_equations = self.combine_equations;
+        options.max_equations = if self.no_equations {
+            0
+        } else {
+            self.max_equations
+        };
+        options.image_limits = ImageLimits {
+            max_dim: self.image_max_dim,
+            quality: self.image_quality,
+        };
+    }
+}
+
+/// Settings that can also come from the environment or `paper_scan.toml`.
+/// Flags take precedence over env vars, which take precedence over the file.
+#[derive(Args)]
+struct ConfigArgs {
+    /// OpenAI-compatible API base URL [env: BASE_URL]
+    #[arg(long, global = true)]
+    base_url: Option<String>,
+    /// Read the API key from this file instead of API_KEY [env: API_KEY_FILE]
+    #[arg(long, global = true)]
+    api_key_file: Option<PathBuf>,
+    /// Model name [env: MODEL]
+    #[arg(long, global = true)]
+    model: Option<String>,
+    /// Sampling temperature [env: TEMPERATURE]
+    #[arg(long, global = true)]
+    temperature: Option<f32>,
+    /// Maximum tokens to generate [env: MAX_TOKENS]
+    #[arg(long, global = true)]
+    max_tokens: Option<u32>,
+    /// Nucleus sampling cutoff; omitted from requests unless set [env: TOP_P]
+    #[arg(long, global = true)]
+    top_p: Option<f32>,
+    /// Penalty for repeating frequent tokens; omitted unless set [env: FREQUENCY_PENALTY]
+    #[arg(long, global = true)]
+    frequency_penalty: Option<f32>,
+    /// Penalty for tokens already present; omitted unless set [env: PRESENCE_PENALTY]
+    #[arg(long, global = true)]
+    presence_penalty: Option<f32>,
+    /// Wrap generated notes in a .tex skeleton containing a {{CONTENT}} placeholder
+    /// (optionally also {{TITLE}} and {{PAPER_ID}}) [env: TEMPLATE]
+    #[arg(long, global = true)]
+    template: Option<PathBuf>,
+    /// Directory generated notes are written to (default "tex") [env: OUTPUT_DIR]
+    #[arg(long, global = true)]
+    output_dir: Option<PathBuf>,
+    /// Number of papers downloaded and sent to the LLM at once in batch mode; the
+    /// stage-specific flags below take precedence [env: CONCURRENCY]
+    #[arg(long, global = true)]
+    concurrency: Option<usize>,
+    /// Number of concurrent arXiv downloads (default 2) [env: DOWNLOAD_CONCURRENCY]
+    #[arg(long, global = true)]
+    download_concurrency: Option<usize>,
+    /// Number of concurrent LLM requests (default 4) [env: LLM_CONCURRENCY]
+    #[arg(long, global = true)]
+    llm_concurrency: Option<usize>,
+    /// Keep the estimated prompt tokens sent per rolling minute under this, for
+    /// providers with tokens-per-minute limits [env: TPM_LIMIT]
+    #[arg(long, global = true)]
+    tpm_limit: Option<usize>,
+    /// Language to write the note in, e.g. "English" [env: OUTPUT_LANG]
+    #[arg(long, global = true)]
+    lang: Option<String>,
+    /// System prompt file (default "prompts.txt", or "prompts_html.txt" for HTML) [env: PROMPT_FILE]
+    #[arg(long, global = true)]
+    prompt_file: Option<PathBuf>,
+    /// Shell command to run after each note is saved; receives PAPER_ID, PAPER_TITLE
+    /// and NOTE_PATH in its environment [env: ON_COMPLETE]
+    #[arg(long, global = true)]
+    on_complete: Option<String>,
+    /// Append every LLM request and raw response to this JSONL file (API key redacted)
+    /// [env: LLM_LOG]
+    #[arg(long, global = true)]
+    log_llm: Option<PathBuf>,
+    /// Comma-separated main file names (e.g. "thesis.tex,body.tex") tried before the
+    /// built-in ones when no file has \documentclass [env: MAIN_HINTS]
+    #[arg(long, global = true, value_delimiter = ',')]
+    main_hints: Option<Vec<String>>,
+}
+
+impl ConfigArgs {
+    fn into_settings(self) -> Settings {
+        Settings {
+            base_url: self.base_url,
+            api_key: None,
+            api_key_file: self.api_key_file,
+            model: self.model,
+            temperature: self.temperature,
+            max_tokens: self.max_tokens,
+            top_p: self.top_p,
+            frequency_penalty: self.frequency_penalty,
+            presence_penalty: self.presence_penalty,
+            template: self.template,
+            output_dir: self.output_dir,
+            concurrency: self.concurrency,
+            download_concurrency: self.download_concurrency,
+            llm_concurrency: self.llm_concurrency,
+            tpm_limit: self.tpm_limit,
+            lang: self.lang,
+            prompt_file: self.prompt_file,
+            on_complete: self.on_complete,
+            log_llm: self.log_llm,
+            main_hints: self.main_hints,
+        }
+    }
 }
 
 #[derive(Subcommand)]
@@ -34,6 +435,108 @@ enum Commands {
     Batch {
         /// Path to file containing URLs (one per line)
         file_path: String,
+        /// Only process the first N URLs that don't have a note yet
+        #[arg(long)]
+        limit: Option<usize>,
+    },
+    /// Process the URLs in a file, then keep checking it and process the lines
+    /// appended to it until Ctrl-C
+    Watch {
+        /// Path to file containing URLs (one per line)
+        file_path: String,
+        /// Seconds between checks of the file
+        #[arg(long, default_value_t = 2.0)]
+        interval: f64,
+    },
+    /// Process the papers returned by an arXiv API search (e.g. "cat:cs.CL")
+    Search {
+        /// arXiv API search query
+        query: String,
+        /// Maximum number of results to fetch
+        #[arg(long, default_value_t = 50)]
+        max_results: usize,
+        /// Only keep papers submitted on or after this date (YYYY-MM-DD)
+        #[arg(long)]
+        since: Option<NaiveDate>,
+        /// Only keep papers submitted on or before this date (YYYY-MM-DD)
+        #[arg(long)]
+        until: Option<NaiveDate>,
+    },
+    /// Generate a note from a local source archive (.tar.gz, .tgz, .gz, .zip) or a
+    /// directory of .tex files, without downloading anything
+    Local {
+        /// Source archive or directory; its name becomes the paper ID
+        path: PathBuf,
+    },
+    /// Download and process a paper, then estimate the size of its prompt without
+    /// calling the model
+    CountTokens {
+        /// arXiv paper URL
+        url: String,
+        /// Also list every section sent, to see which ones are expensive
+        #[arg(long)]
+        prompt_detail: bool,
+    },
+    /// Regenerate the note for a paper from its previously extracted content
+    Reprocess {
+        /// arXiv paper ID or URL
+        paper: String,
+    },
+    /// Check the API key, endpoint, prompt file and output directories
+    Doctor,
+    /// List the model IDs offered at the configured BASE_URL
+    Models,
+    /// List the notes in the output directory
+    List {
+        /// Order by generation date (newest first), title or arXiv ID
+        #[arg(long, value_enum, default_value_t)]
+        sort: library::SortKey,
+        /// Reverse the order
+        #[arg(long)]
+        reverse: bool,
+        /// Only list notes whose title, authors, keywords or tags contain this (ignoring case)
+        #[arg(long)]
+        filter: Option<String>,
+        /// Only list notes with an author whose name contains this (ignoring case)
+        #[arg(long)]
+        author: Option<String>,
+        /// Only list papers submitted in this year
+        #[arg(long)]
+        year: Option<i32>,
+        /// Print the notes as a JSON array
+        #[arg(long)]
+        json: bool,
+    },
+    /// Summarize the note library: token use, estimated cost, models, months,
+    /// paper length and the most common authors and keywords
+    Stats {
+        /// USD per million prompt tokens, instead of the built-in prices
+        #[arg(long, requires = "output_price")]
+        input_price: Option<f64>,
+        /// USD per million completion tokens, instead of the built-in prices
+        #[arg(long, requires = "input_price")]
+        output_price: Option<f64>,
+        /// Print the statistics as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Check that every .tex note's figures exist and its braces and environments
+    /// balance
+    Validate {
+        /// Point broken figure paths at a file of the same name in the paper's
+        /// working directory
+        #[arg(long)]
+        fix_paths: bool,
+        /// Also compile each note with pdflatex (draft mode) and report errors
+        #[arg(long)]
+        compile: bool,
+    },
+    /// Find notes for several versions of the same paper (e.g. 2401.08027 and
+    /// 2401.08027v2) and keep only the newest
+    DedupeLibrary {
+        /// Delete the older versions' notes instead of only listing them
+        #[arg(long)]
+        remove: bool,
     },
     /// Collect PDF files from tex folder to pdfs folder
     CollectPdf {
@@ -50,12 +553,93 @@ enum Commands {
 async fn main() -> Result<()> {
     let cli = Cli::parse();
 
-    match cli.command {
+    dotenv::dotenv().ok();
+    let settings = Settings::resolve(cli.config.into_settings())?;
+    if let Some(format) = cli.print_config {
+        print!("{}", settings.effective().render(format)?);
+        return Ok(());
+    }
+    let Some(command) = cli.command else {
+        anyhow::bail!("No command given; see --help");
+    };
+    let mut options = RunOptions::from_settings(settings)?;
+    cli.run.apply(&mut options);
+
+    match command {
         Commands::Single { url } => {
-            process_single_paper(&url).await?;
+            process_single_paper(&url, &options).await?;
+        }
+        Commands::Batch { file_path, limit } => {
+            process_batch_papers(&file_path, limit, &options).await?;
+        }
+        Commands::Watch {
+            file_path,
+            interval,
+        } => {
+            watch_batch_papers(&file_path, interval, &options).await?;
+        }
+        Commands::Search {
+            query,
+            max_results,
+            since,
+            until,
+        } => {
+            process_search_papers(&query, max_results, since, until, &options).await?;
         }
-        Commands::Batch { file_path } => {
-            process_batch_papers(&file_path).await?;
+        Commands::Local { path } => {
+            process_local(&path, &options).await?;
+        }
+        Commands::CountTokens { url, prompt_detail } => {
+            let estimate = count_tokens(&ArxivUrl::parse(&url)?, &options).await?;
+            print_estimate(&estimate, prompt_detail);
+        }
+        Commands::Reprocess { paper } => {
+            reprocess_paper(&paper, &options).await?;
+        }
+        Commands::Doctor => {
+            doctor::run(&options).await?;
+        }
+        Commands::Models => {
+            let models =
+                llm_client::list_models(&options.llm.base_url, options.llm.api_key.as_deref())
+                    .await?;
+            if models.is_empty() {
+                println!("{} lists no models", options.llm.base_url);
+            }
+            for model in models {
+                println!("{model}");
+            }
+        }
+        Commands::List {
+            sort,
+            reverse,
+            filter,
+            author,
+            year,
+            json,
+        } => {
+            let filter = library::NoteFilter {
+                query: filter,
+                author,
+                year,
+            };
+            library::list(&options.notes_dir, &filter, sort, reverse, json)?;
+        }
+        Commands::Stats {
+            input_price,
+            output_price,
+            json,
+        } => {
+            let price = input_price
+                .zip(output_price)
+                .map(|(prompt, completion)| stats::Price { prompt, completion });
+            stats::run(&options.notes_dir, price, json)?;
+        }
+        Commands::Validate { fix_paths, compile } => {
+            validate::run(&options, fix_paths, compile)?;
+        }
+        Commands::DedupeLibrary { remove } => {
+            library::dedupe(&options.notes_dir, !remove)?;
         }
         Commands::CollectPdf {
             source,
@@ -68,33 +652,560 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
-async fn process_single_paper(url: &str) -> Result<()> {
+/// Download, extract and summarize one paper, returning how long each stage took.
+async fn process_single_paper(url: &str, options: &RunOptions) -> Result<StageTimings> {
     println!("Processing single paper: {url}");
 
     let arxiv_url = ArxivUrl::parse(url)?;
-    let paper_id = arxiv_url.paper_id().to_string();
+    process_paper(&arxiv_url, options).await
+}
+
+/// Process one paper unless it already has a note. Duplicate URLs in a batch wait
+/// for each other, so the second one sees the first one's note instead of reading a
+/// half-written archive.
+async fn process_paper(arxiv_url: &ArxivUrl, options: &RunOptions) -> Result<StageTimings> {
+    let paper_id = arxiv_url.paper_id();
+    let _paper = options.limits.paper(paper_id).await;
+    let version = paper_version(options, paper_id).await;
+
+    if should_skip(options, paper_id) {
+        if !should_update(options, paper_id, version) {
+            println!("[Exist]: generated note existed, skip.");
+            options.events.emit(Event::PaperDone {
+                paper_id,
+                skipped: true,
+            });
+            return Ok(StageTimings::default());
+        }
+        println!(
+            "[{paper_id}] arXiv has v{}, newer than the existing note; regenerating",
+            version.unwrap_or_default()
+        );
+    }
+
+    let result = process_arxiv_paper(arxiv_url, options, version).await;
+    match &result {
+        Ok(_) => options.events.emit(Event::PaperDone {
+            paper_id,
+            skipped: false,
+        }),
+        Err(e) => options.events.emit(Event::PaperFailed {
+            paper_id,
+            error: format!("{e:#}"),
+        }),
+    }
+    result
+}
+
+/// The version a note would be generated from: the one in the ID, or with
+/// `--resolve-version` the latest one arXiv lists.
+async fn paper_version(options: &RunOptions, paper_id: &str) -> Option<u32> {
+    if !options.resolve_version {
+        return arxiv::version_of(paper_id);
+    }
+    let resolved = options
+        .resolved_versions
+        .lock()
+        .unwrap()
+        .get(paper_id)
+        .copied();
+    if let Some(version) = resolved {
+        return version;
+    }
+    let version = resolve_version(paper_id).await;
+    options
+        .resolved_versions
+        .lock()
+        .unwrap()
+        .insert(paper_id.to_string(), version);
+    version
+}
+
+/// Whether `process_paper` would skip the paper: it has a note that the overwrite
+/// policy keeps and `--update` has no newer version for.
+async fn is_done(options: &RunOptions, paper_id: &str) -> bool {
+    should_skip(options, paper_id)
+        && !should_update(options, paper_id, paper_version(options, paper_id).await)
+}
+
+/// The version in the ID, or else the latest one the arXiv API lists.
+async fn resolve_version(paper_id: &str) -> Option<u32> {
+    if let Some(version) = arxiv::version_of(paper_id) {
+        return Some(version);
+    }
+    match arxiv::latest_version(paper_id).await {
+        Ok(version) => version,
+        Err(e) => {
+            eprintln!("Warning: could not look up the latest version of {paper_id}: {e}");
+            None
+        }
+    }
+}
+
+/// The arXiv version recorded in the metadata of the paper's existing notes.
+fn recorded_version(options: &RunOptions, paper_id: &str) -> Option<u32> {
+    let entries = std::fs::read_dir(options.notes_dir.join(paper_id)).ok()?;
+    entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .filter_map(|path| {
+            let sidecar: serde_json::Value =
+                serde_json::from_str(&std::fs::read_to_string(path).ok()?).ok()?;
+            u32::try_from(sidecar["metadata"]["arxiv_version"].as_u64()?).ok()
+        })
+        .max()
+}
+
+/// With `--update`, whether arXiv's `latest` version is newer than the one the
+/// existing note was generated from. Notes from before versions were recorded are
+/// taken to be of the version in the ID, or of v1 if it names none.
+fn should_update(options: &RunOptions, paper_id: &str, latest: Option<u32>) -> bool {
+    let existing = || {
+        recorded_version(options, paper_id)
+            .or(arxiv::version_of(paper_id))
+            .unwrap_or(1)
+    };
+    options.update && latest.is_some_and(|latest| latest > existing())
+}
+
+async fn process_arxiv_paper(
+    arxiv_url: &ArxivUrl,
+    options: &RunOptions,
+    arxiv_version: Option<u32>,
+) -> Result<StageTimings> {
+    let mut timings = StageTimings::default();
+    let processed_content =
+        download_and_process(arxiv_url, options, arxiv_version, &mut timings).await?;
+
+    let timings = generate_from_processed(&processed_content, options, timings).await?;
+    println!(
+        "[{}] Time per stage: {}",
+        arxiv_url.paper_id(),
+        timings.summary()
+    );
+    Ok(timings)
+}
+
+/// Everything before the LLM: download, extract and process the source.
+async fn download_and_process(
+    arxiv_url: &ArxivUrl,
+    options: &RunOptions,
+    arxiv_version: Option<u32>,
+    timings: &mut StageTimings,
+) -> Result<ProcessedContent> {
+    let downloader = PaperDownloader::new(options);
+    let processor = PaperProcessor::new(options);
+
+    let from_html = if options.source == config::PaperSource::Html {
+        download_html(&downloader, arxiv_url, options, arxiv_version, timings).await?
+    } else {
+        None
+    };
+    let mut processed_content = match from_html {
+        Some(processed_content) => processed_content,
+        None => download_source(&downloader, &processor, arxiv_url, options, timings).await?,
+    };
+
+    if processed_content.arxiv_version.is_none() {
+        processed_content.arxiv_version = arxiv_version;
+    }
+    if needs_api_abstract(&processed_content.abstract_text, options.min_abstract_chars) {
+        fill_abstract_from_api(&mut processed_content, options.min_abstract_chars).await;
+    }
+    Ok(processed_content)
+}
+
+/// With `--source html`, the paper parsed from its HTML rendering, or `None` when
+/// there is none and the LaTeX source is to be used instead.
+async fn download_html(
+    downloader: &PaperDownloader,
+    arxiv_url: &ArxivUrl,
+    options: &RunOptions,
+    arxiv_version: Option<u32>,
+    timings: &mut StageTimings,
+) -> Result<Option<ProcessedContent>> {
+    let paper_id = arxiv_url.paper_id();
+    let url = arxiv_url.html_url(arxiv_version);
+    let page = {
+        let _slot = options.limits.download().await;
+        options.events.emit(Event::DownloadStart {
+            paper_id,
+            url: &url,
+        });
+        let start = Instant::now();
+        let page = match downloader.download_html(paper_id, &url).await {
+            Ok(page) => page,
+            Err(e) if e.is::<arxiv::Withdrawn>() => return Err(e),
+            Err(e) => {
+                eprintln!("Warning: could not download the HTML of {paper_id}: {e:#}");
+                None
+            }
+        };
+        timings.download_secs = timing::secs_since(start);
+        options.events.emit(Event::DownloadDone {
+            paper_id,
+            bytes: page.as_ref().map_or(0, |page| page.len() as u64),
+            secs: timings.download_secs,
+        });
+        page
+    };
+
+    let start = Instant::now();
+    let processed_content = page
+        .and_then(|page| arxiv_html::parse(paper_id, &page))
+        .filter(|processed_content| !processed_content.sections.is_empty());
+    timings.process_secs = timing::secs_since(start);
+    if processed_content.is_none() {
+        println!("[{paper_id}] No HTML rendering on arXiv; using the LaTeX source");
+    }
+    Ok(processed_content)
+}
+
+/// Download the source archive, extract it and parse the LaTeX.
+async fn download_source(
+    downloader: &PaperDownloader,
+    processor: &PaperProcessor,
+    arxiv_url: &ArxivUrl,
+    options: &RunOptions,
+    timings: &mut StageTimings,
+) -> Result<ProcessedContent> {
+    let paper_id = arxiv_url.paper_id();
+    let paper_data = {
+        let _slot = options.limits.download().await;
+        options.events.emit(Event::DownloadStart {
+            paper_id,
+            url: &arxiv_url.src_url,
+        });
+        let start = Instant::now();
+        let paper_data = downloader.download(arxiv_url).await?;
+        timings.download_secs = timing::secs_since(start);
+        options.events.emit(Event::DownloadDone {
+            paper_id,
+            bytes: std::fs::metadata(&paper_data.archive_path).map_or(0, |m| m.len()),
+            secs: timings.download_secs,
+        });
+        paper_data
+    };
+    processor.process(paper_data, timings).await
+}
+
+/// Estimate the prompt for a paper the way `single` would build it.
+async fn count_tokens(arxiv_url: &ArxivUrl, options: &RunOptions) -> Result<PromptEstimate> {
+    let paper_id = arxiv_url.paper_id();
+    let mut timings = StageTimings::default();
+    let processed_content = download_and_process(
+        arxiv_url,
+        options,
+        arxiv::version_of(paper_id),
+        &mut timings,
+    )
+    .await?;
+    let note_generator = NoteGenerator::new(options)?;
+    note_generator.report_omissions(&processed_content);
+    Ok(note_generator.estimate_prompt(&processed_content))
+}
 
-    // Check if tex file already exists
-    let tex_path = Path::new("tex").join(&paper_id);
-    if tex_path.exists() {
+fn print_estimate(estimate: &PromptEstimate, prompt_detail: bool) {
+    println!(
+        "Estimated prompt size: ~{} tokens (4 characters per token)",
+        estimate.total()
+    );
+    println!("  {:<20}{:>8}", "system prompt", estimate.system_prompt);
+    for (name, tokens) in &estimate.parts {
+        if *tokens == 0 {
+            continue;
+        }
+        println!("  {name:<20}{tokens:>8}");
+        if prompt_detail && *name == "sections" {
+            for (title, tokens) in &estimate.sections {
+                println!("    - {title}: {tokens}");
+            }
+        }
+    }
+}
+
+/// Regenerate a note from the `processed.json` left by an earlier run.
+async fn reprocess_paper(paper: &str, options: &RunOptions) -> Result<()> {
+    let paper_id = ArxivUrl::parse(paper)?.paper_id().to_string();
+    println!("Reprocessing paper: {paper_id}");
+
+    let path = processor::processed_path(options, &paper_id);
+    if !path.exists() {
+        anyhow::bail!(
+            "No extracted content for {paper_id} at {}; run `single` first",
+            path.display()
+        );
+    }
+    let processed_content = ProcessedContent::load(&path)?;
+
+    generate_from_processed(&processed_content, options, StageTimings::default()).await?;
+    Ok(())
+}
+
+/// Generate a note from sources already on disk: the same extraction and processing
+/// as a download, minus arXiv.
+async fn process_local(path: &Path, options: &RunOptions) -> Result<StageTimings> {
+    let paper_id = local_paper_id(path)?;
+    let _paper = options.limits.paper(&paper_id).await;
+    println!("Processing local source {} as {paper_id}", path.display());
+
+    if should_skip(options, &paper_id) {
         println!("[Exist]: generated note existed, skip.");
-        return Ok(());
+        return Ok(StageTimings::default());
     }
 
-    let downloader = PaperDownloader::new();
-    let processor = PaperProcessor::new();
+    let output_dir = options.work_dir.join(&paper_id);
+    std::fs::create_dir_all(&output_dir)?;
+    // Work on a copy so cleaning up after processing never deletes the user's file
+    let archive_path = if path.is_dir() {
+        path.to_path_buf()
+    } else {
+        let name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or_default();
+        let extension = if name.to_lowercase().ends_with(".zip") {
+            "zip"
+        } else {
+            "tar.gz"
+        };
+        let copy = output_dir.join(format!("{paper_id}.{extension}"));
+        std::fs::copy(path, &copy).with_context(|| format!("Failed to copy {}", path.display()))?;
+        copy
+    };
+
+    let processor = PaperProcessor::new(options);
+    let mut timings = StageTimings::default();
+    let paper_data = downloader::PaperData::new(paper_id.clone(), archive_path, output_dir);
+    let processed_content = processor.process(paper_data, &mut timings).await?;
+
+    let timings = generate_from_processed(&processed_content, options, timings).await?;
+    println!("[{paper_id}] Time per stage: {}", timings.summary());
+    Ok(timings)
+}
 
-    let paper_data = downloader.download(&arxiv_url).await?;
-    let processed_content = processor.process(paper_data).await?;
+/// The paper ID for a local source: its file name without the archive extension.
+fn local_paper_id(path: &Path) -> Result<String> {
+    if !path.exists() {
+        anyhow::bail!("{} does not exist", path.display());
+    }
+    let name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .with_context(|| format!("Cannot name a paper after {}", path.display()))?;
+    let paper_id = if path.is_dir() {
+        name
+    } else {
+        let lower = name.to_lowercase();
+        let Some(suffix) = [".tar.gz", ".tgz", ".gz", ".zip"]
+            .iter()
+            .find(|suffix| lower.ends_with(*suffix))
+        else {
+            anyhow::bail!(
+                "Unsupported source {}; expected a .tar.gz, .tgz, .gz or .zip archive or a directory",
+                path.display()
+            );
+        };
+        &name[..name.len() - suffix.len()]
+    };
+    if paper_id.is_empty() {
+        anyhow::bail!("Cannot name a paper after {}", path.display());
+    }
+    Ok(paper_id.to_string())
+}
 
-    let note_generator = NoteGenerator::new()?;
-    let generated_note = note_generator.generate_note(&processed_content).await?;
+/// Whether an extracted abstract is missing, or so short it is probably a fragment.
+fn needs_api_abstract(abstract_text: &str, min_chars: usize) -> bool {
+    let abstract_text = abstract_text.trim();
+    abstract_text.is_empty() || abstract_text.chars().count() < min_chars
+}
+
+/// Use the abstract listed on arXiv when none, or only a fragment, could be
+/// extracted from the source.
+async fn fill_abstract_from_api(processed_content: &mut ProcessedContent, min_chars: usize) {
+    let paper_id = processed_content.paper_id.clone();
+    match arxiv::fetch_entry(&paper_id).await {
+        Ok(entry) => {
+            let summary = entry.map(|entry| entry.summary);
+            if !use_api_abstract(processed_content, summary, min_chars) {
+                eprintln!("Warning: arXiv API has no abstract for {paper_id}");
+            }
+        }
+        Err(e) => eprintln!("Warning: failed to fetch the abstract for {paper_id}: {e}"),
+    }
+}
+
+/// Replace a missing or too-short abstract with `api_summary`. Returns whether the
+/// API had an abstract to offer.
+fn use_api_abstract(
+    processed_content: &mut ProcessedContent,
+    api_summary: Option<String>,
+    min_chars: usize,
+) -> bool {
+    let Some(summary) = api_summary.filter(|s| !s.trim().is_empty()) else {
+        return false;
+    };
+    let paper_id = &processed_content.paper_id;
+    if processed_content.abstract_text.trim().is_empty() {
+        println!("[{paper_id}] No abstract in the source; using the one from the arXiv API");
+    } else {
+        println!(
+            "[{paper_id}] Extracted abstract is under {min_chars} characters (--min-abstract-chars); \
+             using the one from the arXiv API"
+        );
+    }
+    processed_content.abstract_text = summary;
+    processed_content.abstract_source = processor::AbstractSource::ArxivApi;
+    true
+}
+
+/// Words of each section kept by `--on-large summarize`
+const SUMMARY_SECTION_WORDS: usize = 200;
+
+/// How the paper exceeds `--max-sections` or `--max-words`, if it does.
+fn large_paper_reason(
+    processed_content: &ProcessedContent,
+    options: &RunOptions,
+) -> Option<String> {
+    let sections = processed_content.sections.len();
+    if options.max_sections.is_some_and(|max| sections > max) {
+        return Some(format!(
+            "paper has {sections} sections, over --max-sections {}",
+            options.max_sections.unwrap_or_default()
+        ));
+    }
+    let words: usize = processed_content
+        .sections
+        .iter()
+        .map(|s| s.content.split_whitespace().count())
+        .sum();
+    if options.max_words.is_some_and(|max| words > max) {
+        return Some(format!(
+            "paper has {words} words, over --max-words {}",
+            options.max_words.unwrap_or_default()
+        ));
+    }
+    None
+}
+
+/// A copy of the paper with every section cut to its first `SUMMARY_SECTION_WORDS` words.
+fn condense_sections(processed_content: &ProcessedContent) -> ProcessedContent {
+    let mut condensed = processed_content.clone();
+    for section in &mut condensed.sections {
+        let words: Vec<&str> = section.content.split_whitespace().collect();
+        if words.len() > SUMMARY_SECTION_WORDS {
+            section.content = format!("{} …", words[..SUMMARY_SECTION_WORDS].join(" "));
+        }
+    }
+    condensed
+}
+
+/// Extraction failed so badly (no abstract and hardly any sections) that a note
+/// generated from it would be garbage.
+fn is_poor_extraction(processed_content: &ProcessedContent, min_sections: usize) -> bool {
+    processed_content.abstract_text.trim().is_empty()
+        && processed_content.sections.len() < min_sections
+}
+
+/// Keep the extracted content, then generate the note from it. If the LLM step
+/// fails the extraction work isn't lost: `reprocess` can pick it up.
+async fn generate_from_processed(
+    processed_content: &ProcessedContent,
+    options: &RunOptions,
+    timings: StageTimings,
+) -> Result<StageTimings> {
+    let paper_id = &processed_content.paper_id;
+    processed_content.save(&processor::processed_path(options, paper_id))?;
+
+    if !options.allow_poor_extraction && is_poor_extraction(processed_content, options.min_sections)
+    {
+        eprintln!(
+            "Warning: [{paper_id}] extraction found no abstract and only {} section(s); skipping \
+             note generation (use --allow-poor-extraction to generate anyway)",
+            processed_content.sections.len()
+        );
+        return Ok(timings);
+    }
+
+    let condensed;
+    let processed_content = match large_paper_reason(processed_content, options) {
+        None => processed_content,
+        Some(reason) => {
+            match options.on_large {
+                OnLarge::Skip => {
+                    eprintln!("Warning: [{paper_id}] {reason}; skipping note generation (--on-large skip)");
+                    return Ok(timings);
+                }
+                OnLarge::Summarize => {
+                    println!(
+                        "[{paper_id}] {reason}; sending the first {SUMMARY_SECTION_WORDS} words of each section"
+                    );
+                    condensed = condense_sections(processed_content);
+                    &condensed
+                }
+                OnLarge::Proceed => {
+                    println!("[{paper_id}] {reason}; generating from the full text anyway");
+                    processed_content
+                }
+            }
+        }
+    };
+
+    generate_and_save_note(processed_content, options, timings)
+        .await
+        .inspect_err(|_| {
+            eprintln!(
+                "[{paper_id}] extraction succeeded, note generation failed — rerun with `reprocess {paper_id}`"
+            );
+        })
+}
+
+async fn generate_and_save_note(
+    processed_content: &ProcessedContent,
+    options: &RunOptions,
+    mut timings: StageTimings,
+) -> Result<StageTimings> {
+    let note_generator = NoteGenerator::new(options)?;
+    let compare_generator = match &options.compare_model {
+        Some(model) => {
+            let mut compare_options = options.clone();
+            compare_options.llm.model = model.clone();
+            // Its own dump, instead of racing the main note's for the same file
+            compare_options.dump_prompt = options
+                .dump_prompt
+                .as_deref()
+                .map(|path| note_generator::compared_note_path(path, model));
+            Some(NoteGenerator::new(&compare_options)?)
+        }
+        None => None,
+    };
+    // Both notes are built from the same content, so report what it leaves out once
+    note_generator.report_omissions(processed_content);
+    let (generated, compared) = tokio::join!(
+        generate_in_llm_slot(&note_generator, processed_content, options),
+        async {
+            match &compare_generator {
+                Some(generator) => {
+                    Some(generate_in_llm_slot(generator, processed_content, options).await)
+                }
+                None => None,
+            }
+        }
+    );
+    let (mut generated_note, llm_secs) = generated?;
+    timings.llm_secs = llm_secs;
+    generated_note.metadata.timings = Some(timings);
 
     // Save the generated note
-    let output_dir = Path::new("tex").join(&paper_id);
-    std::fs::create_dir_all(&output_dir)?;
-    let output_filename = format!("{}.tex", processed_content.paper_id);
-    let output_path = output_dir.join(output_filename);
+    let output_path = note_path(options, processed_content);
+    let output_path = match options.overwrite_policy {
+        OverwritePolicy::Version => next_version_path(&output_path),
+        OverwritePolicy::Skip | OverwritePolicy::Overwrite => output_path,
+    };
+    if let Some(output_dir) = output_path.parent() {
+        std::fs::create_dir_all(output_dir)?;
+    }
     note_generator
         .save_note(&generated_note, &output_path)
         .await?;
@@ -102,29 +1213,373 @@ async fn process_single_paper(url: &str) -> Result<()> {
     println!("Successfully processed paper: {}", processed_content.title);
     println!("Generated note saved to: {}", output_path.display());
 
-    Ok(())
+    if let Some(compared) = compared {
+        let model = options.compare_model.as_deref().unwrap_or_default();
+        match compared {
+            Ok((mut compared_note, secs)) => {
+                compared_note.metadata.timings = Some(StageTimings {
+                    llm_secs: secs,
+                    ..timings
+                });
+                compared_note.metadata.compare_of =
+                    Some(generated_note.metadata.model_used.clone());
+                let compared_path = note_generator::compared_note_path(&output_path, model);
+                note_generator
+                    .save_note(&compared_note, &compared_path)
+                    .await?;
+                println!("Comparison note saved to: {}", compared_path.display());
+                print_comparison(&[&generated_note, &compared_note]);
+            }
+            Err(e) => {
+                eprintln!("Warning: generating the --compare note with {model} failed: {e:#}")
+            }
+        }
+    }
+
+    if let Some(combined) = &options.append_to {
+        append_note(&generated_note, options.format, combined)?;
+        println!("Appended note to: {}", combined.display());
+    }
+
+    if options.overleaf_bundle {
+        if options.format == OutputFormat::Latex {
+            let bundled = bundle::write_bundle(&output_path)?;
+            println!("Overleaf bundle written to: {}", bundled.display());
+        } else {
+            eprintln!("Warning: --overleaf-bundle only applies to LaTeX notes; skipping");
+        }
+    }
+
+    if let Some(command) = &options.on_complete {
+        hook::run_on_complete(
+            command,
+            &processed_content.paper_id,
+            &processed_content.title,
+            &output_path,
+        )
+        .await;
+    }
+
+    Ok(timings)
+}
+
+/// Generate a note once an LLM slot is free, returning it with the seconds the call
+/// took.
+async fn generate_in_llm_slot(
+    note_generator: &NoteGenerator,
+    processed_content: &ProcessedContent,
+    options: &RunOptions,
+) -> Result<(GeneratedNote, f64)> {
+    let _slot = options.limits.llm().await;
+    let start = Instant::now();
+    let note = note_generator.generate_note(processed_content).await?;
+    let secs = timing::secs_since(start);
+    options.events.emit(Event::LlmDone {
+        paper_id: &processed_content.paper_id,
+        model: &note.metadata.model_used,
+        prompt_tokens: note.metadata.prompt_tokens,
+        completion_tokens: note.metadata.completion_tokens,
+        total_tokens: note.metadata.total_tokens,
+        secs,
+    });
+    Ok((note, secs))
+}
+
+/// The token use and list-price cost of the same paper's note from each model.
+fn print_comparison(notes: &[&GeneratedNote]) {
+    println!("Model comparison:");
+    for note in notes {
+        let metadata = &note.metadata;
+        let (Some(prompt), Some(completion)) = (metadata.prompt_tokens, metadata.completion_tokens)
+        else {
+            println!("  {:<24}usage not reported", metadata.model_used);
+            continue;
+        };
+        let cost = stats::price_for(&metadata.model_used, None)
+            .map_or("unknown price".to_string(), |price| {
+                format!("${:.4}", price.cost(prompt, completion))
+            });
+        println!(
+            "  {:<24}{prompt:>8} prompt + {completion:>6} completion tokens  {cost}",
+            metadata.model_used
+        );
+    }
+}
+
+/// Whether the paper already has a note and the overwrite policy says to leave it.
+fn should_skip(options: &RunOptions, paper_id: &str) -> bool {
+    options.overwrite_policy == OverwritePolicy::Skip && note_exists(options, paper_id)
+}
+
+/// `path` if it is free, otherwise the first free `<stem>.vN.<ext>` from v2 on.
+fn next_version_path(path: &Path) -> PathBuf {
+    if !path.exists() {
+        return path.to_path_buf();
+    }
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path.extension().unwrap_or_default().to_string_lossy();
+    (2..)
+        .map(|n| path.with_file_name(format!("{stem}.v{n}.{extension}")))
+        .find(|candidate| !candidate.exists())
+        .expect("some version number is free")
 }
 
-async fn process_batch_papers(file_path: &str) -> Result<()> {
+/// Whether a note for this paper has already been generated. Only a non-empty note
+/// file counts, so a directory left behind by a crashed run doesn't block a retry.
+/// The file name can depend on the title, which isn't known before extraction, so
+/// any note file in the paper's directory counts, unless its sidecar marks it as a
+/// `--compare` note. Notes from before sidecars were written count too.
+fn note_exists(options: &RunOptions, paper_id: &str) -> bool {
+    let Ok(entries) = std::fs::read_dir(options.notes_dir.join(paper_id)) else {
+        return false;
+    };
+    let extension = options.format.extension();
+    entries.flatten().any(|entry| {
+        let path = entry.path();
+        path.extension().is_some_and(|ext| ext == extension)
+            && entry.metadata().is_ok_and(|m| m.is_file() && m.len() > 0)
+            && !is_compared_note(&path)
+    })
+}
+
+/// Whether the sidecar next to `note_path` says it was written by `--compare`.
+fn is_compared_note(note_path: &Path) -> bool {
+    std::fs::read_to_string(note_generator::sidecar_path(note_path))
+        .ok()
+        .and_then(|json| serde_json::from_str::<serde_json::Value>(&json).ok())
+        .is_some_and(|sidecar| !sidecar["metadata"]["compare_of"].is_null())
+}
+
+fn note_path(options: &RunOptions, content: &ProcessedContent) -> PathBuf {
+    let stem = note_generator::note_file_stem(
+        &options.filename_pattern,
+        content,
+        chrono::Local::now().date_naive(),
+    );
+    options
+        .notes_dir
+        .join(&content.paper_id)
+        .join(format!("{stem}.{}", options.format.extension()))
+}
+
+async fn process_batch_papers(
+    file_path: &str,
+    limit: Option<usize>,
+    options: &RunOptions,
+) -> Result<()> {
     println!("Processing batch papers from: {file_path}");
 
-    let content = std::fs::read_to_string(file_path)?;
-    let urls: Vec<String> = content
+    let urls = read_url_list(Path::new(file_path))?;
+
+    let Some(limit) = limit else {
+        return process_urls(urls, options).await;
+    };
+
+    let total = urls.len();
+    let selected = select_pending(urls, limit, |url| async move {
+        match ArxivUrl::parse(&url) {
+            Ok(arxiv_url) => is_done(options, arxiv_url.paper_id()).await,
+            Err(_) => false,
+        }
+    })
+    .await;
+    let processed = selected.len();
+
+    process_urls(selected, options).await?;
+    println!("Processed {processed} of {total} URL(s) (limited)");
+
+    Ok(())
+}
+
+/// `batch` for a reading list that keeps growing: papers that already have a note
+/// are skipped as usual, and each URL is processed at most once per run.
+async fn watch_batch_papers(file_path: &str, interval: f64, options: &RunOptions) -> Result<()> {
+    let interval = std::time::Duration::try_from_secs_f64(interval)
+        .context("--interval must be a non-negative number of seconds")?;
+    // Listen from the start, so Ctrl-C during a batch also waits for it to finish
+    let (stop_tx, stop_rx) = tokio::sync::oneshot::channel();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            println!("Ctrl-C received; stopping once the current papers finish");
+            let _ = stop_tx.send(());
+        }
+    });
+
+    watch_urls(
+        Path::new(file_path),
+        interval,
+        async {
+            let _ = stop_rx.await;
+        },
+        options,
+    )
+    .await
+}
+
+/// Process the URLs in `path` and those appended to it until `stop`. `--failed-urls`
+/// lists the failures of the whole session, not only of the latest batch.
+async fn watch_urls(
+    path: &Path,
+    interval: std::time::Duration,
+    stop: impl std::future::Future<Output = ()>,
+    options: &RunOptions,
+) -> Result<()> {
+    let failed = std::sync::Mutex::new(Vec::new());
+    let failed = &failed;
+    watch::watch(path, interval, stop, move |urls| async move {
+        let batch_failed = run_batch(urls, options).await;
+        let mut failed = failed.lock().unwrap();
+        failed.extend(batch_failed);
+        write_failed_urls(options, &failed)
+    })
+    .await
+}
+
+/// Read a URL list: one URL per line, blank lines ignored.
+fn read_url_list(path: &Path) -> Result<Vec<String>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read URL list {}", path.display()))?;
+    Ok(content
         .lines()
         .filter(|line| !line.trim().is_empty())
         .map(|s| s.to_string())
-        .collect();
+        .collect())
+}
+
+/// Write the URLs that failed in a batch, in the format `batch` reads back.
+fn write_url_list(path: &Path, urls: &[String]) -> Result<()> {
+    let mut content = urls.join("\n");
+    if !content.is_empty() {
+        content.push('\n');
+    }
+    std::fs::write(path, content)
+        .with_context(|| format!("Failed to write URL list {}", path.display()))
+}
 
+/// The first `limit` URLs that are not already done, in file order.
+async fn select_pending<F>(
+    urls: Vec<String>,
+    limit: usize,
+    is_done: impl Fn(String) -> F,
+) -> Vec<String>
+where
+    F: std::future::Future<Output = bool>,
+{
+    let mut selected = Vec::new();
+    for url in urls {
+        if selected.len() == limit {
+            break;
+        }
+        if !is_done(url.clone()).await {
+            selected.push(url);
+        }
+    }
+    selected
+}
+
+async fn process_search_papers(
+    query: &str,
+    max_results: usize,
+    since: Option<NaiveDate>,
+    until: Option<NaiveDate>,
+    options: &RunOptions,
+) -> Result<()> {
+    println!("Searching arXiv for: {query}");
+
+    let entries = arxiv::search(query, max_results).await?;
+    let (entries, filtered) = arxiv::filter_by_date(entries, since, until);
+    if filtered > 0 {
+        println!("Filtered out {filtered} paper(s) outside the requested date range");
+    }
+    println!("Found {} paper(s) to process", entries.len());
+    for entry in &entries {
+        println!(
+            "  {} ({}): {}",
+            entry.paper_id, entry.published, entry.title
+        );
+    }
+
+    let urls = entries.iter().map(|e| e.abs_url()).collect();
+    process_urls(urls, options).await
+}
+
+async fn process_urls(urls: Vec<String>, options: &RunOptions) -> Result<()> {
+    let failed = run_batch(urls, options).await;
+    write_failed_urls(options, &failed)
+}
+
+/// Process every URL at once and report how the batch went. Returns the URLs that
+/// failed and are worth retrying.
+async fn run_batch(urls: Vec<String>, options: &RunOptions) -> Vec<String> {
+    let total = urls.len();
+    println!(
+        "Processing {total} paper(s): up to {} download(s) and {} LLM request(s) at once",
+        options.limits.download_slots, options.limits.llm_slots
+    );
+
+    // Every paper starts right away; the download and LLM stages each wait on their
+    // own slots in `options.limits`
     let mut tasks = vec![];
     for url in urls {
-        tasks.push(tokio::spawn(async move {
-            if let Err(e) = process_single_paper(&url).await {
+        let options = options.clone();
+        let task_url = url.clone();
+        let task = tokio::spawn(async move { process_single_paper(&task_url, &options).await });
+        tasks.push((url, task));
+    }
+
+    let mut failed = Vec::new();
+    let mut withdrawn = 0;
+    let mut total_timings = StageTimings::default();
+    for (url, task) in tasks {
+        match task.await {
+            Ok(Ok(timings)) => total_timings.add(&timings),
+            // Retrying can't help, so these stay out of --failed-urls
+            Ok(Err(e)) if e.downcast_ref::<arxiv::Withdrawn>().is_some() => {
+                println!("[Withdrawn] {url}: {e}");
+                withdrawn += 1;
+            }
+            Ok(Err(e)) => {
                 eprintln!("Error processing {url}: {e}");
+                failed.push(url);
+            }
+            Err(e) => {
+                eprintln!("Error processing {url}: task panicked: {e}");
+                failed.push(url);
             }
-        }));
+        }
+    }
+
+    println!(
+        "Batch finished: {} succeeded, {} failed, {withdrawn} withdrawn",
+        total - failed.len() - withdrawn,
+        failed.len()
+    );
+    if options.limits.retries_exhausted() {
+        println!("The --max-total-retries budget ran out during the batch; later failures were not retried");
     }
+    let (slowest, secs) = total_timings.slowest();
+    if secs > 0.0 {
+        println!(
+            "Time per stage across the batch: {}; slowest stage: {slowest} ({secs:.2}s)",
+            total_timings.summary()
+        );
+    }
+    failed
+}
 
-    join_all(tasks).await;
+/// Write `failed` to `--failed-urls`, if set.
+fn write_failed_urls(options: &RunOptions, failed: &[String]) -> Result<()> {
+    if let Some(path) = &options.failed_urls {
+        write_url_list(path, failed)?;
+        if !failed.is_empty() {
+            println!(
+                "Wrote {} failed URL(s) to {}; retry them with `batch {}`",
+                failed.len(),
+                path.display(),
+                path.display()
+            );
+        }
+    }
 
     Ok(())
 }
@@ -184,3 +1639,1179 @@ async fn collect_pdf_files(source: Option<String>, destination: Option<String>)
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::tar_gz;
+
+    /// Options pointing the LLM at `server` and all output into `dir`.
+    fn test_options(dir: &Path, server: &mockito::Server) -> RunOptions {
+        let prompt_file = dir.join("prompts.txt");
+        std::fs::write(&prompt_file, "summarize").unwrap();
+        let mut options = RunOptions::from_settings(Settings {
+            base_url: Some(format!("{}/v1", server.url())),
+            api_key: Some("sk-test".to_string()),
+            prompt_file: Some(prompt_file),
+            output_dir: Some(dir.join("tex")),
+            ..Default::default()
+        })
+        .unwrap();
+        options.work_dir = dir.join("output");
+        // Short test abstracts would otherwise be looked up on arXiv
+        options.min_abstract_chars = 0;
+        options
+    }
+
+    /// A chat completions mock that answers with `content`, to be created by the caller.
+    fn mock_chat(server: &mut mockito::Server, content: &str) -> mockito::Mock {
+        let body = serde_json::json!({
+            "choices": [{"message": {"role": "assistant", "content": content}}],
+        });
+        server
+            .mock("POST", "/v1/chat/completions")
+            .with_body(body.to_string())
+    }
+
+    fn sample_processed(abstract_text: &str) -> ProcessedContent {
+        ProcessedContent {
+            paper_id: "2401.08027".to_string(),
+            title: "A Paper".to_string(),
+            authors: vec!["Ada".to_string()],
+            abstract_text: abstract_text.to_string(),
+            alternate_abstracts: vec![],
+            abstract_source: processor::AbstractSource::Latex,
+            sections: vec![],
+            keywords: vec![],
+            code_links: vec![],
+            figure_references: vec![],
+            figures: vec![],
+            contributions: vec![],
+            equations: vec![],
+            statements: vec![],
+            algorithms: vec![],
+            arxiv_version: None,
+            references: Vec::new(),
+            full_text: String::new(),
+            image_files: vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_large_papers_are_skipped_or_condensed() {
+        let mut server = mockito::Server::new_async().await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut options = test_options(dir.path(), &server);
+        options.max_words = Some(100);
+        let mut processed = sample_processed("We study things.");
+        processed.sections.push(processor::Section {
+            title: "Survey".to_string(),
+            content: (0..300)
+                .map(|i| format!("word{i}"))
+                .collect::<Vec<_>>()
+                .join(" "),
+            level: 1,
+            appendix: false,
+        });
+        let note = options.notes_dir.join("2401.08027/2401.08027.tex");
+
+        let skipped = server
+            .mock("POST", "/v1/chat/completions")
+            .expect(0)
+            .create_async()
+            .await;
+        generate_from_processed(&processed, &options, StageTimings::default())
+            .await
+            .unwrap();
+        skipped.assert_async().await;
+        assert!(!note.exists());
+
+        let condensed = mock_chat(&mut server, "\\section{A}")
+            .match_body(mockito::Matcher::AllOf(vec![
+                mockito::Matcher::Regex("word199 …".to_string()),
+                mockito::Matcher::Regex("word0 ".to_string()),
+            ]))
+            .create_async()
+            .await;
+        options.on_large = OnLarge::Summarize;
+        generate_from_processed(&processed, &options, StageTimings::default())
+            .await
+            .unwrap();
+        condensed.assert_async().await;
+        assert!(note.exists());
+        assert!(!condense_sections(&processed).sections[0]
+            .content
+            .contains("word200"));
+
+        options.max_words = None;
+        options.max_sections = Some(0);
+        assert_eq!(
+            large_paper_reason(&processed, &options).as_deref(),
+            Some("paper has 1 sections, over --max-sections 0")
+        );
+        options.max_sections = None;
+        assert_eq!(large_paper_reason(&processed, &options), None);
+    }
+
+    #[tokio::test]
+    async fn test_abstract_summary_source_omits_section_bodies() {
+        let mut server = mockito::Server::new_async().await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut options = test_options(dir.path(), &server);
+        options.overwrite_policy = OverwritePolicy::Overwrite;
+        options.summary_source = config::SummarySource::Abstract;
+        let mut processed = sample_processed("We study sparse attention for long documents.");
+        processed.sections.push(processor::Section {
+            title: "Method".to_string(),
+            content: "The method body describes the kernel in detail.".to_string(),
+            level: 1,
+            appendix: false,
+        });
+
+        let abstract_only = mock_chat(&mut server, "\\section{A}")
+            .match_request(|request| {
+                let body = request.utf8_lossy_body().unwrap();
+                body.contains("sparse attention") && !body.contains("kernel in detail")
+            })
+            .create_async()
+            .await;
+        generate_from_processed(&processed, &options, StageTimings::default())
+            .await
+            .unwrap();
+        abstract_only.assert_async().await;
+
+        // A six-word abstract is too thin for auto, which falls back to the full paper
+        options.summary_source = config::SummarySource::Auto;
+        let full = mock_chat(&mut server, "\\section{A}")
+            .match_body(mockito::Matcher::Regex("kernel in detail".to_string()))
+            .create_async()
+            .await;
+        generate_from_processed(&processed, &options, StageTimings::default())
+            .await
+            .unwrap();
+        full.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_abstract_conclusion_focus_sends_only_those() {
+        let mut server = mockito::Server::new_async().await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut options = test_options(dir.path(), &server);
+        options.focus = config::Focus::AbstractConclusion;
+        let mut processed = sample_processed("We study sparse attention for long documents.");
+        for (title, content) in [
+            ("Method", "The method body describes the kernel in detail."),
+            (
+                "6 Conclusions and Future Work",
+                "Sparse kernels scale well.",
+            ),
+        ] {
+            processed.sections.push(processor::Section {
+                title: title.to_string(),
+                content: content.to_string(),
+                level: 1,
+                appendix: false,
+            });
+        }
+        processed.equations.push(processor::Equation {
+            content: "E = mc^2".to_string(),
+            display: true,
+            label: None,
+            referenced: false,
+            section: Some("Method".to_string()),
+        });
+
+        let focused = mock_chat(&mut server, "\\section{A}")
+            .match_request(|request| {
+                let body = request.utf8_lossy_body().unwrap();
+                body.contains("sparse attention")
+                    && body.contains("Sparse kernels scale well.")
+                    && !body.contains("kernel in detail")
+                    && !body.contains("mc^2")
+            })
+            .create_async()
+            .await;
+        generate_from_processed(&processed, &options, StageTimings::default())
+            .await
+            .unwrap();
+        focused.assert_async().await;
+
+        // Without a conclusion there is no empty section heading either
+        processed.sections.pop();
+        let estimate = NoteGenerator::new(&options)
+            .unwrap()
+            .estimate_prompt(&processed);
+        assert!(estimate.parts.contains(&("sections", 0)));
+    }
+
+    #[tokio::test]
+    async fn test_truncated_structured_note_is_continued() {
+        let mut server = mockito::Server::new_async().await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut options = test_options(dir.path(), &server);
+        options.structured = true;
+        let processed = sample_processed("We study things.");
+
+        let reply = |content: &str, finish_reason: &str| {
+            serde_json::json!({
+                "choices": [{
+                    "message": {"role": "assistant", "content": content},
+                    "finish_reason": finish_reason,
+                }]
+            })
+            .to_string()
+        };
+        let partial = r#"{"summary": "Sparse attention.", "contributions": ["Fa"#;
+        let first = server
+            .mock("POST", "/v1/chat/completions")
+            .match_request(|request| !request.utf8_lossy_body().unwrap().contains("cut off"))
+            .with_body(reply(partial, "length"))
+            .create_async()
+            .await;
+        let continued = server
+            .mock("POST", "/v1/chat/completions")
+            .match_body(mockito::Matcher::AllOf(vec![
+                mockito::Matcher::Regex("cut off".to_string()),
+                mockito::Matcher::Regex(r#""role":"assistant""#.to_string()),
+            ]))
+            .with_body(reply(
+                r#"ster kernels"], "method": "Blocks.", "results": "2x faster."}"#,
+                "stop",
+            ))
+            .create_async()
+            .await;
+
+        generate_from_processed(&processed, &options, StageTimings::default())
+            .await
+            .unwrap();
+
+        first.assert_async().await;
+        continued.assert_async().await;
+        let note =
+            std::fs::read_to_string(options.notes_dir.join("2401.08027/2401.08027.tex")).unwrap();
+        assert!(note.contains("Faster kernels"), "{note}");
+        assert!(note.contains("2x faster."), "{note}");
+    }
+
+    #[tokio::test]
+    async fn test_continuation_keeps_whitespace_at_the_cut() {
+        let mut server = mockito::Server::new_async().await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut options = test_options(dir.path(), &server);
+        options.structured = true;
+        let processed = sample_processed("We study things.");
+
+        let reply = |content: &str, finish_reason: &str| {
+            serde_json::json!({
+                "choices": [{
+                    "message": {"role": "assistant", "content": content},
+                    "finish_reason": finish_reason,
+                }]
+            })
+            .to_string()
+        };
+        // The first cut falls after a space, the second before one
+        server
+            .mock("POST", "/v1/chat/completions")
+            .match_request(|request| !request.utf8_lossy_body().unwrap().contains("cut off"))
+            .with_body(reply(r#"{"summary": "Sparse attention for the "#, "length"))
+            .create_async()
+            .await;
+        server
+            .mock("POST", "/v1/chat/completions")
+            .match_request(|request| {
+                let body = request.utf8_lossy_body().unwrap();
+                body.contains("cut off") && !body.contains("long")
+            })
+            .with_body(reply(r#"long"#, "length"))
+            .create_async()
+            .await;
+        server
+            .mock("POST", "/v1/chat/completions")
+            .match_request(|request| request.utf8_lossy_body().unwrap().contains("the long"))
+            .with_body(reply(
+                r#" documents.", "contributions": ["Kernels"], "method": "Blocks.", "results": "Faster."}"#,
+                "stop",
+            ))
+            .create_async()
+            .await;
+
+        generate_from_processed(&processed, &options, StageTimings::default())
+            .await
+            .unwrap();
+
+        let note =
+            std::fs::read_to_string(options.notes_dir.join("2401.08027/2401.08027.tex")).unwrap();
+        assert!(
+            note.contains("Sparse attention for the long documents."),
+            "{note}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_processed_content_kept_when_llm_fails() {
+        let mut server = mockito::Server::new_async().await;
+        let llm = server
+            .mock("POST", "/v1/chat/completions")
+            .with_status(500)
+            .with_body(r#"{"error":"overloaded"}"#)
+            .create_async()
+            .await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let options = test_options(dir.path(), &server);
+        let processed = sample_processed("We study things.");
+
+        let err = generate_from_processed(&processed, &options, StageTimings::default())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("API request failed"), "{err}");
+        llm.assert_async().await;
+
+        let saved =
+            ProcessedContent::load(&processor::processed_path(&options, "2401.08027")).unwrap();
+        assert_eq!(saved.title, "A Paper");
+        assert!(!options.notes_dir.join("2401.08027").exists());
+    }
+
+    #[tokio::test]
+    async fn test_stage_timings_are_recorded() {
+        let mut server = mockito::Server::new_async().await;
+        let tex = format!(
+            "\\documentclass{{article}}\n\\title{{Timed}}\n\\begin{{document}}\n\
+             \\begin{{abstract}}We time things.\\end{{abstract}}\n\\section{{Intro}}\n{}\n\\end{{document}}",
+            "Body text. ".repeat(100)
+        );
+        server
+            .mock("GET", "/src/2401.08027")
+            .with_body(tar_gz(&[("main.tex", &tex)]))
+            .create_async()
+            .await;
+        mock_chat(&mut server, "\\section{A}").create_async().await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut options = test_options(dir.path(), &server);
+        options.min_archive_size = 1;
+        let arxiv_url = ArxivUrl {
+            paper_id: "2401.08027".to_string(),
+            src_url: format!("{}/src/2401.08027", server.url()),
+        };
+
+        let timings = process_arxiv_paper(&arxiv_url, &options, Some(3))
+            .await
+            .unwrap();
+
+        assert!(timings.download_secs > 0.0, "{timings:?}");
+        assert!(timings.extract_secs > 0.0, "{timings:?}");
+        assert!(timings.process_secs > 0.0, "{timings:?}");
+        assert!(timings.llm_secs > 0.0, "{timings:?}");
+
+        let sidecar = options.notes_dir.join("2401.08027/2401.08027.json");
+        let metadata: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(sidecar).unwrap()).unwrap();
+        assert_eq!(
+            metadata["metadata"]["timings"]["llm_secs"].as_f64(),
+            Some(timings.llm_secs)
+        );
+        assert_eq!(recorded_version(&options, "2401.08027"), Some(3));
+    }
+
+    #[tokio::test]
+    async fn test_html_source_falls_back_to_latex() {
+        let mut server = mockito::Server::new_async().await;
+        let page = r#"<html><body><article class="ltx_document">
+<h1 class="ltx_title ltx_title_document">From HTML</h1>
+<section class="ltx_section"><h2 class="ltx_title ltx_title_section">Intro</h2>
+<p class="ltx_p">Rendered text.</p></section></article></body></html>"#;
+        server
+            .mock("GET", "/html/2401.00001v2")
+            .with_header("content-type", "text/html")
+            .with_body(page)
+            .create_async()
+            .await;
+        let unused_source = server
+            .mock("GET", "/src/2401.00001")
+            .expect(0)
+            .create_async()
+            .await;
+        server
+            .mock("GET", "/html/2401.00002")
+            .with_status(404)
+            .create_async()
+            .await;
+        server
+            .mock("GET", "/src/2401.00002")
+            .with_body(tar_gz(&[(
+                "main.tex",
+                "\\title{From LaTeX}\n\\begin{document}\n\\section{Intro}\nSource text.\n\\end{document}",
+            )]))
+            .create_async()
+            .await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut options = test_options(dir.path(), &server);
+        options.min_archive_size = 1;
+        options.source = config::PaperSource::Html;
+        let arxiv_url = |id: &str| ArxivUrl {
+            paper_id: id.to_string(),
+            src_url: format!("{}/src/{id}", server.url()),
+        };
+
+        let mut timings = StageTimings::default();
+        let processed =
+            download_and_process(&arxiv_url("2401.00001"), &options, Some(2), &mut timings)
+                .await
+                .unwrap();
+        assert_eq!(processed.title, "From HTML");
+        assert_eq!(processed.sections[0].content, "Rendered text.");
+        assert_eq!(processed.arxiv_version, Some(2));
+        unused_source.assert_async().await;
+
+        let processed =
+            download_and_process(&arxiv_url("2401.00002"), &options, None, &mut timings)
+                .await
+                .unwrap();
+        assert_eq!(processed.title, "From LaTeX");
+    }
+
+    #[tokio::test]
+    async fn test_count_tokens_reports_the_prompt_size() {
+        let mut server = mockito::Server::new_async().await;
+        let tex = format!(
+            "\\documentclass{{article}}\n\\title{{Counted}}\n\\begin{{document}}\n\
+             \\begin{{abstract}}We count things.\\end{{abstract}}\n\\section{{Intro}}\n{}\n\
+             \\section{{Method}}\nShort.\n\\begin{{equation}}E = mc^2\\end{{equation}}\n\\end{{document}}",
+            "Body text. ".repeat(100)
+        );
+        server
+            .mock("GET", "/src/2401.08027")
+            .with_body(tar_gz(&[("main.tex", &tex)]))
+            .create_async()
+            .await;
+        let llm = server
+            .mock("POST", "/v1/chat/completions")
+            .expect(0)
+            .create_async()
+            .await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut options = test_options(dir.path(), &server);
+        options.min_archive_size = 1;
+        let arxiv_url = ArxivUrl {
+            paper_id: "2401.08027".to_string(),
+            src_url: format!("{}/src/2401.08027", server.url()),
+        };
+
+        let estimate = count_tokens(&arxiv_url, &options).await.unwrap();
+
+        let part = |name: &str| estimate.parts.iter().find(|(n, _)| *n == name).unwrap().1;
+        assert!(part("abstract") > 0);
+        assert!(part("equations") > 0);
+        assert!(part("sections") > 250, "{estimate:?}");
+        assert_eq!(estimate.system_prompt, 3);
+        let titles: Vec<&str> = estimate.sections.iter().map(|(t, _)| t.as_str()).collect();
+        assert_eq!(titles, ["Intro", "Method"]);
+        assert!(estimate.sections[0].1 > estimate.sections[1].1);
+        assert!(estimate.total() > part("sections"));
+        llm.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_local_sources_produce_notes() {
+        let mut server = mockito::Server::new_async().await;
+        mock_chat(&mut server, "\\section{A}")
+            .expect(2)
+            .create_async()
+            .await;
+        let tex = format!(
+            "\\documentclass{{article}}\n\\title{{Local}}\n\\begin{{document}}\n\
+             \\begin{{abstract}}Offline.\\end{{abstract}}\n\\section{{Intro}}\n{}\n\\end{{document}}",
+            "Body text. ".repeat(100)
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let options = test_options(dir.path(), &server);
+        let archive = dir.path().join("my-paper.tar.gz");
+        std::fs::write(&archive, tar_gz(&[("main.tex", &tex)])).unwrap();
+        let source_dir = dir.path().join("draft");
+        std::fs::create_dir_all(&source_dir).unwrap();
+        std::fs::write(source_dir.join("main.tex"), &tex).unwrap();
+
+        process_local(&archive, &options).await.unwrap();
+        process_local(&source_dir, &options).await.unwrap();
+
+        assert!(options.notes_dir.join("my-paper/my-paper.tex").exists());
+        assert!(options.notes_dir.join("draft/draft.tex").exists());
+        // The user's sources are left as they were
+        assert!(archive.exists());
+        assert_eq!(std::fs::read_dir(&source_dir).unwrap().count(), 1);
+        assert!(local_paper_id(&dir.path().join("prompts.txt")).is_err());
+    }
+
+    #[test]
+    fn test_short_abstract_is_replaced_by_the_api_one() {
+        let api = "We introduce a sparse attention kernel that scales to million-token contexts \
+                   while matching dense attention quality on long-document benchmarks."
+            .to_string();
+        let mut processed = sample_processed("We introduce a");
+        assert!(needs_api_abstract(&processed.abstract_text, 100));
+
+        assert!(use_api_abstract(&mut processed, Some(api.clone()), 100));
+        assert_eq!(processed.abstract_text, api);
+        assert_eq!(
+            processed.abstract_source,
+            processor::AbstractSource::ArxivApi
+        );
+        assert!(!needs_api_abstract(&processed.abstract_text, 100));
+
+        // Without an API abstract the fragment is kept
+        let mut processed = sample_processed("We introduce a");
+        assert!(!use_api_abstract(
+            &mut processed,
+            Some("  ".to_string()),
+            100
+        ));
+        assert_eq!(processed.abstract_text, "We introduce a");
+        assert_eq!(processed.abstract_source, processor::AbstractSource::Latex);
+        assert!(!needs_api_abstract("We introduce a", 0));
+        assert!(needs_api_abstract(" ", 0));
+    }
+
+    #[tokio::test]
+    async fn test_tags_are_written_to_the_sidecar() {
+        let mut server = mockito::Server::new_async().await;
+        mock_chat(&mut server, "\\section{A}").create_async().await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut options = test_options(dir.path(), &server);
+        options.tags = vec!["thesis".to_string(), "reading-group".to_string()];
+
+        generate_from_processed(
+            &sample_processed("We study things."),
+            &options,
+            StageTimings::default(),
+        )
+        .await
+        .unwrap();
+
+        let sidecar = options.notes_dir.join("2401.08027/2401.08027.json");
+        let metadata: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(sidecar).unwrap()).unwrap();
+        assert_eq!(
+            metadata["metadata"]["tags"],
+            serde_json::json!(["thesis", "reading-group"])
+        );
+        assert_eq!(metadata["metadata"]["abstract_source"], "latex");
+        let notes = library::list_notes(&options.notes_dir).unwrap();
+        let filter = library::NoteFilter {
+            query: Some("Reading".to_string()),
+            ..Default::default()
+        };
+        assert!(filter.matches(&notes[0]));
+    }
+
+    #[tokio::test]
+    async fn test_compare_writes_a_note_per_model() {
+        let mut server = mockito::Server::new_async().await;
+        let mut providers = Vec::new();
+        for (model, section, usage) in [
+            (
+                "gpt-4o-mini",
+                "From A",
+                r#","usage":{"prompt_tokens":1000,"completion_tokens":200,"total_tokens":1200}"#,
+            ),
+            ("deepseek-chat", "From B", ""),
+        ] {
+            let body = format!(
+                r#"{{"choices":[{{"message":{{"role":"assistant","content":"\\section{{{section}}}"}}}}]{usage}}}"#
+            );
+            providers.push(
+                server
+                    .mock("POST", "/v1/chat/completions")
+                    .match_body(mockito::Matcher::PartialJson(
+                        serde_json::json!({ "model": model }),
+                    ))
+                    .with_body(body)
+                    .expect(1)
+                    .create_async()
+                    .await,
+            );
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let mut options = test_options(dir.path(), &server);
+        options.llm.model = "gpt-4o-mini".to_string();
+        options.compare_model = Some("deepseek-chat".to_string());
+        options.dump_prompt = Some(dir.path().join("prompts/{id}.txt"));
+
+        generate_from_processed(
+            &sample_processed("We study things."),
+            &options,
+            StageTimings::default(),
+        )
+        .await
+        .unwrap();
+
+        for provider in providers {
+            provider.assert_async().await;
+        }
+        let paper_dir = options.notes_dir.join("2401.08027");
+        let read = |name: &str| std::fs::read_to_string(paper_dir.join(name)).unwrap();
+        assert!(read("2401.08027.tex").contains("\\section{From A}"));
+        assert!(read("2401.08027.deepseek-chat.tex").contains("\\section{From B}"));
+        let sidecar: serde_json::Value = serde_json::from_str(&read("2401.08027.json")).unwrap();
+        assert_eq!(sidecar["metadata"]["total_tokens"], 1200);
+        let sidecar: serde_json::Value =
+            serde_json::from_str(&read("2401.08027.deepseek-chat.json")).unwrap();
+        assert_eq!(sidecar["metadata"]["model_used"], "deepseek-chat");
+        assert_eq!(sidecar["metadata"]["compare_of"], "gpt-4o-mini");
+        assert!(sidecar["metadata"]["total_tokens"].is_null());
+
+        // The comparison is not a second note on the paper
+        let notes = library::list_notes(&options.notes_dir).unwrap();
+        assert_eq!(notes.len(), 1);
+        assert_eq!(notes[0].model, "gpt-4o-mini");
+
+        // Each model's prompt is dumped to its own file
+        for dump in ["2401.08027.txt", "2401.08027.deepseek-chat.txt"] {
+            assert!(dir.path().join("prompts").join(dump).exists(), "{dump}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_response_without_usage_records_no_tokens() {
+        let mut server = mockito::Server::new_async().await;
+        mock_chat(&mut server, "\\section{A}").create_async().await;
+        let dir = tempfile::tempdir().unwrap();
+        let options = test_options(dir.path(), &server);
+        let processed = sample_processed("We study things.");
+
+        let note = NoteGenerator::new(&options)
+            .unwrap()
+            .generate_note(&processed)
+            .await
+            .unwrap();
+        assert_eq!(note.metadata.prompt_tokens, None);
+        assert_eq!(note.metadata.completion_tokens, None);
+        assert_eq!(note.metadata.total_tokens, None);
+
+        // Stats count the tokens of the notes that have them and no more
+        generate_from_processed(&processed, &options, StageTimings::default())
+            .await
+            .unwrap();
+        let other = options.notes_dir.join("2401.00001");
+        std::fs::create_dir_all(&other).unwrap();
+        let sidecar = serde_json::json!({
+            "paper_id": "2401.00001",
+            "metadata": {
+                "model_used": options.llm.model,
+                "prompt_tokens": 1000,
+                "completion_tokens": 200,
+                "total_tokens": 1200,
+            },
+        });
+        std::fs::write(other.join("2401.00001.json"), sidecar.to_string()).unwrap();
+
+        let flat = stats::Price {
+            prompt: 1.0,
+            completion: 1.0,
+        };
+        let stats = stats::compute(
+            &library::list_notes(&options.notes_dir).unwrap(),
+            Some(flat),
+        );
+        assert_eq!(stats.notes, 2);
+        assert_eq!(stats.notes_with_usage, 1);
+        assert_eq!(stats.total_tokens, 1200);
+        assert!((stats.estimated_cost - 0.0012).abs() < 1e-12);
+        assert_eq!(stats.by_model[&options.llm.model].notes, 2);
+    }
+
+    #[tokio::test]
+    async fn test_retries_stop_once_the_budget_is_spent() {
+        let mut server = mockito::Server::new_async().await;
+        let llm = mock_chat(&mut server, "not json")
+            .expect(3)
+            .create_async()
+            .await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut options = test_options(dir.path(), &server);
+        options.structured = true;
+        options.limits = options.limits.clone().with_retry_budget(Some(1));
+
+        // The first paper gets the one retry, the second fails on its first attempt
+        for expected in [
+            "after 2 attempt(s) (--max-total-retries used up)",
+            "after 1 attempt(s)",
+        ] {
+            let err = generate_from_processed(
+                &sample_processed("We study things."),
+                &options,
+                StageTimings::default(),
+            )
+            .await
+            .unwrap_err();
+            assert!(format!("{err:#}").contains(expected), "{err:#}");
+        }
+
+        llm.assert_async().await;
+        assert!(options.limits.retries_exhausted());
+    }
+
+    #[tokio::test]
+    async fn test_every_request_counts_against_the_tpm_limit() {
+        let mut server = mockito::Server::new_async().await;
+        let llm = mock_chat(&mut server, "not json")
+            .expect(3)
+            .create_async()
+            .await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut options = test_options(dir.path(), &server);
+        options.structured = true;
+        options.limits = options.limits.clone().with_tpm_limit(Some(1_000_000));
+
+        generate_from_processed(
+            &sample_processed("We study things."),
+            &options,
+            StageTimings::default(),
+        )
+        .await
+        .unwrap_err();
+
+        llm.assert_async().await;
+        assert_eq!(options.limits.tpm_requests().await, 3);
+    }
+
+    #[tokio::test]
+    async fn test_instructions_reach_the_system_prompt() {
+        let mut server = mockito::Server::new_async().await;
+        let llm = mock_chat(&mut server, "\\section{A}")
+            .match_body(mockito::Matcher::Regex(
+                r#""content":"Be skeptical\.\\n\\nsummarize\\n\\nFocus on the method\.\\n\\nUse bullet points\.""#
+                    .to_string(),
+            ))
+            .create_async()
+            .await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut options = test_options(dir.path(), &server);
+        options.instructions = vec![
+            "Focus on the method.".to_string(),
+            "Use bullet points.".to_string(),
+        ];
+        options.prepend_instructions = vec!["Be skeptical.".to_string()];
+
+        generate_from_processed(
+            &sample_processed("We study things."),
+            &options,
+            StageTimings::default(),
+        )
+        .await
+        .unwrap();
+        llm.assert_async().await;
+
+        let sidecar = options.notes_dir.join("2401.08027/2401.08027.json");
+        let metadata: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(sidecar).unwrap()).unwrap();
+        assert_eq!(
+            metadata["metadata"]["instructions"],
+            serde_json::json!(["Focus on the method.", "Use bullet points."])
+        );
+        assert_eq!(
+            metadata["metadata"]["prepend_instructions"],
+            serde_json::json!(["Be skeptical."])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_papers_are_processed_once() {
+        let mut server = mockito::Server::new_async().await;
+        let tex = format!(
+            "\\documentclass{{article}}\n\\title{{Twice}}\n\\begin{{document}}\n\
+             \\begin{{abstract}}We run twice.\\end{{abstract}}\n\\section{{Intro}}\n{}\n\\end{{document}}",
+            "Body text. ".repeat(100)
+        );
+        let src = server
+            .mock("GET", "/src/2401.08027")
+            .with_body(tar_gz(&[("main.tex", &tex)]))
+            .expect(1)
+            .create_async()
+            .await;
+        let llm = mock_chat(&mut server, "\\section{A}")
+            .expect(1)
+            .create_async()
+            .await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut options = test_options(dir.path(), &server);
+        options.min_archive_size = 1;
+        let arxiv_url = ArxivUrl {
+            paper_id: "2401.08027".to_string(),
+            src_url: format!("{}/src/2401.08027", server.url()),
+        };
+
+        let tasks: Vec<_> = (0..2)
+            .map(|_| {
+                let options = options.clone();
+                let arxiv_url = arxiv_url.clone();
+                tokio::spawn(async move { process_paper(&arxiv_url, &options).await })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap().unwrap();
+        }
+
+        src.assert_async().await;
+        llm.assert_async().await;
+        let note = options.notes_dir.join("2401.08027/2401.08027.tex");
+        assert!(std::fs::read_to_string(note)
+            .unwrap()
+            .contains("\\section{A}"));
+    }
+
+    #[tokio::test]
+    async fn test_json_log_events() {
+        let mut server = mockito::Server::new_async().await;
+        let tex = format!(
+            "\\documentclass{{article}}\n\\title{{Logged}}\n\\begin{{document}}\n\
+             \\begin{{abstract}}We log things.\\end{{abstract}}\n\\section{{Intro}}\n{}\n\\end{{document}}",
+            "Body text. ".repeat(100)
+        );
+        server
+            .mock("GET", "/src/2401.08027")
+            .with_body(tar_gz(&[("main.tex", &tex)]))
+            .create_async()
+            .await;
+        server
+            .mock("GET", "/src/2401.99999")
+            .with_status(404)
+            .create_async()
+            .await;
+        mock_chat(&mut server, "\\section{A}").create_async().await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut options = test_options(dir.path(), &server);
+        options.min_archive_size = 1;
+        let captured = events::Captured::default();
+        options.events = EventLog::to_writer(captured.clone());
+        let arxiv_url = |id: &str| ArxivUrl {
+            paper_id: id.to_string(),
+            src_url: format!("{}/src/{id}", server.url()),
+        };
+
+        process_paper(&arxiv_url("2401.08027"), &options)
+            .await
+            .unwrap();
+        process_paper(&arxiv_url("2401.08027"), &options)
+            .await
+            .unwrap();
+        assert!(process_paper(&arxiv_url("2401.99999"), &options)
+            .await
+            .is_err());
+
+        let events = captured.events();
+        let names: Vec<&str> = events
+            .iter()
+            .map(|e| e["event"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "download_start",
+                "download_done",
+                "extract_done",
+                "llm_done",
+                "paper_done",
+                "paper_done",
+                "download_start",
+                "paper_failed"
+            ]
+        );
+        assert!(events.iter().all(|e| e["timestamp"].is_string()));
+        assert!(events[..6].iter().all(|e| e["paper_id"] == "2401.08027"));
+        assert!(events[1]["bytes"].as_u64().unwrap() > 0);
+        assert_eq!(events[2]["tex_files"], 1);
+        assert_eq!(events[3]["model"], options.llm.model.as_str());
+        assert!(events[3]["secs"].as_f64().unwrap() > 0.0);
+        assert_eq!(events[4]["skipped"], false);
+        assert_eq!(events[5]["skipped"], true);
+        assert!(
+            events[7]["error"].as_str().unwrap().contains("404"),
+            "{}",
+            events[7]
+        );
+    }
+
+    #[test]
+    fn test_newer_arxiv_version_triggers_update() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut options = RunOptions::from_settings(Settings {
+            output_dir: Some(dir.path().join("tex")),
+            ..Default::default()
+        })
+        .unwrap();
+        options.update = true;
+        let note_dir = options.notes_dir.join("2401.08027");
+        std::fs::create_dir_all(&note_dir).unwrap();
+        std::fs::write(note_dir.join("2401.08027.tex"), "\\section{A}").unwrap();
+        std::fs::write(
+            note_dir.join("2401.08027.json"),
+            r#"{"metadata":{"arxiv_version":1}}"#,
+        )
+        .unwrap();
+
+        assert_eq!(recorded_version(&options, "2401.08027"), Some(1));
+        assert!(should_update(&options, "2401.08027", Some(2)));
+        assert!(!should_update(&options, "2401.08027", Some(1)));
+        assert!(!should_update(&options, "2401.08027", None));
+
+        options.update = false;
+        assert!(!should_update(&options, "2401.08027", Some(2)));
+    }
+
+    #[tokio::test]
+    async fn test_prompt_dumped_even_when_llm_fails() {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("POST", "/v1/chat/completions")
+            .with_status(500)
+            .create_async()
+            .await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut options = test_options(dir.path(), &server);
+        options.dump_prompt = Some(dir.path().join("prompts/{id}.txt"));
+        let processed = sample_processed("We study things.");
+
+        assert!(
+            generate_from_processed(&processed, &options, StageTimings::default())
+                .await
+                .is_err()
+        );
+
+        let dump = std::fs::read_to_string(dir.path().join("prompts/2401.08027.txt")).unwrap();
+        assert!(dump.contains("===== system =====\nsummarize"), "{dump}");
+        assert!(dump.contains("论文标题: A Paper"), "{dump}");
+        assert!(dump.contains("摘要:\nWe study things."), "{dump}");
+    }
+
+    #[tokio::test]
+    async fn test_poor_extraction_skips_llm() {
+        let mut server = mockito::Server::new_async().await;
+        let llm = server
+            .mock("POST", "/v1/chat/completions")
+            .expect(0)
+            .create_async()
+            .await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let options = test_options(dir.path(), &server);
+        let processed = sample_processed("  ");
+
+        generate_from_processed(&processed, &options, StageTimings::default())
+            .await
+            .unwrap();
+        llm.assert_async().await;
+        assert!(!options.notes_dir.join("2401.08027").exists());
+
+        assert!(!is_poor_extraction(&sample_processed("Abstract."), 1));
+        assert!(is_poor_extraction(&processed, 1));
+        assert!(!is_poor_extraction(&processed, 0));
+    }
+
+    #[test]
+    fn test_partial_note_dir_does_not_count_as_done() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = RunOptions::from_settings(Settings {
+            output_dir: Some(dir.path().join("tex")),
+            ..Default::default()
+        })
+        .unwrap();
+        let note_dir = options.notes_dir.join("2401.08027");
+
+        std::fs::create_dir_all(&note_dir).unwrap();
+        assert!(!note_exists(&options, "2401.08027"));
+
+        std::fs::write(note_dir.join("2401.08027.tex"), "").unwrap();
+        assert!(!note_exists(&options, "2401.08027"));
+
+        std::fs::write(note_dir.join("2401.08027.tex"), "\\documentclass{ctexart}").unwrap();
+        assert!(note_exists(&options, "2401.08027"));
+    }
+
+    #[test]
+    fn test_note_without_sidecar_counts_as_done() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = RunOptions::from_settings(Settings {
+            output_dir: Some(dir.path().join("tex")),
+            ..Default::default()
+        })
+        .unwrap();
+        // Written before sidecars existed, under a title-based name
+        let note_dir = options.notes_dir.join("1706.03762");
+        std::fs::create_dir_all(&note_dir).unwrap();
+        std::fs::write(
+            note_dir.join("attention-is-all-you-need.tex"),
+            "\\section{A}",
+        )
+        .unwrap();
+
+        assert!(note_exists(&options, "1706.03762"));
+        assert!(should_skip(&options, "1706.03762"));
+    }
+
+    #[test]
+    fn test_compare_note_or_stray_sidecar_is_not_a_note() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = RunOptions::from_settings(Settings {
+            output_dir: Some(dir.path().join("tex")),
+            ..Default::default()
+        })
+        .unwrap();
+        let note_dir = options.notes_dir.join("2401.08027");
+        std::fs::create_dir_all(&note_dir).unwrap();
+
+        std::fs::write(note_dir.join("notes.json"), "{}").unwrap();
+        std::fs::write(note_dir.join("2401.08027.gpt-4o.tex"), "\\section{A}").unwrap();
+        std::fs::write(
+            note_dir.join("2401.08027.gpt-4o.json"),
+            r#"{"metadata":{"compare_of":"gpt-4o-mini"}}"#,
+        )
+        .unwrap();
+        assert!(!note_exists(&options, "2401.08027"));
+
+        std::fs::write(note_dir.join("2401.08027.tex"), "\\section{A}").unwrap();
+        assert!(note_exists(&options, "2401.08027"));
+    }
+
+    #[test]
+    fn test_version_policy_numbers_new_notes() {
+        let dir = tempfile::tempdir().unwrap();
+        let note = dir.path().join("2401.08027.tex");
+        assert_eq!(next_version_path(&note), note);
+
+        std::fs::write(&note, "first").unwrap();
+        let second = next_version_path(&note);
+        assert_eq!(second, dir.path().join("2401.08027.v2.tex"));
+
+        std::fs::write(&second, "second").unwrap();
+        assert_eq!(
+            next_version_path(&note),
+            dir.path().join("2401.08027.v3.tex")
+        );
+        assert_eq!(std::fs::read_to_string(&note).unwrap(), "first");
+    }
+
+    #[test]
+    fn test_failed_url_list_round_trips() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("failed_urls.txt");
+        let failed = [
+            "https://arxiv.org/abs/2401.00001".to_string(),
+            "10.48550/arXiv.2401.00002".to_string(),
+        ];
+
+        write_url_list(&path, &failed).unwrap();
+        assert_eq!(read_url_list(&path).unwrap(), failed);
+
+        write_url_list(&path, &[]).unwrap();
+        assert!(read_url_list(&path).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_watch_keeps_failures_of_earlier_batches() {
+        let server = mockito::Server::new_async().await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut options = test_options(dir.path(), &server);
+        let failed_path = dir.path().join("failed_urls.txt");
+        options.failed_urls = Some(failed_path.clone());
+        let list = dir.path().join("urls.txt");
+        // Neither parses as an arXiv URL, so each fails without a request
+        std::fs::write(&list, "not an arxiv url\n").unwrap();
+
+        let (stop_tx, stop_rx) = tokio::sync::oneshot::channel::<()>();
+        let watcher = {
+            let list = list.clone();
+            let options = options.clone();
+            tokio::spawn(async move {
+                watch_urls(
+                    &list,
+                    std::time::Duration::from_millis(10),
+                    async {
+                        let _ = stop_rx.await;
+                    },
+                    &options,
+                )
+                .await
+            })
+        };
+        let wait_for_failures = |n: usize| {
+            let failed_path = failed_path.clone();
+            async move {
+                for _ in 0..200 {
+                    if read_url_list(&failed_path).is_ok_and(|urls| urls.len() >= n) {
+                        return;
+                    }
+                    tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+                }
+                panic!("watcher did not record {n} failure(s)");
+            }
+        };
+
+        wait_for_failures(1).await;
+        std::fs::write(&list, "not an arxiv url\nanother bad url\n").unwrap();
+        wait_for_failures(2).await;
+        stop_tx.send(()).unwrap();
+        watcher.await.unwrap().unwrap();
+
+        assert_eq!(
+            read_url_list(&failed_path).unwrap(),
+            ["not an arxiv url", "another bad url"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_select_pending_respects_limit() {
+        let urls: Vec<String> = (1..=5)
+            .map(|i| format!("https://arxiv.org/abs/2401.0000{i}"))
+            .collect();
+
+        // The second paper is already done, so it doesn't count towards the limit
+        let selected = select_pending(urls, 2, |url| async move { url.ends_with("00002") }).await;
+        assert_eq!(
+            selected,
+            [
+                "https://arxiv.org/abs/2401.00001",
+                "https://arxiv.org/abs/2401.00003"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_outdated_note_is_pending_with_update() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut options = RunOptions::from_settings(Settings {
+            output_dir: Some(dir.path().join("tex")),
+            ..Default::default()
+        })
+        .unwrap();
+        // A note from before versions were recorded counts as the ID's version
+        let note_dir = options.notes_dir.join("2401.08027v2");
+        std::fs::create_dir_all(&note_dir).unwrap();
+        std::fs::write(note_dir.join("2401.08027v2.tex"), "\\section{A}").unwrap();
+        let sidecar = note_dir.join("2401.08027v2.json");
+        std::fs::write(&sidecar, r#"{"metadata":{}}"#).unwrap();
+        let urls = || vec!["https://arxiv.org/abs/2401.08027v2".to_string()];
+        async fn url_done(options: &RunOptions, url: String) -> bool {
+            is_done(options, ArxivUrl::parse(&url).unwrap().paper_id()).await
+        }
+
+        let pending = select_pending(urls(), 1, |url| url_done(&options, url)).await;
+        assert!(pending.is_empty());
+        options.update = true;
+        let pending = select_pending(urls(), 1, |url| url_done(&options, url)).await;
+        assert!(pending.is_empty());
+
+        std::fs::write(&sidecar, r#"{"metadata":{"arxiv_version":1}}"#).unwrap();
+        let pending = select_pending(urls(), 1, |url| url_done(&options, url)).await;
+        assert_eq!(pending, urls());
+    }
+}
@@ -1,19 +1,180 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use std::fs;
-use std::path::Path;
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
 
-use crate::llm_client::LLMClient;
-use crate::processor::ProcessedContent;
+use crate::config::{Focus, RunOptions, SummarySource};
+use crate::figures::{self, ImageLimits, Rasterizer};
+use crate::limits::StageLimits;
+use crate::llm_client::{self, Completion, LLMClient, Usage};
+use crate::processor::{AbstractSource, Equation, Figure, ProcessedContent, Section};
+use crate::structured::{StructuredNote, STRUCTURED_LATEX_SKELETON, STRUCTURED_PROMPT_FILE};
+use crate::timing::StageTimings;
+use crate::util;
+use crate::validate;
+
+const CONTENT_PLACEHOLDER: &str = "{{CONTENT}}";
+const TITLE_PLACEHOLDER: &str = "{{TITLE}}";
+const PAPER_ID_PLACEHOLDER: &str = "{{PAPER_ID}}";
+
+const BODY_ONLY_INSTRUCTION: &str = "\n\n**重要：** 只输出文档正文（即 \\begin{document} 与 \\end{document} 之间的内容），\
+不要输出 \\documentclass、\\usepackage、\\begin{document}、\\end{document} 等导言区或文档结构命令，它们将由用户提供的模板补全。";
+
+/// Serializes `--append-to` writes across concurrent batch tasks.
+static APPEND_LOCK: Mutex<()> = Mutex::new(());
+
+/// How many times a `--structured` request is made before giving up on invalid JSON.
+const MAX_STRUCTURED_ATTEMPTS: usize = 3;
+/// How many times a structured response cut off by `max_tokens` is continued.
+const MAX_CONTINUATIONS: usize = 3;
+/// Below this many words `--summary-source auto` treats the abstract as trivial
+const MIN_SUMMARY_ABSTRACT_WORDS: usize = 20;
+
+/// Words in the titles of the sections `--focus abstract-conclusion` keeps
+const CONCLUSION_TITLES: [&str; 2] = ["conclusion", "discussion"];
+
+/// Words in the titles of the sections `--focus method` keeps
+const METHOD_TITLES: [&str; 7] = [
+    "method",
+    "methodology",
+    "approach",
+    "model",
+    "algorithm",
+    "proposed",
+    "framework",
+];
+
+const MATHJAX_SCRIPT: &str = r#"<script id="MathJax-script" async src="https://cdn.jsdelivr.net/npm/mathjax@3/es5/tex-mml-chtml.js"></script>"#;
+
+/// File format of the generated note.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    #[default]
+    Latex,
+    Html,
+}
+
+impl OutputFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Latex => "tex",
+            OutputFormat::Html => "html",
+        }
+    }
+
+    /// Prompt used when no prompt file is configured.
+    fn default_prompt_file(self) -> &'static str {
+        match self {
+            OutputFormat::Latex => "prompts.txt",
+            OutputFormat::Html => "prompts_html.txt",
+        }
+    }
+
+    /// Text that precedes a figure path in the generated markup.
+    fn figure_path_marker(self) -> &'static str {
+        match self {
+            OutputFormat::Latex => "{",
+            OutputFormat::Html => "src=\"",
+        }
+    }
+}
 
 pub struct NoteGenerator {
     client: LLMClient,
     system_prompt: String,
+    template: Option<NoteTemplate>,
+    format: OutputFormat,
+    vision: bool,
+    max_figures: usize,
+    image_limits: ImageLimits,
+    /// Total size of the figures attached to one request
+    max_figure_bytes: usize,
+    structured: bool,
+    /// Run-wide limits; structured retries draw on its retry budget
+    limits: StageLimits,
+    /// Most equations listed in the prompt (0 disables them)
+    max_equations: usize,
+    /// Section titles whose equations are preferred under `max_equations`
+    equation_sections: Vec<String>,
+    /// Equations are already inline in the sections, so no separate list
+    combine_equations: bool,
+    /// Where to write the prompt before each request
+    dump_prompt: Option<PathBuf>,
+    /// Run-specific instructions already folded into `system_prompt`, kept for the metadata
+    instructions: Vec<String>,
+    prepend_instructions: Vec<String>,
+    tags: Vec<String>,
+    /// Section titles left out of the prompt
+    exclude_sections: Vec<String>,
+    summary_source: SummarySource,
+    focus: Focus,
+    /// Converts EPS/PDF figures for vision requests and HTML notes, if installed
+    rasterizer: Option<Rasterizer>,
+    /// Figure paths as the model sees them (relative to the working directory)
+    figure_root: String,
+    /// What `figure_root` must become for paths to resolve from the note's directory
+    figure_root_from_note: String,
+}
+
+/// A user-supplied `.tex` skeleton that the generated note body is substituted into.
+#[derive(Debug, Clone)]
+pub struct NoteTemplate {
+    skeleton: String,
+}
+
+impl NoteTemplate {
+    pub fn load(path: &Path) -> Result<Self> {
+        let skeleton = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read template {}", path.display()))?;
+        Self::from_skeleton(skeleton)
+            .with_context(|| format!("Invalid template {}", path.display()))
+    }
+
+    pub fn from_skeleton(skeleton: String) -> Result<Self> {
+        if !skeleton.contains(CONTENT_PLACEHOLDER) {
+            anyhow::bail!("template must contain a {CONTENT_PLACEHOLDER} placeholder");
+        }
+        Ok(Self { skeleton })
+    }
+
+    pub fn render(&self, body: &str, title: &str, paper_id: &str) -> String {
+        // Substitute the content last so placeholders inside the body are left alone
+        self.skeleton
+            .replace(TITLE_PLACEHOLDER, title)
+            .replace(PAPER_ID_PLACEHOLDER, paper_id)
+            .replace(CONTENT_PLACEHOLDER, body)
+    }
+}
+
+/// Estimated tokens in the request for one paper, by where they come from.
+#[derive(Debug)]
+pub struct PromptEstimate {
+    pub system_prompt: usize,
+    /// Each part of the user message, in prompt order
+    pub parts: Vec<(&'static str, usize)>,
+    /// Each section sent, by title
+    pub sections: Vec<(String, usize)>,
+}
+
+impl PromptEstimate {
+    pub fn total(&self) -> usize {
+        self.system_prompt + self.parts.iter().map(|(_, tokens)| tokens).sum::<usize>()
+    }
 }
 
 #[derive(Debug, serde::Serialize)]
 pub struct GeneratedNote {
     pub paper_id: String,
     pub title: String,
+    pub authors: Vec<String>,
+    /// Read back by `list` and `stats`
+    pub keywords: Vec<String>,
+    /// Repository URLs found in the source
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub code_links: Vec<String>,
+    /// Written to the note file itself, not the sidecar
+    #[serde(skip)]
     pub latex_content: String,
     pub metadata: NoteMetadata,
 }
@@ -22,19 +183,105 @@ pub struct GeneratedNote {
 pub struct NoteMetadata {
     pub generated_at: String,
     pub model_used: String,
+    pub seed: Option<u64>,
+    /// arXiv version the note was generated from, when known
+    pub arxiv_version: Option<u32>,
     pub prompt_tokens: Option<u32>,
     pub completion_tokens: Option<u32>,
     pub total_tokens: Option<u32>,
+    /// Words of section text in the paper, for library statistics
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_words: Option<usize>,
+    /// Whether the abstract sent came from the source or the arXiv API
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub abstract_source: Option<AbstractSource>,
+    /// Time spent in each stage; only the LLM stage is timed on `reprocess`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timings: Option<StageTimings>,
+    /// `--instruction` texts appended to the system prompt
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub instructions: Vec<String>,
+    /// `--prepend-instruction` texts placed before the system prompt
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub prepend_instructions: Vec<String>,
+    /// `--tag` values given when the note was generated
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// Unclosed environments and braces closed after generation
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub latex_repairs: Vec<String>,
+    /// Model of the note this one was generated beside with `--compare`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compare_of: Option<String>,
 }
 
 impl NoteGenerator {
-    pub fn new() -> Result<Self> {
-        let client = LLMClient::new()?;
-        let system_prompt = load_system_prompt()?;
+    pub fn new(options: &RunOptions) -> Result<Self> {
+        let mut llm = options.llm.clone();
+        llm.json_mode = options.structured;
+        let client = LLMClient::new(llm)?.with_limits(options.limits.clone());
+        let format = options.format;
+        let mut system_prompt = load_system_prompt(&prompt_file(options), options, |name| {
+            std::env::var(name).ok()
+        })?;
+        let template = options.template.clone();
+
+        if let Some(lang) = &options.lang {
+            system_prompt.push_str(&format!(
+                "\n\n**语言要求：** 忽略上文中关于输出语言的要求，所有输出内容必须使用 {lang}。"
+            ));
+        }
+
+        // With a template the model only writes the body; the skeleton supplies the rest
+        if template.is_some() && format == OutputFormat::Latex && !options.structured {
+            system_prompt.push_str(BODY_ONLY_INSTRUCTION);
+        }
+
+        for instruction in &options.instructions {
+            system_prompt.push_str(&format!("\n\n{instruction}"));
+        }
+        if !options.prepend_instructions.is_empty() {
+            let mut prefix = options.prepend_instructions.join("\n\n");
+            prefix.push_str("\n\n");
+            system_prompt.insert_str(0, &prefix);
+        }
+
+        let work_dir = options.work_dir.to_string_lossy();
+        let marker = format.figure_path_marker();
+        let figure_root = format!("{marker}{work_dir}/");
+        let figure_root_from_note = format!(
+            "{marker}{}{work_dir}/",
+            path_to_working_dir(&options.notes_dir)?
+        );
 
         Ok(Self {
             client,
             system_prompt,
+            template,
+            format,
+            vision: options.vision,
+            max_figures: options.max_figures,
+            image_limits: options.image_limits,
+            max_figure_bytes: options.max_figure_bytes,
+            structured: options.structured,
+            limits: options.limits.clone(),
+            max_equations: options.max_equations,
+            equation_sections: options.equation_sections.clone(),
+            combine_equations: options.combine_equations,
+            dump_prompt: options.dump_prompt.clone(),
+            instructions: options.instructions.clone(),
+            prepend_instructions: options.prepend_instructions.clone(),
+            tags: options.tags.clone(),
+            exclude_sections: options.exclude_sections.clone(),
+            summary_source: options.summary_source,
+            focus: options.focus,
+            rasterizer: if options.vision || format == OutputFormat::Html {
+                Rasterizer::detect()
+            } else {
+                None
+            },
+            figure_root,
+            figure_root_from_note,
         })
     }
 
@@ -43,69 +290,501 @@ impl NoteGenerator {
         processed_content: &ProcessedContent,
     ) -> Result<GeneratedNote> {
         let paper_summary = self.format_paper_content(processed_content);
+        let image_files = self.displayable_figures(processed_content).await;
 
-        let generated_content = self
-            .client
-            .generate_note_with_images(
-                &self.system_prompt,
-                &paper_summary,
-                &processed_content.image_files,
-            )
-            .await?;
+        let (mut processed_latex, usage) = if self.structured {
+            let (note, usage) = self
+                .generate_structured(processed_content, &paper_summary, &image_files)
+                .await?;
+            (note.render(self.format), usage)
+        } else {
+            let completion = self
+                .request_note(processed_content, &paper_summary, &image_files)
+                .await?;
+
+            // Post-process the generated content
+            let processed_latex = self.post_process_latex(completion.content.trim());
+            if processed_latex.is_empty() {
+                anyhow::bail!(
+                    "Generated note for {} is empty after post-processing; the model may have been \
+                     blocked by a content filter or overloaded",
+                    processed_content.paper_id
+                );
+            }
+            (processed_latex, completion.usage)
+        };
+
+        match (&self.template, self.format) {
+            (_, OutputFormat::Html) => {
+                processed_latex = render_html(&processed_latex, &processed_content.title);
+            }
+            (Some(template), OutputFormat::Latex) => {
+                processed_latex = template.render(
+                    extract_document_body(&processed_latex),
+                    &processed_content.title,
+                    &processed_content.paper_id,
+                );
+            }
+            (None, OutputFormat::Latex) if self.structured => {
+                processed_latex =
+                    NoteTemplate::from_skeleton(STRUCTURED_LATEX_SKELETON.to_string())?.render(
+                        &processed_latex,
+                        &processed_content.title,
+                        &processed_content.paper_id,
+                    );
+            }
+            (None, OutputFormat::Latex) => {}
+        }
 
-        // Post-process the generated content
-        let processed_latex = self.post_process_latex(&generated_content);
+        let mut latex_repairs = Vec::new();
+        if self.format == OutputFormat::Latex {
+            (processed_latex, latex_repairs) = validate::repair_syntax(&processed_latex);
+            if !latex_repairs.is_empty() {
+                eprintln!(
+                    "Warning: repaired the LaTeX of the note for {}: {}",
+                    processed_content.paper_id,
+                    latex_repairs.join(", ")
+                );
+            }
+            for error in validate::check_syntax(&processed_latex) {
+                eprintln!(
+                    "Warning: note for {} still has a LaTeX problem: {error}",
+                    processed_content.paper_id
+                );
+            }
+        }
 
         let note = GeneratedNote {
             paper_id: processed_content.paper_id.clone(),
             title: processed_content.title.clone(),
+            authors: processed_content.authors.clone(),
+            keywords: processed_content.keywords.clone(),
+            code_links: processed_content.code_links.clone(),
             latex_content: processed_latex,
             metadata: NoteMetadata {
                 generated_at: chrono::Utc::now().to_rfc3339(),
-                model_used: "gpt-3.5-turbo".to_string(),
-                prompt_tokens: None,
-                completion_tokens: None,
-                total_tokens: None,
+                model_used: self.client.config().model.clone(),
+                seed: self.client.config().seed,
+                arxiv_version: processed_content.arxiv_version,
+                prompt_tokens: usage.map(|u| u.prompt_tokens),
+                completion_tokens: usage.map(|u| u.completion_tokens),
+                total_tokens: usage.map(|u| u.total_tokens),
+                source_words: Some(
+                    processed_content
+                        .sections
+                        .iter()
+                        .map(|s| s.content.split_whitespace().count())
+                        .sum(),
+                ),
+                abstract_source: Some(processed_content.abstract_source),
+                timings: None,
+                instructions: self.instructions.clone(),
+                prepend_instructions: self.prepend_instructions.clone(),
+                tags: self.tags.clone(),
+                latex_repairs,
+                compare_of: None,
             },
         };
 
         Ok(note)
     }
 
+    /// The paper's figures, with EPS/PDF ones converted to PNG when they have to be
+    /// viewable: attached to a vision request or embedded in an HTML note.
+    async fn displayable_figures(&self, processed_content: &ProcessedContent) -> Vec<String> {
+        if !self.vision && self.format != OutputFormat::Html {
+            return processed_content.image_files.clone();
+        }
+        figures::rasterize_figures(&processed_content.image_files, self.rasterizer.as_ref()).await
+    }
+
+    /// Ask the model for the note, attaching figures as images in vision mode.
+    async fn request_note(
+        &self,
+        processed_content: &ProcessedContent,
+        paper_summary: &str,
+        image_files: &[String],
+    ) -> Result<Completion> {
+        if self.vision {
+            let (selected, skipped) = figures::select_figures(
+                image_files,
+                &processed_content.figure_references,
+                self.max_figures,
+            );
+            let figures = figures::encode_figures(&selected, self.image_limits).await;
+            let (figures, over_budget) = figures::apply_byte_budget(figures, self.max_figure_bytes);
+            println!(
+                "Attaching {} figure(s) as images, {skipped} skipped (--max-figures {})",
+                figures.len(),
+                self.max_figures
+            );
+            if over_budget > 0 {
+                println!(
+                    "Dropped {over_budget} figure(s) over the {}-byte budget (--max-input-figures-bytes)",
+                    self.max_figure_bytes
+                );
+            }
+            let mut user_text = paper_summary.to_string();
+            for (i, figure) in figures.iter().enumerate() {
+                user_text.push_str(&format!(
+                    "\n图像 {}: {} [attached as image]",
+                    i + 1,
+                    figure.path
+                ));
+            }
+            self.dump_prompt(&processed_content.paper_id, &user_text)?;
+            self.client
+                .generate_note_with_figures(
+                    &processed_content.paper_id,
+                    &self.system_prompt,
+                    paper_summary,
+                    &figures,
+                )
+                .await
+        } else {
+            let user_text = llm_client::with_image_references(paper_summary, image_files);
+            self.dump_prompt(&processed_content.paper_id, &user_text)?;
+            self.client
+                .generate_note(&processed_content.paper_id, &self.system_prompt, &user_text)
+                .await
+        }
+    }
+
+    /// Write the system prompt and user message to `--dump-prompt`, if set, before
+    /// they are sent. `{id}` in the path is replaced by the paper ID.
+    fn dump_prompt(&self, paper_id: &str, user_text: &str) -> Result<()> {
+        let Some(path) = &self.dump_prompt else {
+            return Ok(());
+        };
+        let path = PathBuf::from(
+            path.to_string_lossy()
+                .replace("{id}", &paper_id.replace('/', "_")),
+        );
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let dump = format!(
+            "===== system =====\n{}\n\n===== user =====\n{user_text}\n",
+            self.system_prompt
+        );
+        fs::write(&path, dump)
+            .with_context(|| format!("Failed to write prompt dump {}", path.display()))?;
+        println!("Prompt written to: {}", path.display());
+        Ok(())
+    }
+
+    /// Request a JSON note, asking again while the response fails to parse. Returns
+    /// the note and the tokens of every request made for it.
+    async fn generate_structured(
+        &self,
+        processed_content: &ProcessedContent,
+        paper_summary: &str,
+        image_files: &[String],
+    ) -> Result<(StructuredNote, Option<Usage>)> {
+        let mut attempt = 1;
+        let mut usages = Vec::new();
+        loop {
+            let response = self
+                .request_note(processed_content, paper_summary, image_files)
+                .await?;
+            let response = self
+                .complete_truncated(processed_content, paper_summary, image_files, response)
+                .await?;
+            usages.push(response.usage);
+            match StructuredNote::parse(&response.content) {
+                Ok(note) => return Ok((note, usages.into_iter().sum())),
+                Err(e) if attempt < MAX_STRUCTURED_ATTEMPTS && self.limits.retry() => {
+                    eprintln!(
+                        "Warning: invalid structured note for {} (attempt {attempt}/{MAX_STRUCTURED_ATTEMPTS}): {e:#}; retrying",
+                        processed_content.paper_id
+                    );
+                    attempt += 1;
+                }
+                Err(e) => {
+                    let budget = if attempt < MAX_STRUCTURED_ATTEMPTS {
+                        " (--max-total-retries used up)"
+                    } else {
+                        ""
+                    };
+                    return Err(e.context(format!(
+                        "No valid structured note for {} after {attempt} attempt(s){budget}",
+                        processed_content.paper_id
+                    )));
+                }
+            }
+        }
+    }
+
+    /// Ask the model to continue a structured response while it was cut off by the
+    /// token limit and is not yet valid JSON, up to `MAX_CONTINUATIONS` times.
+    async fn complete_truncated(
+        &self,
+        processed_content: &ProcessedContent,
+        paper_summary: &str,
+        image_files: &[String],
+        mut response: Completion,
+    ) -> Result<Completion> {
+        let user_text = llm_client::with_image_references(paper_summary, image_files);
+        let mut continuations = 0;
+        while response.truncated()
+            && continuations < MAX_CONTINUATIONS
+            && StructuredNote::parse(&response.content).is_err()
+        {
+            continuations += 1;
+            eprintln!(
+                "Warning: structured note for {} was cut off by the token limit; continuing ({continuations}/{MAX_CONTINUATIONS})",
+                processed_content.paper_id
+            );
+            let continuation = self
+                .client
+                .continue_note(
+                    &processed_content.paper_id,
+                    &self.system_prompt,
+                    &user_text,
+                    &response.content,
+                )
+                .await?;
+            // Both untrimmed, so a space or line break at the cut is kept
+            response = Completion {
+                content: response.content + &continuation.content,
+                finish_reason: continuation.finish_reason,
+                usage: [response.usage, continuation.usage].into_iter().sum(),
+            };
+        }
+        response.content = response.content.trim().to_string();
+        Ok(response)
+    }
+
     fn format_paper_content(&self, processed_content: &ProcessedContent) -> String {
-        let mut content = String::new();
+        self.prompt_parts(processed_content)
+            .into_iter()
+            .map(|(_, text)| text)
+            .collect()
+    }
 
-        content.push_str(&format!("论文标题: {}\n\n", processed_content.title));
-        content.push_str(&format!(
-            "作者: {}\n\n",
+    /// The user message in the pieces `format_paper_content` joins, each named for
+    /// `count-tokens`. Parts with nothing to say are empty.
+    fn prompt_parts(&self, processed_content: &ProcessedContent) -> Vec<(&'static str, String)> {
+        let mut header = format!(
+            "论文标题: {}\n\n作者: {}\n\n",
+            processed_content.title,
             processed_content.authors.join(", ")
-        ));
-        content.push_str(&format!("摘要:\n{}\n\n", processed_content.abstract_text));
-
-        content.push_str("章节内容:\n");
-        for section in &processed_content.sections {
-            content.push_str(&format!(
-                "{} {}\n{}",
-                "#".repeat(section.level as usize),
-                section.title,
-                section.content
+        );
+        if !processed_content.keywords.is_empty() {
+            header.push_str(&format!(
+                "关键词: {}\n\n",
+                processed_content.keywords.join(", ")
             ));
-            content.push_str("\n\n");
+        }
+        if !processed_content.code_links.is_empty() {
+            header.push_str(&format!(
+                "代码: {}\n\n",
+                processed_content.code_links.join(", ")
+            ));
+        }
+        let abstract_text = format!("摘要:\n{}\n\n", processed_content.abstract_text);
+        if self.abstract_only(processed_content) {
+            return vec![("title and authors", header), ("abstract", abstract_text)];
+        }
+
+        let mut contributions = String::new();
+        if !processed_content.contributions.is_empty() {
+            contributions.push_str("主要贡献:\n");
+            for (i, contribution) in processed_content.contributions.iter().enumerate() {
+                contributions.push_str(&format!("{}. {contribution}\n", i + 1));
+            }
+            contributions.push('\n');
+        }
+
+        let sections = self.kept_sections(processed_content);
+        if self.focus == Focus::AbstractConclusion {
+            let mut section_text = String::new();
+            if !sections.is_empty() {
+                section_text.push_str("章节内容:\n");
+                push_sections(&mut section_text, &sections);
+            }
+            return vec![
+                ("title and authors", header),
+                ("abstract", abstract_text),
+                ("sections", section_text),
+            ];
+        }
+        // Appendix sections go last, under their own heading, wherever an
+        // `\input` happened to place them
+        let (appendix, main): (Vec<&Section>, Vec<&Section>) =
+            sections.into_iter().partition(|s| s.appendix);
+        let mut section_text = String::from("章节内容:\n");
+        push_sections(&mut section_text, &main);
+        if !appendix.is_empty() {
+            section_text.push_str("附录:\n");
+            push_sections(&mut section_text, &appendix);
+        }
+
+        let mut statements = String::new();
+        if !processed_content.statements.is_empty() {
+            statements.push_str("定理与定义:\n");
+            for statement in &processed_content.statements {
+                let label = match &statement.name {
+                    Some(name) => format!("{} ({name})", statement.kind),
+                    None => statement.kind.clone(),
+                };
+                statements.push_str(&format!("[{label}] {}\n", statement.body));
+            }
+            statements.push('\n');
         }
 
-        if !processed_content.equations.is_empty() {
-            content.push_str("重要公式:\n");
-            for (i, eq) in processed_content.equations.iter().enumerate() {
-                content.push_str(&format!("公式 {}: {}\n", i + 1, eq));
+        // Pseudocode goes in as written, so the model sees the steps and not prose about them
+        let mut algorithms = String::new();
+        if !processed_content.algorithms.is_empty() {
+            algorithms.push_str("算法:\n");
+            for (i, algorithm) in processed_content.algorithms.iter().enumerate() {
+                let caption = algorithm.caption.as_deref().unwrap_or("");
+                algorithms.push_str(&format!(
+                    "算法 {}: {caption}\n```\n{}\n```\n",
+                    i + 1,
+                    algorithm.body
+                ));
             }
-            content.push('\n');
+            algorithms.push('\n');
         }
 
-        content
+        let mut figures = String::new();
+        if !processed_content.figures.is_empty() {
+            figures.push_str("图表:\n");
+            figures.push_str(&format_figures(
+                &processed_content.figures,
+                &processed_content.image_files,
+            ));
+            figures.push('\n');
+        }
+
+        // With --combine-equations they are already in the section text
+        let max_equations = if self.combine_equations {
+            0
+        } else {
+            self.max_equations
+        };
+        let (selected, _) = select_equations(
+            &processed_content.equations,
+            max_equations,
+            &self.equation_sections,
+        );
+        let mut equations = String::new();
+        if !selected.is_empty() {
+            equations.push_str("重要公式:\n");
+            for (i, eq) in selected.iter().enumerate() {
+                equations.push_str(&format!("公式 {}: {}\n", i + 1, eq.content));
+            }
+            equations.push('\n');
+        }
+
+        vec![
+            ("title and authors", header),
+            ("abstract", abstract_text),
+            ("contributions", contributions),
+            ("sections", section_text),
+            ("statements", statements),
+            ("algorithms", algorithms),
+            ("figures", figures),
+            ("equations", equations),
+        ]
     }
 
+    /// Whether `--summary-source` limits this paper's prompt to its abstract. Without
+    /// an abstract there is nothing to summarize, so the full content is used.
+    fn abstract_only(&self, processed_content: &ProcessedContent) -> bool {
+        let words = processed_content.abstract_text.split_whitespace().count();
+        match self.summary_source {
+            SummarySource::Full => false,
+            SummarySource::Abstract => words > 0,
+            SummarySource::Auto => words >= MIN_SUMMARY_ABSTRACT_WORDS,
+        }
+    }
+
+    /// The sections that make it into the prompt after `--exclude-sections` and
+    /// `--focus`.
+    fn kept_sections<'a>(&self, processed_content: &'a ProcessedContent) -> Vec<&'a Section> {
+        let (sections, _) = select_sections(&processed_content.sections, &self.exclude_sections);
+        focus_sections(&sections, self.focus)
+    }
+
+    /// Print which sections `--exclude-sections` and `--focus` and which equations
+    /// `--max-equations` leave out of the prompt. The prompt can be built more than
+    /// once per paper, so this is called separately, once.
+    pub fn report_omissions(&self, processed_content: &ProcessedContent) {
+        if self.abstract_only(processed_content) {
+            return;
+        }
+        let (sections, excluded) =
+            select_sections(&processed_content.sections, &self.exclude_sections);
+        if !excluded.is_empty() {
+            let words: usize = excluded
+                .iter()
+                .map(|s| s.content.split_whitespace().count())
+                .sum();
+            println!(
+                "Excluded {} section(s) ({words} words) from the prompt (--exclude-sections)",
+                excluded.len()
+            );
+        }
+        let focused = focus_sections(&sections, self.focus);
+        if focused.len() < sections.len() {
+            println!(
+                "Kept {} of {} section(s) in the prompt (--focus)",
+                focused.len(),
+                sections.len()
+            );
+        }
+        // --focus abstract-conclusion sends no equations, and with
+        // --combine-equations they are all in the section text
+        if self.focus != Focus::AbstractConclusion && !self.combine_equations {
+            let (_, omitted) = select_equations(
+                &processed_content.equations,
+                self.max_equations,
+                &self.equation_sections,
+            );
+            if omitted > 0 {
+                println!(
+                    "Omitted {omitted} of {} equation(s) from the prompt (--max-equations {})",
+                    processed_content.equations.len(),
+                    self.max_equations
+                );
+            }
+        }
+    }
+
+    /// Estimated prompt size for `count-tokens`, without calling the model.
+    pub fn estimate_prompt(&self, processed_content: &ProcessedContent) -> PromptEstimate {
+        let parts = self.prompt_parts(processed_content);
+        let mut kept = self.kept_sections(processed_content);
+        if self.abstract_only(processed_content) {
+            kept.clear();
+        }
+        let sections = kept
+            .into_iter()
+            .map(|section| {
+                let mut text = String::new();
+                push_sections(&mut text, &[section]);
+                (section.title.clone(), util::estimate_tokens(&text))
+            })
+            .collect();
+        PromptEstimate {
+            system_prompt: util::estimate_tokens(&self.system_prompt),
+            parts: parts
+                .iter()
+                .map(|(name, text)| (*name, util::estimate_tokens(text)))
+                .collect(),
+            sections,
+        }
+    }
+
+    /// Write the note, plus a `.json` sidecar with its metadata next to it.
     pub async fn save_note(&self, note: &GeneratedNote, output_path: &Path) -> Result<()> {
         fs::write(output_path, &note.latex_content)?;
+        fs::write(
+            sidecar_path(output_path),
+            serde_json::to_string_pretty(note)?,
+        )?;
         Ok(())
     }
 
@@ -133,22 +812,747 @@ impl NoteGenerator {
             }
         }
 
-        // Replace all occurrences of {output/ with {../../output/
-        processed = processed.replace("{output/", "{../../output/");
+        // Notes live in <notes_dir>/<paper_id>/, so rewrite figure paths such as
+        // {output/...} to be relative to that directory (e.g. {../../output/...})
+        processed = processed.replace(&self.figure_root, &self.figure_root_from_note);
 
         // Ensure the content doesn't start or end with extra newlines
         processed.trim().to_string()
     }
 }
 
-fn load_system_prompt() -> Result<String> {
-    let prompt_path = "prompts.txt";
-    let content = fs::read_to_string(prompt_path)?;
-    Ok(content)
+/// Append a note's body, under a heading naming the paper, to a combined notes file.
+pub fn append_note(note: &GeneratedNote, format: OutputFormat, path: &Path) -> Result<()> {
+    let entry = combined_entry(note, format);
+
+    let _guard = APPEND_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    file.write_all(entry.as_bytes())?;
+    Ok(())
+}
+
+/// A note as it appears in an `--append-to` file: its body without the document
+/// wrapper, introduced by a separator and a heading.
+fn combined_entry(note: &GeneratedNote, format: OutputFormat) -> String {
+    match format {
+        OutputFormat::Latex => {
+            let body = extract_document_body(&note.latex_content).replace("\\maketitle", "");
+            format!(
+                "% ===== arXiv:{id} =====\n\\section*{{{title} (arXiv:{id})}}\n{body}\n\n",
+                id = note.paper_id,
+                title = note.title,
+                body = body.trim()
+            )
+        }
+        OutputFormat::Html => {
+            // render_html already opens the body with an <h1> naming the paper
+            let body = note
+                .latex_content
+                .split_once("<body>")
+                .and_then(|(_, rest)| rest.rsplit_once("</body>"))
+                .map_or(note.latex_content.as_str(), |(body, _)| body);
+            format!(
+                "<!-- arXiv:{} -->\n<hr>\n{}\n\n",
+                note.paper_id,
+                body.trim()
+            )
+        }
+    }
 }
 
-impl Default for NoteGenerator {
-    fn default() -> Self {
-        Self::new().expect("Failed to create NoteGenerator")
+fn push_sections(content: &mut String, sections: &[&Section]) {
+    for section in sections {
+        content.push_str(&format!(
+            "{} {}\n{}",
+            "#".repeat(section.level as usize),
+            section.title,
+            section.content
+        ));
+        content.push_str("\n\n");
+    }
+}
+
+/// The sections `focus` keeps: those with one of its words, or its plural, as a
+/// whole word in the title (ignoring case), with their subsections.
+fn focus_sections<'a>(sections: &[&'a Section], focus: Focus) -> Vec<&'a Section> {
+    let words: &[&str] = match focus {
+        Focus::All => return sections.to_vec(),
+        Focus::AbstractConclusion => &CONCLUSION_TITLES,
+        Focus::Method => &METHOD_TITLES,
+    };
+    let mut kept = Vec::new();
+    // Level of the kept section whose subsections are being kept with it
+    let mut keeping_below: Option<u8> = None;
+    for &section in sections {
+        if keeping_below.is_some_and(|level| section.level > level) {
+            kept.push(section);
+            continue;
+        }
+        let title = section.title.to_lowercase();
+        keeping_below = None;
+        let matches = title
+            .split(|c: char| !c.is_alphanumeric())
+            .any(|title_word| {
+                words.iter().any(|&word| {
+                    title_word == word
+                        || [title_word.strip_suffix('s'), title_word.strip_suffix("es")]
+                            .contains(&Some(word))
+                })
+            });
+        if matches {
+            keeping_below = Some(section.level);
+            kept.push(section);
+        }
+    }
+    kept
+}
+
+/// Split sections into those kept in the prompt and those excluded because their
+/// title starts with one of `exclude` (ignoring case and numbering like "A." or
+/// "7"). Subsections of an excluded section are excluded with it, and "appendix"
+/// also drops every section after `\appendix`.
+fn select_sections<'a>(
+    sections: &'a [Section],
+    exclude: &[String],
+) -> (Vec<&'a Section>, Vec<&'a Section>) {
+    let exclude: Vec<String> = exclude.iter().map(|t| t.to_lowercase()).collect();
+    let exclude_appendix = exclude.iter().any(|t| t == "appendix");
+    let numbering = regex::Regex::new(r"^(?:\d+(?:\.\d+)*\.?|[A-Z]\.(?:\d+\.?)*)\s+").unwrap();
+    let mut kept = Vec::new();
+    let mut excluded = Vec::new();
+    // Level of the excluded section whose subsections are being skipped
+    let mut skipping_below: Option<u8> = None;
+
+    for section in sections {
+        if skipping_below.is_some_and(|level| section.level > level) {
+            excluded.push(section);
+            continue;
+        }
+        skipping_below = None;
+        if exclude_appendix && section.appendix {
+            excluded.push(section);
+            continue;
+        }
+
+        let title = numbering.replace(section.title.trim(), "").to_lowercase();
+        if exclude
+            .iter()
+            .any(|prefix| title.starts_with(prefix.as_str()))
+        {
+            skipping_below = Some(section.level);
+            excluded.push(section);
+        } else {
+            kept.push(section);
+        }
+    }
+
+    (kept, excluded)
+}
+
+/// One line per figure, "图 N (label): caption", followed by the files it shows so
+/// the model can cite figures by number even without vision. Figures are numbered
+/// in document order, as LaTeX numbers them.
+fn format_figures(figures: &[Figure], image_files: &[String]) -> String {
+    let mut content = String::new();
+    for (i, figure) in figures.iter().enumerate() {
+        let label = match &figure.label {
+            Some(label) => format!(" ({label})"),
+            None => String::new(),
+        };
+        let kind = if figure.tikz { " [TikZ]" } else { "" };
+        content.push_str(&format!("图 {}{label}{kind}: {}\n", i + 1, figure.caption));
+        for reference in &figure.graphics {
+            let path = figures::resolve_reference(image_files, reference)
+                .map_or(reference.as_str(), String::as_str);
+            content.push_str(&format!("  文件: {path}\n"));
+        }
+    }
+    content
+}
+
+/// How much an equation is worth keeping: one the text refers to counts most, then
+/// labeled and display ones, then those in a `key_sections` section.
+fn equation_score(equation: &Equation, key_sections: &[String]) -> u32 {
+    let in_key_section = equation.section.as_deref().is_some_and(|title| {
+        let title = title.to_lowercase();
+        key_sections
+            .iter()
+            .any(|prefix| title.starts_with(&prefix.to_lowercase()))
+    });
+    3 * u32::from(equation.referenced)
+        + 2 * u32::from(equation.label.is_some())
+        + 2 * u32::from(equation.display)
+        + u32::from(in_key_section)
+}
+
+/// Up to `max` equations with the highest `equation_score`, in document order; on
+/// equal scores the earlier equation is kept. Also returns how many were left out.
+fn select_equations<'a>(
+    equations: &'a [Equation],
+    max: usize,
+    key_sections: &[String],
+) -> (Vec<&'a Equation>, usize) {
+    let mut keep: Vec<usize> = (0..equations.len()).collect();
+    // Stable sort: highest scores first, ties still in document order
+    keep.sort_by_key(|&i| std::cmp::Reverse(equation_score(&equations[i], key_sections)));
+    keep.truncate(max);
+    keep.sort_unstable();
+
+    let omitted = equations.len() - keep.len();
+    (keep.into_iter().map(|i| &equations[i]).collect(), omitted)
+}
+
+/// The metadata sidecar for a note, e.g. `2401.08027.json` next to `2401.08027.tex`.
+pub fn sidecar_path(note_path: &Path) -> PathBuf {
+    note_path.with_extension("json")
+}
+
+/// Where the `--compare` note from `model` goes: next to the note, with the model
+/// name before the extension (`2401.08027.gpt-4o.tex`).
+pub fn compared_note_path(note_path: &Path, model: &str) -> PathBuf {
+    let stem = note_path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = note_path.extension().unwrap_or_default().to_string_lossy();
+    note_path.with_file_name(format!("{stem}.{}.{extension}", sanitize_file_name(model)))
+}
+
+/// Placeholders a `--filename-pattern` may use.
+const FILENAME_FIELDS: [&str; 4] = ["id", "title_slug", "date", "first_author"];
+
+/// Reject filename patterns with placeholders `note_file_stem` doesn't know.
+pub fn validate_filename_pattern(pattern: &str) -> Result<()> {
+    let placeholder = regex::Regex::new(r"\{([^{}]*)\}").unwrap();
+    for cap in placeholder.captures_iter(pattern) {
+        if !FILENAME_FIELDS.contains(&&cap[1]) {
+            anyhow::bail!(
+                "Unknown placeholder {{{}}} in filename pattern (expected {{id}}, {{title_slug}}, {{date}} or {{first_author}})",
+                &cap[1]
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Expand a filename pattern into a file stem that is safe on every platform.
+/// `{date}` is the day the note is generated; `{first_author}` is the first
+/// author's surname.
+pub fn note_file_stem(
+    pattern: &str,
+    content: &ProcessedContent,
+    date: chrono::NaiveDate,
+) -> String {
+    let first_author = content
+        .authors
+        .first()
+        .and_then(|author| author.split_whitespace().last())
+        .map(util::slugify)
+        .filter(|slug| !slug.is_empty())
+        .unwrap_or_else(|| "unknown".to_string());
+
+    let stem = pattern
+        .replace("{id}", &content.paper_id)
+        .replace(
+            "{title_slug}",
+            &util::title_slug(&content.title, &content.paper_id),
+        )
+        .replace("{date}", &date.format("%Y-%m-%d").to_string())
+        .replace("{first_author}", &first_author);
+
+    match sanitize_file_name(&stem) {
+        stem if stem.is_empty() => sanitize_file_name(&content.paper_id),
+        stem => stem,
+    }
+}
+
+/// Replace path separators and characters Windows forbids, and trim the leading
+/// and trailing dots and spaces some filesystems choke on.
+fn sanitize_file_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_control() || r#"/\:*?"<>|"#.contains(c) {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect();
+    name.trim_matches(|c: char| c == '.' || c.is_whitespace())
+        .to_string()
+}
+
+/// Wrap a generated HTML fragment in a standalone page that renders math with MathJax.
+fn render_html(body: &str, title: &str) -> String {
+    let title = escape_html(title);
+    format!(
+        "<!DOCTYPE html>\n<html lang=\"zh\">\n<head>\n<meta charset=\"utf-8\">\n\
+         <title>{title}</title>\n{MATHJAX_SCRIPT}\n</head>\n<body>\n<h1>{title}</h1>\n{body}\n</body>\n</html>\n"
+    )
+}
+
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Return the text between `\begin{document}` and `\end{document}`, or the whole
+/// input if the model ignored the instruction and produced no document wrapper.
+fn extract_document_body(latex: &str) -> &str {
+    let begin = r"\begin{document}";
+    let end = r"\end{document}";
+
+    match (latex.find(begin), latex.rfind(end)) {
+        (Some(start), Some(stop)) if start + begin.len() <= stop => {
+            latex[start + begin.len()..stop].trim()
+        }
+        _ => latex,
+    }
+}
+
+/// Path prefix leading from a note's directory (`<notes_dir>/<paper_id>/`) back to the
+/// working directory, e.g. `../../` for the default `tex` notes directory.
+pub fn path_to_working_dir(notes_dir: &Path) -> Result<String> {
+    let depth = notes_dir
+        .components()
+        .map(|c| match c {
+            Component::Normal(_) => Some(1),
+            Component::CurDir => Some(0),
+            _ => None,
+        })
+        .sum::<Option<usize>>();
+
+    match depth {
+        Some(depth) => Ok("../".repeat(depth + 1)),
+        // Absolute or `..` paths: fall back to an absolute path to the working directory
+        None => Ok(format!("{}/", std::env::current_dir()?.display())),
+    }
+}
+
+/// The system prompt file for this run: `--prompt-file`, or the default for the mode.
+pub fn prompt_file(options: &RunOptions) -> PathBuf {
+    options.prompt_file.clone().unwrap_or_else(|| {
+        if options.structured {
+            STRUCTURED_PROMPT_FILE.into()
+        } else {
+            options.format.default_prompt_file().into()
+        }
+    })
+}
+
+/// The prompt file with its placeholders filled in, reading environment variables
+/// through `env`.
+fn load_system_prompt(
+    prompt_path: &Path,
+    options: &RunOptions,
+    env: impl Fn(&str) -> Option<String>,
+) -> Result<String> {
+    let content = fs::read_to_string(prompt_path)
+        .with_context(|| format!("Failed to read prompt file {}", prompt_path.display()))?;
+    Ok(interpolate_prompt(&content, |name| {
+        prompt_variable(options, name, &env)
+    }))
+}
+
+/// Variables a prompt file may read from the environment start with this, so a
+/// shared prompt can't pull arbitrary secrets (`${AWS_SECRET_ACCESS_KEY}`) into the
+/// request.
+const PROMPT_ENV_PREFIX: &str = "PAPER_SCAN_";
+
+/// Value of a `${NAME}` placeholder in the prompt file: the resolved option for
+/// `OUTPUT_LANG` and `MODEL`, otherwise the environment variable from `env` if its
+/// name starts with `PROMPT_ENV_PREFIX`.
+fn prompt_variable(
+    options: &RunOptions,
+    name: &str,
+    env: impl Fn(&str) -> Option<String>,
+) -> Option<String> {
+    match name {
+        "OUTPUT_LANG" => options.lang.clone(),
+        "MODEL" => Some(options.llm.model.clone()),
+        _ if name.starts_with(PROMPT_ENV_PREFIX) => env(name),
+        _ => None,
+    }
+}
+
+/// Replace each `${NAME}` in `prompt` with `lookup(NAME)`; unknown placeholders are
+/// left as they are, with a warning.
+fn interpolate_prompt(prompt: &str, lookup: impl Fn(&str) -> Option<String>) -> String {
+    let re = regex::Regex::new(r"\$\{([A-Za-z_][A-Za-z0-9_]*)\}").unwrap();
+    re.replace_all(prompt, |caps: &regex::Captures| match lookup(&caps[1]) {
+        Some(value) => value,
+        None => {
+            eprintln!(
+                "Warning: prompt placeholder {} has no value; leaving it as is",
+                &caps[0]
+            );
+            caps[0].to_string()
+        }
+    })
+    .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_excluded_sections_are_dropped() {
+        let section = |title: &str, level: u8| Section {
+            title: title.to_string(),
+            content: "Some words here.".to_string(),
+            level,
+            appendix: false,
+        };
+        let sections = [
+            section("Introduction", 1),
+            section("Method", 1),
+            section("7 References", 1),
+            section("Appendix: Proofs", 1),
+            section("Proof of Lemma 1", 2),
+            section("A. Acknowledgments", 1),
+            section("A Survey of Attention", 1),
+        ];
+        let exclude = crate::config::DEFAULT_EXCLUDE_SECTIONS.map(String::from);
+
+        let (kept, excluded) = select_sections(&sections, &exclude);
+
+        let titles = |list: &[&Section]| list.iter().map(|s| s.title.clone()).collect::<Vec<_>>();
+        assert_eq!(
+            titles(&kept),
+            ["Introduction", "Method", "A Survey of Attention"]
+        );
+        assert_eq!(
+            titles(&excluded),
+            [
+                "7 References",
+                "Appendix: Proofs",
+                "Proof of Lemma 1",
+                "A. Acknowledgments"
+            ]
+        );
+        assert_eq!(select_sections(&sections, &[]).0.len(), sections.len());
+
+        let extra = Section {
+            appendix: true,
+            ..section("More Experiments", 1)
+        };
+        let with_appendix = [section("Method", 1), extra];
+        let (kept, excluded) = select_sections(&with_appendix, &exclude);
+        assert_eq!(titles(&kept), ["Method"]);
+        assert_eq!(titles(&excluded), ["More Experiments"]);
+        let (kept, _) = select_sections(&with_appendix, &["References".to_string()]);
+        assert_eq!(kept.len(), 2);
+    }
+
+    #[test]
+    fn test_focus_matches_whole_title_words() {
+        let section = |title: &str, level: u8| Section {
+            title: title.to_string(),
+            content: "Some words here.".to_string(),
+            level,
+            appendix: false,
+        };
+        let sections = [
+            section("Introduction", 1),
+            section("Modeling Assumptions", 1),
+            section("3 Our Approaches", 1),
+            section("Training Details", 2),
+            section("Methodology", 1),
+            section("Experiments", 1),
+            section("Conclusions", 1),
+            section("Inconclusive Results", 1),
+        ];
+        let all: Vec<&Section> = sections.iter().collect();
+        let titles = |list: &[&Section]| list.iter().map(|s| s.title.clone()).collect::<Vec<_>>();
+
+        assert_eq!(
+            titles(&focus_sections(&all, Focus::Method)),
+            ["3 Our Approaches", "Training Details", "Methodology"]
+        );
+        assert_eq!(
+            titles(&focus_sections(&all, Focus::AbstractConclusion)),
+            ["Conclusions"]
+        );
+        assert_eq!(focus_sections(&all, Focus::All).len(), sections.len());
+    }
+
+    #[test]
+    fn test_prompt_placeholders_are_interpolated() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("prompts.txt");
+        fs::write(
+            &path,
+            "Write the note in ${OUTPUT_LANG} for ${MODEL}, for ${PAPER_SCAN_TEST_AUDIENCE}. \
+             Keep ${NOT_A_PAPER_SCAN_VAR}, ${PATH}, ${API_KEY} and $HOME.",
+        )
+        .unwrap();
+        let env = |name: &str| match name {
+            "PAPER_SCAN_TEST_AUDIENCE" => Some("students".to_string()),
+            "PATH" | "API_KEY" => Some("set in the environment".to_string()),
+            _ => None,
+        };
+        let mut options = RunOptions::from_settings(crate::config::Settings {
+            model: Some("gpt-4o".to_string()),
+            api_key: Some("sk-secret".to_string()),
+            ..Default::default()
+        })
+        .unwrap();
+        options.lang = Some("English".to_string());
+
+        assert_eq!(
+            load_system_prompt(&path, &options, env).unwrap(),
+            "Write the note in English for gpt-4o, for students. \
+             Keep ${NOT_A_PAPER_SCAN_VAR}, ${PATH}, ${API_KEY} and $HOME."
+        );
+    }
+
+    #[test]
+    fn test_figures_are_paired_with_captions() {
+        let figures = [
+            Figure {
+                label: Some("fig:arch".to_string()),
+                caption: "Overview of the model.".to_string(),
+                graphics: vec!["figs/arch".to_string()],
+                tikz: false,
+            },
+            Figure {
+                label: None,
+                caption: "Training loss.".to_string(),
+                graphics: vec!["loss.png".to_string(), "acc.png".to_string()],
+                tikz: false,
+            },
+        ];
+        let image_files = [
+            "output/2401.08027/acc.png".to_string(),
+            "output/2401.08027/figs/arch.pdf".to_string(),
+            "output/2401.08027/loss.png".to_string(),
+        ];
+
+        assert_eq!(
+            format_figures(&figures, &image_files),
+            "图 1 (fig:arch): Overview of the model.\n\
+             \x20 文件: output/2401.08027/figs/arch.pdf\n\
+             图 2: Training loss.\n\
+             \x20 文件: output/2401.08027/loss.png\n\
+             \x20 文件: output/2401.08027/acc.png\n"
+        );
+    }
+
+    #[test]
+    fn test_filename_pattern() {
+        let content = ProcessedContent {
+            paper_id: "1706.03762".to_string(),
+            title: "Attention Is All You Need".to_string(),
+            authors: vec!["Ashish Vaswani".to_string(), "Noam Shazeer".to_string()],
+            abstract_text: String::new(),
+            alternate_abstracts: vec![],
+            abstract_source: AbstractSource::Missing,
+            sections: vec![],
+            keywords: vec![],
+            code_links: vec![],
+            figure_references: vec![],
+            figures: vec![],
+            contributions: vec![],
+            equations: vec![],
+            statements: vec![],
+            algorithms: vec![],
+            arxiv_version: None,
+            references: Vec::new(),
+            full_text: String::new(),
+            image_files: vec![],
+        };
+        let date = chrono::NaiveDate::from_ymd_opt(2024, 5, 1).unwrap();
+
+        assert_eq!(note_file_stem("{id}", &content, date), "1706.03762");
+        assert_eq!(
+            note_file_stem("{date}-{first_author}-{title_slug}", &content, date),
+            "2024-05-01-vaswani-attention-is-all-you-need"
+        );
+
+        let old_style = ProcessedContent {
+            paper_id: "hep-th/9901001".to_string(),
+            title: "What: a <title>?".to_string(),
+            ..content
+        };
+        assert_eq!(note_file_stem("{id}", &old_style, date), "hep-th_9901001");
+        assert_eq!(
+            note_file_stem("{title_slug}", &old_style, date),
+            "what-a-title"
+        );
+
+        assert!(validate_filename_pattern("{date}-{first_author}-{title_slug}").is_ok());
+        assert!(validate_filename_pattern("{year}-{id}").is_err());
+    }
+
+    #[test]
+    fn test_template_substitution() {
+        let skeleton = "\\documentclass{article}\n\\title{{{TITLE}}}\n% arXiv:{{PAPER_ID}}\n\\begin{document}\n{{CONTENT}}\n\\end{document}\n";
+        let template = NoteTemplate::from_skeleton(skeleton.to_string()).unwrap();
+
+        let rendered = template.render("\\section{Intro}\nBody text.", "My Paper", "2401.08027");
+
+        assert!(rendered.contains("\\title{My Paper}"));
+        assert!(rendered.contains("% arXiv:2401.08027"));
+        assert!(
+            rendered.contains("\\begin{document}\n\\section{Intro}\nBody text.\n\\end{document}")
+        );
+        assert!(!rendered.contains("{{"));
+    }
+
+    #[test]
+    fn test_template_requires_content_placeholder() {
+        let result = NoteTemplate::from_skeleton("\\begin{document}\\end{document}".to_string());
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_html_output() {
+        let html = render_html(
+            "<h2>背景</h2>\n<p>\\(x^2\\)</p>",
+            "Attention & Transformers",
+        );
+
+        assert!(html.contains(MATHJAX_SCRIPT));
+        assert!(html.contains("<h1>Attention &amp; Transformers</h1>"));
+        assert!(html.contains("<p>\\(x^2\\)</p>"));
+    }
+
+    fn note(paper_id: &str, title: &str, latex_content: &str) -> GeneratedNote {
+        GeneratedNote {
+            paper_id: paper_id.to_string(),
+            title: title.to_string(),
+            authors: vec![],
+            keywords: vec![],
+            code_links: vec![],
+            latex_content: latex_content.to_string(),
+            metadata: NoteMetadata {
+                generated_at: String::new(),
+                model_used: String::new(),
+                seed: None,
+                arxiv_version: None,
+                prompt_tokens: None,
+                completion_tokens: None,
+                total_tokens: None,
+                source_words: None,
+                abstract_source: None,
+                timings: None,
+                instructions: vec![],
+                prepend_instructions: vec![],
+                tags: vec![],
+                latex_repairs: vec![],
+                compare_of: None,
+            },
+        }
+    }
+
+    #[test]
+    fn test_append_note_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let combined = dir.path().join("all.tex");
+        let first = note(
+            "2401.00001",
+            "First",
+            "\\documentclass{ctexart}\n\\begin{document}\n\\maketitle\nFirst body.\n\\end{document}",
+        );
+        let second = note("2401.00002", "Second", "Second body.");
+
+        append_note(&first, OutputFormat::Latex, &combined).unwrap();
+        append_note(&second, OutputFormat::Latex, &combined).unwrap();
+
+        let text = fs::read_to_string(&combined).unwrap();
+        let first_at = text
+            .find("\\section*{First (arXiv:2401.00001)}\nFirst body.")
+            .unwrap();
+        let second_at = text
+            .find("\\section*{Second (arXiv:2401.00002)}\nSecond body.")
+            .unwrap();
+        assert!(first_at < second_at);
+        assert!(!text.contains("\\documentclass"));
+        assert!(!text.contains("\\maketitle"));
+    }
+
+    #[test]
+    fn test_equation_cap_prefers_display() {
+        let equation = |content: &str, display| Equation {
+            content: content.to_string(),
+            display,
+            label: None,
+            referenced: false,
+            section: None,
+        };
+        let equations = [
+            equation("x", false),
+            equation("E = mc^2", true),
+            equation("y", false),
+            equation("\\nabla f = 0", true),
+            equation("z", false),
+        ];
+
+        let (selected, omitted) = select_equations(&equations, 3, &[]);
+        let contents: Vec<&str> = selected.iter().map(|e| e.content.as_str()).collect();
+        assert_eq!(contents, ["x", "E = mc^2", "\\nabla f = 0"]);
+        assert_eq!(omitted, 2);
+
+        let (selected, omitted) = select_equations(&equations, 0, &[]);
+        assert!(selected.is_empty());
+        assert_eq!(omitted, 5);
+    }
+
+    #[test]
+    fn test_referenced_equation_is_kept_under_a_tight_cap() {
+        let equation = |content: &str, display, label: Option<&str>, section: &str| Equation {
+            content: content.to_string(),
+            display,
+            label: label.map(String::from),
+            referenced: label.is_some(),
+            section: Some(section.to_string()),
+        };
+        let equations = [
+            equation("x_t", false, None, "Introduction"),
+            equation("a + b", true, None, "Related Work"),
+            equation("h = W x", false, None, "Method"),
+            equation(
+                "\\mathcal{L} = -\\log p \\label{eq:loss}",
+                true,
+                Some("eq:loss"),
+                "Training",
+            ),
+        ];
+        let key_sections = crate::config::DEFAULT_EQUATION_SECTIONS.map(String::from);
+
+        let (selected, omitted) = select_equations(&equations, 1, &key_sections);
+        assert_eq!(selected[0].label.as_deref(), Some("eq:loss"));
+        assert_eq!(omitted, 3);
+
+        let contents: Vec<&str> = select_equations(&equations, 3, &key_sections)
+            .0
+            .iter()
+            .map(|e| e.content.as_str())
+            .collect();
+        assert_eq!(
+            contents,
+            ["a + b", "h = W x", equations[3].content.as_str()]
+        );
+    }
+
+    #[test]
+    fn test_path_to_working_dir() {
+        assert_eq!(path_to_working_dir(Path::new("tex")).unwrap(), "../../");
+        assert_eq!(
+            path_to_working_dir(Path::new("./notes/papers")).unwrap(),
+            "../../../"
+        );
+    }
+
+    #[test]
+    fn test_extract_document_body() {
+        let full = "\\documentclass{article}\n\\begin{document}\nHello\n\\end{document}";
+        assert_eq!(extract_document_body(full), "Hello");
+        assert_eq!(extract_document_body("Just a body"), "Just a body");
     }
 }
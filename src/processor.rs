@@ -1,117 +1,318 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use regex::Regex;
+use std::collections::{HashMap, HashSet};
 use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
 
-use crate::downloader::PaperData;
+use crate::config::{CleanupMode, RunOptions};
+use crate::downloader::{cached_archive_name, PaperData};
+use crate::events::{Event, EventLog};
 use crate::extractor::{ArchiveExtractor, ExtractedContent};
+use crate::timing::{self, StageTimings};
+use crate::util;
 
-#[derive(Debug, serde::Serialize)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct ProcessedContent {
     pub paper_id: String,
     pub title: String,
     pub authors: Vec<String>,
     pub abstract_text: String,
+    /// Further abstracts (e.g. a second-language version), excluding `abstract_text`
+    pub alternate_abstracts: Vec<String>,
+    #[serde(default)]
+    pub abstract_source: AbstractSource,
     pub sections: Vec<Section>,
+    /// Author keywords and ACM CCS concepts, in order, without duplicates
+    #[serde(default)]
+    pub keywords: Vec<String>,
+    /// Repository URLs found in the source, e.g. `https://github.com/org/repo`
+    #[serde(default)]
+    pub code_links: Vec<String>,
+    /// Items of the intro's "our contributions are:" list, if there is one
+    #[serde(default)]
+    pub contributions: Vec<String>,
     pub figure_references: Vec<String>,
-    pub equations: Vec<String>,
+    /// `figure` environments with their captions, in document order
+    #[serde(default)]
+    pub figures: Vec<Figure>,
+    pub equations: Vec<Equation>,
+    /// Theorems, lemmas, definitions etc., in document order
+    #[serde(default)]
+    pub statements: Vec<MathStatement>,
+    /// Pseudocode blocks, in document order
+    #[serde(default)]
+    pub algorithms: Vec<AlgorithmInfo>,
+    /// arXiv version of the processed source, when known
+    #[serde(default)]
+    pub arxiv_version: Option<u32>,
+    /// Entries of the bibliography, in order
+    #[serde(default)]
+    pub references: Vec<Reference>,
     pub full_text: String,
     pub image_files: Vec<String>,
 }
 
-#[derive(Debug, serde::Serialize)]
+/// Where the extracted content for a paper is kept, so `reprocess` can rerun
+/// note generation without downloading and extracting again.
+pub fn processed_path(options: &RunOptions, paper_id: &str) -> PathBuf {
+    options.work_dir.join(paper_id).join("processed.json")
+}
+
+impl ProcessedContent {
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let json = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        serde_json::from_str(&json).with_context(|| format!("Invalid {}", path.display()))
+    }
+}
+
+/// One bibliography entry, e.g. a `\\bibitem` of a `thebibliography` environment.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Reference {
+    /// Citation key used by `\\cite`
+    pub key: String,
+    /// The optional `[label]` printed in place of a number
+    pub label: Option<String>,
+    /// The entry's text with LaTeX markup cleaned up
+    pub text: String,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Equation {
+    pub content: String,
+    /// Set for `equation` environments, unset for inline `$...$` math
+    pub display: bool,
+    /// The `\\label{...}` key, e.g. "eq:loss"
+    #[serde(default)]
+    pub label: Option<String>,
+    /// The label is cited somewhere with `\\eqref`, `\\ref` or similar
+    #[serde(default)]
+    pub referenced: bool,
+    /// Title of the `\\section` the equation appears in, or "Abstract"
+    #[serde(default)]
+    pub section: Option<String>,
+}
+
+/// A `figure` environment: its caption and the graphics it includes.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Figure {
+    /// The `\\label{...}` key, e.g. "fig:arch"
+    pub label: Option<String>,
+    pub caption: String,
+    /// `\\includegraphics` arguments and `\\input` TikZ/PGF files, as written in the
+    /// source
+    pub graphics: Vec<String>,
+    /// Drawn with TikZ/PGF rather than included as an image
+    #[serde(default)]
+    pub tikz: bool,
+}
+
+/// A `theorem`/`lemma`/`definition`/`proposition`/`corollary` environment.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct MathStatement {
+    /// Canonical environment name, e.g. "theorem" (also for `thm`)
+    pub kind: String,
+    /// The optional `[name]` argument
+    pub name: Option<String>,
+    pub body: String,
+}
+
+/// An `algorithm` environment or a bare `algorithmic` block.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct AlgorithmInfo {
+    /// The `\\label{...}` key, e.g. "alg:train"
+    pub label: Option<String>,
+    pub caption: Option<String>,
+    /// The pseudocode as written (`\\State`, `\\If{...}` and so on), trimmed line by line
+    pub body: String,
+}
+
+/// Where `abstract_text` came from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AbstractSource {
+    /// Extracted from the LaTeX source
+    #[default]
+    Latex,
+    /// Extracted from arXiv's HTML rendering (`--source html`)
+    Html,
+    /// The abstract listed by the arXiv API, used when extraction found none or
+    /// only a fragment
+    ArxivApi,
+    /// No abstract was found
+    Missing,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Section {
     pub title: String,
     pub content: String,
     pub level: u8,
+    /// Comes after `\\appendix` (or inside an `appendices` environment)
+    #[serde(default)]
+    pub appendix: bool,
 }
 
 pub struct PaperProcessor {
     extractor: ArchiveExtractor,
+    /// What happens to the downloaded archive once it has been processed
+    cleanup: CleanupMode,
+    /// Where `CleanupMode::Cache` moves archives
+    cache_dir: PathBuf,
+    /// Argument-less layout commands dropped from the cleaned text
+    strip_commands: Vec<String>,
+    /// Keep `\\begin{equation}`-style wrappers so equations stay recognisable inline
+    keep_math_environments: bool,
+    events: EventLog,
 }
 
 impl PaperProcessor {
-    pub fn new() -> Self {
+    pub fn new(options: &RunOptions) -> Self {
         Self {
-            extractor: ArchiveExtractor::new(),
+            extractor: ArchiveExtractor::with_main_hints(options.main_hints.clone())
+                .include_ancillary(options.include_ancillary),
+            cleanup: options.cleanup,
+            cache_dir: options.archive_cache_dir(),
+            strip_commands: options.strip_commands.clone(),
+            keep_math_environments: options.combine_equations,
+            events: options.events.clone(),
         }
     }
 
-    pub async fn process(&self, paper_data: PaperData) -> Result<ProcessedContent> {
+    /// Extract and parse a downloaded archive, recording how long each step took.
+    pub async fn process(
+        &self,
+        paper_data: PaperData,
+        timings: &mut StageTimings,
+    ) -> Result<ProcessedContent> {
         let archive_path = paper_data.archive_path.clone();
+        let paper_id = paper_data.paper_id.clone();
 
         // Extract archive contents
+        let start = Instant::now();
         let extracted = self.extractor.extract(paper_data)?;
+        timings.extract_secs = timing::secs_since(start);
+        self.events.emit(Event::ExtractDone {
+            paper_id: &paper_id,
+            tex_files: extracted.tex_files.len(),
+            image_files: extracted.image_files.len(),
+            skipped_entries: extracted.skipped_entries,
+            secs: timings.extract_secs,
+        });
 
         // Process the extracted content
+        let start = Instant::now();
         let result = self.process_extracted_content(extracted);
+        timings.process_secs = timing::secs_since(start);
 
-        // Clean up the downloaded archive after successful processing
-        if result.is_ok() && archive_path.exists() {
-            if let Err(e) = std::fs::remove_file(&archive_path) {
-                eprintln!(
-                    "Warning: Failed to remove downloaded archive {}: {}",
-                    archive_path.display(),
-                    e
-                );
+        // Clean up the downloaded archive after successful processing; a local
+        // source directory is only ever copied
+        if result.is_ok() && self.cleanup == CleanupMode::Keep {
+            println!("Retained downloaded archive: {}", archive_path.display());
+        } else if result.is_ok() && archive_path.is_file() {
+            self.clean_up_archive(&archive_path, &paper_id);
+        }
+
+        result
+    }
+
+    fn clean_up_archive(&self, archive_path: &Path, paper_id: &str) {
+        if self.cleanup == CleanupMode::Cache {
+            let name = if archive_path.extension().is_some_and(|ext| ext == "zip") {
+                format!("{}.zip", paper_id.replace('/', "_"))
             } else {
-                println!("Cleaned up downloaded archive: {}", archive_path.display());
+                cached_archive_name(paper_id)
+            };
+            let cached = self.cache_dir.join(name);
+            match fs::create_dir_all(&self.cache_dir)
+                .and_then(|_| fs::rename(archive_path, &cached))
+            {
+                Ok(()) => println!("Moved downloaded archive to: {}", cached.display()),
+                Err(e) => eprintln!(
+                    "Warning: Failed to move downloaded archive {} to {}: {e}",
+                    archive_path.display(),
+                    self.cache_dir.display()
+                ),
             }
+            return;
         }
 
-        result
+        if let Err(e) = std::fs::remove_file(archive_path) {
+            eprintln!(
+                "Warning: Failed to remove downloaded archive {}: {}",
+                archive_path.display(),
+                e
+            );
+        } else {
+            println!("Cleaned up downloaded archive: {}", archive_path.display());
+        }
     }
 
     fn process_extracted_content(&self, extracted: ExtractedContent) -> Result<ProcessedContent> {
+        // Extract paper ID from the output directory name
+        let paper_id = extracted
+            .extracted_dir
+            .parent()
+            .and_then(|p| p.file_name())
+            .and_then(|n| n.to_str())
+            .unwrap_or("unknown")
+            .to_string();
+
+        println!("Using paper ID: {paper_id}");
+
         let mut full_text = String::new();
         let mut title = String::new();
         let mut authors = Vec::new();
         let mut abstract_text = String::new();
+        let mut alternate_abstracts = Vec::new();
         let mut sections = Vec::new();
+        let mut keywords = Vec::new();
+        let mut code_links = Vec::new();
         let mut figure_references = Vec::new();
+        let mut figures = Vec::new();
+        let mut contributions = Vec::new();
         let mut equations = Vec::new();
+        let mut statements = Vec::new();
+        let mut algorithms = Vec::new();
+        let mut references = Vec::new();
 
-        // Collect content from all TeX files
-        let mut all_content = String::new();
-        let mut files_read = 0;
-
-        // First, try to read the main TeX file
-        if let Some(main_tex) = &extracted.main_tex_file {
-            if main_tex.exists() {
-                println!("Reading main TeX file: {}", main_tex.display());
-                if let Ok(content) = fs::read_to_string(main_tex) {
-                    all_content.push_str(&content);
-                    all_content.push_str("\n\n");
-                    files_read += 1;
-                }
-            }
-        }
-
-        // Then read all other TeX files to get complete content
-        for tex_file in &extracted.tex_files {
-            if tex_file.exists() {
-                println!("Reading TeX file: {}", tex_file.display());
-                if let Ok(content) = fs::read_to_string(tex_file) {
-                    all_content.push_str(&content);
-                    all_content.push_str("\n\n");
-                    files_read += 1;
-                }
-            }
-        }
+        let (all_content, files_read) = read_tex_sources(&extracted);
 
         if files_read > 0 {
+            let all_content = strip_excluded_blocks(&all_content);
             full_text = self.clean_tex_content(&all_content);
+            if crate::arxiv::is_withdrawal_notice(&full_text, false) {
+                return Err(crate::arxiv::Withdrawn { paper_id }.into());
+            }
 
             // Extract metadata from combined content
             title = self.extract_title(&all_content);
             authors = self.extract_authors(&all_content);
-            abstract_text = self.extract_abstract(&all_content);
+            (abstract_text, alternate_abstracts) = self.extract_abstracts(&all_content);
+            keywords = self.extract_keywords(&all_content);
+            code_links = self.extract_code_links(&all_content);
 
-            // Extract sections
-            sections = self.extract_sections(&all_content);
+            // Extract sections, with pseudocode pulled out of them
+            let without_algorithms;
+            (algorithms, without_algorithms) = self.extract_algorithms(&all_content);
+            sections = self.extract_sections(&without_algorithms);
 
             // Extract figures and equations
             figure_references = self.extract_figures(&all_content);
+            figures = self.extract_figure_captions(&all_content);
+            contributions = self.extract_contributions(&all_content);
             equations = self.extract_equations(&all_content);
+            statements = self.extract_statements(&all_content);
+            references = self.extract_bibitems(&all_content);
 
             println!("Successfully processed {files_read} TeX files");
         } else {
@@ -125,49 +326,75 @@ impl PaperProcessor {
             .map(|p| p.to_string_lossy().to_string())
             .collect();
 
-        // Extract paper ID from the output directory name
-        let paper_id = extracted
-            .extracted_dir
-            .parent()
-            .and_then(|p| p.file_name())
-            .and_then(|n| n.to_str())
-            .unwrap_or("unknown")
-            .to_string();
-
-        println!("Using paper ID: {paper_id}");
-
         Ok(ProcessedContent {
+            arxiv_version: crate::arxiv::version_of(&paper_id),
+            references,
             paper_id,
             title,
             authors,
+            abstract_source: if abstract_text.is_empty() {
+                AbstractSource::Missing
+            } else {
+                AbstractSource::Latex
+            },
             abstract_text,
+            alternate_abstracts,
             sections,
+            keywords,
+            code_links,
             figure_references,
+            figures,
+            contributions,
             equations,
+            statements,
+            algorithms,
             full_text,
             image_files,
         })
     }
 
     fn clean_tex_content(&self, content: &str) -> String {
-        // Remove comments
-        let re = Regex::new(r"(?m)%.*$").unwrap();
-        let cleaned = re.replace_all(content, "");
+        let cleaned = strip_comments(content);
 
-        // Remove common LaTeX commands but preserve content within commands
-        let re = Regex::new(r"\\(usepackage|documentclass|documentstyle|pagestyle|thispagestyle|geometry|hypersetup)\{[^}]*\}").unwrap();
+        // Remove preamble and page-setup commands together with their arguments,
+        // including the optional one (\documentclass[twocolumn]{IEEEtran})
+        let re = Regex::new(r"\\(usepackage|documentclass|documentstyle|pagestyle|thispagestyle|geometry|hypersetup)\s*(?:\[[^\]]*\])?\s*\{[^}]*\}").unwrap();
         let cleaned = re.replace_all(&cleaned, "");
 
-        // Remove begin and end but keep content
-        let re = Regex::new(r"\\(begin|end)\{[^}]*\}").unwrap();
-        let cleaned = re.replace_all(&cleaned, "");
+        // Remove begin and end but keep content, and with --combine-equations keep
+        // those of display math too
+        let re = Regex::new(r"\\(?:begin|end)\{([^}]*)\}").unwrap();
+        let cleaned = re.replace_all(&cleaned, |caps: &regex::Captures| {
+            if self.keep_math_environments && MATH_ENVIRONMENTS.contains(&&caps[1]) {
+                caps[0].to_string()
+            } else {
+                String::new()
+            }
+        });
 
         // Remove other common formatting commands but keep arguments
         let re = Regex::new(
-            r"\\(textbf|textit|emph|texttt|small|large|Large|LARGE|huge|Huge)\{([^}]*)\}",
+            r"\\(textbf|textit|textsc|textrm|textsf|textup|emph|texttt|small|large|Large|LARGE|huge|Huge)\{([^}]*)\}",
         )
         .unwrap();
         let cleaned = re.replace_all(&cleaned, "$2");
+        // and old-style font switches in a group, as in `{\\em word}`
+        let re = Regex::new(r"\{\\(?:em|it|bf|sc|tt|sl|rm|sf)\b\s*([^{}]*)\}").unwrap();
+        let cleaned = re.replace_all(&cleaned, "$1");
+
+        // Remove layout commands that take no argument; `\b` keeps `\newpage` from
+        // matching `\newpaper`
+        let cleaned = if self.strip_commands.is_empty() {
+            cleaned.into_owned()
+        } else {
+            let names: Vec<String> = self
+                .strip_commands
+                .iter()
+                .map(|n| regex::escape(n))
+                .collect();
+            let re = Regex::new(&format!(r"\\(?:{})\b\*?[ \t]*", names.join("|"))).unwrap();
+            re.replace_all(&cleaned, "").into_owned()
+        };
 
         // Clean up extra whitespace
         let cleaned = cleaned.replace("\n\n\n", "\n\n");
@@ -176,32 +403,36 @@ impl PaperProcessor {
             .unwrap()
             .replace_all(&cleaned, "\n\n");
 
-        cleaned.trim().to_string()
+        util::unescape_latex(cleaned.trim())
     }
 
     fn extract_title(&self, content: &str) -> String {
-        let re = Regex::new(r"\\title\{([^}]*)\}").unwrap();
-        if let Some(caps) = re.captures(content) {
-            let title = caps.get(1).map_or("", |m| m.as_str());
-            // Clean up LaTeX formatting in title
-            let cleaned = title.replace("\\", "");
-            cleaned.trim().to_string()
-        } else {
-            "Untitled".to_string()
-        }
+        // `\title[Short]{Full}` (Springer, amsart) puts the running title first;
+        // `\titlerunning{...}` and `\shorttitle{...}` are never matched
+        let Some(title) = command_argument(content, "title") else {
+            return "Untitled".to_string();
+        };
+        let title = match command_argument(content, "subtitle") {
+            Some(subtitle) => format!("{}: {}", title.trim(), subtitle.trim()),
+            None => title.to_string(),
+        };
+
+        // Clean up LaTeX formatting in title
+        let cleaned = util::unescape_latex(&title).replace(['\\', '{', '}'], "");
+        cleaned.split_whitespace().collect::<Vec<_>>().join(" ")
     }
 
     fn extract_authors(&self, content: &str) -> Vec<String> {
         let re = Regex::new(r"\\author\{([^}]*)\}").unwrap();
+        // Commas, `\and`, and line breaks (`\\`, `\\*`, `\\[2mm]`) between names
+        let separator_re = Regex::new(r",|\\and\b|\\\\\*?(?:\[[^\]]*\])?").unwrap();
         let mut authors = Vec::new();
 
         for caps in re.captures_iter(content) {
             if let Some(author) = caps.get(1) {
-                // Split by commas and clean up
-                let author_names: Vec<String> = author
-                    .as_str()
-                    .split(',')
-                    .map(|s| s.trim().to_string())
+                let author_names: Vec<String> = separator_re
+                    .split(author.as_str())
+                    .map(|s| util::unescape_latex(s.trim()))
                     .filter(|s| !s.is_empty())
                     .collect();
                 authors.extend(author_names);
@@ -211,12 +442,35 @@ impl PaperProcessor {
         authors
     }
 
+    /// Return the primary abstract and any alternates. When a paper ships several
+    /// `abstract` environments (e.g. English plus a translation), the English one wins.
+    fn extract_abstracts(&self, content: &str) -> (String, Vec<String>) {
+        let re = Regex::new(r"(?s)\\begin\{abstract\}(.*?)\\end\{abstract\}").unwrap();
+        let mut abstracts: Vec<String> = re
+            .captures_iter(content)
+            .map(|caps| self.clean_tex_content(caps[1].trim()))
+            .filter(|text| !text.is_empty())
+            .collect();
+
+        if abstracts.len() < 2 {
+            return (self.extract_abstract(content), Vec::new());
+        }
+
+        // Reversed so that ties go to the first abstract
+        let primary = (0..abstracts.len())
+            .rev()
+            .max_by(|&a, &b| english_score(&abstracts[a]).total_cmp(&english_score(&abstracts[b])))
+            .unwrap_or(0);
+        let primary = abstracts.remove(primary);
+
+        (primary, abstracts)
+    }
+
     fn extract_abstract(&self, content: &str) -> String {
         // Try different abstract patterns
         let patterns = [
-            r"\\begin\{abstract\}(.*?)\\end\{abstract\}",
+            r"(?s)\\begin\{abstract\}(.*?)\\end\{abstract\}",
             r"\\abstract\{([^}]*)\}",
-            r"\\section\*?\{abstract\}([^\\]*)",
         ];
 
         for pattern in &patterns {
@@ -229,6 +483,24 @@ impl PaperProcessor {
             }
         }
 
+        // Some classes put the abstract under a heading such as \section*{Abstract}
+        // or \paragraph{Abstract}; take everything up to the next sectioning command
+        let heading_re =
+            Regex::new(r"(?i)\\(?:section|subsection|paragraph)\*?\{\s*abstract\.?\s*\}").unwrap();
+        let next_heading_re = Regex::new(
+            r"\\(?:part|chapter|section|subsection|subsubsection|paragraph)\*?\{|\\end\{document\}",
+        )
+        .unwrap();
+
+        if let Some(heading) = heading_re.find(content) {
+            let rest = &content[heading.end()..];
+            let end = next_heading_re.find(rest).map_or(rest.len(), |m| m.start());
+            let abstract_text = rest[..end].trim();
+            if !abstract_text.is_empty() {
+                return self.clean_tex_content(abstract_text);
+            }
+        }
+
         String::new()
     }
 
@@ -257,6 +529,15 @@ impl PaperProcessor {
         // Sort by position
         positions.sort_by_key(|k| k.0);
 
+        // Only in the body; a preamble may redefine `\appendix`
+        let body_start = content
+            .find("\\begin{document}")
+            .map_or(0, |i| i + "\\begin{document}".len());
+        let appendix_start = Regex::new(r"\\appendix\b|\\begin\{appendices\}")
+            .unwrap()
+            .find_at(content, body_start)
+            .map(|m| m.start());
+
         // Extract content between sections
         for i in 0..positions.len() {
             let (start, _, title, level) = positions[i];
@@ -273,50 +554,1582 @@ impl PaperProcessor {
                 title: title.to_string(),
                 content: cleaned_content,
                 level,
+                appendix: appendix_start.is_some_and(|appendix| start > appendix),
             });
         }
 
-        sections
+        dedupe_sections(sections)
     }
 
     fn extract_figures(&self, content: &str) -> Vec<String> {
-        let re = Regex::new(r"\\includegraphics(?:\[[^]]*\])?\{([^}]*)\}").unwrap();
+        let re = Regex::new(
+            r"\\includegraphics(?:\[[^]]*\])?\{([^}]*)\}|\\(?:input|include)\s*\{([^}]*\.(?:tikz|pgf))\}",
+        )
+        .unwrap();
         let mut figures = Vec::new();
 
         for caps in re.captures_iter(content) {
-            if let Some(fig) = caps.get(1) {
-                figures.push(fig.as_str().to_string());
+            if let Some(fig) = caps.get(1).or_else(|| caps.get(2)) {
+                figures.push(fig.as_str().trim().to_string());
             }
         }
 
         figures
     }
 
-    fn extract_equations(&self, content: &str) -> Vec<String> {
-        let mut equations = Vec::new();
+    /// Links to the paper's code: `\\url{...}` and `\\href{...}` targets and bare
+    /// URLs on a known code host, with an owner or project in the path. Returned as
+    /// `https://` URLs in document order, without duplicates.
+    fn extract_code_links(&self, content: &str) -> Vec<String> {
+        let command = Regex::new(r"\\(?:url|href)\s*\{([^}]*)\}").unwrap();
+        let mut candidates: Vec<String> = command
+            .captures_iter(content)
+            .map(|caps| caps[1].to_string())
+            .collect();
+        let hosts = CODE_HOSTS.map(regex::escape).join("|");
+        let bare = Regex::new(&format!(
+            r"(?i)(?:https?://)?(?:www\.)?(?:{hosts})/(?:[^\s{{}}\\]|\\[_%#&~])+"
+        ))
+        .unwrap();
+        candidates.extend(
+            bare.find_iter(&command.replace_all(content, " "))
+                .map(|m| m.as_str().to_string()),
+        );
+
+        let link = Regex::new(r"(?i)^(?:https?://)?(?:www\.)?([^/]+)/(.+)$").unwrap();
+        let mut links: Vec<String> = Vec::new();
+        for candidate in candidates {
+            let url = candidate
+                .trim()
+                .replace("\\textasciitilde{}", "~")
+                .replace("\\~{}", "~")
+                .replace('\\', "");
+            let Some(caps) = link.captures(&url) else {
+                continue;
+            };
+            let host = caps[1].to_lowercase();
+            let path = caps[2].trim_end_matches(['.', ',', ';', ':', ')', ']', '/']);
+            if path.is_empty() || !CODE_HOSTS.contains(&host.as_str()) {
+                continue;
+            }
+            let url = format!("https://{host}/{path}");
+            if !links.iter().any(|l| l.eq_ignore_ascii_case(&url)) {
+                links.push(url);
+            }
+        }
+        links
+    }
+
+    /// Keywords from `\\keywords{...}` and the `keywords`/`IEEEkeywords`
+    /// environments, then ACM CCS concepts from `\\ccsdesc{...}` and `CCSXML`
+    /// blocks. A concept path such as `Computing methodologies~Neural networks`
+    /// contributes its most specific part.
+    fn extract_keywords(&self, content: &str) -> Vec<String> {
+        let mut raw: Vec<String> = Vec::new();
+
+        let command = Regex::new(r"\\keywords\s*\{").unwrap();
+        for m in command.find_iter(content) {
+            // Parse the braces from this occurrence on
+            if let Some(argument) = command_argument(&content[m.start()..], "keywords") {
+                raw.extend(split_keywords(argument));
+            }
+        }
+        let environment = Regex::new(
+            r"(?s)\\begin\{(?:keywords|IEEEkeywords)\}(.*?)\\end\{(?:keywords|IEEEkeywords)\}",
+        )
+        .unwrap();
+        for caps in environment.captures_iter(content) {
+            raw.extend(split_keywords(&caps[1]));
+        }
+
+        let ccsdesc = Regex::new(r"\\ccsdesc\s*(?:\[[^\]]*\])?\s*\{([^}]*)\}").unwrap();
+        let ccsxml = Regex::new(r"(?s)\\begin\{CCSXML\}(.*?)\\end\{CCSXML\}").unwrap();
+        let concept = Regex::new(r"(?s)<concept_desc>(.*?)</concept_desc>").unwrap();
+        let concepts = ccsdesc
+            .captures_iter(content)
+            .map(|caps| caps[1].to_string())
+            .chain(ccsxml.captures_iter(content).flat_map(|block| {
+                concept
+                    .captures_iter(&block[1])
+                    .map(|caps| unescape_xml(&caps[1]))
+                    .collect::<Vec<_>>()
+            }));
+        for concept in concepts {
+            raw.extend(concept.rsplit('~').next().map(str::to_string));
+        }
+
+        let mut keywords: Vec<String> = Vec::new();
+        for keyword in raw {
+            let keyword = self.clean_tex_content(keyword.trim());
+            let keyword = keyword.trim().trim_end_matches('.').trim();
+            if !keyword.is_empty() && !keywords.iter().any(|k| k.eq_ignore_ascii_case(keyword)) {
+                keywords.push(keyword.to_string());
+            }
+        }
+        keywords
+    }
+
+    /// Items of the first `itemize`/`enumerate` introduced by text mentioning
+    /// "contribution", e.g. "Our main contributions are as follows:".
+    fn extract_contributions(&self, content: &str) -> Vec<String> {
+        // How far before a list its introduction is looked for
+        const LEAD_IN_CHARS: usize = 300;
+        let list_re =
+            Regex::new(r"(?s)\\begin\{(?:itemize|enumerate)\}(.*?)\\end\{(?:itemize|enumerate)\}")
+                .unwrap();
+        let item_re = Regex::new(r"\\item\b\s*(?:\[[^\]]*\])?").unwrap();
+
+        let mut previous_end = 0;
+        for list in list_re.captures_iter(content) {
+            let whole = list.get(0).unwrap();
+            let lead_in = &content[previous_end..whole.start()];
+            let from = lead_in
+                .char_indices()
+                .rev()
+                .nth(LEAD_IN_CHARS - 1)
+                .map_or(0, |(i, _)| i);
+            previous_end = whole.end();
+            if !lead_in[from..].to_lowercase().contains("contribution") {
+                continue;
+            }
+
+            return item_re
+                .split(&list[1])
+                .skip(1)
+                .map(|item| {
+                    let item = self.clean_tex_content(item);
+                    item.split_whitespace().collect::<Vec<_>>().join(" ")
+                })
+                .filter(|item| !item.is_empty())
+                .collect();
+        }
+        Vec::new()
+    }
+
+    fn extract_figure_captions(&self, content: &str) -> Vec<Figure> {
+        let figure_re = Regex::new(r"(?s)\\begin\{figure\*?\}(.*?)\\end\{figure\*?\}").unwrap();
+        let label_re = Regex::new(r"\\label\{[^}]*\}").unwrap();
+
+        let mut figures: Vec<Figure> = Vec::new();
+        for caps in figure_re.captures_iter(content) {
+            let body = &caps[1];
+            let caption = command_argument(body, "caption").unwrap_or("");
+            let caption = self.clean_tex_content(&label_re.replace_all(caption, ""));
+            let graphics = self.extract_figures(body);
+            let figure = Figure {
+                label: command_argument(body, "label").map(|l| l.trim().to_string()),
+                caption: caption.split_whitespace().collect::<Vec<_>>().join(" "),
+                tikz: body.contains("\\begin{tikzpicture}")
+                    || graphics.iter().any(|g| is_tikz_file(g)),
+                graphics,
+            };
+            if !figure.caption.is_empty() || !figure.graphics.is_empty() || figure.tikz {
+                position_or_push(&mut figures, figure);
+            }
+        }
+        figures
+    }
+
+    fn extract_statements(&self, content: &str) -> Vec<MathStatement> {
+        let name_re = Regex::new(r"^\s*\[([^\]]*)\]").unwrap();
+
+        let mut statements: Vec<MathStatement> = Vec::new();
+        for environment in environments(content, &STATEMENT_ENVIRONMENTS) {
+            let name = name_re.captures(environment.body);
+            let body = match &name {
+                Some(name) => &environment.body[name.get(0).unwrap().end()..],
+                None => environment.body,
+            };
+            let statement = MathStatement {
+                kind: canonical_statement_kind(environment.name.trim_end_matches('*')).to_string(),
+                name: name
+                    .map(|name| name[1].trim().to_string())
+                    .filter(|n| !n.is_empty()),
+                body: body.trim().to_string(),
+            };
+            position_or_push(&mut statements, statement);
+        }
+
+        statements
+    }
+
+    /// `algorithm` environments, with their caption and the pseudocode inside
+    /// (the `algorithmic` block, or the whole body for `algorithm2e`), and
+    /// `algorithmic` blocks outside any of them. Also returns `content` with each
+    /// block replaced by an "[Algorithm N]" pointer, so section text doesn't carry
+    /// pseudocode mangled by the cleaning.
+    fn extract_algorithms(&self, content: &str) -> (Vec<AlgorithmInfo>, String) {
+        let algorithmic_re =
+            Regex::new(r"(?s)\\begin\{algorithmic\}(?:\s*\[[^\]]*\])?(.*?)\\end\{algorithmic\}")
+                .unwrap();
+        let label_re = Regex::new(r"\\label\s*\{([^}]*)\}").unwrap();
+
+        let mut blocks: Vec<(std::ops::Range<usize>, AlgorithmInfo)> = Vec::new();
+        for environment in environments(content, &["algorithm"]) {
+            // Each block is replaced as a whole, including anything nested in it
+            if blocks
+                .last()
+                .is_some_and(|(block, _)| block.end > environment.range.start)
+            {
+                continue;
+            }
+            let body = environment.body;
+            let caption = command_argument(body, "caption").map(|c| {
+                let caption = self.clean_tex_content(c);
+                caption.split_whitespace().collect::<Vec<_>>().join(" ")
+            });
+            let pseudocode = match algorithmic_re.captures(body) {
+                Some(inner) => inner[1].to_string(),
+                None => {
+                    let mut rest = body.to_string();
+                    if let Some((command, _)) = command_span(body, "caption") {
+                        rest.replace_range(command, "");
+                    }
+                    label_re.replace_all(&rest, "").into_owned()
+                }
+            };
+            blocks.push((
+                environment.range,
+                AlgorithmInfo {
+                    label: label_re.captures(body).map(|l| l[1].trim().to_string()),
+                    caption: caption.filter(|c| !c.is_empty()),
+                    body: pseudocode_lines(&pseudocode),
+                },
+            ));
+        }
+        for caps in algorithmic_re.captures_iter(content) {
+            let range = caps.get(0).unwrap().range();
+            if blocks.iter().any(|(block, _)| block.contains(&range.start)) {
+                continue;
+            }
+            blocks.push((
+                range,
+                AlgorithmInfo {
+                    label: None,
+                    caption: None,
+                    body: pseudocode_lines(&caps[1]),
+                },
+            ));
+        }
+        blocks.sort_by_key(|(range, _)| range.start);
+
+        let mut algorithms: Vec<AlgorithmInfo> = Vec::new();
+        let mut remaining = String::new();
+        let mut copied_to = 0;
+        for (range, algorithm) in blocks {
+            let number = position_or_push(&mut algorithms, algorithm) + 1;
+            remaining.push_str(&content[copied_to..range.start]);
+            remaining.push_str(&format!("[Algorithm {number}]"));
+            copied_to = range.end;
+        }
+        remaining.push_str(&content[copied_to..]);
+        (algorithms, remaining)
+    }
+
+    /// Entries of inline `thebibliography` environments, for papers without a `.bib`.
+    fn extract_bibitems(&self, content: &str) -> Vec<Reference> {
+        let bibliography_re = Regex::new(
+            r"(?s)\\begin\{thebibliography\}(?:\{[^}]*\})?(.*?)\\end\{thebibliography\}",
+        )
+        .unwrap();
+        let bibitem_re = Regex::new(r"\\bibitem\s*(?:\[([^\]]*)\])?\s*\{([^}]*)\}").unwrap();
+        // \newblock and the old-style font switches bibliography styles emit
+        let markup_re = Regex::new(r"\\(?:newblock|em|it|bf|sc|tt|sl|rm)\b").unwrap();
+
+        let mut references: Vec<Reference> = Vec::new();
+        for bibliography in bibliography_re.captures_iter(content) {
+            let body = bibliography.get(1).map_or("", |m| m.as_str());
+            let items: Vec<_> = bibitem_re.captures_iter(body).collect();
+            for (i, item) in items.iter().enumerate() {
+                let start = item.get(0).map_or(0, |m| m.end());
+                let end = items
+                    .get(i + 1)
+                    .and_then(|next| next.get(0))
+                    .map_or(body.len(), |m| m.start());
+                let key = item[2].trim().to_string();
+                // The key alone identifies an entry
+                if references.iter().any(|r| r.key == key) {
+                    continue;
+                }
+
+                let text = markup_re.replace_all(&body[start..end], " ");
+                let text = util::unescape_latex(&text).replace(['{', '}'], "");
+                references.push(Reference {
+                    key,
+                    label: item.get(1).map(|m| m.as_str().trim().to_string()),
+                    text: text.split_whitespace().collect::<Vec<_>>().join(" "),
+                });
+            }
+        }
+        references
+    }
+
+    fn extract_equations(&self, content: &str) -> Vec<Equation> {
+        let mut found: Vec<(usize, String, bool)> = Vec::new();
 
         // Extract display equations
         let re = Regex::new(r"\\begin\{equation\}(.*?)\\end\{equation\}").unwrap();
         for caps in re.captures_iter(content) {
             if let Some(eq) = caps.get(1) {
-                equations.push(eq.as_str().trim().to_string());
+                found.push((eq.start(), eq.as_str().trim().to_string(), true));
             }
         }
 
-        // Extract inline math
-        let re = Regex::new(r"\$([^$]+)\$").unwrap();
+        // Extract `$$...$$` display math and `$...$` inline math in one pass, trying
+        // `$$` first so its delimiters aren't read as an empty inline formula
+        let re = Regex::new(r"(?s)\$\$(.+?)\$\$|\$([^$]+)\$").unwrap();
         for caps in re.captures_iter(content) {
-            if let Some(eq) = caps.get(1) {
-                equations.push(eq.as_str().trim().to_string());
+            let (eq, display) = match (caps.get(1), caps.get(2)) {
+                (Some(eq), _) => (eq, true),
+                (None, Some(eq)) => (eq, false),
+                (None, None) => continue,
+            };
+            found.push((eq.start(), eq.as_str().trim().to_string(), display));
+        }
+        // Document order, which `select_equations` keeps
+        found.sort_by_key(|(offset, _, _)| *offset);
+
+        let label_re = Regex::new(r"\\label\s*\{([^}]*)\}").unwrap();
+        let ref_re = Regex::new(r"\\(?:eqref|ref|autoref|cref|Cref)\s*\{([^}]*)\}").unwrap();
+        let referenced: HashSet<&str> = ref_re
+            .captures_iter(content)
+            .flat_map(|caps| caps.get(1).unwrap().as_str().split(','))
+            .map(str::trim)
+            .collect();
+        let section_re = Regex::new(r"\\section\*?\s*(?:\[[^\]]*\])?\s*\{([^}]*)\}").unwrap();
+        let headings: Vec<(usize, &str)> = section_re
+            .captures_iter(content)
+            .map(|caps| {
+                (
+                    caps.get(0).unwrap().start(),
+                    caps.get(1).unwrap().as_str().trim(),
+                )
+            })
+            .collect();
+        let abstract_re = Regex::new(r"(?s)\\begin\{abstract\}.*?\\end\{abstract\}").unwrap();
+        let abstracts: Vec<std::ops::Range<usize>> =
+            abstract_re.find_iter(content).map(|m| m.range()).collect();
+
+        found
+            .into_iter()
+            .map(|(offset, text, display)| {
+                let label = label_re
+                    .captures(&text)
+                    .map(|caps| caps[1].trim().to_string());
+                let section = if abstracts.iter().any(|range| range.contains(&offset)) {
+                    Some("Abstract".to_string())
+                } else {
+                    headings
+                        .iter()
+                        .take_while(|(start, _)| *start < offset)
+                        .last()
+                        .map(|(_, title)| title.to_string())
+                };
+                Equation {
+                    referenced: label
+                        .as_deref()
+                        .is_some_and(|label| referenced.contains(label)),
+                    label,
+                    section,
+                    content: text,
+                    display,
+                }
+            })
+            .collect()
+    }
+}
+
+/// TeX and e-TeX conditionals, which each take a matching `\\fi`.
+const TEX_CONDITIONALS: [&str; 20] = [
+    "if",
+    "ifcat",
+    "ifnum",
+    "ifdim",
+    "ifodd",
+    "ifvmode",
+    "ifhmode",
+    "ifmmode",
+    "ifinner",
+    "ifvoid",
+    "ifhbox",
+    "ifvbox",
+    "ifx",
+    "ifeof",
+    "iftrue",
+    "iffalse",
+    "ifcase",
+    "ifdefined",
+    "ifcsname",
+    "iffontchar",
+];
+
+/// Remove text the author excluded from the document: the `\\iffalse` branch of
+/// `\\iffalse ... \\else ... \\fi` (counting nested `\\if...\\fi` pairs so the right
+/// `\\else` and `\\fi` end it) and `comment` environments. The `\\else` branch is kept,
+/// without its `\\fi`.
+fn strip_excluded_blocks(content: &str) -> String {
+    let comment_env = Regex::new(r"(?s)\\begin\{comment\}.*?\\end\{comment\}").unwrap();
+    let content = comment_env.replace_all(content, "");
+
+    // Only primitives and conditionals declared with `\\newif` open a level; other
+    // `\\if...` control words, like `\\iff` or `\\ifthenelse`, are macros
+    let declared: HashSet<String> = Regex::new(r"\\newif\s*\\(if[a-zA-Z@]+)")
+        .unwrap()
+        .captures_iter(&content)
+        .map(|caps| caps[1].to_string())
+        .collect();
+    // `\\newif\\iffoo` declares a conditional and has no `\\fi`
+    let token =
+        Regex::new(r"\\newif\s*\\if[a-zA-Z@]*|\\if[a-zA-Z@]*|\\else[a-zA-Z@]*|\\fi[a-zA-Z@]*")
+            .unwrap();
+    let mut kept = String::with_capacity(content.len());
+    let mut copied_to = 0;
+    // Depth of nested conditionals inside the current `\\iffalse` branch
+    let mut depth = 0;
+    // Conditionals open in kept text inside an `\\else` branch: `true` for the
+    // `\\iffalse` whose `\\fi` is dropped, `false` for others, whose `\\fi` stays
+    let mut open: Vec<bool> = Vec::new();
+    for m in token.find_iter(&content) {
+        let name = m.as_str();
+        let opens = name
+            .strip_prefix('\\')
+            .is_some_and(|name| TEX_CONDITIONALS.contains(&name) || declared.contains(name));
+        if depth > 0 {
+            if opens {
+                depth += 1;
+            } else if name == "\\else" && depth == 1 {
+                depth = 0;
+                copied_to = m.end();
+                open.push(true);
+            } else if name == "\\fi" {
+                depth -= 1;
+                if depth == 0 {
+                    copied_to = m.end();
+                }
+            }
+        } else if name == "\\iffalse" {
+            kept.push_str(&content[copied_to..m.start()]);
+            depth = 1;
+        } else if opens && !open.is_empty() {
+            open.push(false);
+        } else if name == "\\fi" && open.pop() == Some(true) {
+            kept.push_str(&content[copied_to..m.start()]);
+            copied_to = m.end();
+        }
+    }
+    // An unterminated `\\iffalse` excludes the rest of the file, as in TeX
+    if depth == 0 {
+        kept.push_str(&content[copied_to..]);
+    }
+    kept
+}
+
+/// Display-math environments kept in the text with `--combine-equations`.
+const MATH_ENVIRONMENTS: [&str; 12] = [
+    "equation",
+    "equation*",
+    "align",
+    "align*",
+    "gather",
+    "gather*",
+    "multline",
+    "multline*",
+    "eqnarray",
+    "eqnarray*",
+    "displaymath",
+    "split",
+];
+
+/// Hosts whose URLs are taken for links to the paper's code.
+const CODE_HOSTS: [&str; 5] = [
+    "github.com",
+    "gitlab.com",
+    "bitbucket.org",
+    "codeberg.org",
+    "huggingface.co",
+];
+
+/// Share of the source that the main file, with its inputs inlined, must make up
+/// to be used on its own. Below it the main file is taken for a stub whose
+/// `\\input`s didn't resolve.
+const MIN_MAIN_SHARE: f64 = 0.2;
+
+/// How deeply `\\input`s are followed.
+const MAX_INPUT_DEPTH: usize = 10;
+
+/// The paper's LaTeX source, without comments, and how many files it came from:
+/// the main file with its `\\input`/`\\include`d files inlined in place, or every
+/// TeX file concatenated (main file first) when there is no main file or it
+/// covers too little of the source.
+fn read_tex_sources(extracted: &ExtractedContent) -> (String, usize) {
+    let mut sources: Vec<(&PathBuf, String)> = Vec::new();
+    for tex_file in &extracted.tex_files {
+        match fs::read_to_string(tex_file) {
+            Ok(content) => sources.push((tex_file, strip_comments(&content))),
+            Err(e) => eprintln!("Warning: failed to read {}: {e}", tex_file.display()),
+        }
+    }
+    let total: usize = sources.iter().map(|(_, content)| content.len()).sum();
+
+    if let Some(main_tex) = &extracted.main_tex_file {
+        println!("Reading main TeX file: {}", main_tex.display());
+        let mut inlined = Vec::new();
+        if let Some(content) = inline_inputs(main_tex, &mut inlined, 0) {
+            if content.len() as f64 >= MIN_MAIN_SHARE * total as f64 {
+                return (content, inlined.len());
+            }
+            println!(
+                "Main TeX file {} covers only {} of {total} bytes of source; reading all TeX files",
+                main_tex.display(),
+                content.len()
+            );
+        }
+    }
+
+    // The main file (if any) first, then the rest in path order
+    sources.sort_by_key(|(path, _)| Some(*path) != extracted.main_tex_file.as_ref());
+    let mut all_content = String::new();
+    for (path, content) in &sources {
+        println!("Reading TeX file: {}", path.display());
+        all_content.push_str(content);
+        all_content.push_str("\n\n");
+    }
+    (all_content, sources.len())
+}
+
+/// `path` without comments, with each `\\input{...}`/`\\include{...}`/`\\subfile{...}`
+/// it makes replaced by the file's own inlined content (only the document body of a
+/// subfile). Inputs are resolved against the main file's directory, as LaTeX does,
+/// with `.tex` optional; ones that can't be found, or that would recurse, are left
+/// as they are.
+fn inline_inputs(path: &Path, inlined: &mut Vec<PathBuf>, depth: usize) -> Option<String> {
+    let content = strip_comments(&fs::read_to_string(path).ok()?);
+    inlined.push(path.to_path_buf());
+    let root = inlined[0].parent().unwrap_or(Path::new("")).to_path_buf();
+
+    let re = Regex::new(r"\\(input|include|subfile)\s*\{([^}]+)\}").unwrap();
+    let mut resolved = String::new();
+    let mut last = 0;
+    for caps in re.captures_iter(&content) {
+        let whole = caps.get(0).unwrap();
+        let name = caps[2].trim();
+        // TikZ sources are drawing code; leave the `\\input` for figure detection
+        let target = [root.join(name), root.join(format!("{name}.tex"))]
+            .into_iter()
+            .find(|p| p.is_file() && !inlined.contains(p) && !is_tikz_file(name));
+        let included = match target {
+            Some(target) if depth < MAX_INPUT_DEPTH => inline_inputs(&target, inlined, depth + 1),
+            _ => None,
+        };
+        let included = match included {
+            Some(included) if &caps[1] == "subfile" => Some(subfile_body(&included).to_string()),
+            included => included,
+        };
+
+        resolved.push_str(&content[last..whole.start()]);
+        match included {
+            Some(included) => {
+                resolved.push('\n');
+                resolved.push_str(&included);
+                resolved.push('\n');
             }
+            None => resolved.push_str(whole.as_str()),
         }
+        last = whole.end();
+    }
+    resolved.push_str(&content[last..]);
+    Some(resolved)
+}
 
-        equations
+/// The part of a `subfiles` chapter between `\\begin{document}` and
+/// `\\end{document}`, dropping its own preamble; the whole file if it has none.
+fn subfile_body(content: &str) -> &str {
+    let Some(start) = content.find(r"\begin{document}") else {
+        return content;
+    };
+    let body = &content[start + r"\begin{document}".len()..];
+    match body.rfind(r"\end{document}") {
+        Some(end) => &body[..end],
+        None => body,
     }
 }
 
-impl Default for PaperProcessor {
-    fn default() -> Self {
-        Self::new()
+/// Whether an `\\input` names a TikZ/PGF drawing (`figs/arch.tikz`, `plot.pgf`).
+fn is_tikz_file(name: &str) -> bool {
+    Path::new(name)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("tikz") || ext.eq_ignore_ascii_case("pgf"))
+}
+
+/// Drop repeats of a section with the same title and text, and number genuinely
+/// different sections sharing a title ("Results (2)") so the model can tell them
+/// apart.
+fn dedupe_sections(sections: Vec<Section>) -> Vec<Section> {
+    let normalize = |text: &str| text.split_whitespace().collect::<Vec<_>>().join(" ");
+    let mut kept: Vec<Section> = Vec::new();
+    let mut seen: Vec<(String, String)> = Vec::new();
+    let mut uses: HashMap<String, usize> = HashMap::new();
+    for mut section in sections {
+        let key = (
+            section.title.trim().to_lowercase(),
+            normalize(&section.content),
+        );
+        if seen.contains(&key) {
+            continue;
+        }
+        let count = uses.entry(key.0.clone()).or_default();
+        *count += 1;
+        if *count > 1 {
+            section.title = format!("{} ({count})", section.title.trim());
+        }
+        seen.push(key);
+        kept.push(section);
+    }
+    kept
+}
+
+/// Environments `extract_statements` picks up, starred or not
+const STATEMENT_ENVIRONMENTS: [&str; 10] = [
+    "theorem",
+    "thm",
+    "lemma",
+    "lem",
+    "definition",
+    "defn",
+    "proposition",
+    "prop",
+    "corollary",
+    "cor",
+];
+
+/// A `\\begin{name}...\\end{name}` block found by `environments`.
+struct Environment<'a> {
+    /// As written, with the star of a starred variant
+    name: &'a str,
+    /// The whole block, from `\\begin` to the end of `\\end`
+    range: std::ops::Range<usize>,
+    body: &'a str,
+}
+
+/// Every environment in `content` named one of `names` (or its starred variant),
+/// in the order they begin. Each is closed by the `\\end` of the same name at the
+/// same depth, so environments nested in others, or in one of their own kind,
+/// are found whole, and are returned along with the ones around them. Blocks
+/// never closed are left out.
+fn environments<'a>(content: &'a str, names: &[&str]) -> Vec<Environment<'a>> {
+    let token_re = Regex::new(r"\\(begin|end)\s*\{([^}]*)\}").unwrap();
+    let mut open: Vec<(&str, usize, usize)> = Vec::new();
+    let mut found = Vec::new();
+    for caps in token_re.captures_iter(content) {
+        let name = caps.get(2).unwrap().as_str();
+        if !names.contains(&name.trim_end_matches('*')) {
+            continue;
+        }
+        let whole = caps.get(0).unwrap();
+        if &caps[1] == "begin" {
+            open.push((name, whole.start(), whole.end()));
+        } else if let Some(i) = open.iter().rposition(|(open_name, ..)| *open_name == name) {
+            let (name, start, body_start) = open[i];
+            // Blocks opened inside this one and never closed end with it
+            open.truncate(i);
+            found.push(Environment {
+                name,
+                range: start..whole.end(),
+                body: &content[body_start..whole.start()],
+            });
+        }
+    }
+    found.sort_by_key(|environment| environment.range.start);
+    found
+}
+
+/// Where `item` is in `items`, after adding it if it isn't there yet. Without a main
+/// file that covers the source every TeX file is read, and archives that ship a
+/// second copy of a file (an old draft, a per-venue variant) repeat what it holds.
+fn position_or_push<T: PartialEq>(items: &mut Vec<T>, item: T) -> usize {
+    match items.iter().position(|existing| *existing == item) {
+        Some(i) => i,
+        None => {
+            items.push(item);
+            items.len() - 1
+        }
+    }
+}
+
+/// Pseudocode with each line trimmed and blank lines dropped.
+fn pseudocode_lines(pseudocode: &str) -> String {
+    pseudocode
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Keywords separated by commas, semicolons, `\\and`, `\\sep` (Elsevier) or
+/// `\\cdot` (Springer).
+fn split_keywords(list: &str) -> Vec<String> {
+    let separator = Regex::new(r"[,;]|\\and\b|\\sep\b|\$?\\cdot\$?").unwrap();
+    separator.split(list).map(str::to_string).collect()
+}
+
+/// Decode the entities XML allows in text, as in CCSXML concept names.
+fn unescape_xml(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Remove comments, but not escaped percent signs (`50\\%`) or the percent signs of
+/// a `\\url{...}` or `\\href{...}` target, which LaTeX reads verbatim.
+fn strip_comments(content: &str) -> String {
+    let re = Regex::new(r"(?m)\\(?:url|href)\s*\{[^}\n]*\}|(^|[^\\])%.*$").unwrap();
+    re.replace_all(content, |caps: &regex::Captures| match caps.get(1) {
+        Some(before) => before.as_str().to_string(),
+        None => caps[0].to_string(),
+    })
+    .into_owned()
+}
+
+/// The braced argument of the first `\\name[optional]{argument}`, honouring nested
+/// braces.
+fn command_argument<'a>(content: &'a str, name: &str) -> Option<&'a str> {
+    command_span(content, name).map(|(_, argument)| &content[argument])
+}
+
+/// Where the first `\\name[optional]{argument}` is: the whole command, from the
+/// backslash to the closing brace, and the argument inside the braces.
+fn command_span(
+    content: &str,
+    name: &str,
+) -> Option<(std::ops::Range<usize>, std::ops::Range<usize>)> {
+    let re = Regex::new(&format!(r"\\{name}\s*(?:\[[^\]]*\])?\s*\{{")).unwrap();
+    let command = re.find(content)?;
+    let start = command.end();
+
+    let mut depth = 1;
+    for (i, c) in content[start..].char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some((command.start()..start + i + 1, start..start + i));
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+fn canonical_statement_kind(env: &str) -> &str {
+    match env {
+        "thm" => "theorem",
+        "lem" => "lemma",
+        "defn" => "definition",
+        "prop" => "proposition",
+        "cor" => "corollary",
+        other => other,
+    }
+}
+
+/// Share of letters that are ASCII; a cheap proxy for "this text is English".
+fn english_score(text: &str) -> f64 {
+    let letters: Vec<char> = text.chars().filter(|c| c.is_alphabetic()).collect();
+    if letters.is_empty() {
+        return 0.0;
+    }
+    letters.iter().filter(|c| c.is_ascii()).count() as f64 / letters.len() as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Settings;
+    use crate::test_support::tar_gz;
+
+    fn processor() -> PaperProcessor {
+        PaperProcessor::new(&RunOptions::from_settings(Settings::default()).unwrap())
+    }
+
+    /// Write a `.tar.gz` containing the given files into `dir`.
+    fn write_archive(dir: &Path, name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let path = dir.join(name);
+        fs::write(&path, tar_gz(files)).unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn test_archive_kept_without_cleanup() {
+        let dir = tempfile::tempdir().unwrap();
+        let output_dir = dir.path().join("2401.08027");
+        fs::create_dir_all(&output_dir).unwrap();
+        let archive = write_archive(
+            &output_dir,
+            "2401.08027.tar.gz",
+            &[("main.tex", "\\documentclass{article}\\title{Kept}")],
+        );
+
+        let mut options = RunOptions::from_settings(Settings::default()).unwrap();
+        options.cleanup = CleanupMode::Keep;
+        let paper_data = PaperData::new("2401.08027".to_string(), archive.clone(), output_dir);
+
+        let processed = PaperProcessor::new(&options)
+            .process(paper_data, &mut StageTimings::default())
+            .await
+            .unwrap();
+        assert_eq!(processed.title, "Kept");
+        assert!(archive.exists());
+    }
+
+    #[tokio::test]
+    async fn test_archive_moved_to_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let output_dir = dir.path().join("2401.08027");
+        fs::create_dir_all(&output_dir).unwrap();
+        let archive = write_archive(
+            &output_dir,
+            "2401.08027.tar.gz",
+            &[("main.tex", "\\documentclass{article}\\title{Cached}")],
+        );
+
+        let mut options = RunOptions::from_settings(Settings::default()).unwrap();
+        options.work_dir = dir.path().to_path_buf();
+        options.cleanup = CleanupMode::Cache;
+        let paper_data = PaperData::new("2401.08027".to_string(), archive.clone(), output_dir);
+
+        PaperProcessor::new(&options)
+            .process(paper_data, &mut StageTimings::default())
+            .await
+            .unwrap();
+        assert!(!archive.exists());
+        let cached = dir.path().join("cache/2401.08027.tar.gz");
+        assert!(cached.is_file());
+
+        // The cache only stands in for deleting: the next download fetches the
+        // source again, which may be of a newer version
+        let mut server = mockito::Server::new_async().await;
+        let src = server
+            .mock("GET", "/src/2401.08027")
+            .with_body("updated source ".repeat(10))
+            .expect(1)
+            .create_async()
+            .await;
+        let downloader = crate::downloader::PaperDownloader::new(&RunOptions {
+            min_archive_size: 1,
+            ..options
+        });
+        let arxiv_url = crate::arxiv::ArxivUrl {
+            paper_id: "2401.08027".to_string(),
+            src_url: format!("{}/src/2401.08027", server.url()),
+        };
+        let paper_data = downloader.download(&arxiv_url).await.unwrap();
+        src.assert_async().await;
+        assert_eq!(paper_data.archive_path, archive);
+        assert_eq!(
+            fs::read_to_string(&archive).unwrap(),
+            "updated source ".repeat(10)
+        );
+        assert!(cached.is_file());
+    }
+
+    #[test]
+    fn test_iff_inside_iffalse_is_not_a_conditional() {
+        let content =
+            "\\newif\\ifdraft\nKept.\n\\iffalse\nDropped: $p \\iff q$, \\ifthenelse{a}{b}{c},\n\
+                       \\ifdraft draft\\fi \\ifx\\a\\b x\\fi\n\\fi\nAlso kept.";
+
+        let kept = strip_excluded_blocks(content);
+
+        assert!(kept.contains("Kept."), "{kept}");
+        assert!(kept.contains("Also kept."), "{kept}");
+        assert!(!kept.contains("Dropped"), "{kept}");
+    }
+
+    #[test]
+    fn test_else_branch_of_iffalse_is_kept() {
+        let content = "Before.\n\\iffalse\nDropped \\ifx\\a\\b x\\else y\\fi.\n\\else\n\
+                       Live \\ifx\\a\\b kept\\fi.\n\\fi\nAfter.";
+
+        let kept = strip_excluded_blocks(content);
+
+        assert_eq!(
+            kept, "Before.\n\nLive \\ifx\\a\\b kept\\fi.\n\nAfter.",
+            "{kept}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_excluded_blocks_are_not_extracted() {
+        let main = "\\documentclass{article}\n\\newif\\ifdraft\n\\begin{document}\n\
+                    \\section{Introduction}\nReal text.\n\
+                    \\begin{comment}\n\\section{Old Related Work}\nDropped.\n\\end{comment}\n\
+                    \\iffalse\n\\section{Appendix Draft}\n\\ifdraft notes\\fi\n\\fi\n\
+                    \\section{Method}\nMore real text.\n\\end{document}";
+        let dir = tempfile::tempdir().unwrap();
+        let output_dir = dir.path().join("2401.08027");
+        fs::create_dir_all(&output_dir).unwrap();
+        let archive = write_archive(&output_dir, "2401.08027.tar.gz", &[("main.tex", main)]);
+        let paper_data = PaperData::new("2401.08027".to_string(), archive, output_dir);
+
+        let processed = processor()
+            .process(paper_data, &mut StageTimings::default())
+            .await
+            .unwrap();
+
+        let titles: Vec<&str> = processed
+            .sections
+            .iter()
+            .map(|s| s.title.as_str())
+            .collect();
+        assert!(titles.contains(&"Introduction") && titles.contains(&"Method"));
+        assert!(!titles.contains(&"Old Related Work"), "{titles:?}");
+        assert!(!titles.contains(&"Appendix Draft"), "{titles:?}");
+        assert!(!processed.full_text.contains("Dropped"));
+        assert!(!processed.full_text.contains("notes"));
+        assert!(processed.full_text.contains("More real text."));
+    }
+
+    /// Extract `files` as a paper's source and return what the processor collected.
+    fn extracted_from(files: &[(&str, &str)]) -> (tempfile::TempDir, ExtractedContent) {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("2401.08027/extracted");
+        for (name, content) in files {
+            let path = root.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+        let tex_files = files.iter().map(|(name, _)| root.join(name)).collect();
+        let extracted = ExtractedContent {
+            tex_files,
+            bib_files: vec![],
+            image_files: vec![],
+            main_tex_file: Some(root.join(files[0].0)),
+            paper_pdf: None,
+            extracted_dir: root,
+            skipped_entries: 0,
+        };
+        (dir, extracted)
+    }
+
+    #[test]
+    fn test_withdrawal_notice_is_reported() {
+        let (_dir, extracted) = extracted_from(&[(
+            "withdrawn.tex",
+            "\\documentclass{article}\n\\begin{document}\n\
+             This paper has been withdrawn by the author due to an error in the proof of Theorem 2.\n\
+             \\end{document}",
+        )]);
+
+        let err = processor()
+            .process_extracted_content(extracted)
+            .unwrap_err();
+
+        let withdrawn = err.downcast_ref::<crate::arxiv::Withdrawn>().unwrap();
+        assert_eq!(withdrawn.paper_id, "2401.08027");
+    }
+
+    #[test]
+    fn test_inputs_of_a_stub_main_file_are_inlined() {
+        let intro = format!("\\section{{Introduction}}\n{}", "Intro text. ".repeat(200));
+        let method = format!("\\section{{Method}}\n{}", "Method text. ".repeat(200));
+        let (_dir, extracted) = extracted_from(&[
+            (
+                "main.tex",
+                "\\documentclass{article}\n\\title{Stub}\n\\begin{document}\\input{sections/intro}\\include{sections/method.tex}\n% \\input{old}\n\\end{document}",
+            ),
+            ("sections/intro.tex", &intro),
+            ("sections/method.tex", &method),
+            ("old.tex", "\\section{Old Draft}\nUnused."),
+        ]);
+
+        let (content, files_read) = read_tex_sources(&extracted);
+        assert_eq!(files_read, 3);
+        assert!(content.find("Intro text.").unwrap() < content.find("Method text.").unwrap());
+        assert!(!content.contains("Old Draft"));
+
+        let processed = processor().process_extracted_content(extracted).unwrap();
+        let titles: Vec<&str> = processed
+            .sections
+            .iter()
+            .map(|s| s.title.as_str())
+            .collect();
+        assert_eq!(titles, ["Introduction", "Method"]);
+        assert_eq!(processed.full_text.matches("Method text.").count(), 200);
+    }
+
+    #[test]
+    fn test_subfiles_are_inlined_in_order() {
+        let chapter = |title: &str| {
+            format!(
+                "\\documentclass[../thesis.tex]{{subfiles}}\n\\begin{{document}}\n\\section{{{title}}}\n{}\n\\end{{document}}",
+                format!("{title} text. ").repeat(100)
+            )
+        };
+        let (_dir, extracted) = extracted_from(&[
+            (
+                "thesis.tex",
+                "\\documentclass{book}\n\\usepackage{subfiles}\n\\title{Thesis}\n\\begin{document}\n\
+                 \\subfile{chapters/method}\n\\subfile{chapters/intro.tex}\n\\end{document}",
+            ),
+            ("chapters/intro.tex", &chapter("Introduction")),
+            ("chapters/method.tex", &chapter("Method")),
+        ]);
+
+        let (content, files_read) = read_tex_sources(&extracted);
+        assert_eq!(files_read, 3);
+        assert_eq!(content.matches("\\documentclass").count(), 1);
+        assert_eq!(content.matches("\\end{document}").count(), 1);
+
+        let processed = processor().process_extracted_content(extracted).unwrap();
+        let titles: Vec<&str> = processed
+            .sections
+            .iter()
+            .map(|s| s.title.as_str())
+            .collect();
+        assert_eq!(titles, ["Method", "Introduction"]);
+    }
+
+    #[test]
+    fn test_unresolved_stub_falls_back_to_all_files() {
+        let body = format!("\\section{{Results}}\n{}", "Result text. ".repeat(200));
+        let (_dir, extracted) = extracted_from(&[
+            (
+                "main.tex",
+                "\\documentclass{article}\n\\begin{document}\n\\input{\\sectiondir/results}\n\\end{document}",
+            ),
+            ("tex/results.tex", &body),
+        ]);
+
+        let (content, files_read) = read_tex_sources(&extracted);
+
+        assert_eq!(files_read, 2);
+        assert!(content.starts_with("\\documentclass"));
+        assert_eq!(content.matches("Result text.").count(), 200);
+    }
+
+    #[test]
+    fn test_combined_equations_stay_in_their_section() {
+        let content = "\\section{Method}\nThe loss is\n\\begin{equation}\nL = -\\log p(y|x)\n\\end{equation}\n\
+                       where $p$ is the model.\n\\begin{itemize}\\item one\\end{itemize}\n\\section{Results}\nGood.";
+        let mut options = RunOptions::from_settings(Settings::default()).unwrap();
+        options.combine_equations = true;
+
+        let sections = PaperProcessor::new(&options).extract_sections(content);
+
+        assert_eq!(sections[0].title, "Method");
+        assert!(
+            sections[0].content.contains(
+                "The loss is\n\\begin{equation}\nL = -\\log p(y|x)\n\\end{equation}\nwhere $p$ is the model."
+            ),
+            "{}",
+            sections[0].content
+        );
+        assert!(!sections[0].content.contains("itemize"));
+        assert!(!sections[1].content.contains("equation"));
+
+        let separate = processor().extract_sections(content);
+        assert!(!separate[0].content.contains("\\begin{equation}"));
+    }
+
+    #[test]
+    fn test_sections_after_appendix_are_flagged() {
+        let content = "\\section{Intro}\nHi.\n\\section{Method}\nHow.\n\\appendix\n\
+                       \\section{Proofs}\nQED.\n\\subsection{Lemma 1}\nTrivial.";
+
+        let sections = processor().extract_sections(content);
+
+        let flags: Vec<(&str, bool)> = sections
+            .iter()
+            .map(|s| (s.title.as_str(), s.appendix))
+            .collect();
+        assert_eq!(
+            flags,
+            [
+                ("Intro", false),
+                ("Method", false),
+                ("Proofs", true),
+                ("Lemma 1", true)
+            ]
+        );
+        assert!(processor()
+            .extract_sections("\\section{Intro}\nHi.")
+            .iter()
+            .all(|s| !s.appendix));
+        let redefined = "\\renewcommand{\\appendix}{\\par}\n\\begin{document}\n\
+                         \\section{Intro}\nHi.\n\\section{Method}\nHow.\n\\end{document}";
+        assert!(processor()
+            .extract_sections(redefined)
+            .iter()
+            .all(|s| !s.appendix));
+    }
+
+    #[test]
+    fn test_layout_commands_are_removed() {
+        let content = "\\maketitle\n\\noindent Deep nets \\newpaper{x} work.\n\\clearpage\n\\centering\\hline Table.\n\\alpha stays.";
+
+        let cleaned = processor().clean_tex_content(content);
+
+        assert_eq!(
+            cleaned,
+            "Deep nets \\newpaper{x} work.\n\nTable.\n\\alpha stays."
+        );
+    }
+
+    #[test]
+    fn test_documentclass_options_are_removed() {
+        let content = "\\documentclass[twocolumn]{IEEEtran}\n\\usepackage[utf8]{inputenc}\n\\usepackage {amsmath}\nBody text.";
+
+        let cleaned = processor().clean_tex_content(content);
+
+        assert_eq!(cleaned, "Body text.");
+    }
+
+    #[test]
+    fn test_cleaned_text_is_unescaped() {
+        let content =
+            "Gains of 50\\% over Erd\\H{o}s--R\\'enyi graphs~\\cite{er59}. % reviewer note\nR\\&D";
+
+        let cleaned = processor().clean_tex_content(content);
+
+        assert_eq!(
+            cleaned,
+            "Gains of 50% over Erdős–Rényi graphs [er59]. \nR&D"
+        );
+    }
+
+    #[test]
+    fn test_contributions_list() {
+        let content = "\\section{Introduction}\nWe need:\n\\begin{itemize}\n\\item data\n\\end{itemize}\n\
+                       Our main Contributions are as follows:\n\\begin{enumerate}\n\
+                       \\item We propose \\textbf{X}, a new model.\n\
+                       \\item[(ii)] We show that it\n  scales.\n\
+                       \\item We release code.\n\\end{enumerate}\n\
+                       \\section{Method}\nContributions aside:\n\\begin{itemize}\\item other\\end{itemize}";
+
+        assert_eq!(
+            processor().extract_contributions(content),
+            [
+                "We propose X, a new model.",
+                "We show that it scales.",
+                "We release code."
+            ]
+        );
+        assert!(processor()
+            .extract_contributions("\\begin{itemize}\\item a\\end{itemize}")
+            .is_empty());
+    }
+
+    #[test]
+    fn test_double_dollar_display_math() {
+        let content =
+            "Energy is $$E=mc^2$$ where $m$ is mass and\n$$\n\\int_0^1 f(x)\\,dx\n$$ holds.";
+
+        let equations: Vec<(String, bool)> = processor()
+            .extract_equations(content)
+            .into_iter()
+            .map(|eq| (eq.content, eq.display))
+            .collect();
+
+        assert_eq!(
+            equations,
+            [
+                ("E=mc^2".to_string(), true),
+                ("m".to_string(), false),
+                ("\\int_0^1 f(x)\\,dx".to_string(), true)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_equation_labels_references_and_sections() {
+        let content = "\\begin{abstract}We minimise $f$.\\end{abstract}\
+                       \\section{Method}We train with \\begin{equation}\\mathcal{L} = -\\log p \
+                       \\label{eq:loss}\\end{equation} and \\begin{equation}y = 1 \\label{eq:y}\\end{equation}\
+                       \\section{Experiments}Minimising \\eqref{eq:loss} on $x$.";
+
+        let equations: Vec<(Option<String>, bool, Option<String>)> = processor()
+            .extract_equations(content)
+            .into_iter()
+            .map(|eq| (eq.label, eq.referenced, eq.section))
+            .collect();
+
+        let some = |s: &str| Some(s.to_string());
+        assert_eq!(
+            equations,
+            [
+                (None, false, some("Abstract")),
+                (some("eq:loss"), true, some("Method")),
+                (some("eq:y"), false, some("Method")),
+                (None, false, some("Experiments")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_duplicate_sections_are_merged_or_numbered() {
+        let intro = "\\section{Introduction}\nWe study sparse attention.\n";
+        let content = format!(
+            "{intro}\\section{{Experiments}}\n\\subsection{{Results}}\nAccuracy improves.\n\
+             \\subsection{{Results}}\nLatency drops.\n{intro}"
+        );
+
+        let sections = processor().extract_sections(&content);
+
+        let titles: Vec<&str> = sections.iter().map(|s| s.title.as_str()).collect();
+        assert_eq!(
+            titles,
+            ["Introduction", "Experiments", "Results", "Results (2)"]
+        );
+        assert!(sections[3].content.contains("Latency drops."));
+    }
+
+    #[test]
+    fn test_code_links_are_extracted() {
+        let content = strip_comments(
+            "Code is at \\url{https://github.com/org/fast\\_attn}. % old: \\url{https://github.com/org/old}\n\
+             Models: \\href{https://huggingface.co/org/model%20v2}{here}; see also\n\
+             github.com/Org/Fast_attn, www.gitlab.com/lab/tool/, \\url{https://example.com/data},\n\
+             \\url{https://github.com}.",
+        );
+
+        assert_eq!(
+            processor().extract_code_links(&content),
+            [
+                "https://github.com/org/fast_attn",
+                "https://huggingface.co/org/model%20v2",
+                "https://gitlab.com/lab/tool",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_acm_ccs_concepts_are_keywords() {
+        let content = r"\documentclass[sigconf]{acmart}
+\begin{document}
+\begin{CCSXML}
+<ccs2012>
+   <concept>
+       <concept_id>10010147.10010257</concept_id>
+       <concept_desc>Computing methodologies~Machine learning</concept_desc>
+       <concept_significance>500</concept_significance>
+   </concept>
+   <concept>
+       <concept_id>10002951.10003317</concept_id>
+       <concept_desc>Information systems~Search &amp; retrieval</concept_desc>
+       <concept_significance>300</concept_significance>
+   </concept>
+</ccs2012>
+\end{CCSXML}
+\ccsdesc[500]{Computing methodologies~Machine learning}
+\ccsdesc[300]{Information systems~Search \& retrieval}
+\ccsdesc[100]{Security and privacy~Software and application security}
+\keywords{dense retrieval, contrastive learning; \textit{RAG}}
+\maketitle
+\section{Introduction}
+\end{document}";
+
+        assert_eq!(
+            processor().extract_keywords(content),
+            [
+                "dense retrieval",
+                "contrastive learning",
+                "RAG",
+                "Machine learning",
+                "Search & retrieval",
+                "Software and application security"
+            ]
+        );
+        assert!(processor()
+            .extract_keywords("\\begin{IEEEkeywords}Graphs, GNNs.\\end{IEEEkeywords}")
+            .iter()
+            .eq(["Graphs", "GNNs"].iter()));
+    }
+
+    #[test]
+    fn test_authors_split_on_line_breaks() {
+        let content = "\\author{Ada Lovelace \\\\ Alan Turing\\\\[2mm]\nGrace Hopper \\\\*[1ex] Edsger Dijkstra, Barbara Liskov \\and Donald Knuth\\\\}";
+
+        assert_eq!(
+            processor().extract_authors(content),
+            [
+                "Ada Lovelace",
+                "Alan Turing",
+                "Grace Hopper",
+                "Edsger Dijkstra",
+                "Barbara Liskov",
+                "Donald Knuth"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_figure_captions() {
+        let content = "\\begin{figure*}[t]\n\\centering\n\\includegraphics[width=\\linewidth]{figs/arch}\n\
+                       \\caption{Overview of \\textbf{our} model.\\label{fig:arch}}\n\\end{figure*}\n\
+                       Text.\n\\begin{figure}\n\\includegraphics{loss.png}\n\\caption[Loss]{Training loss \\& accuracy.}\n\\end{figure}";
+
+        let figures = processor().extract_figure_captions(content);
+
+        assert_eq!(
+            figures,
+            [
+                Figure {
+                    label: Some("fig:arch".to_string()),
+                    caption: "Overview of our model.".to_string(),
+                    graphics: vec!["figs/arch".to_string()],
+                    tikz: false,
+                },
+                Figure {
+                    label: None,
+                    caption: "Training loss & accuracy.".to_string(),
+                    graphics: vec!["loss.png".to_string()],
+                    tikz: false,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tikz_figures_are_captured() {
+        let content = "\\begin{figure}\n\\centering\n\\input{figures/arch.tikz}\n\
+                       \\caption{The architecture.}\\label{fig:arch}\n\\end{figure}\n\
+                       \\begin{figure}\n\\begin{tikzpicture}\\draw (0,0) -- (1,1);\\end{tikzpicture}\n\
+                       \\caption{A line.}\n\\end{figure}\n\\input{sections/intro}";
+
+        let figures = processor().extract_figure_captions(content);
+
+        assert_eq!(
+            figures,
+            [
+                Figure {
+                    label: Some("fig:arch".to_string()),
+                    caption: "The architecture.".to_string(),
+                    graphics: vec!["figures/arch.tikz".to_string()],
+                    tikz: true,
+                },
+                Figure {
+                    label: None,
+                    caption: "A line.".to_string(),
+                    graphics: Vec::new(),
+                    tikz: true,
+                },
+            ]
+        );
+        assert_eq!(processor().extract_figures(content), ["figures/arch.tikz"]);
+
+        // The drawing is not inlined into the text, so the reference survives
+        let dir = tempfile::tempdir().unwrap();
+        let main = dir.path().join("main.tex");
+        fs::create_dir_all(dir.path().join("figures")).unwrap();
+        fs::write(
+            dir.path().join("figures/arch.tikz"),
+            "\\begin{tikzpicture}\\end{tikzpicture}",
+        )
+        .unwrap();
+        fs::write(&main, content).unwrap();
+        let inlined = inline_inputs(&main, &mut Vec::new(), 0).unwrap();
+        assert!(inlined.contains("\\input{figures/arch.tikz}"));
+    }
+
+    #[test]
+    fn test_inline_bibliography() {
+        let content = "\\section{Intro}\nAs in~\\cite{vaswani}.\n\
+                       \\begin{thebibliography}{10}\n\
+                       \\bibitem{vaswani} A.~Vaswani et~al.\n\\newblock Attention is all you need.\n\\newblock In {\\em NeurIPS}, 2017.\n\n\
+                       \\bibitem[He et al.(2016)]{he2016} K.~He, X.~Zhang.\n\\newblock Deep residual learning.\n\
+                       \\end{thebibliography}\n\\end{document}";
+
+        let references = processor().extract_bibitems(content);
+
+        assert_eq!(
+            references,
+            [
+                Reference {
+                    key: "vaswani".to_string(),
+                    label: None,
+                    text: "A. Vaswani et al. Attention is all you need. In NeurIPS, 2017."
+                        .to_string(),
+                },
+                Reference {
+                    key: "he2016".to_string(),
+                    label: Some("He et al.(2016)".to_string()),
+                    text: "K. He, X. Zhang. Deep residual learning.".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_title_with_short_form() {
+        let content =
+            "\\titlerunning{Short}\n\\title[Short]{A {F}ull Title\\\\ on Two Lines}\n\\author{A}";
+
+        assert_eq!(
+            processor().extract_title(content),
+            "A Full Title on Two Lines"
+        );
+    }
+
+    #[test]
+    fn test_title_and_subtitle() {
+        let content = "\\title{Deep Learning}\n\\subtitle{A Survey}\n\\begin{document}";
+
+        assert_eq!(
+            processor().extract_title(content),
+            "Deep Learning: A Survey"
+        );
+        assert_eq!(processor().extract_title("no title here"), "Untitled");
+    }
+
+    #[test]
+    fn test_statement_extraction() {
+        let content = "\\begin{theorem}\nEvery bounded monotone sequence converges.\n\\end{theorem}\n\
+                       Text.\n\\begin{definition}[Regret]\n$R_T = \\sum_t \\ell_t$.\n\\end{definition}\n\
+                       \\begin{lem*} A \\end{lem*}";
+
+        let statements = processor().extract_statements(content);
+
+        assert_eq!(
+            statements,
+            [
+                MathStatement {
+                    kind: "theorem".to_string(),
+                    name: None,
+                    body: "Every bounded monotone sequence converges.".to_string(),
+                },
+                MathStatement {
+                    kind: "definition".to_string(),
+                    name: Some("Regret".to_string()),
+                    body: "$R_T = \\sum_t \\ell_t$.".to_string(),
+                },
+                MathStatement {
+                    kind: "lemma".to_string(),
+                    name: None,
+                    body: "A".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_nested_statements_are_closed_by_name() {
+        let content = "\\begin{theorem}[Main]\nBounds hold.\n\\begin{lemma}\nA step.\n\
+                       \\begin{proof}Easy.\\end{proof}\n\\end{lemma}\nHence the claim.\n\
+                       \\end{theorem}";
+
+        let statements = processor().extract_statements(content);
+
+        assert_eq!(statements.len(), 2, "{statements:?}");
+        assert_eq!(statements[0].kind, "theorem");
+        assert_eq!(statements[0].name.as_deref(), Some("Main"));
+        assert!(statements[0].body.starts_with("Bounds hold."));
+        assert!(statements[0].body.ends_with("Hence the claim."));
+        assert_eq!(statements[1].kind, "lemma");
+        assert_eq!(
+            statements[1].body,
+            "A step.\n\\begin{proof}Easy.\\end{proof}"
+        );
+    }
+
+    #[test]
+    fn test_algorithm_extraction() {
+        let content = r"\section{Method}
+We train as follows.
+\begin{algorithm}[t]
+\caption{Training with {\em replay} and \textsc{Adam} on $\theta$}
+\label{alg:train}
+\begin{algorithmic}[1]
+  \State Initialize $\theta$
+  \For{$t = 1, \dots, T$}
+    \State $\theta \gets \theta - \eta \nabla \ell_t$ % step
+  \EndFor
+\end{algorithmic}
+\end{algorithm}
+Then evaluate.
+\begin{algorithm}
+\caption[Sampling]{Sampling with a {\em long} caption}
+\State Draw $x$
+\end{algorithm}";
+
+        let (algorithms, remaining) = processor().extract_algorithms(content);
+
+        assert_eq!(
+            algorithms,
+            [
+                AlgorithmInfo {
+                    label: Some("alg:train".to_string()),
+                    caption: Some("Training with replay and Adam on $\\theta$".to_string()),
+                    body: "\\State Initialize $\\theta$\n\\For{$t = 1, \\dots, T$}\n\
+                       \\State $\\theta \\gets \\theta - \\eta \\nabla \\ell_t$ % step\n\\EndFor"
+                        .to_string(),
+                },
+                AlgorithmInfo {
+                    label: None,
+                    caption: Some("Sampling with a long caption".to_string()),
+                    body: "\\State Draw $x$".to_string(),
+                }
+            ]
+        );
+        // No font markup is left for the model to read around
+        assert!(algorithms
+            .iter()
+            .filter_map(|a| a.caption.as_deref())
+            .all(|caption| !caption.contains(['{', '}']) && !caption.contains("\\text")));
+        assert!(remaining.contains("We train as follows.\n[Algorithm 1]\nThen evaluate."));
+    }
+
+    #[test]
+    fn test_english_abstract_is_primary() {
+        let content = r"\begin{abstract}
+本文提出了一种新的方法。
+\end{abstract}
+\begin{otherlanguage}{english}
+\begin{abstract}
+We propose a new method.
+\end{abstract}
+\end{otherlanguage}";
+
+        let (primary, alternates) = processor().extract_abstracts(content);
+        assert_eq!(primary, "We propose a new method.");
+        assert_eq!(alternates, ["本文提出了一种新的方法。"]);
+    }
+
+    #[test]
+    fn test_single_abstract_has_no_alternates() {
+        let content = "\\begin{abstract}\nOnly one.\n\\end{abstract}";
+
+        let (primary, alternates) = processor().extract_abstracts(content);
+        assert_eq!(primary, "Only one.");
+        assert!(alternates.is_empty());
+    }
+
+    #[test]
+    fn test_abstract_under_starred_section() {
+        let content = r"\title{T}
+\section*{Abstract}
+We prove that $x^2 \geq 0$ for every \emph{real} $x$, which
+is a classic result.
+\section{Introduction}
+Intro text.";
+
+        let abstract_text = processor().extract_abstract(content);
+        assert_eq!(
+            abstract_text,
+            "We prove that $x^2 \\geq 0$ for every real $x$, which\nis a classic result."
+        );
+    }
+
+    #[test]
+    fn test_abstract_under_paragraph_heading() {
+        let content = r"\paragraph{ABSTRACT.} Short abstract text.
+\subsection{Background}";
+
+        let abstract_text = processor().extract_abstract(content);
+        assert_eq!(abstract_text, "Short abstract text.");
     }
 }
@@ -0,0 +1,283 @@
+use anyhow::Result;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+use crate::library::{self, NoteEntry};
+
+/// How many authors and keywords `stats` reports
+const TOP_N: usize = 10;
+
+/// Approximate list prices in USD per million prompt / completion tokens, matched
+/// against the start of the model name (longest prefix first).
+const MODEL_PRICES: [(&str, f64, f64); 8] = [
+    ("gpt-4o-mini", 0.15, 0.60),
+    ("gpt-4o", 2.50, 10.00),
+    ("gpt-4.1-mini", 0.40, 1.60),
+    ("gpt-4.1", 2.00, 8.00),
+    ("o3-mini", 1.10, 4.40),
+    ("deepseek-chat", 0.27, 1.10),
+    ("deepseek-reasoner", 0.55, 2.19),
+    ("claude-3-5-haiku", 0.80, 4.00),
+];
+
+/// USD per million prompt and completion tokens.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Price {
+    pub prompt: f64,
+    pub completion: f64,
+}
+
+impl Price {
+    /// USD for one request's tokens.
+    pub fn cost(&self, prompt_tokens: u32, completion_tokens: u32) -> f64 {
+        (f64::from(prompt_tokens) * self.prompt + f64::from(completion_tokens) * self.completion)
+            / 1_000_000.0
+    }
+}
+
+/// The price for `model`: `custom` when given, else the built-in list price.
+pub fn price_for(model: &str, custom: Option<Price>) -> Option<Price> {
+    if custom.is_some() {
+        return custom;
+    }
+    let model = model.to_lowercase();
+    MODEL_PRICES
+        .iter()
+        .filter(|(prefix, _, _)| model.starts_with(prefix))
+        .max_by_key(|(prefix, _, _)| prefix.len())
+        .map(|&(_, prompt, completion)| Price { prompt, completion })
+}
+
+#[derive(Debug, Default, PartialEq, serde::Serialize)]
+pub struct ModelStats {
+    pub notes: usize,
+    pub total_tokens: u64,
+    pub estimated_cost: f64,
+}
+
+/// Totals over every note in the library.
+#[derive(Debug, Default, PartialEq, serde::Serialize)]
+pub struct LibraryStats {
+    pub notes: usize,
+    /// Tokens over the notes whose provider reported usage
+    pub total_tokens: u64,
+    pub notes_with_usage: usize,
+    /// Cost of the notes with usage and a known price, in USD
+    pub estimated_cost: f64,
+    pub by_model: BTreeMap<String, ModelStats>,
+    /// Notes generated per month ("2025-01")
+    pub by_month: BTreeMap<String, usize>,
+    /// Notes per `--tag`
+    pub by_tag: BTreeMap<String, usize>,
+    /// Mean words of section text, over the notes that record it
+    pub average_paper_words: Option<f64>,
+    pub top_authors: Vec<(String, usize)>,
+    pub top_keywords: Vec<(String, usize)>,
+}
+
+pub fn compute(notes: &[NoteEntry], custom_price: Option<Price>) -> LibraryStats {
+    let mut stats = LibraryStats {
+        notes: notes.len(),
+        ..Default::default()
+    };
+    let mut authors: HashMap<&str, usize> = HashMap::new();
+    let mut keywords: HashMap<String, usize> = HashMap::new();
+    let mut words = Vec::new();
+
+    for note in notes {
+        let model = if note.model.is_empty() {
+            "unknown"
+        } else {
+            note.model.as_str()
+        };
+        let model_stats = stats.by_model.entry(model.to_string()).or_default();
+        model_stats.notes += 1;
+
+        if let Some(total) = note.total_tokens {
+            stats.notes_with_usage += 1;
+            stats.total_tokens += u64::from(total);
+            model_stats.total_tokens += u64::from(total);
+        }
+        let price = price_for(&note.model, custom_price);
+        if let (Some(price), Some(prompt), Some(completion)) =
+            (price, note.prompt_tokens, note.completion_tokens)
+        {
+            let cost = price.cost(prompt, completion);
+            stats.estimated_cost += cost;
+            model_stats.estimated_cost += cost;
+        }
+
+        *stats
+            .by_month
+            .entry(note.date.format("%Y-%m").to_string())
+            .or_default() += 1;
+        for tag in &note.tags {
+            *stats.by_tag.entry(tag.clone()).or_default() += 1;
+        }
+        words.extend(note.source_words);
+        for author in &note.authors {
+            *authors.entry(author.trim()).or_default() += 1;
+        }
+        for keyword in &note.keywords {
+            *keywords.entry(keyword.trim().to_lowercase()).or_default() += 1;
+        }
+    }
+
+    if !words.is_empty() {
+        stats.average_paper_words = Some(words.iter().sum::<usize>() as f64 / words.len() as f64);
+    }
+    stats.top_authors = most_common(authors.into_iter().map(|(a, n)| (a.to_string(), n)));
+    stats.top_keywords = most_common(keywords.into_iter());
+    stats
+}
+
+/// The `TOP_N` most frequent names, ties broken alphabetically.
+fn most_common(counts: impl Iterator<Item = (String, usize)>) -> Vec<(String, usize)> {
+    let mut counts: Vec<(String, usize)> = counts.filter(|(name, _)| !name.is_empty()).collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    counts.truncate(TOP_N);
+    counts
+}
+
+/// Print statistics for the notes in `notes_dir`, as text or JSON.
+pub fn run(notes_dir: &Path, custom_price: Option<Price>, json: bool) -> Result<()> {
+    let stats = compute(&library::list_notes(notes_dir)?, custom_price);
+    if json {
+        println!("{}", serde_json::to_string_pretty(&stats)?);
+        return Ok(());
+    }
+
+    println!("Notes: {}", stats.notes);
+    println!(
+        "Tokens: {} ({} of {} notes report usage)",
+        stats.total_tokens, stats.notes_with_usage, stats.notes
+    );
+    println!("Estimated cost: ${:.2}", stats.estimated_cost);
+    if let Some(words) = stats.average_paper_words {
+        println!("Average paper length: {words:.0} words");
+    }
+    println!("By model:");
+    for (model, model_stats) in &stats.by_model {
+        println!(
+            "  {model:<24}{:>5} notes{:>12} tokens  ${:.2}",
+            model_stats.notes, model_stats.total_tokens, model_stats.estimated_cost
+        );
+    }
+    println!("By month:");
+    for (month, notes) in &stats.by_month {
+        println!("  {month}  {notes}");
+    }
+    if !stats.by_tag.is_empty() {
+        println!("By tag:");
+        for (tag, notes) in &stats.by_tag {
+            println!("  {tag}  {notes}");
+        }
+    }
+    let join = |counts: &[(String, usize)]| {
+        counts
+            .iter()
+            .map(|(name, n)| format!("{name} ({n})"))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    if !stats.top_authors.is_empty() {
+        println!("Top authors: {}", join(&stats.top_authors));
+    }
+    if !stats.top_keywords.is_empty() {
+        println!("Top keywords: {}", join(&stats.top_keywords));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stats_over_sidecars() {
+        let dir = tempfile::tempdir().unwrap();
+        let sidecars = [
+            serde_json::json!({
+                "paper_id": "2401.00001",
+                "authors": ["Ada Lovelace", "Alan Turing"],
+                "keywords": ["Attention"],
+                "metadata": {
+                    "generated_at": "2025-01-05T00:00:00Z",
+                    "model_used": "gpt-4o-mini",
+                    "prompt_tokens": 1_000_000,
+                    "completion_tokens": 100_000,
+                    "total_tokens": 1_100_000,
+                    "source_words": 3000,
+                    "tags": ["thesis", "survey"],
+                },
+            }),
+            serde_json::json!({
+                "paper_id": "2401.00002",
+                "authors": ["Ada Lovelace"],
+                "keywords": ["attention", "MoE"],
+                "metadata": {
+                    "generated_at": "2025-01-20T00:00:00Z",
+                    "model_used": "deepseek-chat",
+                    "prompt_tokens": 2000,
+                    "completion_tokens": 500,
+                    "total_tokens": 2500,
+                    "source_words": 5000,
+                    "tags": ["thesis"],
+                },
+            }),
+            // Older sidecar without usage or model
+            serde_json::json!({
+                "paper_id": "2402.00003",
+                "authors": ["Grace Hopper"],
+                "metadata": {"generated_at": "2025-02-01T00:00:00Z"},
+            }),
+        ];
+        for sidecar in &sidecars {
+            let id = sidecar["paper_id"].as_str().unwrap();
+            std::fs::create_dir_all(dir.path().join(id)).unwrap();
+            std::fs::write(
+                dir.path().join(id).join(format!("{id}.json")),
+                sidecar.to_string(),
+            )
+            .unwrap();
+        }
+
+        let stats = compute(&library::list_notes(dir.path()).unwrap(), None);
+
+        assert_eq!(stats.notes, 3);
+        assert_eq!(stats.notes_with_usage, 2);
+        assert_eq!(stats.total_tokens, 1_102_500);
+        // 1M prompt tokens at $0.15 plus 100k completion tokens at $0.60, plus a
+        // fraction of a cent for the second note
+        assert!((stats.by_model["gpt-4o-mini"].estimated_cost - 0.21).abs() < 1e-9);
+        assert!((stats.estimated_cost - 0.21 - 0.00109).abs() < 1e-9);
+        assert_eq!(stats.by_model["unknown"].notes, 1);
+        assert_eq!(
+            stats.by_month.into_iter().collect::<Vec<_>>(),
+            [("2025-01".to_string(), 2), ("2025-02".to_string(), 1)]
+        );
+        assert_eq!(
+            stats.by_tag.into_iter().collect::<Vec<_>>(),
+            [("survey".to_string(), 1), ("thesis".to_string(), 2)]
+        );
+        assert_eq!(stats.average_paper_words, Some(4000.0));
+        assert_eq!(stats.top_authors[0], ("Ada Lovelace".to_string(), 2));
+        assert_eq!(stats.top_authors.len(), 3);
+        assert_eq!(
+            stats.top_keywords,
+            [("attention".to_string(), 2), ("moe".to_string(), 1)]
+        );
+
+        let flat = Price {
+            prompt: 1.0,
+            completion: 1.0,
+        };
+        let stats = compute(&library::list_notes(dir.path()).unwrap(), Some(flat));
+        assert!((stats.estimated_cost - 1.1025).abs() < 1e-9);
+        assert_eq!(
+            price_for("gpt-4o-mini-2024-07-18", None).unwrap().prompt,
+            0.15
+        );
+        assert!(price_for("my-local-model", None).is_none());
+    }
+}
@@ -0,0 +1,135 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::note_generator::{escape_html, OutputFormat};
+
+/// Prompt used in `--structured` mode when no prompt file is configured.
+pub const STRUCTURED_PROMPT_FILE: &str = "prompts_structured.txt";
+
+/// Skeleton a structured note is rendered into when no `--template` is given.
+pub const STRUCTURED_LATEX_SKELETON: &str = "\\documentclass[UTF8]{ctexart}
+\\usepackage{amsmath, amssymb}
+\\usepackage{hyperref}
+
+\\title{{{TITLE}}}
+\\author{论文阅读助手}
+\\date{\\today}
+
+\\begin{document}
+\\maketitle
+
+{{CONTENT}}
+
+\\end{document}
+";
+
+/// A note returned as JSON by the model in `--structured` mode.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct StructuredNote {
+    pub summary: String,
+    pub contributions: Vec<String>,
+    pub method: String,
+    pub results: String,
+}
+
+impl StructuredNote {
+    /// Parse and validate the model's response, tolerating a surrounding code fence.
+    pub fn parse(response: &str) -> Result<Self> {
+        let json = strip_code_fence(response);
+        let note: StructuredNote =
+            serde_json::from_str(json).context("response does not match the note schema")?;
+
+        if note.summary.trim().is_empty() {
+            anyhow::bail!("structured note has an empty summary");
+        }
+        if note.contributions.iter().all(|c| c.trim().is_empty()) {
+            anyhow::bail!("structured note lists no contributions");
+        }
+        Ok(note)
+    }
+
+    /// Render the note body (without document wrapper) in the given format.
+    pub fn render(&self, format: OutputFormat) -> String {
+        let contributions = self
+            .contributions
+            .iter()
+            .map(|c| c.trim())
+            .filter(|c| !c.is_empty());
+
+        match format {
+            OutputFormat::Latex => {
+                let items: Vec<String> = contributions.map(|c| format!("  \\item {c}")).collect();
+                format!(
+                    "\\section{{摘要}}\n{}\n\n\\section{{主要贡献}}\n\\begin{{itemize}}\n{}\n\\end{{itemize}}\n\n\
+                     \\section{{方法}}\n{}\n\n\\section{{实验结果}}\n{}",
+                    self.summary.trim(),
+                    items.join("\n"),
+                    self.method.trim(),
+                    self.results.trim(),
+                )
+            }
+            OutputFormat::Html => {
+                let items: Vec<String> = contributions
+                    .map(|c| format!("<li>{}</li>", escape_html(c)))
+                    .collect();
+                format!(
+                    "<h2>摘要</h2>\n<p>{}</p>\n<h2>主要贡献</h2>\n<ul>\n{}\n</ul>\n\
+                     <h2>方法</h2>\n<p>{}</p>\n<h2>实验结果</h2>\n<p>{}</p>",
+                    escape_html(self.summary.trim()),
+                    items.join("\n"),
+                    escape_html(self.method.trim()),
+                    escape_html(self.results.trim()),
+                )
+            }
+        }
+    }
+}
+
+/// Remove a ```json ... ``` wrapper some models add despite JSON mode.
+fn strip_code_fence(response: &str) -> &str {
+    let trimmed = response.trim();
+    let Some(rest) = trimmed.strip_prefix("```") else {
+        return trimmed;
+    };
+    let rest = rest.split_once('\n').map_or("", |(_, body)| body);
+    rest.trim_end().strip_suffix("```").unwrap_or(rest).trim()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: &str = r#"```json
+{
+  "summary": "提出了一种稀疏注意力机制。",
+  "contributions": ["线性复杂度的注意力", "在长文本上的新基准"],
+  "method": "使用 \\( O(n) \\) 的路由。",
+  "results": "在 LRA 上提升 2.1 分。"
+}
+```"#;
+
+    #[test]
+    fn test_parse_structured_response() {
+        let note = StructuredNote::parse(SAMPLE).unwrap();
+
+        assert_eq!(note.summary, "提出了一种稀疏注意力机制。");
+        assert_eq!(note.contributions.len(), 2);
+        assert_eq!(note.method, "使用 \\( O(n) \\) 的路由。");
+
+        let latex = note.render(OutputFormat::Latex);
+        assert!(
+            latex.contains("\\section{主要贡献}\n\\begin{itemize}\n  \\item 线性复杂度的注意力")
+        );
+        assert!(latex.contains("\\section{实验结果}\n在 LRA 上提升 2.1 分。"));
+    }
+
+    #[test]
+    fn test_invalid_structured_response_is_rejected() {
+        assert!(StructuredNote::parse("\\section{Intro} not json").is_err());
+        assert!(StructuredNote::parse(r#"{"summary": "x", "method": "y"}"#).is_err());
+        assert!(StructuredNote::parse(
+            r#"{"summary": " ", "contributions": ["a"], "method": "m", "results": "r"}"#
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,75 @@
+use std::time::Instant;
+
+/// Wall-clock time spent in each stage of processing one paper, in seconds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Serialize)]
+pub struct StageTimings {
+    pub download_secs: f64,
+    pub extract_secs: f64,
+    pub process_secs: f64,
+    pub llm_secs: f64,
+}
+
+impl StageTimings {
+    fn stages(&self) -> [(&'static str, f64); 4] {
+        [
+            ("download", self.download_secs),
+            ("extract", self.extract_secs),
+            ("process", self.process_secs),
+            ("LLM", self.llm_secs),
+        ]
+    }
+
+    /// The stage that took longest and its time.
+    pub fn slowest(&self) -> (&'static str, f64) {
+        self.stages()
+            .into_iter()
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .expect("there are stages")
+    }
+
+    pub fn add(&mut self, other: &StageTimings) {
+        self.download_secs += other.download_secs;
+        self.extract_secs += other.extract_secs;
+        self.process_secs += other.process_secs;
+        self.llm_secs += other.llm_secs;
+    }
+
+    /// One-line breakdown, e.g. `download 1.20s, extract 0.31s, process 0.05s, LLM 14.02s`.
+    pub fn summary(&self) -> String {
+        self.stages()
+            .iter()
+            .map(|(stage, secs)| format!("{stage} {secs:.2}s"))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// Seconds elapsed since `start`.
+pub fn secs_since(start: Instant) -> f64 {
+    start.elapsed().as_secs_f64()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_breakdown_and_slowest_stage() {
+        let mut total = StageTimings {
+            download_secs: 2.0,
+            extract_secs: 0.5,
+            process_secs: 0.25,
+            llm_secs: 1.0,
+        };
+        total.add(&StageTimings {
+            llm_secs: 3.0,
+            ..Default::default()
+        });
+
+        assert_eq!(total.slowest(), ("LLM", 4.0));
+        assert_eq!(
+            total.summary(),
+            "download 2.00s, extract 0.50s, process 0.25s, LLM 4.00s"
+        );
+    }
+}
@@ -0,0 +1,243 @@
+use regex::Regex;
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+/// Longest slug `slugify` produces, in bytes.
+pub const MAX_SLUG_LEN: usize = 60;
+
+/// Lowercase ASCII slug for file names, cite keys and anchors: accents are folded
+/// (`é` → `e`), every run of other characters becomes a single `-`, and long
+/// results are cut at a word boundary.
+pub fn slugify(text: &str) -> String {
+    let mut slug = String::new();
+    for c in text.nfkd().filter(|&c| !is_combining_mark(c)) {
+        match fold_letter(c) {
+            Some(folded) => slug.push_str(folded),
+            None if c.is_ascii_alphanumeric() => slug.push(c.to_ascii_lowercase()),
+            None if !slug.is_empty() && !slug.ends_with('-') => slug.push('-'),
+            None => {}
+        }
+    }
+
+    if slug.len() > MAX_SLUG_LEN {
+        let cut_mid_word = slug.as_bytes()[MAX_SLUG_LEN] != b'-';
+        slug.truncate(MAX_SLUG_LEN);
+        if cut_mid_word {
+            if let Some(end) = slug.rfind('-') {
+                slug.truncate(end);
+            }
+        }
+    }
+    slug.trim_end_matches('-').to_string()
+}
+
+/// Rough token count for English-ish text: one token per four characters, which
+/// is close enough to budget prompts without shipping a tokenizer.
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+/// Slug for a paper's title, falling back to its ID when extraction found no title.
+pub fn title_slug(title: &str, paper_id: &str) -> String {
+    let slug = if title.trim().eq_ignore_ascii_case("untitled") {
+        String::new()
+    } else {
+        slugify(title)
+    };
+    if slug.is_empty() {
+        slugify(paper_id)
+    } else {
+        slug
+    }
+}
+
+/// Turn the LaTeX spellings of accents, dashes, quotes and escaped specials that
+/// survive cleaning into plain Unicode text: `\'e` → `é`, `---` → `—`, `\&` → `&`,
+/// `~` → space. Citations become `[key]`. Math, verbatim text and `\url`/`\href`
+/// arguments are left as written, so `$f''(x)$` keeps its primes.
+pub fn unescape_latex(text: &str) -> String {
+    // An escaped `\$` is text, matched first so it doesn't open math
+    let protected = Regex::new(
+        r"(?s)(\\[$%&_#])|\$\$.*?\$\$|\$(?:[^$\\]|\\.)*\$|\\\[.*?\\\]|\\\(.*?\\\)|\\begin\{(?:equation|align|gather|multline|eqnarray|math|displaymath|verbatim|lstlisting|minted)\*?\}.*?\\end\{(?:equation|align|gather|multline|eqnarray|math|displaymath|verbatim|lstlisting|minted)\*?\}|\\verb\*?(?:\|[^|]*\||\+[^+]*\+|![^!]*!|/[^/]*/)|\\(?:url|href)\s*\{[^}]*\}",
+    )
+    .unwrap();
+    let mut unescaped = String::with_capacity(text.len());
+    let mut copied_to = 0;
+    for caps in protected.captures_iter(text) {
+        if caps.get(1).is_some() {
+            continue;
+        }
+        let span = caps.get(0).unwrap();
+        unescaped.push_str(&unescape_text(&text[copied_to..span.start()]));
+        unescaped.push_str(span.as_str());
+        copied_to = span.end();
+    }
+    unescaped.push_str(&unescape_text(&text[copied_to..]));
+    unescaped
+}
+
+/// `unescape_latex` for text with no math or verbatim in it.
+fn unescape_text(text: &str) -> String {
+    // `{\'e}` and `\'{e}` are both common; drop the group braces first, and
+    // dotless `\i` stands in for `i` under accents
+    let text = Regex::new(r"\{(\\[`'^\x22~=.]\s*\{?[A-Za-z]\}?)\}")
+        .unwrap()
+        .replace_all(text, "$1");
+    let text = Regex::new(r"\\i\b\s*").unwrap().replace_all(&text, "i");
+
+    let symbol_accent = Regex::new(r"\\([`'^\x22~=.])\s*(?:\{([A-Za-z])\}|([A-Za-z]))").unwrap();
+    let text = symbol_accent.replace_all(&text, |caps: &regex::Captures| {
+        let letter = caps.get(2).or(caps.get(3)).map_or("", |m| m.as_str());
+        accented(letter, &caps[1])
+    });
+    // Letter accents need braces or a space so `\v{s}` isn't confused with `\vspace`
+    let letter_accent = Regex::new(r"\\([cvuHrk])(?:\{([A-Za-z])\}|\s+([A-Za-z]))").unwrap();
+    let text = letter_accent.replace_all(&text, |caps: &regex::Captures| {
+        let letter = caps.get(2).or(caps.get(3)).map_or("", |m| m.as_str());
+        accented(letter, &caps[1])
+    });
+
+    let text = Regex::new(r"~?\\cite[a-zA-Z]*\*?(?:\[[^\]]*\])*\{([^}]*)\}")
+        .unwrap()
+        .replace_all(&text, |caps: &regex::Captures| {
+            let keys: Vec<&str> = caps[1].split(',').map(str::trim).collect();
+            format!(" [{}]", keys.join(", "))
+        });
+    let text = Regex::new(r"\\(?:ldots|dots|textellipsis)\b(?:\{\})?")
+        .unwrap()
+        .replace_all(&text, "…");
+
+    text.replace("---", "—")
+        .replace("--", "–")
+        .replace("``", "“")
+        .replace("''", "”")
+        .replace("\\&", "&")
+        .replace("\\%", "%")
+        .replace("\\_", "_")
+        .replace("\\#", "#")
+        .replace("\\$", "$")
+        .replace('~', " ")
+        .nfc()
+        .collect()
+}
+
+/// `letter` with the combining mark for a LaTeX accent command; NFC later
+/// composes the pair into one character where Unicode has one.
+fn accented(letter: &str, accent: &str) -> String {
+    let mark = match accent {
+        "`" => '\u{300}',
+        "'" => '\u{301}',
+        "^" => '\u{302}',
+        "~" => '\u{303}',
+        "=" => '\u{304}',
+        "u" => '\u{306}',
+        "." => '\u{307}',
+        "\"" => '\u{308}',
+        "r" => '\u{30a}',
+        "H" => '\u{30b}',
+        "v" => '\u{30c}',
+        "c" => '\u{327}',
+        "k" => '\u{328}',
+        _ => return letter.to_string(),
+    };
+    format!("{letter}{mark}")
+}
+
+/// ASCII spelling of letters that NFKD doesn't decompose into a base letter.
+fn fold_letter(c: char) -> Option<&'static str> {
+    match c {
+        'ß' => Some("ss"),
+        'æ' | 'Æ' => Some("ae"),
+        'œ' | 'Œ' => Some("oe"),
+        'ø' | 'Ø' => Some("o"),
+        'ł' | 'Ł' => Some("l"),
+        'đ' | 'Đ' | 'ð' => Some("d"),
+        'þ' | 'Þ' => Some("th"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unescape_accents() {
+        assert_eq!(
+            unescape_latex("Erd\\H{o}s and G\\\"odel"),
+            "Erdős and Gödel"
+        );
+        assert_eq!(
+            unescape_latex("{\\'E}cole, \\'{e}t\\'e, na\\\"{\\i}ve"),
+            "École, été, naïve"
+        );
+        assert_eq!(
+            unescape_latex("Fran\\c{c}ois Ho\\v{s}ek, \\~n"),
+            "François Hošek, ñ"
+        );
+        // Commands that merely start with an accent letter are left alone
+        assert_eq!(unescape_latex("\\vspace{1em}"), "\\vspace{1em}");
+    }
+
+    #[test]
+    fn test_unescape_dashes_and_specials() {
+        assert_eq!(
+            unescape_latex("pages 1--3 --- or so\\ldots{} ``quoted''"),
+            "pages 1–3 — or so… “quoted”"
+        );
+        assert_eq!(
+            unescape_latex("R\\&D spent 50\\% on my\\_var \\#1 for \\$5"),
+            "R&D spent 50% on my_var #1 for $5"
+        );
+        assert_eq!(
+            unescape_latex("as shown~\\cite{vaswani2017, he2016} and Fig.~2"),
+            "as shown [vaswani2017, he2016] and Fig. 2"
+        );
+    }
+
+    #[test]
+    fn test_math_and_urls_are_not_unescaped() {
+        assert_eq!(
+            unescape_latex("If $f''(x) > 0$ then ``convex'' -- see \\url{a--b~c}"),
+            "If $f''(x) > 0$ then “convex” – see \\url{a--b~c}"
+        );
+        assert_eq!(
+            unescape_latex("costs \\$5 -- and \\[ g'' = x--y \\] \\verb|a--b| \\begin{equation}h''\\end{equation}"),
+            "costs $5 – and \\[ g'' = x--y \\] \\verb|a--b| \\begin{equation}h''\\end{equation}"
+        );
+    }
+
+    #[test]
+    fn test_slugify_folds_accents() {
+        assert_eq!(slugify("Schrödinger's Équation"), "schrodinger-s-equation");
+        assert_eq!(slugify("Große Łódź Ærø"), "grosse-lodz-aero");
+        assert_eq!(
+            slugify("  --Attention  Is All You Need!  "),
+            "attention-is-all-you-need"
+        );
+    }
+
+    #[test]
+    fn test_slugify_caps_long_titles_at_a_word_boundary() {
+        let title = "A Very Long Title About Scaling Laws for Neural Language Models and Their Consequences";
+        let slug = slugify(title);
+
+        assert!(slug.len() <= MAX_SLUG_LEN, "{slug}");
+        assert_eq!(
+            slug,
+            "a-very-long-title-about-scaling-laws-for-neural-language"
+        );
+    }
+
+    #[test]
+    fn test_empty_and_untitled_fall_back_to_id() {
+        assert_eq!(slugify(""), "");
+        assert_eq!(slugify("∑∫"), "");
+        assert_eq!(title_slug("", "2401.08027"), "2401-08027");
+        assert_eq!(title_slug("Untitled", "2401.08027"), "2401-08027");
+        assert_eq!(
+            title_slug("Deep Residual Learning", "1512.03385"),
+            "deep-residual-learning"
+        );
+    }
+}
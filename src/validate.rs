@@ -0,0 +1,439 @@
+use anyhow::{Context, Result};
+use regex::Regex;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use walkdir::WalkDir;
+
+use crate::config::RunOptions;
+use crate::figures;
+use crate::note_generator;
+
+/// Extensions LaTeX tries for an `\includegraphics` path written without one.
+const GRAPHICS_EXTENSIONS: [&str; 6] = ["", ".pdf", ".png", ".jpg", ".jpeg", ".eps"];
+
+/// What `validate` found in one note.
+#[derive(Debug, Default, PartialEq)]
+pub struct NoteReport {
+    pub path: PathBuf,
+    /// `\includegraphics` paths that don't resolve from the note's directory
+    pub missing_figures: Vec<String>,
+    /// Broken paths rewritten by `--fix-paths`, as (old, new)
+    pub fixed_figures: Vec<(String, String)>,
+    pub syntax_errors: Vec<String>,
+    /// First error reported by `pdflatex`, with `--compile`
+    pub compile_error: Option<String>,
+}
+
+impl NoteReport {
+    pub fn is_ok(&self) -> bool {
+        self.missing_figures.is_empty()
+            && self.syntax_errors.is_empty()
+            && self.compile_error.is_none()
+    }
+}
+
+/// Check every `.tex` note, print the problems, and fail if any remain.
+pub fn run(options: &RunOptions, fix_paths: bool, compile: bool) -> Result<()> {
+    let pdflatex = if compile {
+        let found = figures::find_in_path("pdflatex");
+        if found.is_none() {
+            eprintln!("Warning: pdflatex not found in PATH; skipping --compile");
+        }
+        found
+    } else {
+        None
+    };
+
+    let notes = find_notes(&options.notes_dir)?;
+    let mut failed = 0;
+    for note in &notes {
+        let report = validate_note(note, options, fix_paths, pdflatex.as_deref())?;
+        if report.is_ok() && report.fixed_figures.is_empty() {
+            continue;
+        }
+        println!("{}", report.path.display());
+        for (old, new) in &report.fixed_figures {
+            println!("  fixed figure path: {old} -> {new}");
+        }
+        for figure in &report.missing_figures {
+            println!("  missing figure: {figure}");
+        }
+        for error in &report.syntax_errors {
+            println!("  syntax: {error}");
+        }
+        if let Some(error) = &report.compile_error {
+            println!("  pdflatex: {error}");
+        }
+        if !report.is_ok() {
+            failed += 1;
+        }
+    }
+
+    println!(
+        "Checked {} note(s) in {}",
+        notes.len(),
+        options.notes_dir.display()
+    );
+    if failed > 0 {
+        anyhow::bail!("{failed} note(s) have problems");
+    }
+    Ok(())
+}
+
+/// Every `.tex` note in the per-paper directories of `notes_dir`.
+fn find_notes(notes_dir: &Path) -> Result<Vec<PathBuf>> {
+    if !notes_dir.is_dir() {
+        anyhow::bail!("Notes directory {} does not exist", notes_dir.display());
+    }
+    let mut notes: Vec<PathBuf> = WalkDir::new(notes_dir)
+        .min_depth(2)
+        .into_iter()
+        .flatten()
+        .map(|entry| entry.into_path())
+        .filter(|path| path.is_file() && path.extension().is_some_and(|ext| ext == "tex"))
+        .collect();
+    notes.sort();
+    Ok(notes)
+}
+
+pub fn validate_note(
+    note: &Path,
+    options: &RunOptions,
+    fix_paths: bool,
+    pdflatex: Option<&Path>,
+) -> Result<NoteReport> {
+    let original = std::fs::read_to_string(note)
+        .with_context(|| format!("Failed to read {}", note.display()))?;
+    let note_dir = note.parent().unwrap_or(Path::new(""));
+    let mut report = NoteReport {
+        path: note.to_path_buf(),
+        ..Default::default()
+    };
+
+    // Only `\includegraphics` arguments are rewritten, not the same path elsewhere
+    let mut content = String::with_capacity(original.len());
+    let mut copied_to = 0;
+    for (argument, reference) in graphics_spans(&original) {
+        if resolve_figure(note_dir, &reference).is_some() {
+            continue;
+        }
+        let replacement = if fix_paths {
+            find_replacement(note, &reference, options)?
+        } else {
+            None
+        };
+        match replacement {
+            Some(new) => {
+                content.push_str(&original[copied_to..argument.start]);
+                content.push_str(&new);
+                copied_to = argument.end;
+                report.fixed_figures.push((reference, new));
+            }
+            None => report.missing_figures.push(reference),
+        }
+    }
+    content.push_str(&original[copied_to..]);
+    if !report.fixed_figures.is_empty() {
+        std::fs::write(note, &content)
+            .with_context(|| format!("Failed to write {}", note.display()))?;
+    }
+
+    report.syntax_errors = check_syntax(&content);
+    if let Some(pdflatex) = pdflatex {
+        report.compile_error = compile_error(pdflatex, note)?;
+    }
+    Ok(report)
+}
+
+/// Every `\\includegraphics` path in a note, in order, with where its braced
+/// argument sits in `content`.
+pub fn graphics_spans(content: &str) -> Vec<(std::ops::Range<usize>, String)> {
+    let graphics_re = Regex::new(r"\\includegraphics\s*(?:\[[^\]]*\])?\s*\{([^}]*)\}").unwrap();
+    graphics_re
+        .captures_iter(content)
+        .map(|caps| {
+            let argument = caps.get(1).unwrap();
+            (argument.range(), argument.as_str().trim().to_string())
+        })
+        .collect()
+}
+
+/// The file an `\\includegraphics` path names, looked up from the note's directory
+/// with the extensions LaTeX would try.
+pub fn resolve_figure(note_dir: &Path, reference: &str) -> Option<PathBuf> {
+    GRAPHICS_EXTENSIONS
+        .iter()
+        .map(|ext| note_dir.join(format!("{reference}{ext}")))
+        .find(|path| path.is_file())
+}
+
+/// A path from the note's directory to a file in the paper's working directory
+/// with the same name as the broken reference.
+fn find_replacement(note: &Path, reference: &str, options: &RunOptions) -> Result<Option<String>> {
+    let Some(paper_dir) = note
+        .parent()
+        .and_then(|dir| dir.strip_prefix(&options.notes_dir).ok())
+    else {
+        return Ok(None);
+    };
+    let Some(name) = Path::new(reference).file_name().and_then(|n| n.to_str()) else {
+        return Ok(None);
+    };
+    let files: Vec<String> = WalkDir::new(options.work_dir.join(paper_dir))
+        .into_iter()
+        .flatten()
+        .filter(|entry| entry.file_type().is_file())
+        .map(|entry| entry.path().to_string_lossy().into_owned())
+        .collect();
+    let Some(found) = figures::resolve_reference(&files, name) else {
+        return Ok(None);
+    };
+    if Path::new(found).is_absolute() {
+        return Ok(Some(found.clone()));
+    }
+    Ok(Some(format!(
+        "{}{found}",
+        note_generator::path_to_working_dir(&options.notes_dir)?
+    )))
+}
+
+/// `content` with every comment blanked out by spaces, so byte offsets still line up.
+fn blank_comments(content: &str) -> String {
+    let comment_re = Regex::new(r"(?m)(^|[^\\])(%.*)$").unwrap();
+    comment_re
+        .replace_all(content, |caps: &regex::Captures| {
+            format!("{}{}", &caps[1], " ".repeat(caps[2].len()))
+        })
+        .into_owned()
+}
+
+/// Unbalanced braces and mismatched `\begin`/`\end` pairs, ignoring comments and
+/// escaped braces.
+pub fn check_syntax(content: &str) -> Vec<String> {
+    let content = blank_comments(content);
+    let mut errors = Vec::new();
+
+    let mut depth: i64 = 0;
+    let mut escaped = false;
+    for c in content.chars() {
+        match c {
+            '\\' => {
+                escaped = !escaped;
+                continue;
+            }
+            '{' if !escaped => depth += 1,
+            '}' if !escaped => {
+                depth -= 1;
+                if depth < 0 {
+                    errors.push("unmatched closing brace".to_string());
+                    depth = 0;
+                }
+            }
+            _ => {}
+        }
+        escaped = false;
+    }
+    if depth > 0 {
+        errors.push(format!("{depth} unclosed brace(s)"));
+    }
+
+    let env_re = Regex::new(r"\\(begin|end)\s*\{([^}]*)\}").unwrap();
+    let mut open: Vec<String> = Vec::new();
+    for caps in env_re.captures_iter(&content) {
+        let name = caps[2].trim().to_string();
+        if &caps[1] == "begin" {
+            open.push(name);
+        } else if open.last() == Some(&name) {
+            open.pop();
+        } else {
+            errors.push(format!("\\end{{{name}}} without a matching \\begin"));
+        }
+    }
+    for name in open {
+        errors.push(format!("\\begin{{{name}}} is never closed"));
+    }
+    errors
+}
+
+/// Close what a generated note left open: an environment still open at the `\end`
+/// of an enclosing one (or at the end of the note) is ended there, and an unclosed
+/// brace is closed at the end of its line. Stray closers are left for
+/// `check_syntax` to report. Returns the repaired note and a description of each fix.
+pub fn repair_syntax(content: &str) -> (String, Vec<String>) {
+    let scan = blank_comments(content);
+    let mut insertions: Vec<(usize, String)> = Vec::new();
+    let mut fixes = Vec::new();
+
+    let mut open_braces = Vec::new();
+    let mut escaped = false;
+    for (offset, c) in scan.char_indices() {
+        match c {
+            '\\' => {
+                escaped = !escaped;
+                continue;
+            }
+            '{' if !escaped => open_braces.push(offset),
+            '}' if !escaped => {
+                open_braces.pop();
+            }
+            _ => {}
+        }
+        escaped = false;
+    }
+    for offset in open_braces {
+        let rest = &scan[offset..];
+        let line_end = offset + rest.find('\n').unwrap_or(rest.len());
+        // Before any trailing comment, which is blank in `scan`
+        let end = offset + scan[offset..line_end].trim_end().len();
+        insertions.push((end, "}".to_string()));
+        let line_number = content[..offset].matches('\n').count() + 1;
+        fixes.push(format!("closed brace opened on line {line_number}"));
+    }
+
+    let env_re = Regex::new(r"\\(begin|end)\s*\{([^}]*)\}").unwrap();
+    let mut open: Vec<String> = Vec::new();
+    for caps in env_re.captures_iter(&scan) {
+        let name = caps[2].trim().to_string();
+        if &caps[1] == "begin" {
+            open.push(name);
+            continue;
+        }
+        let Some(position) = open.iter().rposition(|open_name| *open_name == name) else {
+            continue;
+        };
+        let start = caps.get(0).unwrap().start();
+        for unclosed in open.drain(position + 1..).rev() {
+            insertions.push((start, format!("\\end{{{unclosed}}}\n")));
+            fixes.push(format!("closed \\begin{{{unclosed}}}"));
+        }
+        open.pop();
+    }
+    for unclosed in open.into_iter().rev() {
+        insertions.push((content.len(), format!("\n\\end{{{unclosed}}}")));
+        fixes.push(format!("closed \\begin{{{unclosed}}}"));
+    }
+
+    // A stable sort keeps braces ahead of environment ends at the same offset
+    insertions.sort_by_key(|(offset, _)| *offset);
+    let mut repaired = content.to_string();
+    for (offset, text) in insertions.into_iter().rev() {
+        repaired.insert_str(offset, &text);
+    }
+    (repaired, fixes)
+}
+
+/// Run pdflatex in draft mode (no PDF written) and return its first error, if any.
+fn compile_error(pdflatex: &Path, note: &Path) -> Result<Option<String>> {
+    let build_dir = tempfile::tempdir()?;
+    let note_dir = note.parent().unwrap_or(Path::new("."));
+    let output = Command::new(pdflatex)
+        .args(["-interaction=nonstopmode", "-halt-on-error", "-draftmode"])
+        .arg(format!("-output-directory={}", build_dir.path().display()))
+        .arg(note.file_name().context("note has no file name")?)
+        .current_dir(note_dir)
+        .output()
+        .with_context(|| format!("Failed to run {}", pdflatex.display()))?;
+    if output.status.success() {
+        return Ok(None);
+    }
+    let log = String::from_utf8_lossy(&output.stdout);
+    Ok(Some(
+        log.lines()
+            .find(|line| line.starts_with('!'))
+            .unwrap_or("compilation failed")
+            .to_string(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Settings;
+
+    #[test]
+    fn test_missing_figure_is_flagged_and_fixed() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut options = RunOptions::from_settings(Settings::default()).unwrap();
+        options.notes_dir = dir.path().join("tex");
+        options.work_dir = dir.path().join("output");
+        let note_dir = options.notes_dir.join("2401.08027");
+        std::fs::create_dir_all(&note_dir).unwrap();
+        std::fs::write(note_dir.join("here.png"), "").unwrap();
+        let extracted = options.work_dir.join("2401.08027/extracted/figs");
+        std::fs::create_dir_all(&extracted).unwrap();
+        std::fs::write(extracted.join("arch.pdf"), "").unwrap();
+        let note = note_dir.join("2401.08027.tex");
+        std::fs::write(
+            &note,
+            "\\section{Method}\n\\includegraphics[width=0.5\\linewidth]{../../output/2401.08027/arch.pdf}\n\
+             \\includegraphics{here}\n\\includegraphics{gone.png}\n\
+             Source: \\texttt{../../output/2401.08027/arch.pdf}\n",
+        )
+        .unwrap();
+
+        let report = validate_note(&note, &options, false, None).unwrap();
+        assert_eq!(
+            report.missing_figures,
+            ["../../output/2401.08027/arch.pdf", "gone.png"]
+        );
+        assert!(report.syntax_errors.is_empty(), "{report:?}");
+        assert!(!report.is_ok());
+
+        let report = validate_note(&note, &options, true, None).unwrap();
+        let fixed = format!(
+            "{}/output/2401.08027/extracted/figs/arch.pdf",
+            dir.path().display()
+        );
+        assert_eq!(
+            report.fixed_figures,
+            [(
+                "../../output/2401.08027/arch.pdf".to_string(),
+                fixed.clone()
+            )]
+        );
+        assert_eq!(report.missing_figures, ["gone.png"]);
+        let content = std::fs::read_to_string(&note).unwrap();
+        assert!(content.contains(&format!("{{{fixed}}}")), "{content}");
+        assert!(
+            content.contains("\\texttt{../../output/2401.08027/arch.pdf}"),
+            "{content}"
+        );
+    }
+
+    #[test]
+    fn test_unclosed_environment_is_repaired() {
+        let note = "\\begin{document}\n\\section{Method}\n\\begin{itemize}\n\
+                    \\item \\textbf{Key idea % why\n\\end{document}\n";
+
+        let (repaired, fixes) = repair_syntax(note);
+
+        assert_eq!(
+            repaired,
+            "\\begin{document}\n\\section{Method}\n\\begin{itemize}\n\
+             \\item \\textbf{Key idea} % why\n\\end{itemize}\n\\end{document}\n"
+        );
+        assert_eq!(
+            fixes,
+            ["closed brace opened on line 4", "closed \\begin{itemize}"]
+        );
+        assert!(check_syntax(&repaired).is_empty());
+        assert_eq!(repair_syntax(&repaired), (repaired.clone(), vec![]));
+
+        let (repaired, fixes) = repair_syntax("\\begin{enumerate}\\item a");
+        assert_eq!(repaired, "\\begin{enumerate}\\item a\n\\end{enumerate}");
+        assert_eq!(fixes, ["closed \\begin{enumerate}"]);
+    }
+
+    #[test]
+    fn test_syntax_check() {
+        assert!(check_syntax("\\begin{itemize}\\item {a} 50\\% \\{\\end{itemize} % {").is_empty());
+        assert_eq!(
+            check_syntax("\\begin{itemize}\\textbf{a\\end{enumerate}"),
+            [
+                "1 unclosed brace(s)",
+                "\\end{enumerate} without a matching \\begin",
+                "\\begin{itemize} is never closed"
+            ]
+        );
+    }
+}
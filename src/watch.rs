@@ -0,0 +1,147 @@
+use anyhow::Result;
+use std::collections::HashSet;
+use std::future::Future;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+/// The URLs of a watched list that haven't been handed out yet.
+#[derive(Debug, Default)]
+pub struct UrlList {
+    seen: HashSet<String>,
+    /// Modification time and size when the list was last read
+    last_read: Option<(SystemTime, u64)>,
+}
+
+impl UrlList {
+    /// URLs added since the last call, in file order and each only once. The file
+    /// isn't read again while its size and modification time are unchanged.
+    pub fn new_urls(&mut self, path: &Path) -> Result<Vec<String>> {
+        let metadata = std::fs::metadata(path)?;
+        let stamp = (metadata.modified()?, metadata.len());
+        if self.last_read == Some(stamp) {
+            return Ok(Vec::new());
+        }
+        self.last_read = Some(stamp);
+
+        Ok(crate::read_url_list(path)?
+            .into_iter()
+            .map(|url| url.trim().to_string())
+            .filter(|url| self.seen.insert(url.clone()))
+            .collect())
+    }
+}
+
+/// Hand every URL in `path` to `process`, then check the file every `interval` and
+/// hand over the lines appended since, until `stop` completes. A batch that is
+/// running when `stop` fires is finished first.
+pub async fn watch<F, Fut>(
+    path: &Path,
+    interval: Duration,
+    stop: impl Future<Output = ()>,
+    mut process: F,
+) -> Result<()>
+where
+    F: FnMut(Vec<String>) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let mut list = UrlList::default();
+    let urls = list.new_urls(path)?;
+    if !urls.is_empty() {
+        process(urls).await?;
+    }
+    println!("Watching {} for new URLs (Ctrl-C to stop)", path.display());
+
+    tokio::pin!(stop);
+    loop {
+        tokio::select! {
+            _ = &mut stop => break,
+            _ = tokio::time::sleep(interval) => {}
+        }
+        // Editors that save by replacing the file can leave it missing for a moment
+        let urls = match list.new_urls(path) {
+            Ok(urls) => urls,
+            Err(e) => {
+                eprintln!("Warning: could not read {}: {e}", path.display());
+                continue;
+            }
+        };
+        if !urls.is_empty() {
+            println!("Found {} new URL(s) in {}", urls.len(), path.display());
+            process(urls).await?;
+        }
+    }
+
+    println!("Stopped watching {}", path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+
+    #[tokio::test]
+    async fn test_appended_urls_are_processed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("urls.txt");
+        std::fs::write(&path, "https://arxiv.org/abs/2401.00001\n\n").unwrap();
+
+        let batches: Arc<Mutex<Vec<Vec<String>>>> = Arc::default();
+        let (stop_tx, stop_rx) = tokio::sync::oneshot::channel::<()>();
+        let watcher = {
+            let batches = batches.clone();
+            let path = path.clone();
+            tokio::spawn(async move {
+                watch(
+                    &path,
+                    Duration::from_millis(10),
+                    async {
+                        let _ = stop_rx.await;
+                    },
+                    move |urls| {
+                        batches.lock().unwrap().push(urls);
+                        async { Ok(()) }
+                    },
+                )
+                .await
+            })
+        };
+        let wait_for_batches = |n: usize| {
+            let batches = batches.clone();
+            async move {
+                for _ in 0..200 {
+                    if batches.lock().unwrap().len() >= n {
+                        return;
+                    }
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                panic!("watcher did not process batch {n}");
+            }
+        };
+
+        wait_for_batches(1).await;
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        // The first URL again is already done
+        writeln!(
+            file,
+            "https://arxiv.org/abs/2401.00002\nhttps://arxiv.org/abs/2401.00001"
+        )
+        .unwrap();
+        drop(file);
+        wait_for_batches(2).await;
+        stop_tx.send(()).unwrap();
+        watcher.await.unwrap().unwrap();
+
+        assert_eq!(
+            *batches.lock().unwrap(),
+            [
+                vec!["https://arxiv.org/abs/2401.00001".to_string()],
+                vec!["https://arxiv.org/abs/2401.00002".to_string()],
+            ]
+        );
+    }
+}